@@ -0,0 +1,356 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: arith.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The arith module provides a binary arithmetic coder with adaptive probability models,
+as an entropy stage to experiment with for custom formats.
+
+Each bit is coded with a BitModel, which tracks the probability of the bit being 1 and
+adapts to the bits coded with it.  The encoder and the decoder must use the same
+sequence of models to code the same sequence of bits.  BitTreeModel codes the n-bit
+symbols with a binary tree of BitModels.
+
+The coder is the carry-less 32-bit coder with 12-bit probabilities.
+
+Example:
+
+    let mut model = BitModel::new();
+    let mut encoder = ArithEncoder::new();
+    encoder.encode(true, &mut model);
+    let buf = encoder.finish();
+
+    let mut model = BitModel::new();
+    let mut decoder = ArithDecoder::new(buf);
+    let bit = decoder.decode(&mut model);
+
+*/
+
+
+use std::vec;
+
+
+/// The number of bits of the probabilities.
+pub static PROB_BITS: uint = 12;
+/// The probability scale, representing the probability of 1.0.
+pub static PROB_SCALE: u32 = 1 << PROB_BITS;
+/// The default adaptation rate.  A model moves 1/32 of the way toward each coded bit.
+pub static DEFAULT_ADAPT_SHIFT: uint = 5;
+
+
+/// Adaptive probability model of a binary symbol.
+#[deriving(Clone)]
+pub struct BitModel {
+    priv p1:            u32,
+    priv adapt_shift:   uint,
+}
+
+impl BitModel {
+
+    /// Create a model starting at probability 0.5 with the default adaptation rate.
+    pub fn new() -> BitModel {
+        BitModel::with_adapt_shift(DEFAULT_ADAPT_SHIFT)
+    }
+
+    /// Create a model starting at probability 0.5.  A smaller adapt_shift adapts faster.
+    pub fn with_adapt_shift(adapt_shift: uint) -> BitModel {
+        BitModel {
+            p1:             PROB_SCALE / 2,
+            adapt_shift:    if adapt_shift < 1 { 1 } else if adapt_shift > PROB_BITS - 1 { PROB_BITS - 1 } else { adapt_shift },
+        }
+    }
+
+    /// The probability of the next bit being 1, scaled by PROB_SCALE.
+    pub fn p1(&self) -> u32 {
+        self.p1
+    }
+
+    /// Adapt the model to the coded bit.  The probability stays within (0, PROB_SCALE).
+    pub fn update(&mut self, bit: bool) {
+        if bit {
+            self.p1 += (PROB_SCALE - self.p1) >> self.adapt_shift;
+        } else {
+            self.p1 -= self.p1 >> self.adapt_shift;
+        }
+    }
+
+}
+
+
+/// Adaptive model of the n-bit symbols, coding the bits from the most significant one
+/// with a binary tree of BitModels.
+#[deriving(Clone)]
+pub struct BitTreeModel {
+    priv num_bits:      uint,
+    priv models:        ~[BitModel],
+}
+
+impl BitTreeModel {
+
+    /// Create a model for the symbols of num_bits bits.
+    pub fn new(num_bits: uint) -> BitTreeModel {
+        BitTreeModel {
+            num_bits:   num_bits,
+            models:     vec::from_elem(1u << num_bits, BitModel::new()),
+        }
+    }
+
+    /// The number of bits of the symbols.
+    pub fn num_bits(&self) -> uint {
+        self.num_bits
+    }
+
+    /// Encode the low num_bits bits of the symbol.
+    pub fn encode(&mut self, encoder: &mut ArithEncoder, symbol: uint) {
+        let mut node = 1u;
+        let mut i = self.num_bits;
+        while i > 0 {
+            i -= 1;
+            let bit = (symbol >> i) & 1 == 1;
+            encoder.encode(bit, &mut self.models[node]);
+            node = (node << 1) | (bit as uint);
+        }
+    }
+
+    /// Decode a symbol of num_bits bits.
+    pub fn decode(&mut self, decoder: &mut ArithDecoder) -> uint {
+        let mut node = 1u;
+        for _ in range(0, self.num_bits) {
+            let bit = decoder.decode(&mut self.models[node]);
+            node = (node << 1) | (bit as uint);
+        }
+        node - (1u << self.num_bits)
+    }
+
+}
+
+
+/// Binary arithmetic encoder, coding the bits into a byte buffer.
+pub struct ArithEncoder {
+    priv low:           u32,
+    priv high:          u32,
+    priv out_buf:       ~[u8],
+}
+
+impl ArithEncoder {
+
+    /// Create an encoder.
+    pub fn new() -> ArithEncoder {
+        ArithEncoder {
+            low:        0,
+            high:       0xFFFFFFFF,
+            out_buf:    ~[],
+        }
+    }
+
+    /// Encode the bit with the model's probability and adapt the model to it.
+    pub fn encode(&mut self, bit: bool, model: &mut BitModel) {
+        let mid = split_range(self.low, self.high, model.p1());
+        if bit {
+            self.high = mid;
+        } else {
+            self.low = mid + 1;
+        }
+        model.update(bit);
+        // Shift out the leading bytes settled by the range.
+        while (self.low ^ self.high) & 0xFF000000 == 0 {
+            self.out_buf.push((self.high >> 24) as u8);
+            self.low = self.low << 8;
+            self.high = (self.high << 8) | 0xFF;
+        }
+    }
+
+    /// Encode the low count bits of value from the most significant one, with probability 0.5 each.
+    pub fn encode_direct(&mut self, value: u32, count: uint) {
+        let mut i = count;
+        while i > 0 {
+            i -= 1;
+            let mut model = BitModel::new();
+            self.encode((value >> i) & 1 == 1, &mut model);
+        }
+    }
+
+    /// The number of bytes encoded so far, not counting the pending bytes flushed by finish().
+    pub fn encoded_len(&self) -> uint {
+        self.out_buf.len()
+    }
+
+    /// Flush the pending range and return the encoded bytes.
+    pub fn finish(self) -> ~[u8] {
+        let mut out_buf = self.out_buf;
+        let low = self.low;
+        out_buf.push((low >> 24) as u8);
+        out_buf.push((low >> 16) as u8);
+        out_buf.push((low >> 8) as u8);
+        out_buf.push(low as u8);
+        out_buf
+    }
+
+}
+
+
+/// Binary arithmetic decoder, decoding the bits from the bytes of an ArithEncoder.
+pub struct ArithDecoder {
+    priv low:           u32,
+    priv high:          u32,
+    priv code:          u32,
+    priv in_buf:        ~[u8],
+    priv in_pos:        uint,
+}
+
+impl ArithDecoder {
+
+    /// Create a decoder over the encoded bytes.
+    pub fn new(in_buf: ~[u8]) -> ArithDecoder {
+        let mut decoder = ArithDecoder {
+            low:        0,
+            high:       0xFFFFFFFF,
+            code:       0,
+            in_buf:     in_buf,
+            in_pos:     0,
+        };
+        for _ in range(0, 4) {
+            decoder.code = (decoder.code << 8) | (decoder.next_byte() as u32);
+        }
+        decoder
+    }
+
+    // Past the end of the input the decoder reads zero, as the encoder's flush leaves it.
+    fn next_byte(&mut self) -> u8 {
+        if self.in_pos < self.in_buf.len() {
+            self.in_pos += 1;
+            self.in_buf[self.in_pos - 1]
+        } else {
+            0
+        }
+    }
+
+    /// Decode a bit with the model's probability and adapt the model to it.
+    pub fn decode(&mut self, model: &mut BitModel) -> bool {
+        let mid = split_range(self.low, self.high, model.p1());
+        let bit = self.code <= mid;
+        if bit {
+            self.high = mid;
+        } else {
+            self.low = mid + 1;
+        }
+        model.update(bit);
+        while (self.low ^ self.high) & 0xFF000000 == 0 {
+            self.low = self.low << 8;
+            self.high = (self.high << 8) | 0xFF;
+            self.code = (self.code << 8) | (self.next_byte() as u32);
+        }
+        bit
+    }
+
+    /// Decode count bits coded by encode_direct(), returning them as the low bits of the value.
+    pub fn decode_direct(&mut self, count: uint) -> u32 {
+        let mut value = 0u32;
+        for _ in range(0, count) {
+            let mut model = BitModel::new();
+            value = (value << 1) | (self.decode(&mut model) as u32);
+        }
+        value
+    }
+
+    /// The number of input bytes consumed so far.
+    pub fn consumed_len(&self) -> uint {
+        self.in_pos
+    }
+
+}
+
+
+// Split [low, high] at the point given by the probability of 1.  The 1 takes [low, mid].
+fn split_range(low: u32, high: u32, p1: u32) -> u32 {
+    low + ((high - low) >> PROB_BITS) * p1
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use super::BitModel;
+    use super::BitTreeModel;
+    use super::ArithEncoder;
+    use super::ArithDecoder;
+    use super::PROB_SCALE;
+
+
+    #[test]
+    fn test_bit_model() {
+        let mut model = BitModel::new();
+        assert!(model.p1() == PROB_SCALE / 2);
+        for _ in range(0, 1000) {
+            model.update(true);
+        }
+        assert!(model.p1() > PROB_SCALE * 9 / 10 && model.p1() < PROB_SCALE);
+        for _ in range(0, 1000) {
+            model.update(false);
+        }
+        assert!(model.p1() < PROB_SCALE / 10 && model.p1() > 0);
+    }
+
+    #[test]
+    fn test_encode_decode_bits() {
+        let bits = vec::from_fn(5000, |i| (i * 7919) % 13 < 3);
+
+        let mut model = BitModel::new();
+        let mut encoder = ArithEncoder::new();
+        for &bit in bits.iter() {
+            encoder.encode(bit, &mut model);
+        }
+        let buf = encoder.finish();
+        // The skewed bits should take well under one bit each.
+        assert!(buf.len() < bits.len() / 8);
+
+        let mut model = BitModel::new();
+        let mut decoder = ArithDecoder::new(buf.clone());
+        for &bit in bits.iter() {
+            assert_eq!(decoder.decode(&mut model), bit);
+        }
+        assert!(decoder.consumed_len() <= buf.len());
+    }
+
+    #[test]
+    fn test_encode_decode_symbols() {
+        let symbols = vec::from_fn(2000, |i| if i % 5 == 0 { (i * 31) % 256 } else { 65 + i % 4 });
+
+        let mut tree = BitTreeModel::new(8);
+        let mut encoder = ArithEncoder::new();
+        for &symbol in symbols.iter() {
+            tree.encode(&mut encoder, symbol);
+            encoder.encode_direct(symbol as u32, 3);
+        }
+        let buf = encoder.finish();
+
+        let mut tree = BitTreeModel::new(8);
+        let mut decoder = ArithDecoder::new(buf);
+        for &symbol in symbols.iter() {
+            assert_eq!(tree.decode(&mut decoder), symbol);
+            assert_eq!(decoder.decode_direct(3), (symbol & 7) as u32);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let encoder = ArithEncoder::new();
+        let buf = encoder.finish();
+        assert_eq!(buf.len(), 4);
+        let mut decoder = ArithDecoder::new(buf);
+        assert_eq!(decoder.decode_direct(0), 0);
+    }
+
+}
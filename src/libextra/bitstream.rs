@@ -0,0 +1,671 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: bitstream.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The bitstream module packs variable-length bit fields into bytes.  The order the
+bits fill each byte in is the type parameter of the writer and the reader: Lsb
+fills each byte from the least significant bit up, as RFC 1951 lays out DEFLATE,
+so the Huffman codes are written bit-reversed; Msb fills each byte from the most
+significant bit down, as bzip2 and JPEG do.  A writer and a reader of different
+orders don't mix, e.g. in copy_bits(), and the order costs no branch per field.
+DynBitWriter and DynBitReader pick the order at runtime instead.
+
+BitWriter collects the completed bytes in memory; take_bytes() hands them out as
+they are produced, while the last partial byte stays until more bits complete it
+or align_byte() pads it.
+
+BitReader goes the other way.  It pulls the input bytes in only as the bits are
+asked for, and keeps them until they are consumed, so a field cut off at the end
+of one input buffer is read again whole after the next buffer comes in.  bits()
+and symbols() iterate over the single bits or the fixed-width fields of the input.
+
+copy_bits() passes a run of bits from a BitReader to a BitWriter untouched.
+
+Example:
+
+    let mut writer: BitWriter<Lsb> = BitWriter::new();
+    writer.write_bits(1, 1);
+    writer.write_bits(0x1F, 5);
+    writer.align_byte();
+    let buf = writer.take_bytes();
+
+    let mut reader: BitReader<Lsb> = BitReader::new();
+    let mut in_pos = 0;
+    if reader.fill(buf, &mut in_pos, 6) {
+        let value = reader.peek_bits(1, 5);
+        reader.consume(6);
+    }
+
+*/
+
+
+use std::{num, vec, util};
+
+
+/// The maximum number of bits written in one write_bits() call.
+pub static MAX_WRITE_BITS : uint = 24;
+
+/// The maximum number of bits BitReader holds for peeking.
+pub static MAX_READ_BITS : uint = 56;
+
+
+/// The order the bits fill each byte in, Lsb or Msb.  The bit buffers of BitWriter and BitReader
+/// hold their bit_count bits in the low bits of a u64, in the order.
+pub trait BitOrder {
+    /// The order value kept in the writer or the reader.
+    fn new() -> Self;
+    /// Add the count bits of value, with no other bits set, after the bit_count bits in bit_buf.
+    fn append(&self, bit_buf: u64, bit_count: uint, value: u64, count: uint) -> u64;
+    /// Get count bits, up to 32, after the first skip of the bit_count bits in bit_buf.  The bits past bit_count are 0.
+    fn peek(&self, bit_buf: u64, bit_count: uint, skip: uint, count: uint) -> u64;
+    /// Drop the first count of the bit_count bits in bit_buf.
+    fn drop_bits(&self, bit_buf: u64, bit_count: uint, count: uint) -> u64;
+}
+
+/// The least significant bit first order, of DEFLATE.
+#[deriving(Clone)]
+pub struct Lsb;
+
+/// The most significant bit first order, of bzip2 and JPEG.
+#[deriving(Clone)]
+pub struct Msb;
+
+// The first bits are the low bits of the buffer.
+impl BitOrder for Lsb {
+    #[inline]
+    fn new() -> Lsb {
+        Lsb
+    }
+
+    #[inline]
+    fn append(&self, bit_buf: u64, bit_count: uint, value: u64, _count: uint) -> u64 {
+        bit_buf | (value << bit_count)
+    }
+
+    #[inline]
+    fn peek(&self, bit_buf: u64, _bit_count: uint, skip: uint, count: uint) -> u64 {
+        (bit_buf >> skip) & ((1u64 << count) - 1)
+    }
+
+    #[inline]
+    fn drop_bits(&self, bit_buf: u64, _bit_count: uint, count: uint) -> u64 {
+        bit_buf >> count
+    }
+}
+
+// The first bits are the high ones of the bit_count low bits of the buffer.
+impl BitOrder for Msb {
+    #[inline]
+    fn new() -> Msb {
+        Msb
+    }
+
+    #[inline]
+    fn append(&self, bit_buf: u64, _bit_count: uint, value: u64, count: uint) -> u64 {
+        (bit_buf << count) | value
+    }
+
+    #[inline]
+    fn peek(&self, bit_buf: u64, bit_count: uint, skip: uint, count: uint) -> u64 {
+        let end = skip + count;
+        let bits = if end <= bit_count { bit_buf >> (bit_count - end) } else { bit_buf << (end - bit_count) };
+        bits & ((1u64 << count) - 1)
+    }
+
+    #[inline]
+    fn drop_bits(&self, bit_buf: u64, bit_count: uint, count: uint) -> u64 {
+        bit_buf & ((1u64 << (bit_count - count)) - 1)
+    }
+}
+
+
+/// Writer of the bit fields, in the bit order O.
+#[deriving(Clone)]
+pub struct BitWriter<O> {
+    priv order:     O,
+    priv bit_buf:   u64,            // pending bits not making up a byte yet, in the low bit_count bits
+    priv bit_count: uint,
+    priv out:       ~[u8],
+}
+
+impl<O: BitOrder> BitWriter<O> {
+
+    /// Create an empty BitWriter.
+    pub fn new() -> BitWriter<O> {
+        BitWriter {
+            order:      BitOrder::new(),
+            bit_buf:    0,
+            bit_count:  0,
+            out:        vec::with_capacity(1024),
+        }
+    }
+
+    /// Write the low count bits of value, up to MAX_WRITE_BITS, in the bit order.
+    pub fn write_bits(&mut self, value: u32, count: uint) {
+        assert!(count <= MAX_WRITE_BITS);
+        if count == 0 {
+            return;
+        }
+        self.bit_buf = self.order.append(self.bit_buf, self.bit_count, (value & ((1 << count) - 1)) as u64, count);
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.order.peek(self.bit_buf, self.bit_count, 0, 8) as u8);
+            self.bit_buf = self.order.drop_bits(self.bit_buf, self.bit_count, 8);
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Pad the partial byte with zero bits up to the byte boundary.
+    pub fn align_byte(&mut self) {
+        if self.bit_count > 0 {
+            self.out.push(self.order.peek(self.bit_buf, self.bit_count, 0, 8) as u8);
+            self.bit_buf = 0;
+            self.bit_count = 0;
+        }
+    }
+
+    /// Write the bytes as they are.  The writer must be at a byte boundary.
+    pub fn write_bytes(&mut self, buf: &[u8]) {
+        assert!(self.bit_count == 0);
+        self.out.push_all(buf);
+    }
+
+    /// The number of bits written since the last byte boundary, 0 to 7.
+    pub fn pending_bits(&self) -> uint {
+        self.bit_count
+    }
+
+    /// The number of completed bytes not taken out yet.
+    pub fn byte_len(&self) -> uint {
+        self.out.len()
+    }
+
+    /// Take out the completed bytes.  The partial byte stays in the writer.
+    pub fn take_bytes(&mut self) -> ~[u8] {
+        util::replace(&mut self.out, vec::with_capacity(1024))
+    }
+
+}
+
+
+/// Reader of the bit fields, in the bit order O.
+#[deriving(Clone)]
+pub struct BitReader<O> {
+    priv order:     O,
+    priv bit_buf:   u64,            // bits pulled in and not consumed yet, in the low bit_count bits
+    priv bit_count: uint,
+}
+
+impl<O: BitOrder> BitReader<O> {
+
+    /// Create an empty BitReader.
+    pub fn new() -> BitReader<O> {
+        BitReader {
+            order:      BitOrder::new(),
+            bit_buf:    0,
+            bit_count:  0,
+        }
+    }
+
+    /// Pull the bytes of input from in_pos until there are count bits, up to MAX_READ_BITS.
+    /// Return false if the input runs out first; the bytes pulled in stay for the next fill().
+    pub fn fill(&mut self, input: &[u8], in_pos: &mut uint, count: uint) -> bool {
+        assert!(count <= MAX_READ_BITS);
+        while self.bit_count < count {
+            if *in_pos == input.len() {
+                return false;
+            }
+            self.bit_buf = self.order.append(self.bit_buf, self.bit_count, input[*in_pos] as u64, 8);
+            self.bit_count += 8;
+            *in_pos += 1;
+        }
+        true
+    }
+
+    /// Get count bits, up to 32, after the first skip bits, without consuming them.
+    /// The first bit is the least significant of the value with Lsb, the most significant with Msb.
+    /// The bits not pulled in yet read as 0.
+    pub fn peek_bits(&self, skip: uint, count: uint) -> u32 {
+        assert!(count <= 32);
+        self.order.peek(self.bit_buf, self.bit_count, skip, count) as u32
+    }
+
+    /// Drop the first count bits.
+    pub fn consume(&mut self, count: uint) {
+        assert!(count <= self.bit_count);
+        self.bit_buf = self.order.drop_bits(self.bit_buf, self.bit_count, count);
+        self.bit_count -= count;
+    }
+
+    /// Drop the bits up to the byte boundary of the input.
+    pub fn align_byte(&mut self) {
+        let partial_bits = self.bit_count % 8;
+        self.consume(partial_bits);
+    }
+
+    /// The number of bits pulled in and not consumed.
+    pub fn available(&self) -> uint {
+        self.bit_count
+    }
+
+    /// Drop all the bits pulled in.
+    pub fn reset(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+
+    /// Iterate over the bits of input from in_pos, true for 1, until the input runs out.
+    pub fn bits<'a>(&'a mut self, input: &'a [u8], in_pos: &'a mut uint) -> Bits<'a, O> {
+        Bits { reader: self, input: input, in_pos: in_pos }
+    }
+
+    /// Iterate over the fields of width bits, up to 32, of input from in_pos, until the input runs out.
+    /// The bits of the last field cut off by the end of input stay in the reader.
+    pub fn symbols<'a>(&'a mut self, input: &'a [u8], in_pos: &'a mut uint, width: uint) -> Symbols<'a, O> {
+        assert!(width > 0 && width <= 32);
+        Symbols { reader: self, input: input, in_pos: in_pos, width: width }
+    }
+
+}
+
+
+/// Copy count bits of input from in_pos, through the reader, to the writer as they are.  When the reader and the writer
+/// are both at a byte boundary, the whole bytes go from the input straight to the writer.
+/// Return the number of bits copied, less than count if the input runs out first.
+pub fn copy_bits<O: BitOrder>(reader: &mut BitReader<O>, input: &[u8], in_pos: &mut uint, writer: &mut BitWriter<O>, count: uint) -> uint {
+    let mut copied = 0u;
+    if reader.available() % 8 == 0 && writer.pending_bits() == 0 {
+        // The bytes pulled into the reader go first, then the bytes of the input.
+        while count - copied >= 8 && reader.available() >= 8 {
+            writer.write_bits(reader.peek_bits(0, 8), 8);
+            reader.consume(8);
+            copied += 8;
+        }
+        if reader.available() == 0 {
+            let byte_len = num::min((count - copied) / 8, input.len() - *in_pos);
+            writer.write_bytes(input.slice(*in_pos, *in_pos + byte_len));
+            *in_pos += byte_len;
+            copied += byte_len * 8;
+        }
+    }
+    // The bits left over, or all of them when unaligned, in fields of up to MAX_WRITE_BITS.
+    while copied < count {
+        let field_len = num::min(MAX_WRITE_BITS, count - copied);
+        let field_len = if reader.fill(input, in_pos, field_len) { field_len } else { reader.available() };
+        if field_len == 0 {
+            break;
+        }
+        writer.write_bits(reader.peek_bits(0, field_len), field_len);
+        reader.consume(field_len);
+        copied += field_len;
+    }
+    copied
+}
+
+
+/// Iterator over the bits of a BitReader.  See BitReader::bits().
+pub struct Bits<'a, O> {
+    priv reader:    &'a mut BitReader<O>,
+    priv input:     &'a [u8],
+    priv in_pos:    &'a mut uint,
+}
+
+impl<'a, O: BitOrder> Iterator<bool> for Bits<'a, O> {
+    fn next(&mut self) -> Option<bool> {
+        if !self.reader.fill(self.input, self.in_pos, 1) {
+            return None;
+        }
+        let bit = self.reader.peek_bits(0, 1);
+        self.reader.consume(1);
+        Some(bit == 1)
+    }
+}
+
+
+/// Iterator over the fixed-width fields of a BitReader.  See BitReader::symbols().
+pub struct Symbols<'a, O> {
+    priv reader:    &'a mut BitReader<O>,
+    priv input:     &'a [u8],
+    priv in_pos:    &'a mut uint,
+    priv width:     uint,
+}
+
+impl<'a, O: BitOrder> Iterator<u32> for Symbols<'a, O> {
+    fn next(&mut self) -> Option<u32> {
+        if !self.reader.fill(self.input, self.in_pos, self.width) {
+            return None;
+        }
+        let symbol = self.reader.peek_bits(0, self.width);
+        self.reader.consume(self.width);
+        Some(symbol)
+    }
+}
+
+
+/// BitWriter of the bit order picked at runtime, e.g. from a format flag.
+pub enum DynBitWriter {
+    /// Writing least significant bit first.
+    LsbWriter(BitWriter<Lsb>),
+    /// Writing most significant bit first.
+    MsbWriter(BitWriter<Msb>),
+}
+
+impl DynBitWriter {
+
+    /// Create an empty writer, least significant bit first if lsb_first, most significant bit first otherwise.
+    pub fn new(lsb_first: bool) -> DynBitWriter {
+        if lsb_first { LsbWriter(BitWriter::new()) } else { MsbWriter(BitWriter::new()) }
+    }
+
+    /// See BitWriter::write_bits().
+    pub fn write_bits(&mut self, value: u32, count: uint) {
+        match *self {
+            LsbWriter(ref mut writer)   => writer.write_bits(value, count),
+            MsbWriter(ref mut writer)   => writer.write_bits(value, count),
+        }
+    }
+
+    /// See BitWriter::align_byte().
+    pub fn align_byte(&mut self) {
+        match *self {
+            LsbWriter(ref mut writer)   => writer.align_byte(),
+            MsbWriter(ref mut writer)   => writer.align_byte(),
+        }
+    }
+
+    /// See BitWriter::write_bytes().
+    pub fn write_bytes(&mut self, buf: &[u8]) {
+        match *self {
+            LsbWriter(ref mut writer)   => writer.write_bytes(buf),
+            MsbWriter(ref mut writer)   => writer.write_bytes(buf),
+        }
+    }
+
+    /// See BitWriter::pending_bits().
+    pub fn pending_bits(&self) -> uint {
+        match *self {
+            LsbWriter(ref writer)   => writer.pending_bits(),
+            MsbWriter(ref writer)   => writer.pending_bits(),
+        }
+    }
+
+    /// See BitWriter::byte_len().
+    pub fn byte_len(&self) -> uint {
+        match *self {
+            LsbWriter(ref writer)   => writer.byte_len(),
+            MsbWriter(ref writer)   => writer.byte_len(),
+        }
+    }
+
+    /// See BitWriter::take_bytes().
+    pub fn take_bytes(&mut self) -> ~[u8] {
+        match *self {
+            LsbWriter(ref mut writer)   => writer.take_bytes(),
+            MsbWriter(ref mut writer)   => writer.take_bytes(),
+        }
+    }
+
+}
+
+
+/// BitReader of the bit order picked at runtime, e.g. from a format flag.
+pub enum DynBitReader {
+    /// Reading least significant bit first.
+    LsbReader(BitReader<Lsb>),
+    /// Reading most significant bit first.
+    MsbReader(BitReader<Msb>),
+}
+
+impl DynBitReader {
+
+    /// Create an empty reader, least significant bit first if lsb_first, most significant bit first otherwise.
+    pub fn new(lsb_first: bool) -> DynBitReader {
+        if lsb_first { LsbReader(BitReader::new()) } else { MsbReader(BitReader::new()) }
+    }
+
+    /// See BitReader::fill().
+    pub fn fill(&mut self, input: &[u8], in_pos: &mut uint, count: uint) -> bool {
+        match *self {
+            LsbReader(ref mut reader)   => reader.fill(input, in_pos, count),
+            MsbReader(ref mut reader)   => reader.fill(input, in_pos, count),
+        }
+    }
+
+    /// See BitReader::peek_bits().
+    pub fn peek_bits(&self, skip: uint, count: uint) -> u32 {
+        match *self {
+            LsbReader(ref reader)   => reader.peek_bits(skip, count),
+            MsbReader(ref reader)   => reader.peek_bits(skip, count),
+        }
+    }
+
+    /// See BitReader::consume().
+    pub fn consume(&mut self, count: uint) {
+        match *self {
+            LsbReader(ref mut reader)   => reader.consume(count),
+            MsbReader(ref mut reader)   => reader.consume(count),
+        }
+    }
+
+    /// See BitReader::align_byte().
+    pub fn align_byte(&mut self) {
+        match *self {
+            LsbReader(ref mut reader)   => reader.align_byte(),
+            MsbReader(ref mut reader)   => reader.align_byte(),
+        }
+    }
+
+    /// See BitReader::available().
+    pub fn available(&self) -> uint {
+        match *self {
+            LsbReader(ref reader)   => reader.available(),
+            MsbReader(ref reader)   => reader.available(),
+        }
+    }
+
+    /// See BitReader::reset().
+    pub fn reset(&mut self) {
+        match *self {
+            LsbReader(ref mut reader)   => reader.reset(),
+            MsbReader(ref mut reader)   => reader.reset(),
+        }
+    }
+
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use super::{BitWriter, BitReader, Lsb, Msb, DynBitWriter, DynBitReader, copy_bits};
+
+
+    #[test]
+    fn test_bit_writer() {
+        let mut writer: BitWriter<Lsb> = BitWriter::new();
+        writer.write_bits(1, 1);
+        writer.write_bits(2, 2);
+        writer.write_bits(0x1F, 5);
+        assert_eq!(writer.pending_bits(), 0);
+        writer.write_bits(0xABC, 12);
+        assert_eq!(writer.pending_bits(), 4);
+        assert_eq!(writer.byte_len(), 2);
+        writer.align_byte();
+        writer.write_bytes([0x55u8, 0xAA]);
+        assert_eq!(writer.take_bytes(), ~[0xFDu8, 0xBC, 0x0A, 0x55, 0xAA]);
+        assert_eq!(writer.byte_len(), 0);
+
+        // The bits past count are ignored.
+        writer.write_bits(0xFFFFFF, 3);
+        writer.write_bits(0, 5);
+        assert_eq!(writer.take_bytes(), ~[0x07u8]);
+    }
+
+    #[test]
+    fn test_bit_reader() {
+        let input = [0xFDu8, 0xBC, 0x0A, 0x55];
+        let mut reader: BitReader<Lsb> = BitReader::new();
+        let mut in_pos = 0u;
+        assert!(( reader.fill(input, &mut in_pos, 8) ));
+        assert_eq!(in_pos, 1);
+        assert_eq!(reader.peek_bits(0, 1), 1);
+        assert_eq!(reader.peek_bits(1, 2), 2);
+        assert_eq!(reader.peek_bits(3, 5), 0x1F);
+        reader.consume(8);
+
+        // The 12-bit field is cut off by the end of the first part of the input, then read whole with the rest.
+        assert!(( !reader.fill(input.slice_to(2), &mut in_pos, 12) ));
+        assert_eq!(reader.available(), 8);
+        assert!(( reader.fill(input, &mut in_pos, 12) ));
+        assert_eq!(reader.peek_bits(0, 12), 0xABC);
+        reader.consume(12);
+
+        reader.align_byte();
+        assert_eq!(reader.available(), 0);
+        assert_eq!(reader.peek_bits(0, 8), 0);
+        assert!(( reader.fill(input, &mut in_pos, 8) ));
+        assert_eq!(reader.peek_bits(0, 8), 0x55);
+        assert!(( !reader.fill(input, &mut in_pos, 16) ));
+        reader.reset();
+        assert_eq!(reader.available(), 0);
+    }
+
+    #[test]
+    fn test_copy_bits() {
+        let input = vec::from_fn(256, |i| i as u8);
+
+        // Aligned, the bytes are copied whole, up to the end of input.
+        let mut reader: BitReader<Lsb> = BitReader::new();
+        let mut writer: BitWriter<Lsb> = BitWriter::new();
+        let mut in_pos = 0u;
+        assert!(( reader.fill(input, &mut in_pos, 16) ));
+        assert_eq!(copy_bits(&mut reader, input, &mut in_pos, &mut writer, 2000), 2000);
+        assert_eq!(in_pos, 250);
+        assert_eq!(copy_bits(&mut reader, input, &mut in_pos, &mut writer, 100000), 48);
+        assert_eq!(writer.take_bytes(), input.clone());
+
+        // Unaligned on both sides, the same bits as copying them one by one.
+        let mut reader: BitReader<Lsb> = BitReader::new();
+        let mut writer: BitWriter<Lsb> = BitWriter::new();
+        let mut in_pos = 0u;
+        assert!(( reader.fill(input, &mut in_pos, 5) ));
+        reader.consume(5);
+        writer.write_bits(5, 3);
+        assert_eq!(copy_bits(&mut reader, input, &mut in_pos, &mut writer, 1001), 1001);
+        writer.align_byte();
+
+        let mut reader2: BitReader<Lsb> = BitReader::new();
+        let mut writer2: BitWriter<Lsb> = BitWriter::new();
+        let mut in_pos2 = 0u;
+        writer2.write_bits(5, 3);
+        for (i, bit) in reader2.bits(input, &mut in_pos2).enumerate() {
+            if i >= 5 + 1001 {
+                break;
+            }
+            if i >= 5 {
+                writer2.write_bits(bit as u32, 1);
+            }
+        }
+        writer2.align_byte();
+        assert_eq!(writer.take_bytes(), writer2.take_bytes());
+    }
+
+    #[test]
+    fn test_bit_iterators() {
+        let input = [0xFDu8, 0xBC, 0x0A];
+        let mut reader: BitReader<Lsb> = BitReader::new();
+        let mut in_pos = 0u;
+        let bits: ~[bool] = reader.bits(input.slice_to(1), &mut in_pos).collect();
+        assert_eq!(bits, ~[true, false, true, true, true, true, true, true]);
+        assert_eq!(reader.bits(input.slice_to(2), &mut in_pos).fold(0u, |ones, bit| if bit { ones + 1 } else { ones }), 5);
+        assert_eq!(in_pos, 2);
+
+        // The 5-bit fields, with the 4 bits left over staying in the reader.
+        let mut in_pos = 0u;
+        let symbols: ~[u32] = reader.symbols(input, &mut in_pos, 5).collect();
+        assert_eq!(symbols, ~[0x1D, 0x07, 0x0F, 0x15]);
+        assert_eq!(reader.available(), 4);
+        let mut in_pos = 0u;
+        assert_eq!(reader.symbols([0x0Fu8], &mut in_pos, 12).next(), Some(0xF0));
+    }
+
+    #[test]
+    fn test_msb_bit_order() {
+        let mut writer: BitWriter<Msb> = BitWriter::new();
+        writer.write_bits(1, 1);
+        writer.write_bits(2, 2);
+        writer.write_bits(0x1F, 5);
+        writer.write_bits(0xABC, 12);
+        assert_eq!(writer.pending_bits(), 4);
+        writer.align_byte();
+        let buf = writer.take_bytes();
+        assert_eq!(buf, ~[0xDFu8, 0xAB, 0xC0]);
+
+        let mut reader: BitReader<Msb> = BitReader::new();
+        let mut in_pos = 0u;
+        assert!(( reader.fill(buf, &mut in_pos, 8) ));
+        assert_eq!(reader.peek_bits(0, 1), 1);
+        assert_eq!(reader.peek_bits(1, 2), 2);
+        assert_eq!(reader.peek_bits(3, 5), 0x1F);
+        assert_eq!(reader.peek_bits(4, 8), 0xF0);
+        reader.consume(8);
+        assert!(( reader.fill(buf, &mut in_pos, 12) ));
+        assert_eq!(reader.peek_bits(0, 12), 0xABC);
+        reader.consume(12);
+        assert_eq!(reader.available(), 4);
+
+        let mut in_pos = 0u;
+        let symbols: ~[u32] = reader.symbols([0xDFu8, 0xAB], &mut in_pos, 5).collect();
+        // The 4 bits left over go first.
+        assert_eq!(symbols, ~[0x01, 0x17, 0x1D, 0x0B]);
+        assert_eq!(reader.available(), 0);
+    }
+
+    #[test]
+    fn test_dyn_bit_order() {
+        for &lsb_first in [true, false].iter() {
+            let mut writer = DynBitWriter::new(lsb_first);
+            writer.write_bits(0x5, 3);
+            writer.write_bits(0x1ABC, 13);
+            writer.write_bits(0x3, 2);
+            writer.align_byte();
+            let buf = writer.take_bytes();
+            assert_eq!(buf.len(), 3);
+            assert_eq!(writer.byte_len(), 0);
+
+            let mut reader = DynBitReader::new(lsb_first);
+            let mut in_pos = 0u;
+            assert!(( reader.fill(buf, &mut in_pos, 18) ));
+            assert_eq!(reader.peek_bits(0, 3), 0x5);
+            assert_eq!(reader.peek_bits(3, 13), 0x1ABC);
+            assert_eq!(reader.peek_bits(16, 2), 0x3);
+            reader.consume(18);
+            reader.align_byte();
+            assert_eq!(reader.available(), 0);
+        }
+        assert_eq!(DynBitWriter::new(true).pending_bits(), 0);
+
+        let mut writer = DynBitWriter::new(false);
+        writer.write_bits(1, 1);
+        writer.align_byte();
+        assert_eq!(writer.take_bytes(), ~[0x80u8]);
+        let mut writer = DynBitWriter::new(true);
+        writer.write_bits(1, 1);
+        writer.align_byte();
+        assert_eq!(writer.take_bytes(), ~[0x01u8]);
+    }
+
+}
@@ -27,7 +27,12 @@
 
 The deflate module provides compression and decompression support with the
 DEFLATE algorithm.  Its wraps the miniz.c as basis for the implementation
-for DEFLATE and provides an easy to use API in Rust.
+for DEFLATE and provides an easy to use API in Rust.  Building the crate with
+--cfg zlib runs it on the system zlib instead, through the same API; see zlib.rs.
+A Deflator or an Inflator created with_backend(BackendNative) compresses or
+decompresses with the DEFLATE code written in Rust instead; see native_deflate.rs
+and native_inflate.rs.  Building with --cfg native drops the miniz of the runtime
+altogether: the runtime backend is then the native one, and so are the byte helpers.
 
 There are two sets of API: cursor-style API for operating data in batches,
 and the stream-style API for compressing and decompressing the whole streams.
@@ -42,6 +47,9 @@ the data from the source to the destination stream automatically.  It is
 callee-driven with an internal loop running until all the data have been
 processed.  It's more efficient with less buffer copying.
 
+DeflateWriter and InflateReader put the compression and the decompression behind
+the standard Writer and Reader traits, for the raw DEFLATE data or the zlib format.
+
 Note that often time a DEFLATE data stream is embedded in an outer containing
 stream, e.g. a zip file or a gzip file.  The API here only work with the DEFLATE
 portion of the data stream.  Be careful not to pass in data belonging to the
@@ -56,9 +64,15 @@ See gzip.rs for sample usage.
 
 */
 
-use std::io::{Reader, Writer};
+use std::io::{Reader, Writer, Decorator};
+use std::io::{io_error, IoError, OtherIoError};
 use std::{vec, num, ptr};
 use std::libc::{c_void, size_t, c_int, c_uint};
+#[cfg(zlib, not(native))]
+use rustrt = super::zlib;
+use super::native_deflate;
+use super::native_deflate::NativeCompressor;
+use super::native_inflate::NativeDecompressor;
 
 
 
@@ -72,6 +86,9 @@ pub enum DeflateStatus {
     DeflateStatusOkay = 0,
     /// All data have been compressed and finalized.
     DeflateStatusDone = 1,
+    /// compress_buf() has filled up the out_buf before finishing.  The rest of the compressed data is kept
+    /// in the Deflator and comes out in the next call with more room in out_buf.
+    DeflateStatusOutputFull = 2,
     /// The callback write fn wants to abort the compression operation.  Stream-loop will be broken and returned.
     DeflateStatusAbort = -9998,
     /// Unknown status from low layer
@@ -85,11 +102,34 @@ impl DeflateStatus {
             -1 => DeflateStatusPutBufFailed,
             0  => DeflateStatusOkay,
             1  => DeflateStatusDone,
+            2  => DeflateStatusOutputFull,
             _  => DeflateStatusUnknown
         }
     }
 }
 
+/// The flush modes of Deflator::flush(), ending the compressed data written so far at a byte boundary
+/// in the middle of the DEFLATE stream.
+pub enum FlushMode {
+    /// Sync flush.  The receiver can decompress all the data written so far.
+    FlushSync,
+    /// Full flush.  Like FlushSync, and the data after it don't refer back to the data before it,
+    /// so the receiver can start decompressing from the flush point.
+    FlushFull,
+}
+
+/// The compression strategies, tuning the match finding and the block types for the kind of data.
+pub enum CompressStrategy {
+    /// Normal LZ matching with dynamic Huffman blocks, good for most data.
+    StrategyDefault,
+    /// Only matches longer than 5 bytes are used, better for data with small variations like filtered image rows.
+    StrategyFiltered,
+    /// Only matches at distance 1 are used, i.e. run-length encoding, fast and good for image data like PNG.
+    StrategyRle,
+    /// Only the static Huffman blocks are used, skipping the Huffman table building.  Good for small inputs.
+    StrategyFixed,
+}
+
 /// INFLATE function return status
 pub enum InflateStatus {
     /// The inflator needs 1 or more input bytes to make forward progress, but the caller is indicating that no more are available. The compressed data is probably corrupted.
@@ -129,9 +169,79 @@ impl InflateStatus {
     }
 }
 
+/// The error of the compression functions returning a Result, with the failed status and the progress
+/// made before the failure.
+pub struct DeflateError {
+    /// The failed status, e.g. DeflateStatusBadParam for an invalid compress_level.
+    status: DeflateStatus,
+    /// The number of input bytes consumed before the failure.
+    in_consumed: u64,
+    /// The number of compressed bytes output before the failure.
+    out_total: u64,
+}
+
+impl DeflateError {
+    /// The description of the failed status.
+    pub fn desc(&self) -> &'static str {
+        match self.status {
+            DeflateStatusBadParam       => "Invalid compression parameter.",
+            DeflateStatusPutBufFailed   => "Failed to write the compressed data.",
+            DeflateStatusAbort          => "Compression aborted.",
+            _                           => "Compression failed."
+        }
+    }
+}
+
+impl ToStr for DeflateError {
+    fn to_str(&self) -> ~str {
+        format!("{:s}  Consumed {:u} input bytes, output {:u} bytes.", self.desc(), self.in_consumed, self.out_total)
+    }
+}
+
+/// The error of the decompression functions returning a Result, with the failed status and the progress
+/// made before the failure.  The data decompressed before the failure are intact.
+pub struct InflateError {
+    /// The failed status, e.g. StatusAdler32Mismatch or InflateStatusFailedCannotMakeProgress for cut-off data.
+    status: InflateStatus,
+    /// The number of compressed bytes consumed before the failure.
+    in_consumed: u64,
+    /// The number of decompressed bytes output before the failure.
+    out_total: u64,
+}
+
+impl InflateError {
+    /// The description of the failed status.
+    pub fn desc(&self) -> &'static str {
+        match self.status {
+            InflateStatusFailedCannotMakeProgress   => "Compressed data cut off.",
+            InflateStatusBadParam                   => "Invalid decompression parameter.",
+            StatusAdler32Mismatch                   => "ADLER32 mismatch.",
+            InflateStatusFailed                     => "Corrupted compressed data.",
+            InflateStatusAbort                      => "Decompression aborted.",
+            _                                       => "Decompression failed."
+        }
+    }
+
+    /// Whether the data decompressed fine but failed the ADLER32 check of the zlib format.
+    pub fn is_adler32_mismatch(&self) -> bool {
+        match self.status {
+            StatusAdler32Mismatch   => true,
+            _                       => false
+        }
+    }
+}
+
+impl ToStr for InflateError {
+    fn to_str(&self) -> ~str {
+        format!("{:s}  Consumed {:u} input bytes, output {:u} bytes.", self.desc(), self.in_consumed, self.out_total)
+    }
+}
+
 
 /// The number of dictionary probes to use at each compression level (0-9). 0=implies fastest/minimal possible probing, 9=best compression but slowest.
 pub static MAX_COMPRESS_LEVEL : uint = 9;
+/// The compression level used when none is given, balancing the compression ratio and the speed.
+pub static DEFAULT_COMPRESS_LEVEL : uint = 6;
 static TDEFL_NUM_PROBES : [c_uint, ..10] = [ 0 as c_uint, 2, 8, 32, 128, 256, 512, 1024, 2048, 4095 ];
 
 /// Max size of the LZ dictionary is 32K at the beginning of an out_buf, which becomes the minimum output buffer size for decompression.
@@ -149,7 +259,7 @@ static TDEFL_NONDETERMINISTIC_PARSING_FLAG : c_uint = 0x08000;
 static TDEFL_RLE_MATCHES : c_uint                   = 0x10000;
 static TDEFL_FILTER_MATCHES : c_uint                = 0x20000;
 static TDEFL_FORCE_ALL_STATIC_BLOCKS : c_uint       = 0x40000;
-static TDEFL_FORCE_ALL_RAW_BLOCKS : c_uint          = 0x08000;
+static TDEFL_FORCE_ALL_RAW_BLOCKS : c_uint          = 0x80000;
 
 static TDEFL_NO_FLUSH : c_int   = 0;
 static TDEFL_SYNC_FLUSH : c_int = 2;
@@ -167,6 +277,7 @@ static LZ_NORM : c_int = 0x80;  // LZ with 128 probes, "normal"
 static LZ_BEST : c_int = 0xfff; // LZ with 4095 probes, "best"
 
 
+#[cfg(not(zlib), not(native))]
 mod rustrt {
     use std::libc::{c_void, size_t, c_int, c_uint};
 
@@ -212,6 +323,63 @@ mod rustrt {
     }
 }
 
+// Built with --cfg native, the runtime backend runs on the native compressor and decompressor, through
+// their functions of the miniz API, without linking to the miniz of the runtime.
+#[cfg(native)]
+mod rustrt {
+    use std::libc::{c_void, c_int};
+    use super::super::native_deflate;
+    pub use super::super::native_deflate::{tdefl_compressor_alloc, tdefl_compressor_free, tdefl_compress};
+    pub use super::super::native_inflate::{tinfl_decompressor_alloc, tinfl_decompressor_free, tinfl_decompress};
+
+    pub unsafe fn tdefl_init(tdefl_compressor: *c_void, _pPut_buf_func: *c_void, _pPut_buf_user: *c_void,
+                             compress_flags: c_int) -> c_int {
+        native_deflate::tdefl_init(tdefl_compressor, compress_flags)
+    }
+}
+
+
+/// The name of the compression library the crate is built with: "miniz", the one bundled in the runtime,
+/// "zlib", the system zlib when built with --cfg zlib, or "native", the Rust code when built with --cfg native.
+#[cfg(not(zlib), not(native))]
+pub fn backend_name() -> &'static str {
+    "miniz"
+}
+
+/// The name of the compression library the crate is built with: "miniz", the one bundled in the runtime,
+/// "zlib", the system zlib when built with --cfg zlib, or "native", the Rust code when built with --cfg native.
+#[cfg(zlib, not(native))]
+pub fn backend_name() -> &'static str {
+    "zlib"
+}
+
+/// The name of the compression library the crate is built with: "miniz", the one bundled in the runtime,
+/// "zlib", the system zlib when built with --cfg zlib, or "native", the Rust code when built with --cfg native.
+#[cfg(native)]
+pub fn backend_name() -> &'static str {
+    "native"
+}
+
+
+// The tdefl flags of the CompressParams.  greedy_parsing of None goes by the compress_level.
+fn compress_flags(params: &CompressParams, deterministic: bool, greedy_parsing: Option<bool>) -> c_int {
+    let compress_level = num::min(MAX_COMPRESS_LEVEL, params.compress_level);
+    let greedy_parsing = greedy_parsing.unwrap_or(compress_level <= 3);
+    let strategy_flags = match params.strategy {
+        StrategyDefault     => 0,
+        StrategyFiltered    => TDEFL_FILTER_MATCHES,
+        StrategyRle         => TDEFL_RLE_MATCHES,
+        StrategyFixed       => TDEFL_FORCE_ALL_STATIC_BLOCKS,
+    };
+    let compress_flags = 
+        TDEFL_NUM_PROBES[compress_level] | 
+        (if greedy_parsing { TDEFL_GREEDY_PARSING_FLAG } else { 0 }) |
+        (if compress_level > 0  { strategy_flags } else { TDEFL_FORCE_ALL_RAW_BLOCKS }) |
+        (if deterministic { 0 } else { TDEFL_NONDETERMINISTIC_PARSING_FLAG }) |
+        (if params.add_zlib_header { TDEFL_WRITE_ZLIB_HEADER } else { 0 }) |
+        (if params.add_crc32 { TDEFL_COMPUTE_ADLER32 } else { 0 });
+    compress_flags as c_int
+}
 
 /// Calculate the IO buffer size in bytes given a buf_size_factor.
 /// buf_size_factor is a power of 2.   buf_in_bytes = 1024 * 2 ^ buf_size_factor
@@ -220,6 +388,57 @@ pub fn calc_buf_size(buf_size_factor: uint) -> uint {
 }
 
 
+/// The compression library behind a Deflator or an Inflator.
+pub enum DeflateBackend {
+    /// The library of the runtime, miniz, or the system zlib with --cfg zlib, or the native code with --cfg native.
+    /// See backend_name().
+    BackendRuntime,
+    /// The DEFLATE compressor and decompressor written in Rust, in native_deflate.rs and native_inflate.rs.
+    BackendNative,
+}
+
+/// The compression parameters of Deflator::init_params(), built up from CompressParams::new(compress_level).
+/// The strategy is StrategyDefault and the zlib header and the ADLER32 are off by default.
+///
+/// Example:
+///
+///     let params = CompressParams::new(6).strategy(StrategyRle).zlib_header(true).adler32(true);
+///     deflator.init_params(&params);
+pub struct CompressParams {
+    priv compress_level: uint,
+    priv strategy: CompressStrategy,
+    priv add_zlib_header: bool,
+    priv add_crc32: bool,
+}
+
+impl CompressParams {
+    /// Creates the parameters with the compress_level, 0 to 9, and the defaults for the rest.
+    pub fn new(compress_level: uint) -> CompressParams {
+        CompressParams {
+            compress_level:     compress_level,
+            strategy:           StrategyDefault,
+            add_zlib_header:    false,
+            add_crc32:          false,
+        }
+    }
+
+    /// Sets the compression strategy.
+    pub fn strategy(self, strategy: CompressStrategy) -> CompressParams {
+        CompressParams { strategy: strategy, ..self }
+    }
+
+    /// Sets whether to add the ZLib-format header in front of and an ADLER32 CRC at the end of the deflated data.
+    pub fn zlib_header(self, add_zlib_header: bool) -> CompressParams {
+        CompressParams { add_zlib_header: add_zlib_header, ..self }
+    }
+
+    /// Sets whether to add an ADLER32 CRC at the end of the deflated data regardless of the zlib header.
+    pub fn adler32(self, add_crc32: bool) -> CompressParams {
+        CompressParams { add_crc32: add_crc32, ..self }
+    }
+}
+
+
 /// Compression data structure
 struct Deflator {
     priv tdefl_compressor: *c_void,
@@ -228,6 +447,9 @@ struct Deflator {
     priv in_offset: uint,
     priv in_buf_total: uint,
     priv out_offset: uint,
+    priv deterministic: bool,
+    priv greedy_parsing: Option<bool>,
+    priv backend: DeflateBackend,
     read_total: uint,
     write_total: uint,
 }
@@ -241,28 +463,61 @@ impl Deflator {
     /// Creates the Deflator structure and allocates the underlying tdefl_compressor structure.
     /// Allocates the IO buffers with buf_size_factor.  The buf_size_factor is a power of 2 of K: 2^buf_size_factor X 1K.
     pub fn with_size_factor(buf_size_factor: uint) -> Deflator {
+        Deflator::with_backend(buf_size_factor, BackendRuntime)
+    }
+
+    /// Creates the Deflator structure with the compression backend, and the IO buffers with buf_size_factor.
+    /// The compressed data of the backends differ but decompress to the same data.
+    pub fn with_backend(buf_size_factor: uint, backend: DeflateBackend) -> Deflator {
         #[inline(never)];
         unsafe {
             Deflator {
-                tdefl_compressor:   rustrt::tdefl_compressor_alloc(),
+                tdefl_compressor:   match backend {
+                                        BackendRuntime  => rustrt::tdefl_compressor_alloc(),
+                                        BackendNative   => native_deflate::tdefl_compressor_alloc(),
+                                    },
                 in_buf:             vec::from_elem(calc_buf_size(buf_size_factor), 0u8),
                 out_buf:            vec::from_elem(calc_buf_size(buf_size_factor) + MIN_DECOMPRESS_BUF_SIZE, 0u8),
                 in_offset:          0u,
                 in_buf_total:       0u,
                 out_offset:         0u,
+                deterministic:      true,
+                greedy_parsing:     None,
+                backend:            backend,
                 read_total:         0u,
                 write_total:        0u,
             }
         }
     }
 
+    /// Sets whether the compressed output is deterministic, taking effect at the next init().  It's on by default.
+    /// When on, the same input data with the same compress_level always compress to byte-identical output,
+    /// as needed for reproducible builds.  Turning it off skips clearing the match hash table in init(),
+    /// making the re-init of a re-used Deflator faster, but the output may then differ from run to run
+    /// at every compress_level except 0, which stores the data in raw blocks without matching.
+    /// The output always decompresses to the same data either way.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Sets the parsing of the matches, taking effect at the next init().  Some(true) for greedy parsing, which takes
+    /// the first match found and is faster.  Some(false) for lazy parsing, which checks whether the next position has
+    /// a longer match and compresses better.  None to go by the compress_level, greedy for 1 to 3 and lazy for 4 to 9.
+    /// The compress_level still sets the number of probes, so high probes can combine with greedy parsing and vice versa.
+    pub fn set_greedy_parsing(&mut self, greedy_parsing: Option<bool>) {
+        self.greedy_parsing = greedy_parsing;
+    }
+
     /// Releases the underlying tdefl_compressor structure.  After this call, the instance can not be used any more.
     /// Called by the drop() destructor.
     fn free(&mut self) {
         #[inline(never)];
         unsafe {
             if self.tdefl_compressor != ptr::null() {
-                rustrt::tdefl_compressor_free(self.tdefl_compressor);
+                match self.backend {
+                    BackendRuntime  => rustrt::tdefl_compressor_free(self.tdefl_compressor),
+                    BackendNative   => native_deflate::tdefl_compressor_free(self.tdefl_compressor),
+                }
             }
             self.tdefl_compressor = ptr::null();
         }
@@ -273,27 +528,49 @@ impl Deflator {
     /// compress_level is 0 to 9, where 0 is the fastest with decompressed raw data and 9 is the slowest with best compression.
     /// add_zlib_header set to true to add the ZLib-format header in front of and an ADLER32 CRC at the end of the deflated data.
     /// add_crc32 set to true to add an ADLER32 CRC at the end of the deflated data regardless how add_zlib is set.
-    pub fn init(&self, compress_level: uint, add_zlib_header: bool, add_crc32: bool) -> DeflateStatus {
-        #[inline(never)];
+    /// Return the DeflateError with DeflateStatusBadParam for an invalid compress_level.
+    pub fn init(&self, compress_level: uint, add_zlib_header: bool, add_crc32: bool) -> Result<(), DeflateError> {
+        self.init_params(&CompressParams::new(compress_level).zlib_header(add_zlib_header).adler32(add_crc32))
+    }
 
-        let compress_level = num::min(MAX_COMPRESS_LEVEL, compress_level);
-        let compress_flags = 
-            TDEFL_NUM_PROBES[compress_level] | 
-            (if compress_level <= 3 { TDEFL_GREEDY_PARSING_FLAG } else { 0 }) |
-            (if compress_level > 0  { 0 } else { TDEFL_FORCE_ALL_RAW_BLOCKS }) |
-            (if add_zlib_header { TDEFL_WRITE_ZLIB_HEADER } else { 0 }) |
-            (if add_crc32 { TDEFL_COMPUTE_ADLER32 } else { 0 });
+    /// Initializes the Deflator with the CompressParams, which also set the compression strategy.
+    /// The strategy doesn't apply to compress_level 0, which stores the data in raw blocks.
+    pub fn init_params(&self, params: &CompressParams) -> Result<(), DeflateError> {
+        #[inline(never)];
+        match self.init_flags(compress_flags(params, self.deterministic, self.greedy_parsing)) {
+            DeflateStatusOkay => Ok(()),
+            status => Err(self.error(status))
+        }
+    }
 
+    // Initializes the backend compressor with the tdefl flags.
+    fn init_flags(&self, compress_flags: c_int) -> DeflateStatus {
         unsafe {
-            let status = rustrt::tdefl_init(self.tdefl_compressor, ptr::null(), ptr::null(), compress_flags as c_int);
+            let status = match self.backend {
+                BackendRuntime  => rustrt::tdefl_init(self.tdefl_compressor, ptr::null(), ptr::null(), compress_flags),
+                BackendNative   => native_deflate::tdefl_init(self.tdefl_compressor, compress_flags),
+            };
             return DeflateStatus::from_status(status);
         }
     }
 
+    /// Resets the Deflator for a new compression session, re-using the tdefl_compressor and the IO buffers.
+    /// Unlike init() alone, it also discards the input pending in compress_write() from the previous session
+    /// and clears the offsets and the read_total and write_total counts.  The parameters are the same as init()'s.
+    /// Use it to compress many small payloads with one Deflator without the alloc/free churn.
+    pub fn reset(&mut self, compress_level: uint, add_zlib_header: bool, add_crc32: bool) -> Result<(), DeflateError> {
+        self.in_offset = 0;
+        self.in_buf_total = 0;
+        self.out_offset = 0;
+        self.read_total = 0;
+        self.write_total = 0;
+        self.init(compress_level, add_zlib_header, add_crc32)
+    }
+
     /// Compresses all data read from the reader and writes the compressed data to the writer.
     /// Runs until reading EOF from reader.  Waits on read or wait on write if they are blocked.
     /// Demo usage of compress_stream().
-    pub fn compress_stream_rw<R: Reader, W: Writer>(&mut self, in_reader: &mut R, out_writer: &mut W) -> DeflateStatus {
+    pub fn compress_stream_rw<R: Reader, W: Writer>(&mut self, in_reader: &mut R, out_writer: &mut W) -> Result<(), DeflateError> {
         self.compress_stream(
             // upcall function to read data for compression
             |in_buf| {
@@ -322,10 +599,10 @@ impl Deflator {
     ///
     /// The callback write_fn function takes in the out_buf buffer containing one batch of compressed data.
     /// The is_eof flag is set for the last batch of compressed data.
-    /// Write_fn can return an abort flag to abort the compression.
+    /// Write_fn can return an abort flag to abort the compression, which returns the DeflateError with DeflateStatusAbort.
     pub fn compress_stream(&mut self, 
                            read_fn:  |in_buf: &mut [u8]|->uint, 
-                           write_fn: |out_buf: &[u8], is_eof: bool|->bool) -> DeflateStatus {
+                           write_fn: |out_buf: &[u8], is_eof: bool|->bool) -> Result<(), DeflateError> {
 
         let out_buf_total = self.out_buf.len();
 
@@ -334,32 +611,35 @@ impl Deflator {
             if self.in_offset == self.in_buf_total {
                 self.in_buf_total = read_fn(self.in_buf);               // in_buf_total == 0 for EOF
                 self.in_offset = 0;
+                self.read_total += self.in_buf_total;
             }
 
             let mut in_bytes = self.in_buf_total - self.in_offset;      // number of bytes to compress in this batch;
             let mut out_bytes = out_buf_total - self.out_offset;        // number of bytes of space avaiable in the out_buf;
             let final_input = self.in_buf_total == 0;
-            let status = self.compress_buf(self.in_buf, self.in_offset, &mut in_bytes, self.out_buf, self.out_offset, &mut out_bytes, final_input);
+            let status = self.compress_batch(self.in_buf, self.in_offset, &mut in_bytes, self.out_buf, self.out_offset, &mut out_bytes, final_input);
 
             self.in_offset += in_bytes;                                 // advance offset by the number of bytes consumed.
             self.out_offset += out_bytes;                               // advance offset by the number of bytes written.
 
             match status {
-                DeflateStatusOkay => {
+                DeflateStatusOkay | DeflateStatusOutputFull => {
                     // If out_buf is full, write its content out.  Reset it.
                     if self.out_offset == out_buf_total {
+                        self.write_total += self.out_offset;
                         if write_fn(self.out_buf, false) {
-                            return DeflateStatusAbort;
+                            return Err(self.error(DeflateStatusAbort));
                         }
                         self.out_offset = 0;
                     }
                 },
                 DeflateStatusDone => {
                     // Write the remaining content in out_buf out.
+                    self.write_total += self.out_offset;
                     write_fn(self.out_buf.slice(0, self.out_offset), true);
-                    return DeflateStatusDone;
+                    return Ok(());
                 },
-                _ => return status  // Return error
+                _ => return Err(self.error(status))
             }
         }
     }
@@ -369,13 +649,19 @@ impl Deflator {
     //  This approach has more buffer copying than the stream approach.
     ///
     /// Input to compress is supplied in input_buf, which will be fully compressed and written out before returning.
+    /// An input_buf as big as the internal in_buf is compressed from where it is; smaller ones go through in_buf.
     /// The compressed data are sent to caller via the write_fn callback.
     /// The final_write flag must be set for the last batch of data to compress, to finalize the compressed data.
     /// The last batch of data can be zero-length.
     pub fn compress_write(&mut self,
                           input_buf: &[u8],
                           final_write: bool,
-                          write_fn: |out_buf: &[u8], is_eof: bool|) -> DeflateStatus {
+                          write_fn: |out_buf: &[u8], is_eof: bool|) -> Result<(), DeflateError> {
+
+        // The data pending in in_buf go first, before the input_buf's.
+        if self.in_offset == self.in_buf_total && input_buf.len() >= self.in_buf.len() {
+            return self.compress_write_direct(input_buf, final_write, write_fn);
+        }
 
         let out_buf_total = self.out_buf.len();
         let input_total = input_buf.len();
@@ -397,13 +683,13 @@ impl Deflator {
             let mut in_bytes = self.in_buf_total - self.in_offset;      // number of bytes to compress in this batch
             let mut out_bytes = out_buf_total - self.out_offset;        // number of bytes of space avaiable in the out_buf
             let final_input = (final_write && input_remaining == 0);    // final_write is set and last batch in input_buf
-            let status = self.compress_buf(self.in_buf, self.in_offset, &mut in_bytes, 
-                                           self.out_buf, self.out_offset, &mut out_bytes, final_input);
+            let status = self.compress_batch(self.in_buf, self.in_offset, &mut in_bytes, 
+                                             self.out_buf, self.out_offset, &mut out_bytes, final_input);
             self.in_offset += in_bytes;                                 // advance offset by the number of bytes consumed
             self.out_offset += out_bytes;                               // advance offset by the number of bytes written
 
             match status {
-                DeflateStatusOkay => {
+                DeflateStatusOkay | DeflateStatusOutputFull => {
                     // Only when out_buf is full, write its content out.  Reset it.
                     if self.out_offset == out_buf_total {
                         write_fn(self.out_buf, false);
@@ -415,20 +701,153 @@ impl Deflator {
                     // Write the remaining content in out_buf out.
                     write_fn(self.out_buf.slice(0, self.out_offset), true);
                     self.write_total += self.out_offset;
-                    return DeflateStatusDone;
+                    return Ok(());
                 },
-                _ => return status  // Return error
+                _ => return Err(self.error(status))
             }
 
             // Has compressed all input_buf for the current non-final write.  Return to caller.
             if !final_write && input_remaining == 0 {
-                return DeflateStatusOkay;
+                return Ok(());
             }
             // Important: for the final_write, need to loop to flush all remaining data in 
             // in_buf and out_buf until DeflateStatusDone.
         }
     }
 
+    // Compress input_buf without copying it into in_buf.  tdefl keeps its own copy of the dictionary, so all of
+    // input_buf is consumed before returning and nothing refers to it afterward.
+    fn compress_write_direct(&mut self,
+                             input_buf: &[u8],
+                             final_write: bool,
+                             write_fn: |out_buf: &[u8], is_eof: bool|) -> Result<(), DeflateError> {
+
+        let out_buf_total = self.out_buf.len();
+        let mut input_offset = 0u;
+
+        loop {
+            let mut in_bytes = input_buf.len() - input_offset;
+            let mut out_bytes = out_buf_total - self.out_offset;
+            let status = self.compress_batch(input_buf, input_offset, &mut in_bytes, 
+                                             self.out_buf, self.out_offset, &mut out_bytes, final_write);
+            input_offset += in_bytes;
+            self.read_total += in_bytes;
+            self.out_offset += out_bytes;
+
+            match status {
+                DeflateStatusOkay | DeflateStatusOutputFull => {
+                    if self.out_offset == out_buf_total {
+                        write_fn(self.out_buf, false);
+                        self.write_total += self.out_offset;
+                        self.out_offset = 0;
+                    }
+                },
+                DeflateStatusDone => {
+                    write_fn(self.out_buf.slice(0, self.out_offset), true);
+                    self.write_total += self.out_offset;
+                    return Ok(());
+                },
+                _ => return Err(self.error(status))
+            }
+
+            if !final_write && input_offset == input_buf.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Flush the data pending in compress_write() with the flush_mode, ending them at a byte boundary without ending
+    /// the DEFLATE stream, e.g. for a network protocol to send out a complete message.
+    /// The compressed data, including those pending in the internal out_buf, are passed to write_fn.
+    /// The compression session continues; call compress_write() for more data.
+    pub fn flush(&mut self, flush_mode: FlushMode, write_fn: |out_buf: &[u8]|) -> Result<(), DeflateError> {
+        match flush_mode {
+            FlushSync => self.compress_write_flush(TDEFL_SYNC_FLUSH, write_fn),
+            FlushFull => self.compress_write_flush(TDEFL_FULL_FLUSH, write_fn),
+        }
+    }
+
+    /// Sync flush the data pending in compress_write(), so that the receiver can decompress all the data written so far.
+    /// Same as flush(FlushSync).
+    pub fn compress_write_sync(&mut self, write_fn: |out_buf: &[u8]|) -> Result<(), DeflateError> {
+        self.flush(FlushSync, write_fn)
+    }
+
+    /// End the current segment of compress_write() with a full flush at a byte boundary.
+    /// The compressed data up to the end of the segment are passed to write_fn.
+    /// The data after the full flush don't refer back to the segment, so the segments can be
+    /// concatenated with the segments compressed elsewhere, with concat_segments().
+    pub fn end_segment(&mut self, write_fn: |out_buf: &[u8]|) -> Result<(), DeflateError> {
+        self.flush(FlushFull, write_fn)
+    }
+
+    // Flush the data pending in compress_write() with the tdefl_flush mode, passing the compressed data to write_fn.
+    fn compress_write_flush(&mut self, tdefl_flush: c_int, write_fn: |out_buf: &[u8]|) -> Result<(), DeflateError> {
+        let out_buf_total = self.out_buf.len();
+
+        loop {
+            let mut in_bytes = self.in_buf_total - self.in_offset;
+            let mut out_bytes = out_buf_total - self.out_offset;
+            let status = self.compress_buf_flush(self.in_buf, self.in_offset, &mut in_bytes, 
+                                                 self.out_buf, self.out_offset, &mut out_bytes, tdefl_flush);
+            self.in_offset += in_bytes;
+            self.out_offset += out_bytes;
+
+            match status {
+                DeflateStatusOkay => {
+                    // The flush has completed when all pending input is consumed and out_buf has room left.
+                    let flushed = self.in_offset == self.in_buf_total && self.out_offset < out_buf_total;
+                    if self.out_offset > 0 {
+                        write_fn(self.out_buf.slice(0, self.out_offset));
+                        self.write_total += self.out_offset;
+                        self.out_offset = 0;
+                    }
+                    if flushed {
+                        return Ok(());
+                    }
+                },
+                _ => return Err(self.error(status))
+            }
+        }
+    }
+
+    /// Compresses one chunk of input data and ends it with a full flush.  The compressed output of the chunk 
+    /// ends at a byte boundary and the next chunk is compressed without referencing the data before it.
+    /// Set final_input for the last chunk to finish the DEFLATE stream instead.
+    /// The compressed data are sent to caller via the write_fn callback.
+    pub fn compress_flush(&mut self,
+                          input_buf: &[u8],
+                          final_input: bool,
+                          write_fn: |out_buf: &[u8]|) -> Result<(), DeflateError> {
+
+        let tdefl_flush = if final_input { TDEFL_FINISH } else { TDEFL_FULL_FLUSH };
+        let out_buf_total = self.out_buf.len();
+        let mut in_offset = 0u;
+
+        loop {
+            let mut in_bytes = input_buf.len() - in_offset;
+            let mut out_bytes = out_buf_total;
+            let status = self.compress_buf_flush(input_buf, in_offset, &mut in_bytes, self.out_buf, 0, &mut out_bytes, tdefl_flush);
+            in_offset += in_bytes;
+            self.read_total += in_bytes;
+            self.write_total += out_bytes;
+            if out_bytes > 0 {
+                write_fn(self.out_buf.slice(0, out_bytes));
+            }
+
+            match status {
+                DeflateStatusDone => return Ok(()),
+                DeflateStatusOkay => {
+                    // The flush has completed when all input is consumed and out_buf has room left.
+                    if !final_input && in_offset == input_buf.len() && out_bytes < out_buf_total {
+                        return Ok(());
+                    }
+                },
+                _ => return Err(self.error(status))
+            }
+        }
+    }
+
     /// Low level compress method to compress input data to DEFLATE compliant compressed data.
     /// You really need to know what you are doing to call this directly.  It's fragile with edge cases.
     /// It has multiple modes of operation depending on the parameters.
@@ -437,15 +856,51 @@ impl Deflator {
     /// in_offset is the offset into in_buf to start reading the data.
     /// in_bytes is the number of bytes to read starting from in_offset, as call input.
     /// in_bytes is the number of bytes has been consumed, as call output.
-    /// out_buf is the compressed output data.  The size of out_buf must be as big or bigger than in_buf.
+    /// out_buf is the compressed output data.  Size it with compress_bound() to compress all of in_buf in one call.
     /// out_offset is the offset into out_buf to start writing the compressed data.
     /// out_bytes is the number of bytes available to store the compressed data starting from out_offset, as call input.
     /// out_bytes is the number of bytes has been used up to store the compressed data, as call output.
     /// final_input set to false if there will be calls again for more input data, set to true for the last batch of input.
+    /// Returns Ok(DeflateStatusOkay) for more input, Ok(DeflateStatusDone) when the data are finalized, and
+    /// Ok(DeflateStatusOutputFull) when out_buf has run out of room; call again with more room to get the rest.
+    /// The DeflateError has the in_bytes and out_bytes of the failed call.
     pub fn compress_buf(&self, 
                         in_buf:  &[u8], in_offset:  uint, in_bytes:  &mut uint, 
                         out_buf: &[u8], out_offset: uint, out_bytes: &mut uint, 
-                        final_input: bool) -> DeflateStatus {
+                        final_input: bool) -> Result<DeflateStatus, DeflateError> {
+        let status = self.compress_batch(in_buf, in_offset, in_bytes, out_buf, out_offset, out_bytes, final_input);
+        match status {
+            DeflateStatusOkay | DeflateStatusDone | DeflateStatusOutputFull => Ok(status),
+            _ => Err(DeflateError { status: status, in_consumed: *in_bytes as u64, out_total: *out_bytes as u64 })
+        }
+    }
+
+    // Same as compress_buf() but with the status returned as it is.
+    fn compress_batch(&self, 
+                      in_buf:  &[u8], in_offset:  uint, in_bytes:  &mut uint, 
+                      out_buf: &[u8], out_offset: uint, out_bytes: &mut uint, 
+                      final_input: bool) -> DeflateStatus {
+        let out_space = *out_bytes;
+        let status = self.compress_buf_flush(in_buf, in_offset, in_bytes, out_buf, out_offset, out_bytes, 
+                                             if final_input { TDEFL_FINISH } else { TDEFL_NO_FLUSH });
+        match status {
+            DeflateStatusOkay if *out_bytes == out_space => DeflateStatusOutputFull,
+            _ => status
+        }
+    }
+
+    /// Returns the worst-case size of the compressed data for input_len bytes of input, including the
+    /// ZLib header and ADLER32.  An out_buf of this size lets compress_buf() compress all the input in one call.
+    pub fn compress_bound(input_len: uint) -> uint {
+        // Incompressible data goes out in stored blocks, 5 bytes of overhead per block of up to 31K.
+        num::max(128 + (input_len * 110) / 100, 128 + input_len + ((input_len / (31 * 1024)) + 1) * 5)
+    }
+
+    /// Same as compress_buf() but with the tdefl flush mode passed in directly.
+    fn compress_buf_flush(&self, 
+                          in_buf:  &[u8], in_offset:  uint, in_bytes:  &mut uint, 
+                          out_buf: &[u8], out_offset: uint, out_bytes: &mut uint, 
+                          tdefl_flush: c_int) -> DeflateStatus {
         #[inline(never)];
 
         let mut status : c_int = 0;
@@ -457,12 +912,20 @@ impl Deflator {
         in_buf_next.as_imm_buf(|in_next_ptr, _| {
             out_buf_next.as_imm_buf(|out_next_ptr, _| {
                 unsafe {
-                    status = rustrt::tdefl_compress(self.tdefl_compressor, 
-                                                    in_next_ptr as *c_void, 
-                                                    &mut in_bytes_sz, 
-                                                    out_next_ptr as *c_void, 
-                                                    &mut out_bytes_sz, 
-                                                    if final_input { TDEFL_FINISH } else { TDEFL_NO_FLUSH });
+                    status = match self.backend {
+                        BackendRuntime  => rustrt::tdefl_compress(self.tdefl_compressor, 
+                                                                  in_next_ptr as *c_void, 
+                                                                  &mut in_bytes_sz, 
+                                                                  out_next_ptr as *c_void, 
+                                                                  &mut out_bytes_sz, 
+                                                                  tdefl_flush),
+                        BackendNative   => native_deflate::tdefl_compress(self.tdefl_compressor, 
+                                                                          in_next_ptr as *c_void, 
+                                                                          &mut in_bytes_sz, 
+                                                                          out_next_ptr as *c_void, 
+                                                                          &mut out_bytes_sz, 
+                                                                          tdefl_flush),
+                    };
                 }
             })
         });
@@ -472,6 +935,183 @@ impl Deflator {
         return DeflateStatus::from_status(status);
     }
 
+    // The DeflateError of the failed status, with the progress so far.
+    fn error(&self, status: DeflateStatus) -> DeflateError {
+        DeflateError { status: status, in_consumed: self.read_total as u64, out_total: self.write_total as u64 }
+    }
+
+}
+
+/// Compresses a block of data independently into a DEFLATE segment.  Used for compressing blocks in parallel.
+/// A non-last segment ends with a sync flush at a byte boundary, and the last segment finishes the DEFLATE stream.
+/// Concatenating all the segments in order forms one valid DEFLATE stream.
+/// Each segment starts with an empty dictionary, so the compression ratio is slightly lower than compressing in one pass.
+pub fn compress_segment(input: &[u8], compress_level: uint, is_last: bool) -> Result<~[u8], DeflateError> {
+    // Only the tdefl_compressor of the Deflator is used; use the smallest internal buffers.
+    let deflator = Deflator::with_size_factor(MIN_SIZE_FACTOR);
+    match deflator.init(compress_level, false, false) {
+        Ok(_) => (),
+        Err(err) => return Err(err)
+    }
+
+    let tdefl_flush = if is_last { TDEFL_FINISH } else { TDEFL_SYNC_FLUSH };
+    let mut output = vec::with_capacity(input.len() + 64);
+    match compress_all_flush(&deflator, input, tdefl_flush, &mut output) {
+        Ok(_) => Ok(output),
+        Err(err) => Err(err)
+    }
+}
+
+/// The final empty block with the fixed Huffman codes, ending a DEFLATE stream.
+static FINAL_EMPTY_BLOCK : [u8, ..2] = [0x03, 0x00];
+
+/// Concatenate the segments into one DEFLATE stream, without recompressing them.
+/// Each segment must end on a byte-aligned flush without the final block, as the segments from
+/// compress_segment() with is_last unset and from Deflator::end_segment() do.
+/// A final empty block is appended to end the stream.
+pub fn concat_segments(segments: &[~[u8]]) -> ~[u8] {
+    let total_len = segments.iter().fold(FINAL_EMPTY_BLOCK.len(), |total, segment| total + segment.len());
+    let mut output = vec::with_capacity(total_len);
+    for segment in segments.iter() {
+        output.push_all(*segment);
+    }
+    output.push_all(FINAL_EMPTY_BLOCK);
+    output
+}
+
+// Compress all the input with the tdefl_flush mode, appending the compressed data to output.
+// Return DeflateStatusDone for TDEFL_FINISH, or DeflateStatusOkay when the flush has completed.
+fn compress_all_flush(deflator: &Deflator, input: &[u8], tdefl_flush: c_int, output: &mut ~[u8]) -> Result<DeflateStatus, DeflateError> {
+    let out_buf = vec::from_elem(input.len() + MIN_DECOMPRESS_BUF_SIZE, 0u8);
+    let out_start = output.len();
+    let mut in_offset = 0u;
+    loop {
+        let mut in_bytes = input.len() - in_offset;
+        let mut out_bytes = out_buf.len();
+        let status = deflator.compress_buf_flush(input, in_offset, &mut in_bytes, out_buf, 0, &mut out_bytes, tdefl_flush);
+        in_offset += in_bytes;
+        output.push_all(out_buf.slice(0, out_bytes));
+
+        match status {
+            DeflateStatusDone => return Ok(status),
+            DeflateStatusOkay => {
+                // The flush has completed when all input is consumed and out_buf has room left.
+                if tdefl_flush != TDEFL_FINISH && in_offset == input.len() && out_bytes < out_buf.len() {
+                    return Ok(status);
+                }
+            },
+            _ => return Err(DeflateError { status: status, in_consumed: in_offset as u64, out_total: (output.len() - out_start) as u64 })
+        }
+    }
+}
+
+
+/// The size of the blocks sampled by estimate_ratio().
+pub static SAMPLE_BLOCK_SIZE : uint = 4096;
+/// The maximum number of blocks sampled by estimate_ratio().
+pub static SAMPLE_BLOCK_COUNT : uint = 8;
+// Deflating is worth it when the samples compress below this ratio.
+static WORTH_DEFLATING_RATIO : f64 = 0.9;
+
+/// Estimates the compression ratio of the data, the compressed size over the original size, by compressing
+/// up to SAMPLE_BLOCK_COUNT blocks of SAMPLE_BLOCK_SIZE bytes sampled evenly across the data at level 1.
+/// It's much faster than compressing all the data, and close enough to decide whether to deflate or store.
+/// Return 1.0 for empty data.
+pub fn estimate_ratio(data: &[u8]) -> f64 {
+    if data.len() == 0 {
+        return 1f64;
+    }
+
+    let deflator = Deflator::with_size_factor(MIN_SIZE_FACTOR);
+    let block_count = num::min(SAMPLE_BLOCK_COUNT, (data.len() + SAMPLE_BLOCK_SIZE - 1) / SAMPLE_BLOCK_SIZE);
+    let stride = if block_count > 1 { (data.len() - SAMPLE_BLOCK_SIZE) / (block_count - 1) } else { 0 };
+    let mut sampled_len = 0u;
+    let mut compressed = ~[];
+    for i in range(0, block_count) {
+        let block = data.slice(i * stride, num::min(i * stride + SAMPLE_BLOCK_SIZE, data.len()));
+        match deflator.init(1, false, false) {
+            Ok(_) => (),
+            Err(_) => return 1f64
+        }
+        match compress_all_flush(&deflator, block, TDEFL_FINISH, &mut compressed) {
+            Ok(_) => sampled_len += block.len(),
+            Err(_) => return 1f64
+        }
+    }
+    compressed.len() as f64 / sampled_len as f64
+}
+
+/// Checks whether the data are worth deflating, with estimate_ratio().  Already-compressed data, like
+/// JPEG, MP3 and zip files, aren't, and are better stored as they are.
+pub fn is_worth_deflating(data: &[u8]) -> bool {
+    estimate_ratio(data) < WORTH_DEFLATING_RATIO
+}
+
+/// Compressor for many small independent messages sharing a dictionary, e.g. sub-kilobyte JSON messages
+/// with common keys.  It is constructed once with the dictionary and the level, and reused for each message.
+///
+/// The dictionary is a preset dictionary.  Each payload is a complete zlib stream (RFC 1950) with the FDICT flag
+/// and the ADLER32 of the dictionary as the DICTID, whose matches can refer back into the last 32K of the
+/// dictionary.  Any zlib decompressor given the same dictionary decompresses it, e.g. zlib's inflateSetDictionary().
+/// The native compressor and decompressor are primed with the dictionary once at construction, and each message
+/// starts from a copy of them.  The messages don't depend on each other; any payload decompresses on its own.
+pub struct SmallMessageCompressor {
+    priv compressor:    NativeCompressor,
+    priv decompressor:  NativeDecompressor,
+}
+
+impl SmallMessageCompressor {
+
+    /// Create the compressor with the shared dictionary and the compress_level (0-9).
+    /// Return the DeflateError with DeflateStatusBadParam for an invalid compress_level.
+    pub fn new(dictionary: &[u8], compress_level: uint) -> Result<SmallMessageCompressor, DeflateError> {
+        if compress_level > MAX_COMPRESS_LEVEL {
+            return Err(DeflateError { status: DeflateStatusBadParam, in_consumed: 0, out_total: 0 });
+        }
+        let params = CompressParams::new(compress_level).zlib_header(true);
+        let mut compressor = NativeCompressor::new();
+        compressor.init(compress_flags(&params, true, None) as uint);
+        compressor.set_dictionary(dictionary);
+        let mut decompressor = NativeDecompressor::new();
+        decompressor.set_dictionary(dictionary);
+        Ok(SmallMessageCompressor { compressor: compressor, decompressor: decompressor })
+    }
+
+    /// Compress one message.  Return its payload, which doesn't include the dictionary.
+    pub fn compress(&self, message: &[u8]) -> ~[u8] {
+        let mut compressor = self.compressor.clone();
+        let mut out_buf = vec::from_elem(Deflator::compress_bound(message.len()), 0u8);
+        let mut payload = ~[];
+        let mut in_offset = 0u;
+        loop {
+            let (in_used, out_used, done) = compressor.compress(message.slice_from(in_offset), out_buf, TDEFL_FINISH);
+            in_offset += in_used;
+            payload.push_all(out_buf.slice_to(out_used));
+            if done {
+                return payload;
+            }
+        }
+    }
+
+    /// Decompress the payload of one message compressed with the same dictionary.
+    pub fn decompress(&self, payload: &[u8]) -> Result<~[u8], InflateError> {
+        let mut decompressor = self.decompressor.clone();
+        let mut out_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut output = ~[];
+        let mut in_offset = 0u;
+        loop {
+            let (in_used, out_used, status) = decompressor.decompress(payload.slice_from(in_offset), out_buf, 
+                                                                      TINFL_FLAG_PARSE_ZLIB_HEADER as uint);
+            in_offset += in_used;
+            output.push_all(out_buf.slice_to(out_used));
+            match InflateStatus::from_status(status) {
+                InflateStatusHasMoreOutput => (),
+                InflateStatusDone => return Ok(output),
+                status => return Err(InflateError { status: status, in_consumed: in_offset as u64, out_total: output.len() as u64 })
+            }
+        }
+    }
+
 }
 
 /// destructor
@@ -482,9 +1122,86 @@ impl Drop for Deflator {
 }
 
 
+// The decompressor of the Inflator's backend, apart from the Inflator's buffers so they can be passed to it.
+// The runtime's is behind the tinfl_decompressor handle.  The native one is called on the slices directly.
+struct Decompressor {
+    tinfl_decompressor: *c_void,
+    native: Option<NativeDecompressor>,
+}
+
+impl Decompressor {
+    fn new(backend: DeflateBackend) -> Decompressor {
+        match backend {
+            BackendRuntime  => Decompressor { tinfl_decompressor: unsafe { rustrt::tinfl_decompressor_alloc() }, native: None },
+            BackendNative   => Decompressor { tinfl_decompressor: ptr::null(), native: Some(NativeDecompressor::new()) },
+        }
+    }
+
+    fn is_allocated(&self) -> bool {
+        self.tinfl_decompressor != ptr::null() || self.native.is_some()
+    }
+
+    fn free(&mut self) {
+        unsafe {
+            if self.tinfl_decompressor != ptr::null() {
+                rustrt::tinfl_decompressor_free(self.tinfl_decompressor);
+            }
+        }
+        self.tinfl_decompressor = ptr::null();
+        self.native = None;
+    }
+
+    // Decompress in_buf into out_buf from out_offset with the tinfl flags.  in_bytes and out_bytes are the bytes
+    // available as call input, and the bytes consumed and written as call output.
+    fn decompress(&mut self, 
+                  in_buf:  &[u8], in_bytes: &mut uint, 
+                  out_buf: &mut [u8], out_offset: uint, out_bytes: &mut uint, 
+                  decompress_flags: c_uint) -> c_int {
+        #[inline(never)];
+
+        let in_buf_next = in_buf.slice_to(*in_bytes);
+        match self.native {
+            Some(ref mut decompressor) => {
+                let (in_used, out_used, status) = decompressor.decompress(in_buf_next, 
+                                                                          out_buf.mut_slice(out_offset, out_offset + *out_bytes), 
+                                                                          decompress_flags as uint);
+                *in_bytes = in_used;
+                *out_bytes = out_used;
+                status
+            },
+            None => {
+                let mut status : c_int = 0;
+                let mut in_bytes_sz  = *in_bytes as size_t;
+                let mut out_bytes_sz = *out_bytes as size_t;
+                let out_buf: &[u8] = out_buf;
+                let out_buf_next = out_buf.slice(out_offset, out_offset + *out_bytes);
+                in_buf_next.as_imm_buf( |in_next_ptr, _| {
+                    out_buf.as_imm_buf( |out_base_ptr, _| {
+                        out_buf_next.as_imm_buf( |out_next_ptr, _| {
+                            unsafe {
+                                status = rustrt::tinfl_decompress(self.tinfl_decompressor, 
+                                                                  in_next_ptr as *c_void, 
+                                                                  &mut in_bytes_sz, 
+                                                                  out_base_ptr as *c_void, 
+                                                                  out_next_ptr as *c_void, 
+                                                                  &mut out_bytes_sz, 
+                                                                  decompress_flags);
+                            }
+                        })
+                    })
+                });
+                *in_bytes = in_bytes_sz as uint;
+                *out_bytes = out_bytes_sz as uint;
+                status
+            }
+        }
+    }
+}
+
+
 /// Decompression data structure
 struct Inflator {
-    priv tinfl_decompressor: *c_void,
+    priv decompressor: Decompressor,
     priv in_buf: ~[u8],
     priv out_buf: ~[u8],
     priv in_offset: uint,                // beginning of the pending input data for decompression
@@ -492,6 +1209,11 @@ struct Inflator {
     priv out_begin: uint,                // beginning of cached output
     priv out_offset: uint,               // end of the cached output, beginning of available space for decompression.
     priv decomp_done: bool,
+    priv in_consumed: u64,               // input bytes consumed by decompression, excluding the unprocessed rest
+    priv out_total: u64,                 // bytes decompressed by decompress_read() and decompress_drain()
+    priv parse_zlib_header: bool,
+    priv verify_adler32: bool,
+    priv backend: DeflateBackend,
     read_total: uint,
     write_total: uint,
 }
@@ -505,39 +1227,68 @@ impl Inflator {
     /// Creates the Inflator structure and allocates the underlying tdefl_compressor structure.
     /// Allocates the IO buffers with buf_size_factor.  The buf_size_factor is a power of 2 of K: 2^buf_size_factor X 1K.
     pub fn with_size_factor(buf_size_factor: uint) -> Inflator {
+        Inflator::with_backend(buf_size_factor, BackendRuntime)
+    }
+
+    /// Creates the Inflator structure with the decompression backend, and the IO buffers with buf_size_factor.
+    pub fn with_backend(buf_size_factor: uint, backend: DeflateBackend) -> Inflator {
         #[inline(never)];
-        unsafe {
-            Inflator {
-                tinfl_decompressor: rustrt::tinfl_decompressor_alloc(),
-                in_buf:             vec::from_elem(calc_buf_size(buf_size_factor), 0u8),
-                out_buf:            vec::from_elem(calc_buf_size(buf_size_factor) * 2, 0u8),  // out_buf size must be power of 2
-                in_offset:          0u,
-                in_buf_total:       0u,
-                out_begin:          0u,
-                out_offset:         0u,
-                decomp_done:        false,
-                read_total:         0u,
-                write_total:        0u,
-            }
+        Inflator {
+            decompressor:       Decompressor::new(backend),
+            in_buf:             vec::from_elem(calc_buf_size(buf_size_factor), 0u8),
+            out_buf:            vec::from_elem(calc_buf_size(buf_size_factor) * 2, 0u8),  // out_buf size must be power of 2
+            in_offset:          0u,
+            in_buf_total:       0u,
+            out_begin:          0u,
+            out_offset:         0u,
+            decomp_done:        false,
+            in_consumed:        0u64,
+            out_total:          0u64,
+            parse_zlib_header:  false,
+            verify_adler32:     false,
+            backend:            backend,
+            read_total:         0u,
+            write_total:        0u,
         }
     }
 
+    /// Initializes the Inflator for a new stream, discarding the state and the buffered data of any previous stream.
+    /// A new Inflator decompresses raw DEFLATE data without calling this.
+    ///
+    /// parse_zlib_header set to true to decompress the ZLib-format data (RFC 1950), with the zlib header in front of
+    /// and the ADLER32 checksum at the end of the deflated data, like the Deflator's add_zlib_header makes.
+    /// verify_adler32 set to true to fail with StatusAdler32Mismatch when the ADLER32 of the zlib data doesn't match.
+    /// It only applies with parse_zlib_header.
+    pub fn init(&mut self, parse_zlib_header: bool, verify_adler32: bool) -> Result<(), InflateError> {
+        #[inline(never)];
+        self.free();
+        self.decompressor = Decompressor::new(self.backend);
+        if !self.decompressor.is_allocated() {
+            return Err(InflateError { status: InflateStatusBadParam, in_consumed: 0, out_total: 0 });
+        }
+        self.in_offset = 0;
+        self.in_buf_total = 0;
+        self.out_begin = 0;
+        self.out_offset = 0;
+        self.decomp_done = false;
+        self.in_consumed = 0;
+        self.out_total = 0;
+        self.parse_zlib_header = parse_zlib_header;
+        self.verify_adler32 = verify_adler32;
+        Ok(())
+    }
+
     /// Releases the underlying tinfl_decompressor structure.  After this call, the instance must not be used anymore.
     fn free(&mut self) {
         #[inline(never)];
-        unsafe {
-            if self.tinfl_decompressor != ptr::null() {
-                rustrt::tinfl_decompressor_free(self.tinfl_decompressor);
-            }
-            self.tinfl_decompressor = ptr::null();
-        }
+        self.decompressor.free();
     }
 
     /// Reads the input data from reader, decompressed them, and writes them to writer.
     /// Any extra input data from the reader beyond the compressed data are discarded.
     /// Loops until reading EOF from reader.  Waits on read or wait on write if they are blocked.
     /// Demo usage of decompress_stream().
-    pub fn decompress_stream_rw<R: Reader, W: Writer>(&mut self, in_reader: &mut R, out_writer: &mut W) -> InflateStatus {
+    pub fn decompress_stream_rw<R: Reader, W: Writer>(&mut self, in_reader: &mut R, out_writer: &mut W) -> Result<(), InflateError> {
         self.decompress_stream(
             // upcall function to read input data for decompression
             |in_buf| {
@@ -580,13 +1331,16 @@ impl Inflator {
     ///
     /// The callback write_fn takes in the out_buf buffer containing one batch of decompressed data.
     /// The is_eof flag is set for the last batch of decompressed data.
-    /// Write_fn can return an abort flag to abort the decompression.
+    /// Write_fn can return an abort flag to abort the decompression, which returns the InflateError with InflateStatusAbort.
     ///
     /// The callback rest_fn takes in the rest_buf buffer containing any extra unprocessed input data.
+    ///
+    /// When the input is cut off before the end of the compressed data, the data decompressed so far are passed
+    /// to write_fn as the last batch, and the InflateError with InflateStatusFailedCannotMakeProgress is returned.
     pub fn decompress_stream(&mut self, 
                              read_fn:  |in_buf: &mut [u8]|->uint, 
                              write_fn: |out_buf: &[u8], is_eof: bool|->bool,
-                             rest_fn:  |rest_buf: &[u8]| ) -> InflateStatus {
+                             rest_fn:  |rest_buf: &[u8]| ) -> Result<(), InflateError> {
 
         let out_buf_total = self.out_buf.len();
 
@@ -601,9 +1355,10 @@ impl Inflator {
             let mut in_bytes = self.in_buf_total - self.in_offset;
             let mut out_bytes = out_buf_total - self.out_offset;
             let final_input = self.in_buf_total == 0;
-            let status = self.decompress_buf(self.in_buf, self.in_offset, &mut in_bytes, final_input, 
-                                             self.out_buf, self.out_offset, &mut out_bytes, true);
+            let status = self.decompress_in_buf(&mut in_bytes, final_input, &mut out_bytes);
             self.in_offset += in_bytes;
+            self.in_consumed += in_bytes as u64;
+            self.out_total += out_bytes as u64;
             self.out_offset += out_bytes;
 
             match status {
@@ -614,7 +1369,7 @@ impl Inflator {
                     if self.out_offset == out_buf_total {
                         self.write_total += self.out_offset;
                         if write_fn(self.out_buf, false) {
-                            return InflateStatusAbort;
+                            return Err(self.error(InflateStatusAbort));
                         }
                         self.out_offset = 0;
                     }
@@ -623,9 +1378,15 @@ impl Inflator {
                     self.write_total += self.out_offset;
                     write_fn(self.out_buf.slice(0, self.out_offset), true);
                     rest_fn(self.in_buf.slice(self.in_offset, self.in_buf_total));
-                    return status;
+                    return Ok(());
+                },
+                InflateStatusFailedCannotMakeProgress => {
+                    // The input is cut off.  Write out what has been decompressed for the caller to recover.
+                    self.write_total += self.out_offset;
+                    write_fn(self.out_buf.slice(0, self.out_offset), true);
+                    return Err(self.error(status));
                 },
-                _ => return status  // return error
+                _ => return Err(self.error(status))
             }
         }
     }
@@ -635,68 +1396,227 @@ impl Inflator {
     /// Caller calls this function repeatedly to read all the decompressed data until output_len is 0.
     //  This approach has more buffer copying than the stream approach.
     /// The input to decompress are supplied by the read_fn callback function of the caller.
-    /// The decompressed data are returned to caller one batch at a time.
+    /// The decompressed data are returned to caller one batch at a time.  An output_buf larger than the internal
+    /// out_buf is filled with several batches in one call, as far as the decompressed data go.
+    /// The first read of at least MIN_DECOMPRESS_BUF_SIZE bytes decompresses straight into output_buf.
     /// After reaching the end of output, the remaining unprocessed input data can be retrieved with get_rest().
     pub fn decompress_read(&mut self, 
                            read_fn:  |in_buf: &mut [u8]|->uint, 
-                           output_buf: &mut [u8]) -> Result<uint, InflateStatus> {
+                           output_buf: &mut [u8]) -> Result<uint, InflateError> {
 
-        let out_buf_total = self.out_buf.len();
+        // The LZ dictionary has to be in the output buffer.  Before any output, output_buf can be the dictionary.
+        if self.out_total == 0 && !self.decomp_done && output_buf.len() >= MIN_DECOMPRESS_BUF_SIZE {
+            return self.decompress_read_direct(|in_buf| read_fn(in_buf), output_buf);
+        }
 
+        // Afterward the decompression stays in the internal out_buf, which is also the LZ dictionary being re-used,
+        // so the output is copied out of it once.
+        let mut output_len = 0u;
         loop {
             // Drain all output data from the internal out_buf.
             let out_available_bytes = self.out_offset - self.out_begin;
             if out_available_bytes > 0 {
-                let output_len = num::min(output_buf.len(), out_available_bytes);
-                vec::bytes::copy_memory(output_buf, self.out_buf.slice(self.out_begin, self.out_begin + output_len), output_len);
-                self.out_begin += output_len;
+                let copy_len = num::min(output_buf.len() - output_len, out_available_bytes);
+                vec::bytes::copy_memory(output_buf.mut_slice_from(output_len), 
+                                        self.out_buf.slice(self.out_begin, self.out_begin + copy_len), copy_len);
+                self.out_begin += copy_len;
+                output_len += copy_len;
+            }
+
+            // Stop when output_buf is full or it's already done.  Refill only when a whole out_buf fits,
+            // to keep the small reads at one batch.
+            if output_len == output_buf.len() || self.decomp_done || 
+                (output_len > 0 && output_buf.len() - output_len < self.out_buf.len()) {
                 return Ok(output_len);
             }
 
-            // If it's already done, just return
+            match self.fill_out_buf(|in_buf| read_fn(in_buf)) {
+                Ok(_) => (),
+                // The error shows up again on the next call after returning the data decompressed so far.
+                Err(_) if output_len > 0 => return Ok(output_len),
+                Err(status) => return Err(status)
+            }
+        }
+    }
+
+    // Decompress into output_buf from its beginning, with output_buf as the non-wrapping dictionary.  When output_buf
+    // fills up before the end, its last bytes are moved into out_buf as the dictionary for the decompression after it.
+    fn decompress_read_direct(&mut self, 
+                              read_fn:  |in_buf: &mut [u8]|->uint, 
+                              output_buf: &mut [u8]) -> Result<uint, InflateError> {
+        let mut output_len = 0u;
+        loop {
+            if self.in_offset == self.in_buf_total {
+                self.in_buf_total = read_fn(self.in_buf);       // in_buf_total == 0 for EOF
+                self.in_offset = 0;
+            }
+
+            let mut in_bytes = self.in_buf_total - self.in_offset;
+            let mut out_bytes = output_buf.len() - output_len;
+            let final_input = self.in_buf_total == 0;
+            let decompress_flags = self.decompress_flags(final_input, false);
+            let status = self.decompressor.decompress(self.in_buf.slice_from(self.in_offset), &mut in_bytes, 
+                                                      output_buf, output_len, &mut out_bytes, decompress_flags);
+            let status = self.to_status(status);
+            self.in_offset += in_bytes;
+            self.in_consumed += in_bytes as u64;
+            self.out_total += out_bytes as u64;
+            output_len += out_bytes;
+
+            match status {
+                InflateStatusNeedsMoreInput | InflateStatusHasMoreOutput => {
+                    if output_len == output_buf.len() {
+                        self.load_dictionary(output_buf.slice_to(output_len));
+                        return Ok(output_len);
+                    }
+                },
+                InflateStatusDone => {
+                    self.decomp_done = true;
+                    return Ok(output_len);
+                },
+                _ => return Err(self.error(status))
+            }
+        }
+    }
+
+    // Copy the end of the output decompressed outside out_buf to where the wrapping decompression expects it in
+    // out_buf, at the output position modulo the out_buf size, and continue from there.
+    fn load_dictionary(&mut self, output: &[u8]) {
+        let out_buf_total = self.out_buf.len();
+        let keep_len = num::min(output.len(), out_buf_total);
+        for pos in range(output.len() - keep_len, output.len()) {
+            self.out_buf[pos % out_buf_total] = output[pos];
+        }
+        self.out_offset = output.len() % out_buf_total;
+        self.out_begin = self.out_offset;
+    }
+
+    /// Decompress all the remaining data, passing them to the write_fn callback directly from the internal out_buf.
+    /// It's like calling decompress_read() until the end of output, without copying the data to a caller's buffer.
+    /// Return the number of decompressed bytes passed to write_fn.
+    /// After reaching the end of output, the remaining unprocessed input data can be retrieved with get_rest().
+    pub fn decompress_drain(&mut self, 
+                            read_fn:  |in_buf: &mut [u8]|->uint, 
+                            write_fn: |out_buf: &[u8]|) -> Result<u64, InflateError> {
+        let mut total = 0u64;
+        loop {
+            // Drain all output data from the internal out_buf.
+            if self.out_offset > self.out_begin {
+                write_fn(self.out_buf.slice(self.out_begin, self.out_offset));
+                total += (self.out_offset - self.out_begin) as u64;
+                self.out_begin = self.out_offset;
+            }
+
             if self.decomp_done {
-                return Ok(0);
+                return Ok(total);
+            }
+
+            match self.fill_out_buf(|in_buf| read_fn(in_buf)) {
+                Ok(_) => (),
+                Err(status) => return Err(status)
+            }
+        }
+    }
+
+    /// Decompress like decompress_read(), but return as soon as some data are decompressed, instead of reading
+    /// more input to fill up output_buf or the internal out_buf.  It suits the interactive streams, where the peer
+    /// sync flushes a message and waits for the reply before sending more.  Return Ok(0) at the end of output.
+    pub fn decompress_read_some(&mut self, 
+                                read_fn:  |in_buf: &mut [u8]|->uint, 
+                                output_buf: &mut [u8]) -> Result<uint, InflateError> {
+        let out_buf_total = self.out_buf.len();
+        loop {
+            let out_available_bytes = self.out_offset - self.out_begin;
+            if out_available_bytes > 0 || self.decomp_done {
+                let copy_len = num::min(output_buf.len(), out_available_bytes);
+                vec::bytes::copy_memory(output_buf, self.out_buf.slice(self.out_begin, self.out_begin + copy_len), copy_len);
+                self.out_begin += copy_len;
+                return Ok(copy_len);
+            }
+
+            // Everything is drained.  Wrap around when out_buf is full.
+            if self.out_offset == out_buf_total {
+                self.out_offset = 0;
+                self.out_begin = 0;
+            }
+            if self.in_offset == self.in_buf_total {
+                self.in_buf_total = read_fn(self.in_buf);       // in_buf_total == 0 for EOF
+                self.in_offset = 0;
+            }
+
+            let mut in_bytes = self.in_buf_total - self.in_offset;
+            let mut out_bytes = out_buf_total - self.out_offset;
+            let final_input = self.in_buf_total == 0;
+            let status = self.decompress_in_buf(&mut in_bytes, final_input, &mut out_bytes);
+            self.in_offset += in_bytes;
+            self.in_consumed += in_bytes as u64;
+            self.out_total += out_bytes as u64;
+            self.out_offset += out_bytes;
+
+            match status {
+                InflateStatusNeedsMoreInput | InflateStatusHasMoreOutput => (),
+                InflateStatusDone => self.decomp_done = true,
+                _ => return Err(self.error(status))
             }
+        }
+    }
 
-            // Loop to decompress to fill up the internal out_buf
-            self.out_begin = 0;
+    // Decompress the input from read_fn to fill up the internal out_buf, or until the end of the compressed data.
+    fn fill_out_buf(&mut self, read_fn: |in_buf: &mut [u8]|->uint) -> Result<(), InflateError> {
+        let out_buf_total = self.out_buf.len();
+        // Wrap around when out_buf is full.  It's partly filled only after load_dictionary().
+        if self.out_offset == out_buf_total {
             self.out_offset = 0;
-            loop {
-                // Read some input data if in_buf is empty
-                if self.in_offset == self.in_buf_total {
-                    self.in_buf_total = read_fn(self.in_buf);       // in_buf_total == 0 for EOF
-                    self.in_offset = 0;
-                }
+        }
+        self.out_begin = self.out_offset;
+        loop {
+            // Read some input data if in_buf is empty
+            if self.in_offset == self.in_buf_total {
+                self.in_buf_total = read_fn(self.in_buf);       // in_buf_total == 0 for EOF
+                self.in_offset = 0;
+            }
 
-                let mut in_bytes = self.in_buf_total - self.in_offset;
-                let mut out_bytes = out_buf_total - self.out_offset;
-                let final_input = self.in_buf_total == 0;
-                let status = self.decompress_buf(self.in_buf, self.in_offset, &mut in_bytes, final_input, 
-                                                 self.out_buf, self.out_offset, &mut out_bytes, true);
-                self.in_offset += in_bytes;
-                self.out_offset += out_bytes;
-
-                match status {
-                    InflateStatusNeedsMoreInput | InflateStatusHasMoreOutput => {
-                        // The internal out_buf is full; break out to drain output.
-                        // Important to process until out_buf is full because the LZ dictionary 
-                        // at the beginning of the buffer is being re-used until buf is full.
-                        if self.out_offset == out_buf_total {
-                            break;
-                        }
-                        // Otherwise loop back to read more input
-                    },
-                    InflateStatusDone => {
-                        // Break out to drain output.
-                        self.decomp_done = true;
-                        break;
-                    },
-                    _ => return Err(status)
-                }
+            let mut in_bytes = self.in_buf_total - self.in_offset;
+            let mut out_bytes = out_buf_total - self.out_offset;
+            let final_input = self.in_buf_total == 0;
+            let status = self.decompress_in_buf(&mut in_bytes, final_input, &mut out_bytes);
+            self.in_offset += in_bytes;
+            self.in_consumed += in_bytes as u64;
+            self.out_total += out_bytes as u64;
+            self.out_offset += out_bytes;
+
+            match status {
+                InflateStatusNeedsMoreInput | InflateStatusHasMoreOutput => {
+                    // The internal out_buf is full; return to drain output.
+                    // Important to process until out_buf is full because the LZ dictionary 
+                    // at the beginning of the buffer is being re-used until buf is full.
+                    if self.out_offset == out_buf_total {
+                        return Ok(());
+                    }
+                    // Otherwise loop back to read more input
+                },
+                InflateStatusDone => {
+                    // Return to drain output.
+                    self.decomp_done = true;
+                    return Ok(());
+                },
+                _ => return Err(self.error(status))
             }
         }
     }
 
+    // The InflateError of the failed status, with the progress so far.
+    fn error(&self, status: InflateStatus) -> InflateError {
+        InflateError { status: status, in_consumed: self.in_consumed, out_total: self.out_total }
+    }
+
+    /// Gets the number of input bytes consumed by decompression so far, counting from the first byte read.
+    /// It's valid in the middle of the stream.  After reaching the end of output, it's the exact length of
+    /// the compressed data, with the bytes after it left in get_rest().
+    pub fn get_in_consumed(&self) -> u64 {
+        return self.in_consumed;
+    }
+
     /// Gets the buffer length of the rest_buf, the extra data left over from decompression.
     pub fn get_rest_len(&self) -> uint {
         return self.in_buf_total - self.in_offset;
@@ -726,51 +1646,290 @@ impl Inflator {
     /// beginning of the buffer needed to be kept for subsequent calls).  This is typically for using a smaller out_buf
     /// to repeatedly decompress large input data.  Set reuse_out_buf to false if out_buf is not being reused;
     /// typically the buffer is big enough to contain all decompressed data.
-    pub fn decompress_buf(&self,
+    /// Returns Ok(InflateStatusDone) at the end of the compressed data, Ok(InflateStatusNeedsMoreInput) or
+    /// Ok(InflateStatusHasMoreOutput) to call again.  The InflateError has the in_bytes and out_bytes of the failed call.
+    pub fn decompress_buf(&mut self,
                           in_buf:  &[u8], in_offset:  uint, in_bytes:  &mut uint, final_input: bool, 
-                          out_buf: &[u8], out_offset: uint, out_bytes: &mut uint, reuse_out_buf: bool) -> InflateStatus {
-        #[inline(never)];
+                          out_buf: &mut [u8], out_offset: uint, out_bytes: &mut uint, reuse_out_buf: bool) -> Result<InflateStatus, InflateError> {
+        let decompress_flags = self.decompress_flags(final_input, reuse_out_buf);
+        let status = self.decompressor.decompress(in_buf.slice_from(in_offset), in_bytes, 
+                                                  out_buf, out_offset, out_bytes, decompress_flags);
+        let status = self.to_status(status);
+        match status {
+            InflateStatusDone | InflateStatusNeedsMoreInput | InflateStatusHasMoreOutput => Ok(status),
+            _ => Err(InflateError { status: status, in_consumed: *in_bytes as u64, out_total: *out_bytes as u64 })
+        }
+    }
+
+    // Decompress the pending input of in_buf into out_buf from out_offset, re-using out_buf, like decompress_buf().
+    fn decompress_in_buf(&mut self, in_bytes: &mut uint, final_input: bool, out_bytes: &mut uint) -> InflateStatus {
+        let decompress_flags = self.decompress_flags(final_input, true);
+        let status = self.decompressor.decompress(self.in_buf.slice_from(self.in_offset), in_bytes, 
+                                                  self.out_buf, self.out_offset, out_bytes, decompress_flags);
+        self.to_status(status)
+    }
+
+    fn decompress_flags(&self, final_input: bool, reuse_out_buf: bool) -> c_uint {
+        if final_input   { 0 } else { TINFL_FLAG_HAS_MORE_INPUT } |
+        if reuse_out_buf { 0 } else { TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF } |
+        if self.parse_zlib_header { TINFL_FLAG_PARSE_ZLIB_HEADER } else { 0 } |
+        if self.parse_zlib_header && self.verify_adler32 { TINFL_FLAG_COMPUTE_ADLER32 } else { 0 }
+    }
+
+    fn to_status(&self, status: c_int) -> InflateStatus {
+        match InflateStatus::from_status(status) {
+            // The backends always check the ADLER32 of the zlib data.  The data are all out on the mismatch.
+            StatusAdler32Mismatch if !self.verify_adler32 => InflateStatusDone,
+            status => status
+        }
+    }
+
+}
+
+impl Drop for Inflator {
+    fn drop(&mut self) {
+        self.free();
+    }
+}
+
+
+/// A writer compressing any data written to it, as the raw DEFLATE data or the zlib format.
+/// Usage:
+///     let mut dwriter = DeflateWriter::new(output_writer);
+///     dwriter.write(plain_data_buf);
+///     write repeatedly to compress more data in the stream.
+///     dwriter.flush_with(FlushSync);     // optionally, to let the receiver decompress all the data so far.
+///     ...
+///     dwriter.finalize();     // must call finalize() to finish compression.
+///
+/// The output_writer receives the compressed data.
+pub struct DeflateWriter<W> {
+    priv inner_writer:  W,
+    priv deflator:      Deflator,
+    priv finalized:     bool,
+}
+
+impl<W: Writer> DeflateWriter<W> {
+
+    /// Create a DeflateWriter writing the raw DEFLATE data, with the default compress level.
+    pub fn new(inner_writer: W) -> DeflateWriter<W> {
+        DeflateWriter::with_params(inner_writer, &CompressParams::new(DEFAULT_COMPRESS_LEVEL), DEFAULT_SIZE_FACTOR)
+    }
+
+    /// Create a DeflateWriter compressing with the params, e.g. with the zlib header for the zlib format.
+    /// buf_size_factor is used for internal IO buffers.  It is the power of 2.
+    pub fn with_params(inner_writer: W, params: &CompressParams, buf_size_factor: uint) -> DeflateWriter<W> {
+        let deflator = Deflator::with_size_factor(buf_size_factor);
+        deflator.init_params(params);
+        DeflateWriter {
+            inner_writer:   inner_writer,
+            deflator:       deflator,
+            finalized:      false,
+        }
+    }
+
+    /// Finalize the compression stream and flush out any pending compressed data.
+    /// The caller must call this at the end of writing data into this writer.
+    /// After this is called, this writer cannot be written again.
+    pub fn finalize(&mut self) {
+        if !self.finalized {
+            self.do_write([], true);
+        }
+    }
+
+    /// Flush the compressed data of everything written so far to the inner writer with the flush_mode,
+    /// then flush the inner writer.  The compression stream continues.
+    /// Unlike flush(), which only flushes the inner writer, this flushes the data pending in the compressor.
+    pub fn flush_with(&mut self, flush_mode: FlushMode) {
+        if self.finalized {
+            io_error::cond.raise(IoError {
+                    kind:   OtherIoError,
+                    desc:   "Flushing on a closed stream.",
+                    detail: Some(~"The compression stream has been closed.")
+                });
+            return;
+        }
+        let inner_writer = &mut self.inner_writer;
+        match self.deflator.flush(flush_mode, |out_buf| inner_writer.write(out_buf)) {
+            Ok(_) => inner_writer.flush(),
+            Err(err) => {
+                self.finalized = true;
+                io_error::cond.raise(IoError {
+                        kind:   OtherIoError,
+                        desc:   "Flush failure in compression.",
+                        detail: Some(err.to_str())
+                    });
+            }
+        }
+    }
+
+    fn do_write(&mut self, buf: &[u8], final_write: bool) {
+        if self.finalized {
+            io_error::cond.raise(IoError {
+                    kind:   OtherIoError,
+                    desc:   "Writing on a closed stream.",
+                    detail: Some(~"The compression stream has been closed.")
+                });
+            return;
+        }
+        let inner_writer = &mut self.inner_writer;
+        let result = self.deflator.compress_write(buf, final_write, |out_buf, is_eof| {
+                inner_writer.write(out_buf);
+                if is_eof {
+                    inner_writer.flush();
+                }
+            });
+        match result {
+            Ok(_) => self.finalized = final_write,
+            Err(err) => {
+                self.finalized = true;
+                io_error::cond.raise(IoError {
+                        kind:   OtherIoError,
+                        desc:   "Write failure in compression.",
+                        detail: Some(err.to_str())
+                    });
+            }
+        }
+    }
+
+}
+
+impl<W: Writer> Writer for DeflateWriter<W> {
+
+    fn write(&mut self, buf: &[u8]) {
+        self.do_write(buf, false);
+    }
+
+    fn flush(&mut self) {
+        self.inner_writer.flush();
+    }
+
+}
+
+/// Decorator to access the inner writer
+impl<W: Writer> Decorator<W> for DeflateWriter<W> {
+    fn inner(self) -> W {
+        self.inner_writer
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a W {
+        &self.inner_writer
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        &mut self.inner_writer
+    }
+}
+
+
+/// A reader to read decompressed data automatically from an inner reader of the raw DEFLATE data or the zlib format.
+/// Usage:
+///     let mut dreader = InflateReader::new(input_reader);
+///     dreader.read(output_data_buf);
+///     ...
+///     read until got None
+///
+/// InflateReader.read() returns None at EOF.  The data after the end of the compressed data are read from the
+/// inner reader but not decompressed; get_rest() returns them.
+pub struct InflateReader<R> {
+    priv inner_reader:  R,
+    priv inflator:      Inflator,
+    priv is_eof:        bool,
+}
+
+impl<R: Reader> InflateReader<R> {
 
-        let mut status : c_int = 0;
-        let mut in_bytes_sz  = *in_bytes as size_t;
-        let mut out_bytes_sz = *out_bytes as size_t;
-        let in_buf_next  = in_buf.slice(in_offset, in_offset + *in_bytes);
-        let out_buf_next = out_buf.slice(out_offset, out_offset + *out_bytes);
-        let decompress_flags: c_uint = 
-            if final_input   { 0 } else { TINFL_FLAG_HAS_MORE_INPUT } |
-            if reuse_out_buf { 0 } else { TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF };
-
-        in_buf_next.as_imm_buf( |in_next_ptr, _| {
-            out_buf.as_imm_buf( |out_base_ptr, _| {
-                out_buf_next.as_imm_buf( |out_next_ptr, _| {
-                    unsafe {
-                        status = rustrt::tinfl_decompress(self.tinfl_decompressor, 
-                                                          in_next_ptr as *c_void, 
-                                                          &mut in_bytes_sz, 
-                                                          out_base_ptr as *c_void, 
-                                                          out_next_ptr as *c_void, 
-                                                          &mut out_bytes_sz, 
-                                                          decompress_flags);
-                    }
-                })
-            })
-        });
+    /// Create an InflateReader to decompress the raw DEFLATE data from the inner_reader.
+    pub fn new(inner_reader: R) -> InflateReader<R> {
+        InflateReader::with_params(inner_reader, false, DEFAULT_SIZE_FACTOR)
+    }
 
-        *in_bytes = in_bytes_sz as uint;
-        *out_bytes = out_bytes_sz as uint;
-        return InflateStatus::from_status(status);
+    /// Create an InflateReader to decompress the raw DEFLATE data from the inner_reader.
+    /// buf_size_factor is used for internal IO buffers.  It is the power of 2.
+    pub fn with_size_factor(inner_reader: R, buf_size_factor: uint) -> InflateReader<R> {
+        InflateReader::with_params(inner_reader, false, buf_size_factor)
+    }
+
+    /// Create an InflateReader to decompress data from the inner_reader.
+    /// parse_zlib_header set to true for the zlib format, with its ADLER32 checked at the end.
+    /// buf_size_factor is used for internal IO buffers.  It is the power of 2.
+    pub fn with_params(inner_reader: R, parse_zlib_header: bool, buf_size_factor: uint) -> InflateReader<R> {
+        let mut inflator = Inflator::with_size_factor(buf_size_factor);
+        inflator.init(parse_zlib_header, true);
+        InflateReader {
+            inner_reader:   inner_reader,
+            inflator:       inflator,
+            is_eof:         false,
+        }
+    }
+
+    /// Get the data read from the inner reader after the end of the compressed data.  Only valid at EOF.
+    pub fn get_rest(&self) -> ~[u8] {
+        let mut rest = vec::from_elem(self.inflator.get_rest_len(), 0u8);
+        self.inflator.get_rest(rest);
+        rest
     }
 
 }
 
-impl Drop for Inflator {
-    fn drop(&mut self) {
-        self.free();
+impl<R: Reader> Reader for InflateReader<R> {
+    /// Read the decompressed data from the inner_reader.
+    fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
+        if self.is_eof {
+            return None;
+        }
+        let inner_reader = &mut self.inner_reader;
+        let status = self.inflator.decompress_read(
+            // Callback to read input data.
+            |in_buf| {
+                match inner_reader.read(in_buf) {
+                    Some(nread) => nread,
+                    None => 0               // EOF
+                }
+            },
+            output_buf);
+
+        match status {
+            Ok(0) => {
+                self.is_eof = true;
+                None
+            },
+            Ok(output_len) => Some(output_len),
+            Err(err) => {
+                self.is_eof = true;
+                io_error::cond.raise(IoError {
+                        kind:   OtherIoError,
+                        desc:   "Read failure in decompression.",
+                        detail: Some(err.to_str())
+                    });
+                None
+            }
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        self.is_eof
+    }
+}
+
+/// Decorator to access the inner reader
+impl<R: Reader> Decorator<R> for InflateReader<R> {
+    fn inner(self) -> R {
+        self.inner_reader
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a R {
+        &self.inner_reader
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut R {
+        &mut self.inner_reader
     }
 }
 
 
 
+#[cfg(not(native))]
 fn deflate_bytes_internal(bytes: &[u8], flags: c_int) -> ~[u8] {
     #[inline(never)];
 
@@ -801,6 +1960,7 @@ pub fn deflate_bytes_zlib(bytes: &[u8]) -> ~[u8] {
     deflate_bytes_internal(bytes, LZ_NORM | TDEFL_WRITE_ZLIB_HEADER as c_int)
 }
 
+#[cfg(not(native))]
 fn inflate_bytes_internal(bytes: &[u8], flags: c_int) -> ~[u8] {
     #[inline(never)];
 
@@ -821,6 +1981,46 @@ fn inflate_bytes_internal(bytes: &[u8], flags: c_int) -> ~[u8] {
     })
 }
 
+// Without the miniz of the runtime, the byte helpers compress with BackendNative.
+#[cfg(native)]
+fn deflate_bytes_internal(bytes: &[u8], flags: c_int) -> ~[u8] {
+    let deflator = Deflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+    match deflator.init_flags(flags) {
+        DeflateStatusOkay => (),
+        _ => fail!("Failed to initialize the Deflator.")
+    }
+    let mut output = vec::with_capacity(Deflator::compress_bound(bytes.len()));
+    match compress_all_flush(&deflator, bytes, TDEFL_FINISH, &mut output) {
+        Ok(_) => output,
+        Err(_) => fail!("Failed to compress the data.")
+    }
+}
+
+#[cfg(native)]
+fn inflate_bytes_internal(bytes: &[u8], flags: c_int) -> ~[u8] {
+    let mut inflator = Inflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+    let parse_zlib_header = flags & (TINFL_FLAG_PARSE_ZLIB_HEADER as c_int) != 0;
+    inflator.init(parse_zlib_header, parse_zlib_header);
+    let mut output = vec::with_capacity(bytes.len() * 3);
+    let mut offset = 0u;
+    let result = inflator.decompress_stream(
+        |in_buf| {
+            let len = num::min(in_buf.len(), bytes.len() - offset);
+            vec::bytes::copy_memory(in_buf, bytes.slice(offset, offset + len), len);
+            offset += len;
+            len
+        },
+        |out_buf, _| {
+            output.push_all(out_buf);
+            false
+        },
+        |_| () );
+    match result {
+        Ok(_) => output,
+        Err(_) => fail!("Failed to decompress the data.")
+    }
+}
+
 /// Decompress a byte buffer to a buffer in heap
 pub fn inflate_bytes(bytes: &[u8]) -> ~[u8] {
     inflate_bytes_internal(bytes, 0)
@@ -831,6 +2031,61 @@ pub fn inflate_bytes_zlib(bytes: &[u8]) -> ~[u8] {
     inflate_bytes_internal(bytes, TINFL_FLAG_PARSE_ZLIB_HEADER as c_int)
 }
 
+/// Compress the data into a raw DEFLATE stream at the compress_level (0-9), in one call.
+/// Return the DeflateError for an invalid compress_level or a failed compression.
+pub fn compress(data: &[u8], compress_level: uint) -> Result<~[u8], DeflateError> {
+    let deflator = Deflator::with_size_factor(MIN_SIZE_FACTOR);
+    match deflator.init(compress_level, false, false) {
+        Ok(_) => (),
+        Err(err) => return Err(err)
+    }
+    let mut output = vec::with_capacity(Deflator::compress_bound(data.len()));
+    match compress_all_flush(&deflator, data, TDEFL_FINISH, &mut output) {
+        Ok(_) => Ok(output),
+        Err(err) => Err(err)
+    }
+}
+
+/// Decompress a raw DEFLATE stream in one call.  Any data after the end of the stream are ignored.
+/// Return the InflateError for corrupted or cut-off data.
+pub fn decompress(data: &[u8]) -> Result<~[u8], InflateError> {
+    let mut inflator = Inflator::with_size_factor(MIN_SIZE_FACTOR);
+    let mut output = vec::with_capacity(data.len() * 3);
+    let mut offset = 0u;
+    let result = inflator.decompress_stream(
+        |in_buf| {
+            let len = num::min(in_buf.len(), data.len() - offset);
+            vec::bytes::copy_memory(in_buf, data.slice(offset, offset + len), len);
+            offset += len;
+            len
+        },
+        |out_buf, _| {
+            output.push_all(out_buf);
+            false
+        },
+        |_| () );
+    match result {
+        Ok(_) => Ok(output),
+        Err(err) => Err(err)
+    }
+}
+
+/// Update the Adler-32 checksum with the data.  The checksum starts at 1.
+pub fn update_adler32(adler32: u32, buf: &[u8]) -> u32 {
+    let mut a = adler32 & 0xFFFF;
+    let mut b = adler32 >> 16;
+    // 5552 is the most bytes summed before b can overflow u32.
+    for chunk in buf.chunks(5552) {
+        for &byte in chunk.iter() {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
 
 
 #[cfg(test)]
@@ -838,6 +2093,7 @@ mod tests {
     use std::io::mem::MemWriter;
     use std::io::mem::MemReader;
     use std::io::Decorator;
+    use std::io::io_error;
     use std::vec;
     use std::num;
     use std::ptr;
@@ -846,8 +2102,29 @@ mod tests {
     use super::Deflator;
     use super::Inflator;
     use super::MIN_DECOMPRESS_BUF_SIZE;
+    use super::MIN_SIZE_FACTOR;
     use super::deflate_bytes;
+    use super::deflate_bytes_zlib;
     use super::inflate_bytes;
+    use super::inflate_bytes_zlib;
+    use super::compress;
+    use super::decompress;
+    use super::{FlushSync, FlushFull};
+    use super::CompressParams;
+    use super::DeflateWriter;
+    use super::InflateReader;
+    use super::BackendNative;
+    use super::backend_name;
+    use super::{StrategyDefault, StrategyFiltered, StrategyRle, StrategyFixed};
+    use super::compress_segment;
+    use super::SmallMessageCompressor;
+    use super::concat_segments;
+    use super::estimate_ratio;
+    use super::is_worth_deflating;
+    use super::update_adler32;
+    use super::{DeflateStatusOkay, DeflateStatusDone, DeflateStatusOutputFull};
+    use super::{InflateError, InflateStatusDone, InflateStatusHasMoreOutput, InflateStatusNeedsMoreInput};
+    use super::{InflateStatusFailedCannotMakeProgress, StatusAdler32Mismatch};
 
     #[test]
     fn test_deflator_alloc() {
@@ -872,7 +2149,7 @@ mod tests {
         let deflator = Deflator::new();
 
         match deflator.init(6, false, false) {
-            DeflateStatusOkay => (),
+            Ok(_) => (),
             _ =>  fail!()
         }
     }
@@ -882,17 +2159,53 @@ mod tests {
         let deflator = Deflator::new();
 
         match deflator.init(6, false, false) {
-            DeflateStatusOkay => (),
+            Ok(_) => (),
             _ =>  fail!()
         }
 
         match deflator.init(6, false, false) {
-            DeflateStatusOkay => (),
+            Ok(_) => (),
             _ =>  fail!()
         }
 
     }
 
+    #[test]
+    fn test_deflator_reset() {
+        let payload1 = bytes!("The first payload, abandoned in the middle of compression.").to_owned();
+        let payload2 = bytes!("The second payload, the second payload.").to_owned();
+        let compress = |deflator: &mut Deflator, payload: &[u8]| -> ~[u8] {
+            let mut output = ~[];
+            match deflator.compress_write(payload, true, |out_buf, _| output.push_all(out_buf)) {
+                Ok(_) => (),
+                _ => fail!()
+            }
+            output
+        };
+
+        let mut fresh = Deflator::new();
+        fresh.init(6, false, false);
+        let expected = compress(&mut fresh, payload2);
+
+        let mut deflator = Deflator::new();
+        deflator.init(6, false, false);
+        deflator.compress_write(payload1, false, |_, _| ());
+        match deflator.reset(6, false, false) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert_eq!(deflator.read_total, 0);
+        assert_eq!(deflator.write_total, 0);
+        let output = compress(&mut deflator, payload2);
+        assert_eq!(output, expected);
+        assert_eq!(inflate_bytes(output), payload2);
+
+        for _ in range(0, 100) {
+            deflator.reset(6, false, false);
+            assert_eq!(compress(&mut deflator, payload2), expected);
+        }
+    }
+
     #[test]
     fn test_deflator_simple() {
         let mut deflator = Deflator::new();
@@ -903,8 +2216,8 @@ mod tests {
         let out_buf = vec::from_elem(32, 0u8);
         let mut out_bytes = out_buf.len();
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         deflator.free();
@@ -925,8 +2238,8 @@ mod tests {
         let mut out_buf = vec::from_elem(32, 0u8);
         let mut out_bytes = out_buf.len();
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         assert!(( in_bytes == in_buf.len() ));
@@ -943,7 +2256,7 @@ mod tests {
         let mut out_offset = 0;
         out_bytes  = out_buf.len() - out_offset;
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, out_offset, &mut out_bytes, false) {
-            DeflateStatusOkay => (),
+            Ok(DeflateStatusOkay) => (),
             _ => fail!()
         }
         out_offset += out_bytes;
@@ -953,7 +2266,7 @@ mod tests {
         in_buf  = bytes!("ABCDEFGHABCDEFGH");
         in_bytes = in_buf.len();
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, out_offset, &mut out_bytes, true) {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
 
@@ -979,8 +2292,8 @@ mod tests {
         let mut out_buf = vec::from_elem(32, 0u8);
         let mut out_bytes = out_buf.len();
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         assert!(( in_bytes == in_buf.len() ));
@@ -998,7 +2311,7 @@ mod tests {
         let mut out_offset = 0;
         out_bytes  = out_buf.len() - out_offset;
         match deflator.compress_buf(in_buf, in_offset, &mut in_bytes, out_buf, out_offset, &mut out_bytes, false) {
-            DeflateStatusOkay => (),
+            Ok(DeflateStatusOkay) => (),
             _ => fail!()
         }
         in_offset += in_bytes;
@@ -1008,7 +2321,7 @@ mod tests {
 
         // Second call with updated in_offset and in_bytes
         match deflator.compress_buf(in_buf, in_offset, &mut in_bytes, out_buf, out_offset, &mut out_bytes, true) {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
 
@@ -1034,8 +2347,8 @@ mod tests {
         let mut out_buf = vec::from_elem(32, 0u8);
         let mut out_bytes = out_buf.len();
         match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         assert!(( in_bytes == in_buf.len() ));
@@ -1053,7 +2366,7 @@ mod tests {
         let mut out_offset = 0;
         out_bytes  = out_buf.len() - out_offset;
         match deflator.compress_buf(in_buf, in_offset, &mut in_bytes, out_buf, out_offset, &mut out_bytes, false) {
-            DeflateStatusOkay => (),
+            Ok(DeflateStatusOkay) => (),
             _ => fail!()
         }
         in_offset += in_bytes;
@@ -1063,7 +2376,7 @@ mod tests {
 
         // Second call with updated in_offset and in_bytes
         match deflator.compress_buf(in_buf, in_offset, &mut in_bytes, out_buf, out_offset, &mut out_bytes, false) {
-            DeflateStatusOkay => (),
+            Ok(DeflateStatusOkay) => (),
             _ => fail!()
         }
         in_offset += in_bytes;
@@ -1073,7 +2386,7 @@ mod tests {
 
         // Third call with empty input data but with the final_input set to true
         match deflator.compress_buf(in_buf, in_offset, &mut in_bytes, out_buf, out_offset, &mut out_bytes, true) {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
 
@@ -1088,6 +2401,24 @@ mod tests {
         deflator.free();
     }
 
+    #[test]
+    fn test_deflator_compress_write_large() {
+        let data = vec::from_fn(200000, |i| ((i * 13) % 61) as u8);
+        let mut deflator = Deflator::with_size_factor(MIN_SIZE_FACTOR);
+        deflator.init(6, false, false);
+
+        // A small write through in_buf, then the large ones compressed in place.
+        let mut output = ~[];
+        deflator.compress_write(data.slice(0, 10), false, |out_buf, _| output.push_all(out_buf));
+        deflator.compress_write(data.slice(10, 150000), false, |out_buf, _| output.push_all(out_buf));
+        match deflator.compress_write(data.slice(150000, 200000), true, |out_buf, _| output.push_all(out_buf)) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert_eq!(deflator.read_total, 200000);
+        assert!(( inflate_bytes(output) == data ));
+    }
+
     #[test]
     fn test_deflator_outbuf_small_outbuf() {
         let mut deflator = Deflator::new();
@@ -1100,22 +2431,269 @@ mod tests {
         // println(fmt!("1. in_bytes: %?", in_bytes));
         let status = deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusOkay => (),
+            Ok(DeflateStatusOutputFull) => (),
             _ => fail!()
         }
+        assert!(( in_bytes == in_buf.len() ));
+        assert!(( out_bytes == out_buf.len() ));
+
+        // The data not fitted in the small outbuf is kept in the Deflator.  Drain it with more calls.
+        let mut comp_buf = out_buf.slice(0, out_bytes).to_owned();
+        loop {
+            let mut in_bytes = 0;
+            let mut out_bytes = out_buf.len();
+            let status = deflator.compress_buf(in_buf, in_buf.len(), &mut in_bytes, out_buf, 0, &mut out_bytes, true);
+            comp_buf.push_all(out_buf.slice(0, out_bytes));
+            match status {
+                Ok(DeflateStatusOutputFull) => (),
+                Ok(DeflateStatusDone) => break,
+                _ => fail!()
+            }
+        }
         deflator.free();
 
-        // println(fmt!("1. status: %?", status));
-        // println(fmt!("1. in_bytes: %?", in_bytes));
-        // println(fmt!("1. out_buf: %?", out_buf));
-        // println(fmt!("1. out_bytes: %?", out_bytes));
+        assert!(( inflate_bytes(comp_buf) == in_buf.to_owned() ));
+    }
+
+    #[test]
+    fn test_compress_decompress() {
+        let data = vec::from_fn(100000, |i| ((i * 13) % 61) as u8);
+        for level in range(0u, 10) {
+            let comp_buf = compress(data, level).unwrap();
+            assert!(( inflate_bytes(comp_buf) == data ));
+            match decompress(comp_buf) {
+                Ok(decomp_buf) => assert!(( decomp_buf == data )),
+                Err(_) => fail!()
+            }
+        }
+
+        match decompress(compress([], 6).unwrap()) {
+            Ok(decomp_buf) => assert!(( decomp_buf.len() == 0 )),
+            Err(_) => fail!()
+        }
+
+        // Cut-off data
+        let comp_buf = compress(data, 6).unwrap();
+        match decompress(comp_buf.slice(0, comp_buf.len() / 2)) {
+            Ok(_) => fail!(),
+            Err(_) => ()
+        }
+    }
+
+    #[test]
+    fn test_update_adler32() {
+        assert_eq!(update_adler32(1, []), 1);
+        assert_eq!(update_adler32(1, bytes!("Wikipedia")), 0x11E60398);
+        // The checksum can be continued across the buffers.
+        assert_eq!(update_adler32(update_adler32(1, bytes!("Wiki")), bytes!("pedia")), 0x11E60398);
+        let data = vec::from_elem(100000, 0xFFu8);
+        assert_eq!(update_adler32(update_adler32(1, data.slice(0, 6000)), data.slice(6000, 100000)),
+                   update_adler32(1, data));
+    }
 
-        // Compression doesn't handle small outbuf very well.  It would just truncate the data not fitted in the outbuf.
-        // Use out_bytes equals to the original buffer length as an indicator of running out of room.
-        // In general out_buf should be as big as in_buf plus some extra length to ensure capturing all the compressed data.
+    #[test]
+    fn test_deflator_flush() {
+        let data = vec::from_fn(50000, |i| ((i * 13) % 61) as u8);
+        for &flush_mode in [FlushSync, FlushFull].iter() {
+            let mut deflator = Deflator::with_size_factor(MIN_SIZE_FACTOR);
+            deflator.init(6, false, false);
+            let mut output = ~[];
+            deflator.compress_write(data.slice(0, 30000), false, |out_buf, _| output.push_all(out_buf));
+            match deflator.flush(flush_mode, |out_buf| output.push_all(out_buf)) {
+                Ok(_) => (),
+                _ => fail!()
+            }
+
+            // The flushed data end on the sync marker and decompress to all the data written so far.
+            assert!(( output.slice_from(output.len() - 4).to_owned() == ~[0x00u8, 0x00, 0xFF, 0xFF] ));
+            assert!(( inflate_bytes(concat_segments([output.clone()])) == data.slice(0, 30000).to_owned() ));
+
+            // The stream continues after the flush.
+            match deflator.compress_write(data.slice(30000, 50000), true, |out_buf, _| output.push_all(out_buf)) {
+                Ok(_) => (),
+                _ => fail!()
+            }
+            assert!(( inflate_bytes(output) == data ));
+        }
+    }
+
+    #[test]
+    fn test_deflator_strategy() {
+        // Rows of runs like a simple image.
+        let data = vec::from_fn(60000, |i| ((i / 50) % 7 + (i / 3000) * 16) as u8);
+        for &strategy in [StrategyDefault, StrategyFiltered, StrategyRle, StrategyFixed].iter() {
+            let deflator = Deflator::new();
+            match deflator.init_params(&CompressParams::new(6).strategy(strategy)) {
+                Ok(_) => (),
+                _ => fail!()
+            }
+            let mut in_bytes = data.len();
+            let out_buf = vec::from_elem(Deflator::compress_bound(data.len()), 0u8);
+            let mut out_bytes = out_buf.len();
+            match deflator.compress_buf(data, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
+                Ok(DeflateStatusDone) => (),
+                _ => fail!()
+            }
+            let comp_buf = out_buf.slice(0, out_bytes);
+            assert!(( inflate_bytes(comp_buf) == data ));
+            match strategy {
+                StrategyFixed => assert_eq!((comp_buf[0] >> 1) & 3, 1),    // BTYPE of the first block is the static Huffman codes
+                _ => ()
+            }
+        }
+
+        // The zlib header and ADLER32 with a strategy.
+        let deflator = Deflator::new();
+        deflator.init_params(&CompressParams::new(9).strategy(StrategyRle).zlib_header(true).adler32(true));
+        let mut in_bytes = data.len();
+        let out_buf = vec::from_elem(Deflator::compress_bound(data.len()), 0u8);
+        let mut out_bytes = out_buf.len();
+        match deflator.compress_buf(data, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
+            Ok(DeflateStatusDone) => (),
+            _ => fail!()
+        }
+        assert!(( inflate_bytes_zlib(out_buf.slice(0, out_bytes)) == data ));
+    }
+
+    #[test]
+    fn test_deflator_native_backend() {
+        let data = vec::from_fn(300000, |i| (((i * 13) % 61) + (i / 5000)) as u8);
+        for level in [0u, 1, 6, 9].iter() {
+            let mut deflator = Deflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+            deflator.init(*level, *level == 9, false);
+            let mut mwriter = MemWriter::new();
+            match deflator.compress_stream_rw(&mut MemReader::new(data.clone()), &mut mwriter) {
+                Ok(_) => (),
+                _ => fail!()
+            }
+            let comp_buf = mwriter.inner();
+            if *level == 9 {
+                assert!(( inflate_bytes_zlib(comp_buf) == data ));
+            } else {
+                assert!(( inflate_bytes(comp_buf) == data ));
+            }
+            if *level > 0 {
+                assert!(( comp_buf.len() < data.len() / 10 ));
+            }
+        }
+
+        // compress_write() with a sync flush in the middle, and compress_buf() with a small out_buf.
+        let mut deflator = Deflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+        deflator.init(6, false, false);
+        let mut output = ~[];
+        deflator.compress_write(data.slice(0, 1000), false, |out_buf, _| output.push_all(out_buf));
+        deflator.flush(FlushSync, |out_buf| output.push_all(out_buf));
+        assert!(( inflate_bytes(concat_segments([output.clone()])) == data.slice(0, 1000).to_owned() ));
+        match deflator.compress_write(data.slice_from(1000), true, |out_buf, _| output.push_all(out_buf)) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert!(( inflate_bytes(output) == data ));
+
+        deflator.reset(6, false, false);
+        let in_buf = data.slice(0, 5000);
+        let mut in_bytes = in_buf.len();
+        let out_buf = vec::from_elem(16, 0u8);
+        let mut out_bytes = out_buf.len();
+        match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
+            Ok(DeflateStatusOutputFull) => (),
+            _ => fail!()
+        }
         assert!(( in_bytes == in_buf.len() ));
-        assert!(( out_bytes == out_buf.len() ));
+    }
+
+    #[test]
+    fn test_deflate_writer() {
+        let data = vec::from_fn(100000, |i| (((i * 13) % 61) + (i / 5000)) as u8);
+
+        let mut dwriter = DeflateWriter::new(MemWriter::new());
+        dwriter.write(data.slice(0, 1000));
+        dwriter.flush_with(FlushSync);
+        // All the data written so far are out.
+        let flushed = dwriter.inner_ref().inner_ref().clone();
+        assert!(( inflate_bytes(concat_segments([flushed])) == data.slice(0, 1000).to_owned() ));
+        dwriter.write(data.slice(1000, 50000));
+        dwriter.flush_with(FlushFull);
+        dwriter.write(data.slice_from(50000));
+        dwriter.finalize();
+        assert!(( inflate_bytes(dwriter.inner().inner()) == data ));
+
+        let mut dwriter = DeflateWriter::with_params(MemWriter::new(), &CompressParams::new(9).zlib_header(true), MIN_SIZE_FACTOR);
+        for chunk in data.chunks(777) {
+            dwriter.write(chunk);
+        }
+        dwriter.finalize();
+        assert!(( inflate_bytes_zlib(dwriter.inner_ref().inner_ref().as_slice()) == data ));
+
+        // Writing after finalize() raises io_error.
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            dwriter.write(data.slice(0, 10));
+            dwriter.flush_with(FlushSync);
+        });
+        assert_eq!(error_count, 2);
+    }
+
+    #[test]
+    fn test_inflate_reader() {
+        let data = vec::from_fn(100000, |i| (((i * 13) % 61) + (i / 5000)) as u8);
+
+        let mut comp_buf = deflate_bytes(data);
+        comp_buf.push_all(bytes!("extra"));
+        let mut dreader = InflateReader::new(MemReader::new(comp_buf));
+        let mut output_buf = [0u8, ..1000];
+        let mut decomp_buf : ~[u8] = ~[];
+        loop {
+            match dreader.read(output_buf) {
+                Some(read_len)  => decomp_buf.push_all(output_buf.slice_to(read_len)),
+                None            => break
+            }
+        }
+        assert!(( decomp_buf == data ));
+        assert!(( dreader.eof() ));
+        assert!(( dreader.get_rest() == bytes!("extra").to_owned() ));
+
+        // The raw DEFLATE data with the small buffers.
+        let mut dreader = InflateReader::with_size_factor(MemReader::new(deflate_bytes(data)), MIN_SIZE_FACTOR);
+        assert!(( dreader.read_to_end() == data ));
+
+        // The zlib data from a DeflateWriter.
+        let mut dwriter = DeflateWriter::with_params(MemWriter::new(), &CompressParams::new(6).zlib_header(true), MIN_SIZE_FACTOR);
+        dwriter.write(data);
+        dwriter.finalize();
+        let mut zlib_buf = dwriter.inner().inner();
+        let mut dreader = InflateReader::with_params(MemReader::new(zlib_buf.clone()), true, MIN_SIZE_FACTOR);
+        assert!(( dreader.read_to_end() == data ));
+
+        // The ADLER32 mismatch raises io_error.
+        let last = zlib_buf.len() - 1;
+        zlib_buf[last] ^= 0xFF;
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            let mut dreader = InflateReader::with_params(MemReader::new(zlib_buf.clone()), true, MIN_SIZE_FACTOR);
+            dreader.read_to_end();
+        });
+        assert_eq!(error_count, 1);
+    }
 
+    #[test]
+    fn test_deflator_compress_bound() {
+        // Incompressible data, the worst case for the expansion.
+        let in_buf = vec::from_fn(100000, |i| ((i * 7919 + (i >> 3) * 104729) % 251) as u8);
+        for &(level, zlib) in [(0u, false), (1u, true), (6u, false), (9u, true)].iter() {
+            let deflator = Deflator::new();
+            deflator.init(level, zlib, zlib);
+            let mut in_bytes = in_buf.len();
+            let out_buf = vec::from_elem(Deflator::compress_bound(in_buf.len()), 0u8);
+            let mut out_bytes = out_buf.len();
+            match deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true) {
+                Ok(DeflateStatusDone) => (),
+                _ => fail!()
+            }
+            assert!(( in_bytes == in_buf.len() ));
+            assert!(( out_bytes < out_buf.len() ));
+        }
+        assert!(( Deflator::compress_bound(0) > 0 ));
     }
 
     #[test]
@@ -1128,18 +2706,17 @@ mod tests {
         let mut in_bytes = in_buf.len();
         let out_buf = vec::from_elem(64, 0u8);
         let mut out_bytes = out_buf.len();
-        let mut status = deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
+        let status = deflator.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
 
         let mut mreader = MemReader::new(in_buf);
         let mut mwriter = MemWriter::new();
         deflator.init(6, false, false);
-        status = deflator.compress_stream_rw(&mut mreader, &mut mwriter);
-        match status {
-            DeflateStatusDone => (),
+        match deflator.compress_stream_rw(&mut mreader, &mut mwriter) {
+            Ok(_) => (),
             _ => fail!()
         }
 
@@ -1151,18 +2728,33 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_deflator_compress_segments() {
+        let in_buf  = bytes!("ABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGH");
+
+        // Compress the three parts independently and concatenate the segments into one stream.
+        let mut comp_buf : ~[u8] = ~[];
+        comp_buf.push_all(compress_segment(in_buf.slice(0, 20), 6, false).unwrap());
+        comp_buf.push_all(compress_segment(in_buf.slice(20, 50), 6, false).unwrap());
+        comp_buf.push_all(compress_segment(in_buf.slice_from(50), 6, true).unwrap());
+
+        let decomp_buf = inflate_bytes(comp_buf);
+        assert!(( in_buf == decomp_buf.as_slice() ));
+    }
+
+
     #[test]
     fn test_inflator_alloc() {
         let mut inflator = Inflator::new();
-        assert!(( inflator.tinfl_decompressor != ptr::null() ));
+        assert!(( inflator.decompressor.tinfl_decompressor != ptr::null() ));
         inflator.free();
-        assert!(( inflator.tinfl_decompressor == ptr::null() ));
+        assert!(( inflator.decompressor.tinfl_decompressor == ptr::null() ));
         inflator.free();
-        assert!(( inflator.tinfl_decompressor == ptr::null() ));
+        assert!(( inflator.decompressor.tinfl_decompressor == ptr::null() ));
 
         unsafe {
             inflator = Inflator::new();
-            let decomp_bytes = vec::raw::from_buf_raw(inflator.tinfl_decompressor as *u8, 32);
+            let decomp_bytes = vec::raw::from_buf_raw(inflator.decompressor.tinfl_decompressor as *u8, 32);
             //println(fmt!("Inflator::new(), tinfl_decompressor: %?", decomp_bytes));
             // The first 4 bytes are tinfl_decompressor.m_state, and should be 0
             assert!(( decomp_bytes[0] == 0 && decomp_bytes[1] == 0 && decomp_bytes[2] == 0 && decomp_bytes[3] == 0 ));
@@ -1181,8 +2773,8 @@ mod tests {
         let comp_buf = vec::from_elem(64, 0u8);
         let mut comp_bytes = comp_buf.len();
         match comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
@@ -1193,10 +2785,10 @@ mod tests {
         let mut inflator = Inflator::new();
         let de_in_total = comp_buf.len();
         let mut de_in_bytes = de_in_total;
-        let decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
         let mut decomp_bytes = decomp_buf.len();
         match inflator.decompress_buf(comp_buf, 0, &mut de_in_bytes, true, decomp_buf, 0, &mut decomp_bytes, false) {
-            InflateStatusDone => (),
+            Ok(InflateStatusDone) => (),
             _ => fail!()
         }
         inflator.free();
@@ -1218,18 +2810,18 @@ mod tests {
         let comp_buf = vec::from_elem(64, 0u8);
         let mut comp_bytes = comp_buf.len();
         match comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
 
         let mut inflator = Inflator::new();
         let mut de_in_bytes = comp_bytes;
-        let decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
         let mut decomp_bytes = decomp_buf.len();
         match inflator.decompress_buf(comp_buf, 0, &mut de_in_bytes, true, decomp_buf, 0, &mut decomp_bytes, false) {
-            InflateStatusDone => (),
+            Ok(InflateStatusDone) => (),
             _ => fail!()
         }
         inflator.free();
@@ -1257,7 +2849,7 @@ mod tests {
         let mut comp_bytes = comp_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
@@ -1269,12 +2861,12 @@ mod tests {
 
         let mut inflator = Inflator::new();
         let mut de_in_bytes = comp_bytes;
-        let decomp_buf = vec::from_elem(in_bytes, 0u8);
+        let mut decomp_buf = vec::from_elem(in_bytes, 0u8);
         let mut decomp_bytes = decomp_buf.len();
         let status = inflator.decompress_buf(comp_buf, 0, &mut de_in_bytes, true, decomp_buf, 0, &mut decomp_bytes, false);
         match status {
-            InflateStatusDone => (),
-            InflateStatusHasMoreOutput => { println("Has more output."); fail!(); },
+            Ok(InflateStatusDone) => (),
+            Ok(InflateStatusHasMoreOutput) => { println("Has more output."); fail!(); },
             _ => fail!()
         }
         inflator.free();
@@ -1302,8 +2894,8 @@ mod tests {
         let mut comp_bytes = comp_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true);
         match status {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
@@ -1318,7 +2910,7 @@ mod tests {
         let mut de_in_offset = 0;
         let mut de_in_bytes;
         let mut decomp_data : ~[u8] = ~[];
-        let decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
         let decomp_total = decomp_buf.len();
         let mut decomp_offset = 0;
         let mut decomp_bytes;
@@ -1338,11 +2930,11 @@ mod tests {
             decomp_offset += decomp_bytes;
 
             match status {
-                InflateStatusDone => {
+                Ok(InflateStatusDone) => {
                     decomp_data.push_all(decomp_buf.slice(0, decomp_offset));
                     break;
                 },
-                InflateStatusHasMoreOutput => { 
+                Ok(InflateStatusHasMoreOutput) => { 
                     //println("de: Has more output...");
                     if decomp_offset == decomp_total {
                         // output decomp_buf is full.  flush its content to the accumulator buffer.  Reset decomp_buf.
@@ -1350,7 +2942,7 @@ mod tests {
                         decomp_offset = 0;
                     }
                 },
-                InflateStatusNeedsMoreInput => {
+                Ok(InflateStatusNeedsMoreInput) => {
                     fail!(format!("Decompression unexpected status.  status: {:?}", status))
                 },
                 _ => fail!(format!("Decompression failed.  status: {:?}", status))
@@ -1380,8 +2972,8 @@ mod tests {
         let mut comp_bytes = comp_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true);
         match status {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
@@ -1397,7 +2989,7 @@ mod tests {
         let mut de_in_offset = 0;
         let mut de_in_bytes;
         let mut decomp_data : ~[u8] = ~[];
-        let decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
         let decomp_total = decomp_buf.len();
         let mut decomp_offset = 0;
         let mut decomp_bytes;
@@ -1418,11 +3010,11 @@ mod tests {
             decomp_offset += decomp_bytes;
 
             match status {
-                InflateStatusDone => {
+                Ok(InflateStatusDone) => {
                     decomp_data.push_all(decomp_buf.slice(0, decomp_offset));
                     break;
                 },
-                InflateStatusHasMoreOutput => { 
+                Ok(InflateStatusHasMoreOutput) => { 
                     //println("de: Has more output...");
                     if decomp_offset == decomp_total {
                         // output decomp_buf is full.  flush its content to the accumulator buffer.  Reset decomp_buf.
@@ -1430,7 +3022,7 @@ mod tests {
                         decomp_offset = 0;
                     }
                 },
-                InflateStatusNeedsMoreInput => {
+                Ok(InflateStatusNeedsMoreInput) => {
                     //println("de: Need more input......");
                 },
                 _ => fail!(format!("Decompression failed.  status: {:?}", status))
@@ -1454,7 +3046,7 @@ mod tests {
         let mut out_bytes = out_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         let comp_buf = out_buf.slice(0, out_bytes);
@@ -1465,7 +3057,7 @@ mod tests {
         let mut inflator = Inflator::new();
         let status = inflator.decompress_stream_rw(&mut mreader, &mut mwriter);
         match status {
-            InflateStatusDone => (),
+            Ok(_) => (),
             _ => fail!()
         }
 
@@ -1475,6 +3067,146 @@ mod tests {
         inflator.free();
     }
 
+    #[test]
+    fn test_inflator_zlib() {
+        let in_buf = bytes!("ABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGHABCDEFGH, with the zlib header").to_owned();
+        let mut comp_buf = deflate_bytes_zlib(in_buf);
+
+        let mut mwriter = MemWriter::new();
+        let mut inflator = Inflator::new();
+        inflator.init(true, true);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.clone()), &mut mwriter) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert!(( mwriter.inner() == in_buf ));
+
+        // The raw mode fails on the zlib header.
+        inflator.init(false, false);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.clone()), &mut MemWriter::new()) {
+            Ok(_) => fail!(),
+            _ => ()
+        }
+
+        // Corrupt the ADLER32 at the end.
+        let last = comp_buf.len() - 1;
+        comp_buf[last] ^= 0xFF;
+        inflator.init(true, true);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.clone()), &mut MemWriter::new()) {
+            Err(InflateError { status: StatusAdler32Mismatch, .. }) => (),
+            _ => fail!()
+        }
+        let mut mwriter = MemWriter::new();
+        inflator.init(true, false);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.clone()), &mut mwriter) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert!(( mwriter.inner() == in_buf ));
+    }
+
+    #[test]
+    fn test_inflator_native_backend() {
+        let data = vec::from_fn(300000, |i| (((i * 13) % 61) + (i / 5000)) as u8);
+        let comp_buf = deflate_bytes(data);
+
+        let mut mwriter = MemWriter::new();
+        let mut inflator = Inflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.clone()), &mut mwriter) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert!(( mwriter.inner() == data ));
+
+        // decompress_read() with extra data after the compressed data, in small reads.
+        let mut padded = comp_buf.clone();
+        padded.push_all(bytes!("extra"));
+        let mut mreader = MemReader::new(padded);
+        inflator.init(false, false);
+        let mut output_buf = vec::from_elem(1000, 0u8);
+        let mut decomp_buf : ~[u8] = ~[];
+        loop {
+            match inflator.decompress_read(|in_buf| mreader.read(in_buf.mut_slice_to(100)).unwrap_or(0), output_buf) {
+                Ok(0)           => break,
+                Ok(output_len)  => decomp_buf.push_all(output_buf.slice_to(output_len)),
+                _               => fail!()
+            }
+        }
+        assert!(( decomp_buf == data ));
+        assert_eq!(inflator.get_in_consumed(), comp_buf.len() as u64);
+        let mut rest_buf = [0u8, ..16];
+        let rest_len = inflator.get_rest(rest_buf);
+        assert!(( rest_buf.slice_to(rest_len).to_owned() == bytes!("extra").to_owned() ));
+
+        // The zlib data compressed by the native Deflator, with the ADLER32 checked.
+        let mut deflator = Deflator::with_backend(MIN_SIZE_FACTOR, BackendNative);
+        deflator.init(6, true, false);
+        let mut zlib_buf = ~[];
+        deflator.compress_write(data, true, |out_buf, _| zlib_buf.push_all(out_buf));
+        let mut mwriter = MemWriter::new();
+        inflator.init(true, true);
+        match inflator.decompress_stream_rw(&mut MemReader::new(zlib_buf.clone()), &mut mwriter) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+        assert!(( mwriter.inner() == data ));
+        let last = zlib_buf.len() - 1;
+        zlib_buf[last] ^= 0xFF;
+        inflator.init(true, true);
+        match inflator.decompress_stream_rw(&mut MemReader::new(zlib_buf.clone()), &mut MemWriter::new()) {
+            Err(InflateError { status: StatusAdler32Mismatch, .. }) => (),
+            _ => fail!()
+        }
+        inflator.init(true, false);
+        match inflator.decompress_stream_rw(&mut MemReader::new(zlib_buf.clone()), &mut MemWriter::new()) {
+            Ok(_) => (),
+            _ => fail!()
+        }
+
+        // Cut-off data.
+        inflator.init(false, false);
+        match inflator.decompress_stream_rw(&mut MemReader::new(comp_buf.slice_to(comp_buf.len() / 2).to_owned()), &mut MemWriter::new()) {
+            Err(InflateError { status: InflateStatusFailedCannotMakeProgress, .. }) => (),
+            _ => fail!()
+        }
+    }
+
+    #[test]
+    fn test_inflate_error() {
+        let data = vec::from_fn(100000, |i| ((i * 7919 + (i >> 3) * 104729) % 251) as u8);
+        let comp_buf = compress(data, 6).unwrap();
+
+        // Cut-off data, with the data decompressed before the cut-off.
+        match decompress(comp_buf.slice(0, comp_buf.len() / 2)) {
+            Ok(_) => fail!(),
+            Err(err) => {
+                match err.status {
+                    InflateStatusFailedCannotMakeProgress => (),
+                    _ => fail!()
+                }
+                assert_eq!(err.in_consumed, (comp_buf.len() / 2) as u64);
+                assert!(( err.out_total > 0 && err.out_total < data.len() as u64 ));
+                assert!(( !err.is_adler32_mismatch() ));
+            }
+        }
+
+        // ADLER32 mismatch from decompress_drain().
+        let mut zlib_buf = deflate_bytes_zlib(data);
+        let last = zlib_buf.len() - 1;
+        zlib_buf[last] ^= 0xFF;
+        let mut inflator = Inflator::new();
+        inflator.init(true, true);
+        let mut mreader = MemReader::new(zlib_buf.clone());
+        match inflator.decompress_drain(|in_buf| if mreader.eof() { 0 } else { mreader.read(in_buf).unwrap_or(0) }, |_| ()) {
+            Ok(_) => fail!(),
+            Err(err) => {
+                assert!(( err.is_adler32_mismatch() ));
+                assert_eq!(err.in_consumed, zlib_buf.len() as u64);
+                assert_eq!(err.out_total, data.len() as u64);
+            }
+        }
+    }
+
     #[test]
     fn test_inflator_corrupted_data() {
         let mut comp = Deflator::new();
@@ -1485,27 +3217,63 @@ mod tests {
         let comp_buf = vec::from_elem(64, 0u8);
         let mut comp_bytes = comp_buf.len();
         match comp.compress_buf(in_buf, 0, &mut in_bytes, comp_buf, 0, &mut comp_bytes, true) {
-            DeflateStatusOkay => (),
-            DeflateStatusDone => (),
+            Ok(DeflateStatusOkay) => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         comp.free();
 
         let mut inflator = Inflator::new();
         let mut de_in_bytes = comp_bytes - 1;    // missing one byte;
-        let decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
+        let mut decomp_buf = vec::from_elem(MIN_DECOMPRESS_BUF_SIZE, 0u8);
         let mut decomp_bytes = decomp_buf.len();
         let status = inflator.decompress_buf(comp_buf, 0, &mut de_in_bytes, true, decomp_buf, 0, &mut decomp_bytes, false);
         //println(format!("status: {:?}", status));
         match status {
-            InflateStatusDone =>  fail!("Corrupted data should not work"),
-            InflateStatusFailed | _  => (),
+            Ok(InflateStatusDone) =>  fail!("Corrupted data should not work"),
+            _  => (),
         }
         inflator.free();
 
     }
 
 
+    #[test]
+    fn test_inflator_decompress_read_direct() {
+        let data = vec::from_fn(200000, |i| ((i * 13) % 61 + (i / 1000) % 7) as u8);
+        let comp_data = deflate_bytes(data);
+
+        // The whole stream in one read.
+        let mut mreader = MemReader::new(comp_data.clone());
+        let mut inflator = Inflator::with_size_factor(MIN_SIZE_FACTOR);
+        let mut output_buf = vec::from_elem(300000, 0u8);
+        let read_len = match inflator.decompress_read(|in_buf| mreader.read(in_buf).unwrap_or(0), output_buf) {
+            Ok(output_len)  => output_len,
+            _               => fail!()
+        };
+        assert_eq!(read_len, data.len());
+        assert!(( output_buf.slice_to(read_len) == data.as_slice() ));
+        match inflator.decompress_read(|in_buf| mreader.read(in_buf).unwrap_or(0), output_buf) {
+            Ok(0)   => (),
+            _       => fail!()
+        }
+
+        // A large first read, not at the out_buf boundary, then the rest through out_buf.
+        let mut mreader = MemReader::new(comp_data.clone());
+        let mut inflator = Inflator::with_size_factor(MIN_SIZE_FACTOR);
+        let mut output_buf = vec::from_elem(100003, 0u8);
+        let mut decomp_buf : ~[u8] = ~[];
+        loop {
+            match inflator.decompress_read(|in_buf| mreader.read(in_buf).unwrap_or(0), output_buf) {
+                Ok(0)           => break,
+                Ok(output_len)  => decomp_buf.push_all(output_buf.slice_to(output_len)),
+                _               => fail!()
+            }
+            output_buf = vec::from_elem(4096, 0u8);
+        }
+        assert!(( decomp_buf == data ));
+    }
+
     #[test]
     fn test_inflator_decompress_read_out_len_1() {
         let mut comp = Deflator::new();
@@ -1518,7 +3286,7 @@ mod tests {
         let mut out_bytes = out_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         let comp_buf = out_buf.slice(0, out_bytes);
@@ -1569,7 +3337,7 @@ mod tests {
         let mut out_bytes = out_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         let comp_buf = out_buf.slice(0, out_bytes);
@@ -1620,7 +3388,7 @@ mod tests {
         let mut out_bytes = out_buf.len();
         let status = comp.compress_buf(in_buf, 0, &mut in_bytes, out_buf, 0, &mut out_bytes, true);
         match status {
-            DeflateStatusDone => (),
+            Ok(DeflateStatusDone) => (),
             _ => fail!()
         }
         let comp_buf = out_buf.slice(0, out_bytes);
@@ -1694,5 +3462,166 @@ mod tests {
         assert_eq!(inflated, bytes);
     }
 
+    #[test]
+    fn test_deflator_deterministic() {
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nIJKLMNOP\r\nABCDEFGH\r\n");
+        let compress = |compress_level: uint, deterministic: bool| -> ~[u8] {
+            let mut deflator = Deflator::new();
+            deflator.set_deterministic(deterministic);
+            deflator.init(compress_level, false, false);
+            let mut output = ~[];
+            deflator.compress_write(data, true, |out_buf, _| output.push_all(out_buf));
+            output
+        };
+
+        // The output of miniz at level 6, the same as zlib's.  The native compressor has its own matcher.
+        if backend_name() != "native" {
+            assert_eq!(compress(6, true), ~[0x73u8, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x72, 0x84,
+                                            0xB3, 0x3C, 0xBD, 0xBC, 0x7D, 0x7C, 0xFD, 0xFC, 0x03, 0x90, 0xC5, 0x00]);
+        }
+        assert_eq!(compress(6, true), compress(6, true));
+        assert_eq!(inflate_bytes(compress(6, false)), data.to_owned());
+
+        // Level 0 stores the data in one final raw block with TDEFL_FORCE_ALL_RAW_BLOCKS (0x80000),
+        // with or without the deterministic parsing.
+        let mut stored = ~[0x01u8, 0x28, 0x00, 0xD7, 0xFF];
+        stored.push_all(data);
+        assert_eq!(compress(0, true), stored);
+        assert_eq!(compress(0, false), stored);
+    }
+
+    #[test]
+    fn test_deflator_greedy_parsing() {
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nIJKLMNOP\r\nABCDEFGH\r\n");
+        for &(level, greedy_parsing) in [(9u, Some(true)), (1u, Some(false)), (6u, None)].iter() {
+            let mut deflator = Deflator::new();
+            deflator.set_greedy_parsing(greedy_parsing);
+            deflator.init(level, false, false);
+            let mut output = ~[];
+            deflator.compress_write(data, true, |out_buf, _| output.push_all(out_buf));
+            assert_eq!(inflate_bytes(output), data.to_owned());
+        }
+    }
+
+    #[test]
+    fn test_inflator_in_consumed() {
+        let data = bytes!("ABCDEFGHABCDEFGHABCDEFGH");
+        let compressed = deflate_bytes(data);
+        let mut input = compressed.clone();
+        input.push_all(bytes!("TRAILER"));
+
+        let mut inflator = Inflator::new();
+        let mut input_read = false;
+        let mut output = ~[];
+        let result = inflator.decompress_drain(
+            |in_buf| {
+                if input_read {
+                    0
+                } else {
+                    input_read = true;
+                    vec::bytes::copy_memory(in_buf, input, input.len());
+                    input.len()
+                }
+            },
+            |out_buf| output.push_all(out_buf));
+        match result {
+            Ok(total) => assert_eq!(total, data.len() as u64),
+            Err(_) => fail!()
+        }
+        assert_eq!(output, data.to_owned());
+        assert_eq!(inflator.get_in_consumed(), compressed.len() as u64);
+        assert_eq!(inflator.get_rest_len(), 7);
+    }
+
+    #[test]
+    fn test_concat_segments() {
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nIJKLMNOP\r\nABCDEFGH\r\n");
+
+        // Segments compressed independently.
+        let segments = ~[compress_segment(data.slice(0, 10), 6, false).unwrap(),
+                         compress_segment(data.slice(10, 30), 6, false).unwrap(),
+                         compress_segment(data.slice_from(30), 6, false).unwrap()];
+        assert_eq!(inflate_bytes(concat_segments(segments)), data.to_owned());
+
+        // Segments ended with full flushes on one Deflator.
+        let mut deflator = Deflator::new();
+        deflator.init(6, false, false);
+        let mut segments = ~[];
+        for &(start, end) in [(0u, 20u), (20u, data.len())].iter() {
+            let mut segment = ~[];
+            deflator.compress_write(data.slice(start, end), false, |out_buf, _| segment.push_all(out_buf));
+            deflator.end_segment(|out_buf| segment.push_all(out_buf));
+            segments.push(segment);
+        }
+        assert_eq!(inflate_bytes(concat_segments(segments)), data.to_owned());
+    }
+
+    #[test]
+    fn test_estimate_ratio() {
+        let mut text : ~[u8] = ~[];
+        while text.len() < 100000 {
+            text.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+        assert!(( estimate_ratio(text) < 0.2 ));
+        assert!(( is_worth_deflating(text) ));
+
+        // Random data don't compress, like already-compressed data.
+        let mut rnd = rand::rng();
+        let random = rnd.gen_vec::<u8>(100000);
+        assert!(( estimate_ratio(random) > 0.95 ));
+        assert!(( !is_worth_deflating(random) ));
+
+        assert!(( !is_worth_deflating([]) ));
+        assert!(( is_worth_deflating(text.slice(0, 1000)) ));
+    }
+
+    #[test]
+    fn test_small_message_compressor() {
+        let dictionary = bytes!("{\"user_id\": , \"event_type\": \"page_view\", \"timestamp\": , \"session_id\": }");
+        let message = bytes!("{\"user_id\": 1234, \"event_type\": \"page_view\", \"timestamp\": 1382400000, \"session_id\": 42}");
+
+        let compressor = SmallMessageCompressor::new(dictionary, 6).unwrap();
+        let payload = compressor.compress(message);
+        assert!(payload.len() < deflate_bytes(message).len());
+        assert_eq!(compressor.decompress(payload).unwrap(), message.to_owned());
+
+        // Messages are independent; the compressor can be reused.
+        let payload2 = compressor.compress(message);
+        assert_eq!(payload2, payload);
+        assert!(compressor.decompress([0xFFu8, 0xFF, 0xFF]).is_err());
+
+        // The zlib header has the FDICT flag and the ADLER32 of the dictionary.
+        assert!(( payload[0] == 0x78 && payload[1] & 0x20 != 0 && (payload[0] as uint * 256 + payload[1] as uint) % 31 == 0 ));
+        let dict_id = update_adler32(1, dictionary);
+        assert_eq!(payload.slice(2, 6).to_owned(), ~[(dict_id >> 24) as u8, (dict_id >> 16) as u8, (dict_id >> 8) as u8, dict_id as u8]);
+        // With another dictionary, the payload doesn't decompress.
+        let other = SmallMessageCompressor::new(bytes!("{\"id\": }"), 6).unwrap();
+        assert!(other.decompress(payload).is_err());
+
+        assert!(SmallMessageCompressor::new(dictionary, 10).is_err());
+    }
+
+    #[test]
+    fn test_small_message_independent() {
+        let dictionary = bytes!("{\"user_id\": , \"event_type\": \"page_view\", \"timestamp\": , \"session_id\": }");
+        let message1 = bytes!("{\"user_id\": 1234, \"event_type\": \"page_view\", \"timestamp\": 1382400000, \"session_id\": 42}");
+        let message2 = bytes!("{\"user_id\": 77, \"event_type\": \"page_view\", \"timestamp\": 1382400001, \"session_id\": 7}");
+
+        let sender = SmallMessageCompressor::new(dictionary, 9).unwrap();
+        let payload1 = sender.compress(message1);
+        let payload2 = sender.compress(message2);
+
+        // A fresh receiver decompresses the second message alone, without having seen the first.
+        let receiver = SmallMessageCompressor::new(dictionary, 9).unwrap();
+        assert_eq!(receiver.decompress(payload2).unwrap(), message2.to_owned());
+        assert_eq!(receiver.decompress(payload1).unwrap(), message1.to_owned());
+        assert_eq!(receiver.decompress(payload2).unwrap(), message2.to_owned());
+
+        // The payload of message2 made by zlib with the same preset dictionary.
+        let zlib_payload = [0x78u8, 0xBB, 0x52, 0xDA, 0x17, 0x82, 0xAB, 0x46, 0x32, 0xC6, 0xDC, 0x9C, 0x78, 0x83, 0x0C, 0x8D,
+                            0x2D, 0x8C, 0x4C, 0x0C, 0x80, 0xC0, 0x10, 0xDD, 0x48, 0xF3, 0x5A, 0x00, 0x72, 0x3F, 0x1A, 0x1A];
+        assert_eq!(receiver.decompress(zlib_payload).unwrap(), message2.to_owned());
+    }
+
 }
 
@@ -0,0 +1,200 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: endian.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The endian module packs and unpacks the fixed-size unsigned numbers in the given
+byte order, so that the format code is written once and parameterized on the byte
+order.
+
+Example:
+
+    let mut buf = [0u8, ..6];
+    let offset = pack_num::<u16>(buf, 0, 0x1234, LittleEndian);
+    pack_num::<u32>(buf, offset, 0x12345678, BigEndian);
+    let value: u32 = unpack_num(buf, 2, BigEndian);
+
+    let value: u16 = read_num(&mut reader, LittleEndian);
+    write_num::<u32, MemWriter>(&mut writer, value as u32, BigEndian);
+
+*/
+
+
+use std::io::{Reader, Writer};
+use std::io::{io_error, IoError, OtherIoError};
+
+
+/// The byte order of the packed numbers.
+#[deriving(Clone, Eq)]
+pub enum Endian {
+    /// The least significant byte first, as zip and gzip use.
+    LittleEndian,
+    /// The most significant byte first, the network byte order.
+    BigEndian,
+}
+
+
+/// The unsigned numbers that can be packed in either byte order.
+pub trait EndianNum {
+    /// Convert from u64, truncating to the size of the number.
+    fn from_u64(value: u64) -> Self;
+    /// Convert to u64.
+    fn to_u64(&self) -> u64;
+    /// The number of bytes of the packed number.
+    fn byte_size(&self) -> uint;
+}
+
+impl EndianNum for u8 {
+    fn from_u64(value: u64) -> u8 { value as u8 }
+    fn to_u64(&self) -> u64 { *self as u64 }
+    fn byte_size(&self) -> uint { 1 }
+}
+
+impl EndianNum for u16 {
+    fn from_u64(value: u64) -> u16 { value as u16 }
+    fn to_u64(&self) -> u64 { *self as u64 }
+    fn byte_size(&self) -> uint { 2 }
+}
+
+impl EndianNum for u32 {
+    fn from_u64(value: u64) -> u32 { value as u32 }
+    fn to_u64(&self) -> u64 { *self as u64 }
+    fn byte_size(&self) -> uint { 4 }
+}
+
+impl EndianNum for u64 {
+    fn from_u64(value: u64) -> u64 { value }
+    fn to_u64(&self) -> u64 { *self }
+    fn byte_size(&self) -> uint { 8 }
+}
+
+
+/// Pack the number into the byte buffer at the offset.  Return the offset past the packed bytes.
+pub fn pack_num<T: EndianNum>(buf: &mut [u8], offset: uint, value: T, endian: Endian) -> uint {
+    pack_uint(buf, offset, value.to_u64(), value.byte_size(), endian)
+}
+
+/// Unpack a number from the byte buffer at the offset.
+pub fn unpack_num<T: EndianNum>(buf: &[u8], offset: uint, endian: Endian) -> T {
+    let zero: T = EndianNum::from_u64(0);
+    EndianNum::from_u64(unpack_uint(buf.slice(offset, offset + zero.byte_size()), endian))
+}
+
+/// Pack the low size bytes of the value, up to 8, into the byte buffer at the offset.  Return the offset past the packed bytes.
+pub fn pack_uint(buf: &mut [u8], offset: uint, value: u64, size: uint, endian: Endian) -> uint {
+    for i in range(0, size) {
+        let byte = (value >> (i * 8)) as u8;
+        match endian {
+            LittleEndian    => buf[offset + i] = byte,
+            BigEndian       => buf[offset + size - 1 - i] = byte,
+        }
+    }
+    offset + size
+}
+
+/// Unpack an unsigned value of any length up to 8 bytes.
+pub fn unpack_uint(buf: &[u8], endian: Endian) -> u64 {
+    match endian {
+        LittleEndian    => buf.iter().rev().fold(0u64, |value, &b| (value << 8) | b as u64),
+        BigEndian       => buf.iter().fold(0u64, |value, &b| (value << 8) | b as u64),
+    }
+}
+
+/// Read a number from the reader.  Raise io_error if the data ends early.
+pub fn read_num<T: EndianNum, R: Reader>(reader: &mut R, endian: Endian) -> T {
+    let zero: T = EndianNum::from_u64(0);
+    let size = zero.byte_size();
+    let mut buf = [0u8, ..8];
+    let mut read_len = 0;
+    while read_len < size {
+        match reader.read(buf.mut_slice(read_len, size)) {
+            Some(len)   => read_len += len,
+            None        => break
+        }
+    }
+    if read_len < size {
+        io_error::cond.raise(IoError {
+                kind:   OtherIoError,
+                desc:   "Not enough data to read the number",
+                detail: Some(format!("Read {:u} bytes out of {:u} bytes.", read_len, size))
+            });
+        return zero;
+    }
+    EndianNum::from_u64(unpack_uint(buf.slice(0, size), endian))
+}
+
+/// Write the number to the writer.
+pub fn write_num<T: EndianNum, W: Writer>(writer: &mut W, value: T, endian: Endian) {
+    let mut buf = [0u8, ..8];
+    let size = pack_num(buf, 0, value, endian);
+    writer.write(buf.slice(0, size));
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Decorator;
+    use std::io::mem::MemReader;
+    use std::io::mem::MemWriter;
+    use std::io::io_error;
+    use super::{LittleEndian, BigEndian};
+    use super::{pack_num, unpack_num, pack_uint, unpack_uint, read_num, write_num};
+
+
+    #[test]
+    fn test_pack_unpack() {
+        let mut buf = [0u8, ..14];
+        let offset = pack_num::<u16>(buf, 0, 0x1234, LittleEndian);
+        let offset = pack_num::<u32>(buf, offset, 0x12345678, BigEndian);
+        let offset = pack_num::<u64>(buf, offset, 0x0102030405060708, LittleEndian);
+        assert_eq!(offset, 14);
+        assert_eq!(buf.slice(0, 6).to_owned(), ~[0x34u8, 0x12, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(buf[6], 0x08);
+        assert_eq!(buf[13], 0x01);
+
+        assert_eq!(unpack_num::<u16>(buf, 0, LittleEndian), 0x1234);
+        assert_eq!(unpack_num::<u16>(buf, 0, BigEndian), 0x3412);
+        assert_eq!(unpack_num::<u32>(buf, 2, BigEndian), 0x12345678);
+        assert_eq!(unpack_num::<u64>(buf, 6, LittleEndian), 0x0102030405060708);
+
+        let offset = pack_uint(buf, 0, 0xABCDEF, 3, BigEndian);
+        assert_eq!(offset, 3);
+        assert_eq!(unpack_uint(buf.slice(0, 3), BigEndian), 0xABCDEF);
+        assert_eq!(unpack_uint(buf.slice(0, 3), LittleEndian), 0xEFCDAB);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut writer = MemWriter::new();
+        write_num::<u32, MemWriter>(&mut writer, 0xCAFEBABE, BigEndian);
+        write_num::<u16, MemWriter>(&mut writer, 0xBEEF, LittleEndian);
+        let buf = writer.inner();
+        assert_eq!(buf, ~[0xCA, 0xFE, 0xBA, 0xBE, 0xEF, 0xBE]);
+
+        let mut reader = MemReader::new(buf);
+        let value: u32 = read_num(&mut reader, BigEndian);
+        assert_eq!(value, 0xCAFEBABE);
+        let value: u16 = read_num(&mut reader, LittleEndian);
+        assert_eq!(value, 0xBEEF);
+
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            let value: u32 = read_num(&mut reader, LittleEndian);
+            assert_eq!(value, 0);
+        });
+        assert_eq!(error_count, 1);
+    }
+
+}
@@ -92,14 +92,25 @@ use std::num;
 use std::vec;
 use std::io::{Reader, Writer, Decorator};
 use std::io::{io_error, IoError, OtherIoError};
-use std::io::SeekEnd;
+use std::path::Path;
+use std::io::{Open, Read, SeekSet, SeekEnd};
 use std::io::fs::File;
+use std::io::mem::{MemReader, MemWriter};
+use std::comm::{Port, Chan};
+use std::task;
+use extra::future::Future;
+use extra::time;
 
 
 use super::deflate;
 use super::deflate::Deflator;
 use super::deflate::Inflator;
-use super::deflate::{DeflateStatusOkay, DeflateStatusDone, InflateStatusDone};
+use super::deflate::{DeflateError, InflateError, InflateStatusFailedCannotMakeProgress, InflateStatusAbort};
+use super::endian::{pack_num, unpack_num, LittleEndian};
+use super::ioutil;
+use super::ioutil::CancelToken;
+use super::ioutil::{read_upto, read_buf_exact, read_buf_upto};
+use super::trace;
 
 
 /// The buf_size_factor for internal IO buffers.
@@ -108,7 +119,7 @@ pub static DEFAULT_SIZE_FACTOR : uint = deflate::DEFAULT_SIZE_FACTOR;   // defau
 
 /// The number of dictionary probes to use at each compression level (0-9). 0=implies fastest/minimal possible probing, 9=best compression but slowest
 pub static MAX_COMPRESS_LEVEL : uint = deflate::MAX_COMPRESS_LEVEL;
-pub static DEFAULT_COMPRESS_LEVEL : uint = 6;
+pub static DEFAULT_COMPRESS_LEVEL : uint = deflate::DEFAULT_COMPRESS_LEVEL;
 
 
 // GZip format defines
@@ -126,6 +137,17 @@ static FCOMMENT: u8 = 16;       // File comment
 
 static END_LENGTH: uint = 8;    // length of end section of a gzip file - 4 bytes CRC, 4 bytes original size
 
+static RSYNC_WINDOW: uint = 4096;   // window of the rolling sum for finding the rsyncable chunk boundaries, same as gzip
+static PIPELINE_DEPTH: uint = 4;    // number of blocks queued between the stages of compress_pipelined() and to a CrcTask
+static MAX_SIZE_HINT_RATIO: uint = 16;  // the up-front buffer of decompress_file_to_vec() is at most this many times the file size
+
+// The messages from the compression stage to the writing stage of compress_pipelined().
+enum PipelineMsg {
+    PipelineData(~[u8]),
+    PipelineDone(u32, u64),             // the CRC32 and the size of the input
+    PipelineFailed(DeflateError),
+}
+
 
 macro_rules! raise_io(
     ($desc:expr) => (
@@ -150,6 +172,392 @@ pub fn calc_buf_size(buf_size_factor: uint) -> uint {
 
 
 
+/// Decompress the whole gzip file into a vector, with all the members of a multi-member file.
+/// The vector is sized up front with the uncompressed size recorded at the end of the file, as a hint only:
+/// the recorded size can't be trusted, so the hint is capped at a multiple of the file size.
+/// Errors are raised on io_error.
+pub fn decompress_file_to_vec(path: &Path) -> ~[u8] {
+    match File::open_mode(path, Open, Read) {
+        Some(file) => {
+            let mut file = file;
+            let original_size = GZip::read_info(&mut file).original_size as uint;
+            let size_hint = num::min(original_size, file.tell() as uint * MAX_SIZE_HINT_RATIO);
+            file.seek(0, SeekSet);
+            let mut gzip = GZip::decompress_init(&mut file);
+            let mut writer = MemWriter::with_capacity(size_hint);
+            gzip.decompress_all(&mut file, &mut writer, DEFAULT_SIZE_FACTOR);
+            writer.inner()
+        },
+        None => ~[]         // The open failure has been raised.
+    }
+}
+
+
+
+/// A subfield of the gzip extra field, which is a sequence of SI1, SI2, 2-byte LEN and LEN bytes of data.
+#[deriving(Clone, Eq)]
+pub enum ExtraSubfield {
+    /// The BGZF block size record (SI1 'B', SI2 'C'), holding the total size of the BGZF block minus 1
+    BgzfBlockSize(u16),
+    /// Any other subfield, with SI1, SI2 and the data
+    OtherSubfield(u8, u8, ~[u8]),
+}
+
+impl ExtraSubfield {
+    /// The subfield id, SI1 and SI2.
+    pub fn ids(&self) -> (u8, u8) {
+        match *self {
+            BgzfBlockSize(_)            => ('B' as u8, 'C' as u8),
+            OtherSubfield(si1, si2, _)  => (si1, si2),
+        }
+    }
+
+    /// The data of the subfield.
+    pub fn data(&self) -> ~[u8] {
+        match *self {
+            BgzfBlockSize(bsize)            => { let mut buf = ~[0u8, 0]; pack_num::<u16>(buf, 0, bsize, LittleEndian); buf },
+            OtherSubfield(_, _, ref data)   => data.clone(),
+        }
+    }
+
+    /// Describe the subfield.
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            BgzfBlockSize(bsize)                => format!("BGZF block size {:u}", bsize as uint + 1),
+            OtherSubfield(si1, si2, ref data)   => format!("{:c}{:c} ({:u} bytes)", si1 as char, si2 as char, data.len()),
+        }
+    }
+}
+
+/// Parse the gzip extra field into its subfields.  Return an error if a subfield runs past the end of the field.
+pub fn parse_extra_subfields(xfield: &[u8]) -> Result<~[ExtraSubfield], ~str> {
+    let mut subfields = ~[];
+    let mut offset = 0u;
+    while offset < xfield.len() {
+        if offset + 4 > xfield.len() {
+            return Err(format!("The extra subfield header at offset {:u} is cut off.", offset));
+        }
+        let (si1, si2) = (xfield[offset], xfield[offset + 1]);
+        let len = unpack_num::<u16>(xfield, offset + 2, LittleEndian) as uint;
+        offset += 4;
+        if offset + len > xfield.len() {
+            return Err(format!("The extra subfield {:c}{:c} needs {:u} bytes but only {:u} bytes are left.", 
+                               si1 as char, si2 as char, len, xfield.len() - offset));
+        }
+        let data = xfield.slice(offset, offset + len);
+        subfields.push(if si1 == 'B' as u8 && si2 == 'C' as u8 && len == 2 {
+            BgzfBlockSize(unpack_num::<u16>(data, 0, LittleEndian))
+        } else {
+            OtherSubfield(si1, si2, data.to_owned())
+        });
+        offset += len;
+    }
+    Ok(subfields)
+}
+
+/// Pack the subfields into the gzip extra field.
+pub fn pack_extra_subfields(subfields: &[ExtraSubfield]) -> ~[u8] {
+    let mut xfield = ~[];
+    for subfield in subfields.iter() {
+        let (si1, si2) = subfield.ids();
+        let data = subfield.data();
+        let mut header = [si1, si2, 0u8, 0u8];
+        pack_num::<u16>(header, 2, data.len() as u16, LittleEndian);
+        xfield.push_all(header);
+        xfield.push_all(data);
+    }
+    xfield
+}
+
+
+/// The caps on the lengths of the header fields, enforced when reading the header, so that a malicious header
+/// can't make the reading allocate without bound, e.g. a file name without its terminating zero.  None is unlimited.
+#[deriving(Clone)]
+pub struct HeaderLimits {
+    /// Maximum length of the original file name, FNAME.
+    max_name_len:       Option<uint>,
+    /// Maximum length of the comment, FCOMMENT.
+    max_comment_len:    Option<uint>,
+    /// Maximum length of the extra field, FEXTRA.
+    max_extra_len:      Option<uint>,
+}
+
+impl HeaderLimits {
+
+    /// No limit.
+    pub fn new() -> HeaderLimits {
+        HeaderLimits {
+            max_name_len:       None,
+            max_comment_len:    None,
+            max_extra_len:      None,
+        }
+    }
+
+    /// The limits for the untrusted input: 4K bytes of file name, 64K bytes of comment and 8K bytes of extra field.
+    pub fn untrusted() -> HeaderLimits {
+        HeaderLimits {
+            max_name_len:       Some(4096),
+            max_comment_len:    Some(65536),
+            max_extra_len:      Some(8192),
+        }
+    }
+
+}
+
+
+/// The mismatch between the end section of a gzip member and its decompressed data, kept in the salvage mode.
+#[deriving(Clone)]
+pub struct EndMismatch {
+    /// The CRC32 stored in the end section
+    expected_crc32:     u32,
+    /// The CRC32 computed on the decompressed data
+    computed_crc32:     u32,
+    /// The original size stored in the end section, mod 2^32
+    expected_size:      u32,
+    /// The size of the decompressed data
+    computed_size:      u64,
+}
+
+impl EndMismatch {
+    /// Whether the CRC mismatches, which means the data are corrupted.
+    pub fn crc_mismatched(&self) -> bool {
+        self.expected_crc32 != self.computed_crc32
+    }
+
+    /// Whether the size mismatches, which usually means the data are truncated or have extra bytes.
+    pub fn size_mismatched(&self) -> bool {
+        self.expected_size != self.computed_size as u32
+    }
+
+    /// Describe the mismatch, with the expected and the computed values of the mismatched parts.
+    pub fn to_str(&self) -> ~str {
+        let mut parts = ~[];
+        if self.crc_mismatched() {
+            parts.push(format!("CRC mismatch (expected {:08x}, got {:08x})", self.expected_crc32 as uint, self.computed_crc32 as uint));
+        }
+        if self.size_mismatched() {
+            parts.push(format!("size mismatch (expected {:u}, got {:u})", self.expected_size as uint, self.computed_size));
+        }
+        parts.connect("; ")
+    }
+}
+
+
+/// The recovery of a gzip member cut off before its end, kept in the salvage mode.
+#[deriving(Clone)]
+pub struct Truncation {
+    /// The number of decompressed bytes recovered
+    recovered_size:     u64,
+    /// Whether the compressed data are cut off, or only the end section is
+    data_truncated:     bool,
+}
+
+impl Truncation {
+    /// Describe the truncation, with the number of bytes recovered.
+    pub fn to_str(&self) -> ~str {
+        format!("{:u} bytes recovered; the {:s}end section is missing", 
+                self.recovered_size, if self.data_truncated { "compressed data are cut off and the " } else { "" })
+    }
+}
+
+
+/// The outcome of compressing the data with one setting in bench_compress().
+#[deriving(Clone)]
+pub struct BenchResult {
+    /// The compression level used
+    compress_level:     uint,
+    /// The number of tasks compressing in parallel; 1 for the single-task compress_stream()
+    num_tasks:          uint,
+    /// The size of the data
+    original_size:      u64,
+    /// The size of the gzip output
+    compressed_size:    u64,
+    /// The seconds taken to compress
+    secs:               f64,
+}
+
+impl BenchResult {
+
+    /// The percentage of the space saved, as gzip -l shows it.
+    pub fn ratio(&self) -> f64 {
+        if self.original_size > 0 { 100f64 - (self.compressed_size as f64 * 100f64 / self.original_size as f64) } else { 0f64 }
+    }
+
+    /// The compression throughput in MB of the original data per second.
+    pub fn mb_per_sec(&self) -> f64 {
+        self.original_size as f64 / 1048576f64 / (if self.secs > 0f64 { self.secs } else { 1e-9f64 })
+    }
+
+}
+
+/// Time the compression of the data into an in-memory gzip member at the compression level.  More than one
+/// num_tasks compresses with compress_parallel(), otherwise with compress_stream().
+pub fn bench_compress(data: &[u8], compress_level: uint, num_tasks: uint, buf_size_factor: uint) -> BenchResult {
+    let mut reader = MemReader::new(data.to_owned());
+    let mut writer = MemWriter::new();
+    let start_time = time::precise_time_s();
+    let mut gzip = GZip::compress_init(&mut writer, [0u8, ..0], 0u32, data.len() as u32);
+    if num_tasks > 1 {
+        gzip.compress_parallel(&mut reader, &mut writer, compress_level, buf_size_factor, num_tasks);
+    } else {
+        gzip.compress_stream(&mut reader, &mut writer, compress_level, buf_size_factor);
+    }
+    let secs = time::precise_time_s() - start_time;
+    BenchResult {
+        compress_level:     compress_level,
+        num_tasks:          num::max(num_tasks, 1u),
+        original_size:      data.len() as u64,
+        compressed_size:    writer.inner_ref().len() as u64,
+        secs:               secs,
+    }
+}
+
+
+/// Number of the first decompressed bytes examined for the content type.
+pub static SNIFF_LEN: uint = 512;
+
+/// The type of the decompressed content, sniffed from its first bytes.  See sniff_content().
+#[deriving(Clone, Eq)]
+pub enum ContentType {
+    /// No data
+    ContentEmpty,
+    /// Tar archive, ustar or the old format
+    ContentTar,
+    /// Zip archive
+    ContentZip,
+    /// Another gzip file
+    ContentGzip,
+    /// Bzip2 compressed data
+    ContentBzip2,
+    /// Xz compressed data
+    ContentXz,
+    /// UTF-8 or ASCII text
+    ContentText,
+    /// Anything else
+    ContentBinary,
+}
+
+impl ContentType {
+    /// Describe the content type.
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            ContentEmpty    => ~"empty",
+            ContentTar      => ~"tar archive",
+            ContentZip      => ~"zip archive",
+            ContentGzip     => ~"gzip compressed data",
+            ContentBzip2    => ~"bzip2 compressed data",
+            ContentXz       => ~"xz compressed data",
+            ContentText     => ~"text",
+            ContentBinary   => ~"binary data",
+        }
+    }
+}
+
+/// Sniff the content type from the first bytes of the data, up to SNIFF_LEN bytes.
+pub fn sniff_content(buf: &[u8]) -> ContentType {
+    if buf.len() == 0 {
+        ContentEmpty
+    } else if (buf.len() >= 262 && buf.slice(257, 262) == bytes!("ustar")) || is_tar_header(buf) {
+        ContentTar
+    } else if buf.starts_with(bytes!("PK", 3, 4)) || buf.starts_with(bytes!("PK", 5, 6)) {
+        ContentZip
+    } else if buf.starts_with(&[MAGIC1, MAGIC2]) {
+        ContentGzip
+    } else if buf.starts_with(bytes!("BZh")) {
+        ContentBzip2
+    } else if buf.starts_with(bytes!(0xFD, "7zXZ", 0)) {
+        ContentXz
+    } else if is_text(buf) {
+        ContentText
+    } else {
+        ContentBinary
+    }
+}
+
+// Check the header checksum of an old-format tar header, the sum of its bytes with the checksum field as spaces.
+fn is_tar_header(buf: &[u8]) -> bool {
+    if buf.len() < 512 || buf[0] == 0 {
+        return false;
+    }
+    let digits = buf.slice(148, 156).iter().skip_while(|&&c| c == ' ' as u8)
+                    .take_while(|&&c| c >= '0' as u8 && c <= '7' as u8).map(|&c| c).collect::<~[u8]>();
+    if digits.len() == 0 {
+        return false;
+    }
+    let expected = digits.iter().fold(0u, |sum, &c| sum * 8 + (c - '0' as u8) as uint);
+    let computed = buf.slice(0, 512).iter().enumerate().fold(0u, |sum, (i, &c)| {
+        sum + if i >= 148 && i < 156 { ' ' as uint } else { c as uint }
+    });
+    expected == computed
+}
+
+// Text has no control characters other than the whitespaces and escape, and is valid UTF-8 except a
+// character cut off at the end of the sniffed bytes.
+fn is_text(buf: &[u8]) -> bool {
+    let is_control = |c: u8| c < 0x20 && c != '\t' as u8 && c != '\n' as u8 && c != '\r' as u8 && c != 0x0C && c != 0x1B;
+    if buf.iter().any(|&c| is_control(c) || c == 0x7F) {
+        return false;
+    }
+    range(0, num::min(4, buf.len())).any(|cut| str::is_utf8(buf.slice(0, buf.len() - cut)))
+}
+
+/// Writer passing the data through to the inner writer, keeping the first SNIFF_LEN bytes to sniff the content type.
+pub struct SniffWriter<W> {
+    priv inner_writer:  W,
+    priv sniffed:       ~[u8],
+}
+
+impl<W: Writer> SniffWriter<W> {
+
+    /// Create the writer on the inner writer.
+    pub fn new(inner_writer: W) -> SniffWriter<W> {
+        SniffWriter {
+            inner_writer:   inner_writer,
+            sniffed:        ~[],
+        }
+    }
+
+    /// The first bytes written, up to SNIFF_LEN bytes.
+    pub fn sniffed<'a>(&'a self) -> &'a [u8] {
+        self.sniffed.as_slice()
+    }
+
+    /// The content type sniffed from the first bytes written.
+    pub fn content_type(&self) -> ContentType {
+        sniff_content(self.sniffed)
+    }
+
+}
+
+impl<W: Writer> Writer for SniffWriter<W> {
+    fn write(&mut self, buf: &[u8]) {
+        if self.sniffed.len() < SNIFF_LEN {
+            let len = num::min(buf.len(), SNIFF_LEN - self.sniffed.len());
+            self.sniffed.push_all(buf.slice(0, len));
+        }
+        self.inner_writer.write(buf);
+    }
+
+    fn flush(&mut self) {
+        self.inner_writer.flush();
+    }
+}
+
+impl<W: Writer> Decorator<W> for SniffWriter<W> {
+    fn inner(self) -> W {
+        self.inner_writer
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a W {
+        &self.inner_writer
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        &mut self.inner_writer
+    }
+}
+
+
 /// GZip structure for tracking gzip compression and decompression
 pub struct GZip {
     // Header fields
@@ -185,10 +593,21 @@ pub struct GZip {
     crc32:              u32,
     /// The original file length mod 2^32, i.e. the lower 4 bytes of a file size value.
     original_size:      u32,
+    /// The first CRC or size mismatch found in the salvage mode.
+    end_mismatch:       Option<EndMismatch>,
+    /// The truncated member found in the salvage mode.
+    truncation:         Option<Truncation>,
 
     // Misc
 
     priv cmp_crc32:     u32,
+    priv cmp_size:      u64,
+    priv salvage:       bool,
+    priv check_crc:     bool,
+    priv check_size:    bool,
+    priv cancel:        Option<CancelToken>,
+    priv header_limits: HeaderLimits,
+    priv crc_task:      bool,
 }
 
 impl GZip {
@@ -197,10 +616,24 @@ impl GZip {
     /// Write the gzip header to the writer.  The same writer should be passed to subsequent API calls.
     /// file_name is the original filename to store in the gzip file.
     /// mtime is the original modified time in seconds to store in the gzip file.
-    /// file_size is the original file size to store in the gzip file.
+    /// file_size is the original file size to store in the gzip file.  The compress calls store the size of the data
+    /// actually compressed instead, as the size in the end section is checked against the decompressed data.
     /// Return the new GZip structure.
     pub fn compress_init<W: Writer>(writer: &mut W, file_name: &[u8], mtime: u32, file_size: u32) -> GZip {
+        GZip::compress_init_extra(writer, file_name, mtime, file_size, [])
+    }
+
+    /// Initialize a new GZip structure for compression like compress_init(), with the extra field
+    /// built from the subfields.  No extra field is written if there are no subfields.
+    pub fn compress_init_extra<W: Writer>(writer: &mut W, file_name: &[u8], mtime: u32, file_size: u32,
+                                          subfields: &[ExtraSubfield]) -> GZip {
         let mut gzip = GZip::new();
+        if subfields.len() > 0 {
+            let xfield = pack_extra_subfields(subfields);
+            gzip.xfield_len = Some(xfield.len() as u16);
+            gzip.xfield = Some(xfield);
+            gzip.flags |= FEXTRA;
+        }
         gzip.mtime = mtime;
         let file_name = file_name.iter().filter_map(|&c| if c != 0 { Some(c) } else { None }).collect::<~[u8]>();
         gzip.filename = if file_name.len() > 0 { Some(file_name) } else { None };
@@ -216,9 +649,21 @@ impl GZip {
     /// Read the gzip header from reader.  The same reader should be passed to subsequent API calls.
     /// Return the new GZip structure.
     pub fn decompress_init<R: Reader>(reader: &mut R) -> GZip {
+        GZip::decompress_init_limits(reader, HeaderLimits::new())
+    }
+
+    /// Initialize a new GZip structure for decompression like decompress_init(), enforcing the limits on the
+    /// header fields.  A field over its limit raises io_error before it's read in.  The limits apply to
+    /// the following members read by decompress_all() too.
+    pub fn decompress_init_limits<R: Reader>(reader: &mut R, header_limits: HeaderLimits) -> GZip {
         let mut gzip = GZip::new();
+        gzip.header_limits = header_limits;
         gzip.readHeader(reader);
         gzip.readHeaderExtra(reader);
+        if trace::is_on() {
+            trace::trace(format!("gzip: header read, method {:u}, flags {:s}, mtime {:u}, name {:s}",
+                                 gzip.compression as uint, gzip.flags_as_str(), gzip.mtime as uint, gzip.file_name_as_str("")));
+        }
         gzip
     }
 
@@ -229,11 +674,75 @@ impl GZip {
         let mut end_buf = [0u8, ..END_LENGTH];
         let mut gzip = GZip::decompress_init(file_reader);
         file_reader.seek(-END_LENGTH as i64, SeekEnd);
-        read_buf_upto(file_reader, end_buf, 0, END_LENGTH);
-        gzip.unpackEndSection(end_buf, end_buf.len());
+        if read_buf_exact(file_reader, end_buf, 0, END_LENGTH, "Not enough data in the gzip end section.") {
+            gzip.unpackEndSection(end_buf, end_buf.len());
+        }
         gzip
     }
 
+    /// Walk through all the gzip members of the file like gzip -l, reading the header and the end section of
+    /// each member without checking the data.  A BGZF member is skipped over by its block size.  The deflate data
+    /// of other members are decompressed without being kept, only to find where the member ends.
+    /// This only works on file since it's doing seeks.  Any error is raised as io_error.
+    /// Return the number of members and the total of the uncompressed sizes in their end sections.
+    pub fn scan_members(file_reader: &mut File, buf_size_factor: uint) -> (uint, u64) {
+        let mut members = 0u;
+        let mut total_size = 0u64;
+        let mut end_buf = [0u8, ..END_LENGTH];
+        loop {
+            let member_start = file_reader.tell();
+            match file_reader.read_byte() {
+                Some(_) => file_reader.seek(member_start as i64, SeekSet),
+                None    => break
+            }
+            let mut gzip = GZip::decompress_init(file_reader);
+            if !gzip.isValidHeader() {
+                break;
+            }
+            let end_len = match gzip.bgzf_block_size() {
+                Some(block_size) if block_size >= END_LENGTH => {
+                    file_reader.seek((member_start + (block_size - END_LENGTH) as u64) as i64, SeekSet);
+                    read_buf_upto(file_reader, end_buf, 0, END_LENGTH)
+                },
+                _ => {
+                    let mut inflator = Inflator::with_size_factor(buf_size_factor);
+                    let mut rest_len = 0u;
+                    let mut end_len = 0u;
+                    let result = inflator.decompress_stream(
+                        |in_buf| {
+                            match file_reader.read(in_buf) {
+                                Some(nread) => nread,
+                                None => 0
+                            }
+                        },
+                        |_, _| false,
+                        |rest_buf| {
+                            rest_len = rest_buf.len();
+                            end_len = num::min(END_LENGTH, rest_len);
+                            vec::bytes::copy_memory(end_buf, rest_buf, end_len);
+                        });
+                    match result {
+                        Ok(_) => (),
+                        Err(err) => {
+                            raise_io!("Failed to decompress data.", err.to_str());
+                            break;
+                        }
+                    }
+                    // Go back to the start of the next member, after the end section, from what has been read beyond it.
+                    let next_pos = file_reader.tell() - (rest_len - end_len) as u64;
+                    file_reader.seek(next_pos as i64, SeekSet);
+                    end_len + read_buf_upto(file_reader, end_buf, end_len, END_LENGTH - end_len)
+                }
+            };
+            if !gzip.unpackEndSection(end_buf, end_len) {
+                break;
+            }
+            members += 1;
+            total_size += gzip.original_size as u64;
+        }
+        (members, total_size)
+    }
+
     fn new() -> GZip {
         GZip {
             id1:            MAGIC1,
@@ -250,11 +759,61 @@ impl GZip {
             header_crc:     None,
             crc32:          0,
             original_size:  0,
+            end_mismatch:   None,
+            truncation:     None,
             cmp_crc32:      0,
+            cmp_size:       0,
+            salvage:        false,
+            check_crc:      true,
+            check_size:     true,
+            cancel:         None,
+            header_limits:  HeaderLimits::new(),
+            crc_task:       false,
         }
     }
 
 
+    /// Set the salvage mode for decompression.  In the salvage mode, a CRC or size mismatch in the end section
+    /// doesn't raise an error.  The decompressed data, which are written out already, are kept, and the first
+    /// mismatch is recorded in end_mismatch for the caller to report as a warning.  Partially damaged data,
+    /// like logs, are still valuable.  A member cut off before its end doesn't raise an error either.
+    /// As much of it as possible is decompressed, and the truncation is recorded in truncation.
+    pub fn set_salvage(&mut self, salvage: bool) {
+        self.salvage = salvage;
+    }
+
+    /// Set whether to check the CRC and the size in the end section on decompression.  Both are checked by default.
+    /// A CRC mismatch means the data are corrupted, while a size mismatch alone usually means the original
+    /// size is recorded wrongly, like by a compressor writing a stream of unknown length.
+    pub fn set_end_checks(&mut self, check_crc: bool, check_size: bool) {
+        self.check_crc = check_crc;
+        self.check_size = check_size;
+    }
+
+    /// Set the token to stop compress_stream(), compress_parallel(), compress_pipelined(), compress_rsyncable() and
+    /// the decompression of the members.  They check it between the chunks of data and raise io_error with the ioutil::CANCELLED desc.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Set whether compress_stream() and the decompression of the members compute the CRC32 on a CrcTask,
+    /// overlapping it with the compression and the IO instead of computing it inline.  It's off by default.
+    /// It shortens the wall-clock time on big data with a spare core, at the cost of copying the data once.
+    pub fn set_crc_task(&mut self, crc_task: bool) {
+        self.crc_task = crc_task;
+    }
+
+    // Fold the CRC32 computed by the CrcTask into cmp_crc32.
+    fn join_crc_task(&mut self, crc_task: Option<CrcTask>) {
+        match crc_task {
+            Some(crc_task) => {
+                let (crc32, size) = crc_task.join();
+                self.cmp_crc32 = crc32_combine(self.cmp_crc32, crc32, size);
+            },
+            None => ()
+        }
+    }
+
     /// Compresses all the data read from the reader and writes the compressed output to writer.
     /// Runs until reading EOF from reader.  More efficient than GZipWriter, with less buffer copying.
     /// Requires compress_init() to be called first.
@@ -264,18 +823,25 @@ impl GZip {
     /// buf_size_factor is used for internal IO buffers, with MIN_SIZE_FACTOR.  It is the power in 2.
     pub fn compress_stream<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, compress_level: uint, buf_size_factor: uint) {
         let mut deflator = Deflator::with_size_factor(buf_size_factor);
-        let status = deflator.init(compress_level, false, false);
-        match status {
-            DeflateStatusOkay => (),
-            _ => raise_io!("Failed to Initialize deflator.", format!("Status: {:?}", status))
+        match deflator.init(compress_level, false, false) {
+            Ok(_) => (),
+            Err(err) => raise_io!("Failed to Initialize deflator.", err.to_str())
         }
 
-        let status = deflator.compress_stream(
+        let mut crc_task = if self.crc_task { Some(CrcTask::spawn()) } else { None };
+        let result = deflator.compress_stream(
             // upcall function to read input data for compression
             |in_buf| {
+                if ioutil::is_cancelled(&self.cancel) {
+                    return 0;               // stop reading; the cancellation is raised below
+                }
                 match reader.read(in_buf) {
                     Some(nread) => {
-                        self.cmp_crc32 = update_crc(self.cmp_crc32, in_buf, 0, nread);
+                        match crc_task {
+                            Some(ref mut crc_task) => crc_task.update(in_buf.slice(0, nread)),
+                            None => self.cmp_crc32 = update_crc(self.cmp_crc32, in_buf, 0, nread)
+                        }
+                        self.cmp_size += nread as u64;
                         nread               // read number of bytes read, including 0 for EOF
                     },
                     None => 0               // EOF
@@ -289,14 +855,255 @@ impl GZip {
                 }
                 false                           // don't abort
             });
+        self.join_crc_task(crc_task);
 
-        match status {
-            DeflateStatusDone => {
+        match result {
+            Ok(_) if ioutil::is_cancelled(&self.cancel) => 
+                raise_io!(ioutil::CANCELLED, ~"The compression is cancelled."),
+            Ok(_) => {
                 self.crc32 = self.cmp_crc32;
+                self.original_size = self.cmp_size as u32;
                 self.writeEndSection(writer);
             },
-            _ => 
-                raise_io!("Failed to compress data.", format!("Status: {:?}", status))
+            Err(err) => 
+                raise_io!("Failed to compress data.", err.to_str())
+        }
+    }
+
+    /// Compresses all the data read from the reader in parallel and writes the compressed output to writer.
+    /// The input is split into blocks of calc_buf_size(buf_size_factor) bytes, and up to num_tasks blocks 
+    /// are compressed at the same time on separate tasks.  The output is still one standard gzip member.
+    /// Requires compress_init() to be called first.
+    pub fn compress_parallel<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, 
+                                                   compress_level: uint, buf_size_factor: uint, num_tasks: uint) {
+        let block_size = calc_buf_size(buf_size_factor);
+        let num_tasks = num::max(num_tasks, 1u);
+        let mut next_block = read_upto(reader, block_size);
+
+        loop {
+            if ioutil::is_cancelled(&self.cancel) {
+                raise_io!(ioutil::CANCELLED, ~"The compression is cancelled.");
+                return;
+            }
+            // Read ahead one block to find out whether the current block is the last one.
+            let mut futures = ~[];
+            let mut is_last = false;
+            while futures.len() < num_tasks && !is_last {
+                let block = next_block;
+                next_block = read_upto(reader, block_size);
+                is_last = next_block.len() == 0;
+                self.cmp_size += block.len() as u64;
+                // The blocks are independent, so each task computes the CRC32 of its block, combined in order below.
+                futures.push(Future::spawn(proc() {
+                    let crc32 = update_crc(0u32, block, 0, block.len());
+                    (deflate::compress_segment(block, compress_level, is_last), crc32, block.len() as u64)
+                }));
+            }
+
+            // Write out the compressed segments in the original order.
+            for future in futures.mut_iter() {
+                match *future.get_ref() {
+                    (Ok(ref segment), crc32, size) => {
+                        writer.write(*segment);
+                        self.cmp_crc32 = crc32_combine(self.cmp_crc32, crc32, size);
+                    },
+                    (Err(ref err), _, _) => {
+                        raise_io!("Failed to compress data.", err.to_str());
+                        return;
+                    }
+                }
+            }
+
+            if is_last {
+                break;
+            }
+        }
+
+        writer.flush();
+        self.crc32 = self.cmp_crc32;
+        self.original_size = self.cmp_size as u32;
+        self.writeEndSection(writer);
+    }
+
+    /// Compresses all the data read from the reader in a pipeline and writes the compressed output to writer.
+    /// The reading, the compression and the writing run on separate tasks, overlapping the IO with the compression.
+    /// The reader is moved to the reading task, and the writing stays on the calling task.
+    /// Up to PIPELINE_DEPTH blocks of calc_buf_size(buf_size_factor) bytes are queued between the stages.
+    /// Requires compress_init() to be called first.
+    pub fn compress_pipelined<R: Reader + Send, W: Writer>(&mut self, reader: R, writer: &mut W, 
+                                                           compress_level: uint, buf_size_factor: uint) {
+        let block_size = calc_buf_size(buf_size_factor);
+        let (block_port, block_chan) = Chan::new();
+        let (block_ack_port, block_ack_chan) = Chan::new();
+        let (out_port, out_chan) = Chan::new();
+        let (out_ack_port, out_ack_chan) = Chan::new();
+
+        // The reading stage.  An empty block marks the end of input.
+        let cancel = self.cancel.clone();
+        task::spawn(proc() {
+            let mut reader = reader;
+            let mut in_flight = 0u;
+            loop {
+                if ioutil::is_cancelled(&cancel) {
+                    return;                             // stop reading; the cancellation is raised by the writing stage
+                }
+                if in_flight == PIPELINE_DEPTH {
+                    match block_ack_port.recv_opt() {
+                        Some(_) => in_flight -= 1,
+                        None    => return               // the compression stage has stopped
+                    }
+                }
+                let block = read_upto(&mut reader, block_size);
+                let is_eof = block.len() == 0;
+                if !block_chan.try_send(block) || is_eof {
+                    return;
+                }
+                in_flight += 1;
+            }
+        });
+
+        // The compression stage.
+        task::spawn(proc() {
+            let mut deflator = Deflator::with_size_factor(buf_size_factor);
+            match deflator.init(compress_level, false, false) {
+                Ok(_) => (),
+                Err(err) => {
+                    out_chan.try_send(PipelineFailed(err));
+                    return;
+                }
+            }
+
+            let mut crc32 = 0u32;
+            let mut size = 0u64;
+            let mut in_flight = 0u;
+            loop {
+                let block = match block_port.recv_opt() {
+                    Some(block) => block,
+                    None        => return                   // the reading stage has failed
+                };
+                block_ack_chan.try_send(());
+                let is_eof = block.len() == 0;
+                crc32 = update_crc(crc32, block, 0, block.len());
+                size += block.len() as u64;
+
+                let mut output = ~[];
+                match deflator.compress_write(block, is_eof, |out_buf, _| output.push_all(out_buf)) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        out_chan.try_send(PipelineFailed(err));
+                        return;
+                    }
+                }
+
+                if output.len() > 0 {
+                    if in_flight == PIPELINE_DEPTH {
+                        match out_ack_port.recv_opt() {
+                            Some(_) => in_flight -= 1,
+                            None    => return           // the writing stage has stopped
+                        }
+                    }
+                    out_chan.try_send(PipelineData(output));
+                    in_flight += 1;
+                }
+                if is_eof {
+                    out_chan.try_send(PipelineDone(crc32, size));
+                    return;
+                }
+            }
+        });
+
+        // The writing stage.
+        loop {
+            match out_port.recv_opt() {
+                Some(PipelineData(output)) => {
+                    writer.write(output);
+                    out_ack_chan.try_send(());
+                },
+                Some(PipelineDone(crc32, size)) => {
+                    self.cmp_crc32 = crc32;
+                    self.cmp_size = size;
+                    break;
+                },
+                Some(PipelineFailed(err)) => {
+                    raise_io!("Failed to compress data.", err.to_str());
+                    return;
+                },
+                None if ioutil::is_cancelled(&self.cancel) => {
+                    raise_io!(ioutil::CANCELLED, ~"The compression is cancelled.");
+                    return;
+                },
+                None => {
+                    raise_io!("Failed to compress data.", ~"The reading or compression task has failed.");
+                    return;
+                }
+            }
+        }
+
+        writer.flush();
+        self.crc32 = self.cmp_crc32;
+        self.original_size = self.cmp_size as u32;
+        self.writeEndSection(writer);
+    }
+
+    /// Compresses all the data read from the reader in the rsyncable way and writes the compressed output to writer.
+    /// Like gzip --rsyncable, the input is cut into chunks where the rolling sum of the last RSYNC_WINDOW bytes 
+    /// is a multiple of RSYNC_WINDOW.  Each chunk is compressed independently, so a local change in the input 
+    /// only changes the compressed output around it and rsync can skip the rest.  The output is slightly bigger.
+    /// Requires compress_init() to be called first.
+    pub fn compress_rsyncable<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, compress_level: uint, buf_size_factor: uint) {
+        let mut deflator = Deflator::with_size_factor(buf_size_factor);
+        match deflator.init(compress_level, false, false) {
+            Ok(_) => (),
+            Err(err) => raise_io!("Failed to Initialize deflator.", err.to_str())
+        }
+
+        let block_size = calc_buf_size(buf_size_factor);
+        let mut in_buf = vec::from_elem(block_size, 0u8);
+        let mut chunk = vec::with_capacity(block_size);
+        let mut window = vec::from_elem(RSYNC_WINDOW, 0u8);
+        let mut window_pos = 0u;
+        let mut rolling_sum = 0u;
+
+        loop {
+            if ioutil::is_cancelled(&self.cancel) {
+                raise_io!(ioutil::CANCELLED, ~"The compression is cancelled.");
+                return;
+            }
+            let nread = match reader.read(in_buf) {
+                Some(nread) => nread,
+                None => 0                   // EOF
+            };
+            if nread == 0 {
+                break;
+            }
+            self.cmp_crc32 = update_crc(self.cmp_crc32, in_buf, 0, nread);
+            self.cmp_size += nread as u64;
+
+            let mut start = 0u;
+            for i in range(0, nread) {
+                rolling_sum = rolling_sum + in_buf[i] as uint - window[window_pos] as uint;
+                window[window_pos] = in_buf[i];
+                window_pos = (window_pos + 1) % RSYNC_WINDOW;
+
+                // Skip the boundaries in the first RSYNC_WINDOW bytes of a chunk to avoid tiny chunks on runs of zeros.
+                let chunk_len = chunk.len() + i + 1 - start;
+                if (rolling_sum % RSYNC_WINDOW == 0 && chunk_len >= RSYNC_WINDOW) || chunk_len >= block_size {
+                    chunk.push_all(in_buf.slice(start, i + 1));
+                    if !compress_chunk(&mut deflator, chunk, false, writer) {
+                        return;
+                    }
+                    chunk.truncate(0);
+                    start = i + 1;
+                }
+            }
+            chunk.push_all(in_buf.slice(start, nread));
+        }
+
+        if compress_chunk(&mut deflator, chunk, true, writer) {
+            writer.flush();
+            self.crc32 = self.cmp_crc32;
+            self.original_size = self.cmp_size as u32;
+            self.writeEndSection(writer);
         }
     }
 
@@ -307,7 +1114,7 @@ impl GZip {
         buf[1] = self.id2;
         buf[2] = self.compression;
         buf[3] = self.flags;
-        pack_u32_le(buf, 4, self.mtime);
+        pack_num::<u32>(buf, 4, self.mtime, LittleEndian);
         buf[8] = self.xflags;
         buf[9] = self.os;
 
@@ -322,7 +1129,6 @@ impl GZip {
         }
 
         if (self.flags & FNAME) == FNAME {
-            println(format!("filename: {:?}", *self.filename.get_ref()));
             writer.write(*self.filename.get_ref());
             writer.write([0u8]);
         }
@@ -341,8 +1147,8 @@ impl GZip {
     fn writeEndSection<W: Writer>(&self, writer: &mut W) {
         let mut end_buf = [0, ..END_LENGTH];
 
-        pack_u32_le(end_buf, 0, self.crc32);
-        pack_u32_le(end_buf, 4, self.original_size);
+        pack_num::<u32>(end_buf, 0, self.crc32, LittleEndian);
+        pack_num::<u32>(end_buf, 4, self.original_size, LittleEndian);
         writer.write(end_buf);
         writer.flush();
     }
@@ -358,12 +1164,81 @@ impl GZip {
     ///
     /// buf_size_factor is used for internal IO buffers, with MIN_SIZE_FACTOR.  It is the power in 2.
     pub fn decompress_stream<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, buf_size_factor: uint) -> ~[u8] {
+        let (_, extra_buf) = self.decompress_member(reader, writer, buf_size_factor);
+        extra_buf
+    }
+
+    /// Decompresses the current member and all the members following it, writing their decompressed output 
+    /// one after another to writer, like gzip does for the concatenated gzip files.
+    /// Requires decompress_init() to be called first.  The GZip keeps the header info of the first member.
+    /// The CRC and the size of each member are checked.  Decompression stops at the first failed member.
+    /// Return the number of members decompressed and the total decompressed size.
+    pub fn decompress_all<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, buf_size_factor: uint) -> (uint, u64) {
+        let mut reader = PushbackReader::new(reader);
+        let (done, extra_buf) = self.decompress_member(&mut reader, writer, buf_size_factor);
+        if !done {
+            return (0u, self.cmp_size);
+        }
+        let mut members = 1u;
+        let mut total_size = self.cmp_size;
+
+        // Anything after the end section is the beginning of the next member.
+        reader.push_back(extra_buf);
+        while reader.has_more() {
+            let mut gzip = GZip::decompress_init_limits(&mut reader, self.header_limits.clone());
+            if !gzip.isValidHeader() {
+                break;
+            }
+            gzip.salvage = self.salvage;
+            gzip.set_end_checks(self.check_crc, self.check_size);
+            gzip.set_cancel_token(self.cancel.clone());
+            gzip.set_crc_task(self.crc_task);
+            let (done, extra_buf) = gzip.decompress_member(&mut reader, writer, buf_size_factor);
+            if self.end_mismatch.is_none() {
+                self.end_mismatch = gzip.end_mismatch.clone();
+            }
+            if gzip.truncation.is_some() {
+                self.truncation = gzip.truncation.clone();
+            }
+            if !done {
+                break;
+            }
+            members += 1;
+            total_size += gzip.cmp_size;
+            reader.push_back(extra_buf);
+        }
+        (members, total_size)
+    }
+
+    /// Verifies all the gzip members in the reader by decompressing them to a sink, checking their CRC and size.
+    /// Any error is raised as io_error.  Verification stops at the first failed member.
+    /// Return the number of members verified and the total decompressed size.
+    pub fn verify<R: Reader>(reader: R, buf_size_factor: uint) -> (uint, u64) {
+        let (members, total_size, _) = GZip::verify_sniff(reader, buf_size_factor);
+        (members, total_size)
+    }
+
+    /// Verifies all the gzip members like verify(), and sniffs the type of the decompressed content in the same pass.
+    /// Return the number of members verified, the total decompressed size, and the content type.
+    pub fn verify_sniff<R: Reader>(mut reader: R, buf_size_factor: uint) -> (uint, u64, ContentType) {
+        let mut gzip = GZip::decompress_init(&mut reader);
+        if !gzip.isValidHeader() {
+            return (0u, 0u64, ContentEmpty);
+        }
+        let mut sniff_writer = SniffWriter::new(SinkWriter { count: 0u64 });
+        let (members, total_size) = gzip.decompress_all(&mut reader, &mut sniff_writer, buf_size_factor);
+        (members, total_size, sniff_writer.content_type())
+    }
+
+    /// Decompresses one gzip member.  Return whether it's successful and any extra bytes beyond the member's end section.
+    fn decompress_member<R: Reader, W: Writer>(&mut self, reader: &mut R, writer: &mut W, buf_size_factor: uint) -> (bool, ~[u8]) {
         let mut extra_buf = ~[];
         let mut end_buf = [0u8, ..END_LENGTH];
         let mut end_len = 0u;
         let mut inflator = Inflator::with_size_factor(buf_size_factor);
+        let mut crc_task = if self.crc_task { Some(CrcTask::spawn()) } else { None };
 
-        let status = inflator.decompress_stream(
+        let result = inflator.decompress_stream(
             // upcall function to read input data for decompression
             |in_buf| {
                 match reader.read(in_buf) { // read as much data as possible; extra unprocessed data will be returned to caller.
@@ -373,12 +1248,16 @@ impl GZip {
             },
             // upcall function to write the decompressed data
             |out_buf, is_eof| {
-                self.cmp_crc32 = update_crc(self.cmp_crc32, out_buf, 0, out_buf.len());     // compute the CRC on the decompressed data
+                match crc_task {                                                            // compute the CRC on the decompressed data
+                    Some(ref mut crc_task) => crc_task.update(out_buf),
+                    None => self.cmp_crc32 = update_crc(self.cmp_crc32, out_buf, 0, out_buf.len())
+                }
+                self.cmp_size += out_buf.len() as u64;
                 writer.write(out_buf);
                 if is_eof {
                     writer.flush();
                 }
-                false                           // don't abort
+                ioutil::is_cancelled(&self.cancel)  // abort if cancelled
             },
             // upcall function to handle the remaining input data that are not part of the compressed data.
             |rest_buf| {
@@ -392,30 +1271,42 @@ impl GZip {
                     end_len += read_buf_upto(reader, end_buf, end_len, END_LENGTH - end_len);
                 }
             } );
+        self.join_crc_task(crc_task);
 
-        match status {
-            InflateStatusDone => {
-                self.unpackEndSection(end_buf, end_len);
-                self.checkCrc();
+        let done = match result {
+            Ok(_) if end_len < END_LENGTH && self.salvage => {
+                self.keepTruncation(false)
             },
-            _ => 
-                raise_io!("Failed to decompress data.", format!("Status: {:?}", status))
-        }
+            Ok(_) => {
+                self.unpackEndSection(end_buf, end_len) && self.checkEnd()
+            },
+            Err(InflateError { status: InflateStatusFailedCannotMakeProgress, .. }) if self.salvage => {
+                self.keepTruncation(true)
+            },
+            Err(InflateError { status: InflateStatusAbort, .. }) => {
+                raise_io!(ioutil::CANCELLED, ~"The decompression is cancelled.");
+                false
+            },
+            Err(err) => {
+                raise_io!("Failed to decompress data.", err.to_str());
+                false
+            }
+        };
 
-        extra_buf   // Return the extra bytes beyond the end of gzip data.
+        (done, extra_buf)   // Return the extra bytes beyond the end of gzip data.
     }
 
     fn readHeader<R: Reader>(&mut self, reader: &mut R) {
         let mut buf = [0, ..HEADER_FIXED_LEN];
-        if read_buf_upto(reader, buf, 0, HEADER_FIXED_LEN) != HEADER_FIXED_LEN {
-            raise_io!("Too few data to be a valid gzip format.");
+        if !read_buf_exact(reader, buf, 0, HEADER_FIXED_LEN, "Too few data to be a valid gzip format.") {
+            return;
         }
 
         self.id1 = buf[0];
         self.id2 = buf[1];
         self.compression = buf[2];
         self.flags = buf[3];
-        self.mtime = unpack_u32_le(buf, 4);
+        self.mtime = unpack_num::<u32>(buf, 4, LittleEndian);
         self.xflags = buf[8];
         self.os = buf[9];
 
@@ -427,22 +1318,43 @@ impl GZip {
         }
     }
 
+    fn isValidHeader(&self) -> bool {
+        self.id1 == MAGIC1 && self.id2 == MAGIC2 && self.compression == METHOD_DEFLATE
+    }
+
     fn readHeaderExtra<R: Reader>(&mut self, reader: &mut R) {
 
         if (self.flags & FEXTRA) == FEXTRA {
             self.xfield_len = Some(reader.read_le_u16());
             let xf_len = self.xfield_len.unwrap() as uint;
-            let mut buf = vec::from_elem(xf_len, 0u8);
-            read_buf_upto(reader, buf, 0, xf_len);
-            self.xfield = Some(buf);
+            match self.header_limits.max_extra_len {
+                Some(max_len) if xf_len > max_len => {
+                    raise_io!("The gzip extra field is over the limit.", format!("{:u} bytes, over {:u} bytes", xf_len, max_len));
+                    return;
+                },
+                _ => ()
+            }
+            self.xfield = read_exact(reader, xf_len, "Not enough data in the gzip extra field.");
         }
 
         if (self.flags & FNAME) == FNAME {
-            self.filename = Some(read_upto_z(reader));
+            match read_upto_z(reader, self.header_limits.max_name_len) {
+                Some(filename)  => self.filename = Some(filename),
+                None            => {
+                    raise_io!("The gzip file name is over the limit.", format!("over {:u} bytes", self.header_limits.max_name_len.unwrap()));
+                    return;
+                }
+            }
         }
 
         if (self.flags & FCOMMENT) == FCOMMENT {
-            self.comment = Some(str::from_utf8(read_upto_z(reader)));
+            match read_upto_z(reader, self.header_limits.max_comment_len) {
+                Some(comment)   => self.comment = Some(str::from_utf8(comment)),
+                None            => {
+                    raise_io!("The gzip comment is over the limit.", format!("over {:u} bytes", self.header_limits.max_comment_len.unwrap()));
+                    return;
+                }
+            }
         }
 
         if (self.flags & FHCRC) == FHCRC {
@@ -451,36 +1363,190 @@ impl GZip {
 
     }
 
-    fn unpackEndSection(&mut self, end_buf: &[u8], end_len: uint) {
+    fn unpackEndSection(&mut self, end_buf: &[u8], end_len: uint) -> bool {
         if end_len < END_LENGTH {
             raise_io!("Not enough data in the gzip end section.", 
                       format!("Bytes missing: {:u}", (END_LENGTH - end_len)) );
+            return false;
         }
-        self.crc32 = unpack_u32_le(end_buf, 0);
-        self.original_size = unpack_u32_le(end_buf, 4);
+        self.crc32 = unpack_num::<u32>(end_buf, 0, LittleEndian);
+        self.original_size = unpack_num::<u32>(end_buf, 4, LittleEndian);
+        true
     }
 
-    fn checkCrc(&mut self) {
+    // Check the CRC and the size of the end section, as set by set_end_checks().
+    fn checkEnd(&mut self) -> bool {
+        (!self.check_crc || self.checkCrc()) && (!self.check_size || self.checkSize())
+    }
+
+    fn checkCrc(&mut self) -> bool {
         if self.crc32 != self.cmp_crc32 {
-            raise_io!("The computed CRC of the decompressed data does not match the stored CRC in the file.");
+            if self.salvage {
+                return self.keepMismatch();
+            }
+            raise_io!("The computed CRC of the decompressed data does not match the stored CRC in the file.",
+                      format!("CRC mismatch (expected {:08x}, got {:08x})", self.crc32 as uint, self.cmp_crc32 as uint));
+            return false;
         }
+        true
     }
 
-    /// Return the file_name as string.  Return the default_name if no file_name.
-    pub fn file_name_as_str(&self, default_name: &str) -> ~str {
-        match self.filename {
-            Some(ref filename) => {
-                str::from_utf8(*filename)
-            },
-            None => 
-                default_name.to_owned()
+    // Record the first mismatch of the end section in the salvage mode, and carry on.
+    fn keepMismatch(&mut self) -> bool {
+        if self.end_mismatch.is_none() {
+            self.end_mismatch = Some(EndMismatch {
+                expected_crc32: self.crc32,
+                computed_crc32: self.cmp_crc32,
+                expected_size:  self.original_size,
+                computed_size:  self.cmp_size,
+            });
         }
+        true
     }
 
-}
-
+    // Record the truncated member in the salvage mode.  Return false, as the member is not done.
+    fn keepTruncation(&mut self, data_truncated: bool) -> bool {
+        self.truncation = Some(Truncation {
+            recovered_size: self.cmp_size,
+            data_truncated: data_truncated,
+        });
+        false
+    }
+
+    fn checkSize(&mut self) -> bool {
+        // The stored size is the original size mod 2^32.
+        if self.original_size != self.cmp_size as u32 {
+            if self.salvage {
+                return self.keepMismatch();
+            }
+            raise_io!("The size of the decompressed data does not match the stored size in the file.",
+                      format!("size mismatch (expected {:u}, got {:u})", self.original_size as uint, self.cmp_size));
+            return false;
+        }
+        true
+    }
+
+    /// Return the subfields of the extra field, or none if there's no extra field.
+    pub fn extra_subfields(&self) -> Result<~[ExtraSubfield], ~str> {
+        match self.xfield {
+            Some(ref xfield)    => parse_extra_subfields(*xfield),
+            None                => Ok(~[])
+        }
+    }
+
+    /// Return the total size of the BGZF block if the member is a BGZF block.
+    pub fn bgzf_block_size(&self) -> Option<uint> {
+        match self.extra_subfields() {
+            Ok(subfields)   => subfields.iter().filter_map(|subfield| match *subfield {
+                                    BgzfBlockSize(bsize)    => Some(bsize as uint + 1),
+                                    _                       => None
+                                }).next(),
+            Err(_)          => None
+        }
+    }
+
+    /// Return the names of the header flags that are set, separated by space.
+    pub fn flags_as_str(&self) -> ~str {
+        let names = [(FTEXT, "FTEXT"), (FHCRC, "FHCRC"), (FEXTRA, "FEXTRA"), (FNAME, "FNAME"), (FCOMMENT, "FCOMMENT")];
+        let set_names : ~[&str] = names.iter().filter_map(|&(flag, name)| if (self.flags & flag) == flag { Some(name) } else { None }).collect();
+        set_names.connect(" ")
+    }
+
+    /// Return the file_name as string.  Return the default_name if no file_name.
+    pub fn file_name_as_str(&self, default_name: &str) -> ~str {
+        match self.filename {
+            Some(ref filename) => {
+                str::from_utf8(*filename)
+            },
+            None => 
+                default_name.to_owned()
+        }
+    }
+
+}
+
+
+/// Writer to discard all data written to it, only counting the bytes.
+struct SinkWriter {
+    count:  u64,
+}
+
+impl Writer for SinkWriter {
+    fn write(&mut self, buf: &[u8]) {
+        self.count += buf.len() as u64;
+    }
+
+    fn flush(&mut self) {
+    }
+}
+
+
+/// Reader which can push back data already read, to be read again before the data from the inner reader.
+struct PushbackReader<'a, R> {
+    priv inner_reader:  &'a mut R,
+    priv pending:       ~[u8],
+    priv pending_offset: uint,
+}
+
+impl<'a, R: Reader> PushbackReader<'a, R> {
+
+    fn new(inner_reader: &'a mut R) -> PushbackReader<'a, R> {
+        PushbackReader {
+            inner_reader:   inner_reader,
+            pending:        ~[],
+            pending_offset: 0u,
+        }
+    }
+
+    /// Push back the data to be read next.
+    fn push_back(&mut self, data: &[u8]) {
+        let mut pending = data.to_owned();
+        pending.push_all(self.pending.slice_from(self.pending_offset));
+        self.pending = pending;
+        self.pending_offset = 0;
+    }
+
+    /// Check whether there are more data to read, without consuming any.
+    fn has_more(&mut self) -> bool {
+        if self.pending_offset < self.pending.len() {
+            return true;
+        }
+        match self.inner_reader.read_byte() {
+            Some(b) => {
+                self.push_back([b]);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Take out the pushed back data not read yet.
+    fn take_pending(self) -> ~[u8] {
+        self.pending.slice_from(self.pending_offset).to_owned()
+    }
+}
+
+impl<'a, R: Reader> Reader for PushbackReader<'a, R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        if self.pending_offset < self.pending.len() {
+            let copy_len = num::min(buf.len(), self.pending.len() - self.pending_offset);
+            vec::bytes::copy_memory(buf, self.pending.slice(self.pending_offset, self.pending_offset + copy_len), copy_len);
+            self.pending_offset += copy_len;
+            return Some(copy_len);
+        }
+        self.inner_reader.read(buf)
+    }
+
+    fn eof(&mut self) -> bool {
+        self.pending_offset == self.pending.len() && self.inner_reader.eof()
+    }
+}
+
 
 /// A gzip reader to read decompressed data automatically from an inner reader.
+/// It reads one gzip member.  At its end, next_member() moves on to the next of the concatenated members.
+/// GZip::decompress_all() also decompresses all the members.
 /// Usage:
 ///     let greader = GZipReader(input_reader);
 ///     greader.read(output_data_buf);
@@ -494,6 +1560,10 @@ pub struct GZipReader<R> {
     priv inner_reader:  R,
     priv inflator:      Inflator,
     priv is_eof:        bool,
+    priv buf_size:      uint,
+    priv end_buf:       [u8, ..END_LENGTH],
+    priv member_ok:     bool,
+    priv pending:       ~[u8],
 }
 
 /// Decorator to access the inner reader
@@ -521,46 +1591,147 @@ impl<R: Reader> GZipReader<R> {
     /// Create a GZipReader to decompress data from the inner_reader automatically when reading.
     /// Control the internal IO buffer size with buf_size_factor.  See calc_buf_size() for the actual bytes computed.
     /// buf_size_factor is used for internal IO buffers.  It is the power of 2.
-    pub fn with_size_factor(mut inner_reader: R, buf_size_factor: uint) -> GZipReader<R> {
+    pub fn with_size_factor(inner_reader: R, buf_size_factor: uint) -> GZipReader<R> {
+        GZipReader::with_limits(inner_reader, buf_size_factor, HeaderLimits::new())
+    }
+
+    /// Create a GZipReader like with_size_factor(), enforcing the limits on the header fields.
+    /// See GZip::decompress_init_limits().
+    pub fn with_limits(mut inner_reader: R, buf_size_factor: uint, header_limits: HeaderLimits) -> GZipReader<R> {
         GZipReader {
-            gzip:           GZip::decompress_init(&mut inner_reader),
+            gzip:           GZip::decompress_init_limits(&mut inner_reader, header_limits),
             inner_reader:   inner_reader,
             inflator:       Inflator::with_size_factor(buf_size_factor),
             is_eof:         false,
+            buf_size:       calc_buf_size(buf_size_factor),
+            end_buf:        [0u8, ..END_LENGTH],
+            member_ok:      false,
+            pending:        ~[],
+        }
+    }
+
+    /// Move on to the next member after reading the current member to the end, for the concatenated gzip members.
+    /// The gzip field then has the header of the next member.  Return false if there's no more member,
+    /// or the current member has not been read to its end successfully.
+    pub fn next_member(&mut self) -> bool {
+        if !self.is_eof || !self.member_ok {
+            return false;
+        }
+        let header_limits = self.gzip.header_limits.clone();
+        let mut reader = PushbackReader::new(&mut self.inner_reader);
+        reader.push_back(self.pending);
+        if !reader.has_more() {
+            return false;
+        }
+        let gzip = GZip::decompress_init_limits(&mut reader, header_limits);
+        self.pending = reader.take_pending();
+        if !gzip.isValidHeader() {
+            return false;
+        }
+        self.gzip = gzip;
+        self.inflator.init(false, false);
+        self.is_eof = false;
+        self.member_ok = false;
+        true
+    }
+
+    // Read the end section of the member after the deflate data.  Anything beyond it is kept for the next member.
+    fn read_end_section(&mut self) -> bool {
+        let mut rest = vec::from_elem(self.inflator.get_rest_len(), 0u8);
+        self.inflator.get_rest(rest);
+        let mut end_len = num::min(END_LENGTH, rest.len());
+        vec::bytes::copy_memory(self.end_buf, rest, end_len);
+        let mut pending = rest.slice_from(end_len).to_owned();
+        pending.push_all(self.pending);
+        self.pending = pending;
+        if end_len < END_LENGTH {
+            let mut reader = PushbackReader::new(&mut self.inner_reader);
+            reader.push_back(self.pending);
+            end_len += read_buf_upto(&mut reader, self.end_buf, end_len, END_LENGTH - end_len);
+            self.pending = reader.take_pending();
+        }
+        self.member_ok = self.gzip.unpackEndSection(self.end_buf, end_len) && self.gzip.checkEnd();
+        self.member_ok
+    }
+
+    /// Read all the remaining decompressed data into a vector.
+    /// The vector grows by the internal IO buffer size and the data are decompressed directly into it.
+    pub fn read_to_end(&mut self) -> ~[u8] {
+        let mut data : ~[u8] = ~[];
+        let mut data_len = 0u;
+        loop {
+            if data.len() - data_len < self.buf_size {
+                data.grow(self.buf_size, &0u8);
+            }
+            match self.read(data.mut_slice_from(data_len)) {
+                Some(read_len)  => data_len += read_len,
+                None            => break
+            }
+        }
+        data.truncate(data_len);
+        data
+    }
+
+    /// Decompress all the remaining data to the writer straight from the internal buffers.
+    /// Return the number of decompressed bytes written, or the error on decompression or on writing.
+    pub fn copy_to<W: Writer>(&mut self, writer: &mut W) -> Result<u64, ~str> {
+        if self.is_eof {
+            return Ok(0);
         }
+        let mut result = Ok(0u64);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let status = {
+                let inner_reader = &mut self.inner_reader;
+                let pending = &mut self.pending;
+                let gzip = &mut self.gzip;
+                self.inflator.decompress_drain(
+                    // Callback to read input data.
+                    |in_buf| read_pending(pending, inner_reader, in_buf),
+                    // Callback to write the decompressed data.
+                    |out_buf| {
+                        gzip.cmp_crc32 = update_crc(gzip.cmp_crc32, out_buf, 0, out_buf.len());
+                        gzip.cmp_size += out_buf.len() as u64;
+                        writer.write(out_buf);
+                    })
+            };
+            self.is_eof = true;
+            match status {
+                Ok(total) => {
+                    if self.read_end_section() {
+                        result = Ok(total);
+                    }
+                },
+                Err(err) =>
+                    result = Err(format!("Failed to decompress data.  {:s}", err.to_str()))
+            }
+        });
+        result
     }
 }
 
 impl<R: Reader> Reader for GZipReader<R> {
     /// Read the decompressed data from the inner_reader.
     fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
-        let mut end_buf = [0u8, ..END_LENGTH];
-        let mut end_len;
-
-        let status = self.inflator.decompress_read(
-            // Callback to read input data.
-            |in_buf| {
-                match self.inner_reader.read(in_buf) {
-                    Some(nread) => nread,   // Return number of bytes read, including 0 for EOF
-                    None => 0               // REturn 0 for EOF
-                }
-            },
-            output_buf);
+        let status = {
+            let inner_reader = &mut self.inner_reader;
+            let pending = &mut self.pending;
+            self.inflator.decompress_read(
+                // Callback to read input data.  Return the number of bytes read, including 0 for EOF.
+                |in_buf| read_pending(pending, inner_reader, in_buf),
+                output_buf)
+        };
 
         match status {
             Ok(0) => {
                 self.is_eof = true;
-                // Move the rest of the bytes into end_buf, and read more into end_buf if not enough bytes for it.
-                end_len = self.inflator.get_rest(end_buf);
-                if end_len < END_LENGTH {
-                    end_len += read_buf_upto(&mut self.inner_reader, end_buf, end_len, END_LENGTH - end_len);
-                }
-                self.gzip.unpackEndSection(end_buf, end_len);
-                self.gzip.checkCrc();
+                self.read_end_section();
                 None
             },
             Ok(output_len) => {
                 self.gzip.cmp_crc32 = update_crc(self.gzip.cmp_crc32, output_buf, 0, output_len);
+                self.gzip.cmp_size += output_len as u64;
                 Some(output_len)
             },
             _ => {
@@ -578,6 +1749,21 @@ impl<R: Reader> Reader for GZipReader<R> {
 }
 
 
+// Read the data pending from the previous member first, then from the reader.  Return 0 at EOF.
+fn read_pending<R: Reader>(pending: &mut ~[u8], reader: &mut R, buf: &mut [u8]) -> uint {
+    if pending.len() > 0 {
+        let copy_len = num::min(buf.len(), pending.len());
+        vec::bytes::copy_memory(buf, pending.slice_to(copy_len), copy_len);
+        *pending = pending.slice_from(copy_len).to_owned();
+        return copy_len;
+    }
+    match reader.read(buf) {
+        Some(nread) => nread,
+        None => 0
+    }
+}
+
+
 /// A gzip writer to compress any data written to it.
 /// Usage:
 ///     let gwriter = GZipWriter(output_writer);
@@ -593,6 +1779,7 @@ pub struct GZipWriter<W> {
     priv inner_writer:  W,
     priv deflator:      Deflator,
     priv finalized:     bool,
+    priv buf_size:      uint,
 }
 
 impl<W: Writer> GZipWriter<W> {
@@ -628,9 +1815,36 @@ impl<W: Writer> GZipWriter<W> {
             inner_writer:   inner_writer,
             deflator:       deflator,
             finalized:      false,
+            buf_size:       calc_buf_size(buf_size_factor),
         }
     }
 
+    /// Read all the data from the reader until EOF, compress them, and finalize the compression stream.
+    /// Return the number of bytes read, or the error on reading, compressing, or writing.
+    pub fn write_from<R: Reader>(&mut self, reader: &mut R) -> Result<u64, ~str> {
+        let mut result = Ok(0u64);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let mut input_buf = vec::from_elem(self.buf_size, 0u8);
+            let mut total = 0u64;
+            loop {
+                match reader.read(input_buf) {
+                    Some(read_len) => {
+                        self.do_write(input_buf.slice(0, read_len), false);
+                        total += read_len as u64;
+                    },
+                    None => break
+                }
+            }
+            self.finalize();
+            if result.is_ok() {
+                result = Ok(total);
+            }
+        });
+        result
+    }
+
     /// Finalize the compression stream and flush out any pending compressed data.
     /// The caller must call this at the end of writing data into this writer.
     /// After this is called, this writer cannot be written again.
@@ -642,31 +1856,57 @@ impl<W: Writer> GZipWriter<W> {
         }
     }
 
+    /// Sync flush the compressed data of everything written so far to the inner writer, then flush the inner writer.
+    /// The receiver can decompress all the data written so far, e.g. for server-sent events or chunked HTTP responses.
+    /// Unlike flush(), which only flushes the inner writer, this flushes the data pending in the compressor.
+    pub fn flush_sync(&mut self) {
+        if self.finalized {
+            raise_io!("Flushing on a closed stream.", ~"The compression stream has been closed.");
+            return;
+        }
+        let result = self.deflator.compress_write_sync(|out_buf| {
+                // Callback to write the compressed data.
+                self.inner_writer.write(out_buf);
+            });
+        match result {
+            Ok(_) => {
+                self.inner_writer.flush();
+            },
+            Err(err) => {
+                // Clean up states before raising error.
+                self.finalized = true;
+                raise_io!("Flush failure in compression.", err.to_str() );
+            }
+        }
+    }
+
     fn do_write(&mut self, output_buf: &[u8], final_write: bool) {
         if self.finalized {
             raise_io!("Writing on a closed stream.", ~"The compression stream has been closed.");
         }
 
         self.gzip.cmp_crc32 = update_crc(self.gzip.cmp_crc32, output_buf, 0, output_buf.len());
-        let status = self.deflator.compress_write(output_buf, final_write, |out_buf, is_eof| {
+        self.gzip.cmp_size += output_buf.len() as u64;
+        let result = self.deflator.compress_write(output_buf, final_write, |out_buf, is_eof| {
                 // Callback to write the compressed data.
                 self.inner_writer.write(out_buf);
                 if is_eof {
                     self.inner_writer.flush();
                 }
             });
-        match status {
-            DeflateStatusOkay => {
+        match result {
+            Ok(_) if !final_write => {
             },
-            DeflateStatusDone => {
+            Ok(_) => {
                 self.finalized = true;
                 self.gzip.crc32 = self.gzip.cmp_crc32;
+                self.gzip.original_size = self.gzip.cmp_size as u32;
                 self.gzip.writeEndSection(&mut self.inner_writer);
             },
-            _ => {
+            Err(err) => {
                 // Clean up states before raising error.
                 self.finalized = true;
-                raise_io!("Write failure in compression.", format!("Status: {:?}", status) );
+                raise_io!("Write failure in compression.", err.to_str() );
             }
         }
     }
@@ -700,23 +1940,177 @@ impl<W: Writer> Decorator<W> for GZipWriter<W> {
 }
 
 
-/// Pack a u32 into byte buffer in little-endian
-fn pack_u32_le(buf: &mut [u8], offset: uint, value: u32) -> uint {
-    buf[offset + 0] = (value >> 0) as u8;
-    buf[offset + 1] = (value >> 8) as u8;
-    buf[offset + 2] = (value >> 16) as u8;
-    buf[offset + 3] = (value >> 24) as u8;
-    offset + 4
+/// Bidirectional gzip stream over one stream, like a TCP socket, compressing the writes and decompressing the reads
+/// with two independent contexts.  flush() sync flushes the data written so far, so that the peer can decompress
+/// all of them without waiting for more; call it at the end of each message.  A read returns the data as soon
+/// as some are decompressed, without waiting for more input to fill the buffer.
+/// Usage:
+///     let mut stream = GZipStream::new(tcp_stream);
+///     stream.write(request);
+///     stream.flush();
+///     let len = stream.read(buf);
+///     stream.finish();            // Ends the gzip data written.  The reads can go on.
+pub struct GZipStream<S> {
+    priv stream:        S,
+    priv out_gzip:      GZip,
+    priv deflator:      Deflator,
+    priv finished:      bool,
+    priv in_gzip:       Option<GZip>,
+    priv inflator:      Inflator,
+    priv in_eof:        bool,
+    priv end_buf:       [u8, ..END_LENGTH],
 }
 
-/// Unpack a u32 from byte buffer in little-endian
-fn unpack_u32_le(buf: &[u8], offset: uint) -> u32 {
-    ( ((buf[offset + 0] as u32) & 0xFF)       ) |
-    ( ((buf[offset + 1] as u32) & 0xFF) << 8  ) |
-    ( ((buf[offset + 2] as u32) & 0xFF) << 16 ) |
-    ( ((buf[offset + 3] as u32) & 0xFF) << 24 )
+impl<S: Reader + Writer> GZipStream<S> {
+
+    /// Create a GZipStream on the stream, compressing with the default level.  The gzip header of the written
+    /// data is written right away.  The header of the read data is read on the first read.
+    pub fn new(stream: S) -> GZipStream<S> {
+        GZipStream::with_level(stream, DEFAULT_COMPRESS_LEVEL)
+    }
+
+    /// Create a GZipStream on the stream, compressing with the compress_level (0-9).
+    pub fn with_level(mut stream: S, compress_level: uint) -> GZipStream<S> {
+        let out_gzip = GZip::compress_init(&mut stream, [], 0u32, 0u32);
+        let deflator = Deflator::new();
+        deflator.init(compress_level, false, false);
+        GZipStream {
+            stream:     stream,
+            out_gzip:   out_gzip,
+            deflator:   deflator,
+            finished:   false,
+            in_gzip:    None,
+            inflator:   Inflator::new(),
+            in_eof:     false,
+            end_buf:    [0u8, ..END_LENGTH],
+        }
+    }
+
+    /// Finish the compressed data written and write the gzip end section.  No more writes can follow.
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        let result = {
+            let stream = &mut self.stream;
+            self.deflator.compress_write([], true, |out_buf, _| stream.write(out_buf))
+        };
+        match result {
+            Ok(_) => {
+                self.out_gzip.crc32 = self.out_gzip.cmp_crc32;
+                self.out_gzip.original_size = self.out_gzip.cmp_size as u32;
+                self.out_gzip.writeEndSection(&mut self.stream);
+            },
+            Err(err) => raise_io!("Finish failure in compression.", err.to_str())
+        }
+    }
+
 }
 
+impl<S: Reader + Writer> Reader for GZipStream<S> {
+
+    fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
+        if self.in_eof {
+            return None;
+        }
+        if output_buf.len() == 0 {
+            return Some(0);
+        }
+        if self.in_gzip.is_none() {
+            self.in_gzip = Some(GZip::decompress_init(&mut self.stream));
+        }
+        let stream = &mut self.stream;
+        let status = self.inflator.decompress_read_some(
+            // Callback to read input data.
+            |in_buf| {
+                match stream.read(in_buf) {
+                    Some(nread) => nread,
+                    None => 0               // EOF
+                }
+            },
+            output_buf);
+
+        let gzip = self.in_gzip.get_mut_ref();
+        match status {
+            Ok(0) => {
+                self.in_eof = true;
+                let mut end_len = self.inflator.get_rest(self.end_buf);
+                if end_len < END_LENGTH {
+                    end_len += read_buf_upto(stream, self.end_buf, end_len, END_LENGTH - end_len);
+                }
+                gzip.unpackEndSection(self.end_buf, end_len);
+                gzip.checkEnd();
+                None
+            },
+            Ok(output_len) => {
+                gzip.cmp_crc32 = update_crc(gzip.cmp_crc32, output_buf, 0, output_len);
+                gzip.cmp_size += output_len as u64;
+                Some(output_len)
+            },
+            Err(err) => {
+                self.in_eof = true;
+                raise_io!("Read failure in decompression.", err.to_str());
+                None
+            }
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        self.in_eof
+    }
+
+}
+
+impl<S: Reader + Writer> Writer for GZipStream<S> {
+
+    fn write(&mut self, buf: &[u8]) {
+        if self.finished {
+            raise_io!("Writing on a closed stream.", ~"The compression stream has been finished.");
+            return;
+        }
+        self.out_gzip.cmp_crc32 = update_crc(self.out_gzip.cmp_crc32, buf, 0, buf.len());
+        self.out_gzip.cmp_size += buf.len() as u64;
+        let stream = &mut self.stream;
+        match self.deflator.compress_write(buf, false, |out_buf, _| stream.write(out_buf)) {
+            Ok(_) => (),
+            Err(err) => raise_io!("Write failure in compression.", err.to_str())
+        }
+    }
+
+    /// Sync flush the data written so far to the stream and flush the stream.
+    fn flush(&mut self) {
+        if !self.finished {
+            let stream = &mut self.stream;
+            match self.deflator.compress_write_sync(|out_buf| stream.write(out_buf)) {
+                Ok(_) => (),
+                Err(err) => {
+                    raise_io!("Flush failure in compression.", err.to_str());
+                    return;
+                }
+            }
+        }
+        self.stream.flush();
+    }
+
+}
+
+/// Decorator to access the inner stream
+impl<S: Reader + Writer> Decorator<S> for GZipStream<S> {
+    fn inner(self) -> S {
+        self.stream
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a S {
+        &self.stream
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut S {
+        &mut self.stream
+    }
+}
+
+
 /// Pack a string into a zero-terminated buffer.
 fn to_strz(str_value: &str) -> ~[u8] {
     let str_bytes = str_value.as_bytes();
@@ -727,32 +2121,28 @@ fn to_strz(str_value: &str) -> ~[u8] {
 }
 
 /// Read a zero-terminated str.  Read until encountering the terminating 0.
-fn read_upto_z<R: Reader>(reader: &mut R) -> ~[u8] {
+fn read_upto_z<R: Reader>(reader: &mut R, max_len: Option<uint>) -> Option<~[u8]> {
     let mut buf = ~[];
     loop {
         match reader.read_byte() {
             Some(0)     => break,
+            Some(_) if max_len.map_default(false, |max_len| buf.len() >= max_len) => return None,
             Some(ch)    => buf.push(ch),
             None        => break
         }
     }
-    buf
+    Some(buf)
 }
 
-/// Read data upto the len_to_read, unless encounters EOF.
-fn read_buf_upto<R: Reader>(reader: &mut R, buf: &mut [u8], offset: uint, len_to_read: uint) -> uint {
-    let mut total_read = 0u;
-    while total_read < len_to_read {
-        let remaining_len = len_to_read - total_read;
-        let begin = offset + total_read;
-        let end   = offset + total_read + remaining_len;
-        let slice_buf = buf.mut_slice(begin, end);
-        match reader.read(slice_buf) {
-            Some(read_len) => total_read = total_read + read_len,
-            None => break
+/// Compress one rsyncable chunk with a full flush, or finish the DEFLATE stream for the last chunk.
+fn compress_chunk<W: Writer>(deflator: &mut Deflator, chunk: &[u8], final_input: bool, writer: &mut W) -> bool {
+    match deflator.compress_flush(chunk, final_input, |out_buf| writer.write(out_buf)) {
+        Ok(_) => true,
+        Err(err) => {
+            raise_io!("Failed to compress data.", err.to_str());
+            false
         }
     }
-    return total_read;
 }
 
 
@@ -762,8 +2152,9 @@ fn compute_crc(buf: &[u8], from: uint, to: uint) -> u32 {
     return update_crc(0u32, buf, from, to);
 }
 
-// Update an existing CRC with the data of the buffer
-fn update_crc(mut crc: u32, buf: &[u8], from: uint, to: uint) -> u32 {
+/// Update an existing CRC32 with the data of the buffer from the from offset up to the to offset.
+/// The CRC32 is the same for gzip and zip.
+pub fn update_crc(mut crc: u32, buf: &[u8], from: uint, to: uint) -> u32 {
     crc = crc ^ 0xFFFFFFFF;     // Pre one's complement;
     for n in range(from, to) {
         crc = crc_table[(crc ^ buf[n] as u32) & 0xff] ^ (crc >> 8);
@@ -771,6 +2162,132 @@ fn update_crc(mut crc: u32, buf: &[u8], from: uint, to: uint) -> u32 {
     return crc ^ 0xFFFFFFFF;    // Post one's complement
 }
 
+/// Combine the CRC32 of two consecutive pieces of data, crc1 of the first piece and crc2 of the second piece
+/// of len2 bytes, into the CRC32 of the whole data, like zlib's crc32_combine().  The pieces can be checksummed
+/// independently, e.g. on separate tasks.
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+    // The operators to apply len2 zero bytes to crc1, squared from one zero bit up in the GF(2) matrices.
+    let mut even = [0u32, ..32];
+    let mut odd = [0u32, ..32];
+    odd[0] = 0xedb88320u32;
+    for n in range(1, 32) {
+        odd[n] = 1u32 << (n - 1);
+    }
+    gf2_matrix_square(even, odd);           // 2 zero bits
+    gf2_matrix_square(odd, even);           // 4 zero bits
+
+    let mut crc1 = crc1;
+    let mut len2 = len2;
+    loop {
+        gf2_matrix_square(even, odd);       // the first square gives one zero byte
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+        gf2_matrix_square(odd, even);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(odd, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+    }
+    crc1 ^ crc2
+}
+
+fn gf2_matrix_times(mat: &[u32], vec: u32) -> u32 {
+    let mut sum = 0u32;
+    let mut vec = vec;
+    let mut n = 0u;
+    while vec != 0 {
+        if vec & 1 != 0 {
+            sum ^= mat[n];
+        }
+        vec >>= 1;
+        n += 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32], mat: &[u32]) {
+    for n in range(0, 32) {
+        square[n] = gf2_matrix_times(mat, mat[n]);
+    }
+}
+
+
+/// Task computing the CRC32 and the size of the data sent to it, to overlap the checksum with the compression
+/// and the IO on the calling task.  The data are copied to the task.  Up to PIPELINE_DEPTH buffers are queued;
+/// update() waits for the task to checksum one before queueing more, bounding the memory held by the queue.
+/// Usage:
+///     let mut crc_task = CrcTask::spawn();
+///     crc_task.update(buf);
+///     ...
+///     let (crc32, size) = crc_task.join();
+pub struct CrcTask {
+    priv chan:          Option<Chan<~[u8]>>,
+    priv ack_port:      Port<()>,
+    priv in_flight:     uint,
+    priv result_port:   Port<(u32, u64)>,
+}
+
+impl CrcTask {
+
+    /// Spawn the checksum task.
+    pub fn spawn() -> CrcTask {
+        let (data_port, data_chan) = Chan::new();
+        let (ack_port, ack_chan) = Chan::new();
+        let (result_port, result_chan) = Chan::new();
+        task::spawn(proc() {
+            let mut crc32 = 0u32;
+            let mut size = 0u64;
+            loop {
+                match data_port.recv_opt() {
+                    Some(buf) => {
+                        crc32 = update_crc(crc32, buf, 0, buf.len());
+                        size += buf.len() as u64;
+                        ack_chan.try_send(());
+                    },
+                    None => break               // the CrcTask is joined
+                }
+            }
+            result_chan.try_send((crc32, size));
+        });
+        CrcTask {
+            chan:           Some(data_chan),
+            ack_port:       ack_port,
+            in_flight:      0,
+            result_port:    result_port,
+        }
+    }
+
+    /// Send a copy of the data to the task to checksum.  Wait for the task when PIPELINE_DEPTH buffers are queued.
+    pub fn update(&mut self, buf: &[u8]) {
+        if buf.len() > 0 {
+            if self.in_flight == PIPELINE_DEPTH {
+                self.ack_port.recv();
+                self.in_flight -= 1;
+            }
+            self.chan.get_ref().send(buf.to_owned());
+            self.in_flight += 1;
+        }
+    }
+
+    /// Wait for the task to checksum all the data sent.  Return the CRC32 and the size of the data.
+    pub fn join(mut self) -> (u32, u64) {
+        self.chan = None;                       // ends the task's loop
+        self.result_port.recv()
+    }
+
+}
+
 
 // Make CRC table according to the gzip spec.
 fn make_crc_table() -> [u32, ..256] {
@@ -845,15 +2362,29 @@ static crc_table : [u32, ..256] = [
 #[cfg(test)]
 mod tests {
 
-    use std::io::Reader;
+    use std::vec;
+    use std::os;
+    use std::io::{Reader, Writer};
+    use std::io::fs;
+    use std::io::fs::File;
     use std::io::mem::MemReader;
     use std::io::mem::MemWriter;
     use std::io::io_error;
     use super::GZipReader;
     use super::GZipWriter;
+    use super::GZipStream;
     use super::GZip;
     use super::DEFAULT_COMPRESS_LEVEL;
     use super::DEFAULT_SIZE_FACTOR;
+    use super::MIN_SIZE_FACTOR;
+    use super::calc_buf_size;
+    use super::update_crc;
+    use super::{crc32_combine, CrcTask};
+    use super::bench_compress;
+    use super::{sniff_content, ContentEmpty, ContentTar, ContentZip, ContentGzip, ContentText, ContentBinary};
+    use super::{parse_extra_subfields, pack_extra_subfields, BgzfBlockSize, OtherSubfield};
+    use super::super::ioutil::{CancelToken, CANCELLED};
+    use super::HeaderLimits;
 
     #[test]
     fn test_generate_crc_table() {
@@ -902,6 +2433,21 @@ mod tests {
         assert!(expected_error);
     }
 
+    #[test]
+    fn test_gzip_reader_bad_header_extra_too_short() {
+
+        let mut expected_error = false;
+        io_error::cond.trap(|e| {
+            expected_error = true;
+            debug!("{:?}", e);
+        }).inside(|| {
+            // FEXTRA set with a 16-byte extra field, but only 3 bytes follow.
+            let comp_reader = MemReader::new(~[0x1f, 0x8B, 0x08, 0x04, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x10, 0x00, 0x41, 0x42, 0x43]);
+            GZipReader::new(comp_reader);
+        });
+        assert!(expected_error);
+    }
+
     #[test]
     fn test_gzip_reader_bad_crc() {
 
@@ -963,6 +2509,572 @@ mod tests {
         assert!(( decomp_buf.eq(&original_data) ));
     }
 
+    #[test]
+    fn test_gzip_compress_parallel() {
+
+        // Enough data for several blocks at the minimum block size.
+        let mut original_data : ~[u8] = ~[];
+        while original_data.len() < calc_buf_size(MIN_SIZE_FACTOR) * 5 {
+            original_data.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+
+        let mut reader = MemReader::new(original_data.clone());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, original_data.len() as u32);
+        gzip.compress_parallel(&mut reader, &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR, 2);
+        let comp_data = writer.inner();
+
+        // Decompress the compressed data to compare to the original.
+        let mut gzip_reader = GZipReader::new(MemReader::new(comp_data));
+        let mut decomp_data : ~[u8] = ~[];
+        let mut out_buf = [0u8, ..4096];
+        loop {
+            match gzip_reader.read(out_buf) {
+                Some(n) => decomp_data.push_all(out_buf.slice(0, n)),
+                None    => break
+            }
+        }
+        assert!(( decomp_data == original_data ));
+    }
+
+    #[test]
+    fn test_gzip_compress_pipelined() {
+
+        // Enough data for more blocks than the pipeline depth at the minimum block size.
+        let mut original_data : ~[u8] = ~[];
+        while original_data.len() < calc_buf_size(MIN_SIZE_FACTOR) * 6 {
+            original_data.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+
+        let reader = MemReader::new(original_data.clone());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, original_data.len() as u32);
+        gzip.compress_pipelined(reader, &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+        let comp_data = writer.inner();
+
+        let mut gzip_reader = GZipReader::new(MemReader::new(comp_data));
+        assert!(( gzip_reader.read_to_end() == original_data ));
+        assert_eq!(gzip.crc32, update_crc(0, original_data, 0, original_data.len()));
+    }
+
+    #[test]
+    fn test_gzip_compress_rsyncable() {
+
+        // Varying data so that the rolling sum hits some chunk boundaries.
+        let mut original_data : ~[u8] = ~[];
+        let mut seed = 12345u32;
+        while original_data.len() < calc_buf_size(MIN_SIZE_FACTOR) * 3 {
+            seed = seed * 1103515245 + 12345;
+            original_data.push(((seed >> 16) % 64) as u8 + 32);
+        }
+
+        let mut reader = MemReader::new(original_data.clone());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, original_data.len() as u32);
+        gzip.compress_rsyncable(&mut reader, &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+        let comp_data = writer.inner();
+
+        let mut reader = MemReader::new(comp_data);
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::decompress_init(&mut reader);
+        gzip.decompress_stream(&mut reader, &mut writer, MIN_SIZE_FACTOR);
+        assert!(( writer.inner() == original_data ));
+    }
+
+    #[test]
+    fn test_sniff_content() {
+        assert_eq!(sniff_content(&[]), ContentEmpty);
+        assert_eq!(sniff_content(bytes!("PK", 3, 4, 20, 0)), ContentZip);
+        assert_eq!(sniff_content(&[0x1f, 0x8B, 0x08]), ContentGzip);
+        assert_eq!(sniff_content(bytes!("Hello, world.\r\n\tcaf", 0xC3, 0xA9)), ContentText);
+        // A UTF-8 character cut off at the end is still text.
+        assert_eq!(sniff_content(bytes!("caf", 0xC3)), ContentText);
+        assert_eq!(sniff_content(&[0x00, 0x01, 0x02, 0xFF]), ContentBinary);
+
+        // An old-format tar header, with the checksum and without the ustar magic.
+        let mut header = vec::from_elem(512, 0u8);
+        header[0] = 'a' as u8;
+        for i in range(148u, 156u) {
+            header[i] = ' ' as u8;
+        }
+        let sum = header.iter().fold(0u, |sum, &c| sum + c as uint);
+        let digits = format!("{:06o}", sum);
+        for (i, c) in digits.bytes().enumerate() {
+            header[148 + i] = c;
+        }
+        header[154] = 0;
+        assert_eq!(sniff_content(header), ContentTar);
+        header[0] = 'b' as u8;
+        assert_eq!(sniff_content(header), ContentBinary);
+        header[257] = 'u' as u8; header[258] = 's' as u8; header[259] = 't' as u8; header[260] = 'a' as u8; header[261] = 'r' as u8;
+        assert_eq!(sniff_content(header), ContentTar);
+    }
+
+    #[test]
+    fn test_gzip_verify_sniff() {
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let (members, total_size, content_type) = GZip::verify_sniff(MemReader::new(member), DEFAULT_SIZE_FACTOR);
+        assert_eq!(members, 1);
+        assert_eq!(total_size, 10);
+        assert_eq!(content_type, ContentText);
+    }
+
+    #[test]
+    fn test_bench_compress() {
+        let data = vec::from_fn(300000, |i| ((i * 7) % 31) as u8);
+        let single = bench_compress(data, 1, 1, MIN_SIZE_FACTOR);
+        let parallel = bench_compress(data, 9, 4, MIN_SIZE_FACTOR);
+        assert_eq!(single.original_size, 300000);
+        assert_eq!(parallel.num_tasks, 4);
+        assert!(single.compressed_size > 0 && single.compressed_size < single.original_size);
+        assert!(parallel.ratio() > 50f64);
+        assert!(single.mb_per_sec() > 0f64);
+    }
+
+    #[test]
+    fn test_gzip_verify_multi_members() {
+
+        // Two members of the same data back to back.
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut comp_data = member.clone();
+        comp_data.push_all(member);
+
+        let (members, total_size) = GZip::verify(MemReader::new(comp_data), DEFAULT_SIZE_FACTOR);
+        assert_eq!(members, 2);
+        assert_eq!(total_size, 20);
+    }
+
+    #[test]
+    fn test_gzip_decompress_all() {
+
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut comp_data = member.clone();
+        comp_data.push_all(member);
+        comp_data.push_all(member);
+
+        let mut reader = MemReader::new(comp_data);
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::decompress_init(&mut reader);
+        let (members, total_size) = gzip.decompress_all(&mut reader, &mut writer, DEFAULT_SIZE_FACTOR);
+        assert_eq!(members, 3);
+        assert_eq!(total_size, 30);
+        assert!(( writer.inner() == bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n").to_owned() ));
+    }
+
+    #[test]
+    fn test_gzip_reader_next_member() {
+
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut comp_data = member.clone();
+        comp_data.push_all(member);
+        comp_data.push_all(member);
+
+        // The members all come in one read of the inner reader; the ones after the first are kept for next_member().
+        let mut reader = GZipReader::new(MemReader::new(comp_data));
+        let mut data = reader.read_to_end();
+        let mut members = 1;
+        while reader.next_member() {
+            data.push_all(reader.read_to_end());
+            members += 1;
+        }
+        assert_eq!(members, 3);
+        assert!(( data == bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n").to_owned() ));
+
+        // A failed member doesn't move on.
+        let mut bad_data = member.clone();
+        bad_data[28] ^= 0xFF;
+        bad_data.push_all(member);
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            let mut reader = GZipReader::new(MemReader::new(bad_data.clone()));
+            reader.read_to_end();
+            assert!(!reader.next_member());
+        });
+        assert_eq!(error_count, 1);
+    }
+
+    #[test]
+    fn test_gzip_scan_members() {
+
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+
+        // A BGZF member with its block size.  It's skipped over without decompressing, even with the data damaged.
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init_extra(&mut writer, [], 0u32, 0u32, [BgzfBlockSize(0)]);
+        gzip.compress_stream(&mut MemReader::new(bytes!("0123456789ABCDEF").to_owned()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+        let mut bgzf_member = writer.inner();
+        let bsize = bgzf_member.len() - 1;
+        bgzf_member[16] = bsize as u8;
+        bgzf_member[17] = (bsize >> 8) as u8;
+        bgzf_member[18] ^= 0xFF;
+
+        let mut comp_data = member.clone();
+        comp_data.push_all(bgzf_member);
+        comp_data.push_all(member);
+        let path = os::tmpdir().join("test_gzip_scan_members.gz");
+        File::create(&path).unwrap().write(comp_data);
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(GZip::scan_members(&mut file, DEFAULT_SIZE_FACTOR), (3, 36));
+        fs::unlink(&path);
+    }
+
+    #[test]
+    fn test_crc_task() {
+        let data = vec::from_fn(300000, |i| ((i * 7) % 253) as u8);
+        let crc32 = update_crc(0u32, data, 0, data.len());
+        let crc1 = update_crc(0u32, data, 0, 1000);
+        let crc2 = update_crc(0u32, data, 1000, data.len());
+        assert_eq!(crc32_combine(crc1, crc2, (data.len() - 1000) as u64), crc32);
+        assert_eq!(crc32_combine(crc1, 0u32, 0), crc1);
+
+        let mut crc_task = CrcTask::spawn();
+        crc_task.update(data.slice(0, 1000));
+        crc_task.update(data.slice_from(1000));
+        assert_eq!(crc_task.join(), (crc32, data.len() as u64));
+
+        // More updates than the queue holds wait for the task to catch up.
+        let mut crc_task = CrcTask::spawn();
+        for chunk in data.chunks(1000) {
+            crc_task.update(chunk);
+        }
+        assert_eq!(crc_task.join(), (crc32, data.len() as u64));
+
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, [], 0u32, 0u32);
+        gzip.set_crc_task(true);
+        gzip.compress_stream(&mut MemReader::new(data.clone()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+        assert_eq!(gzip.crc32, crc32);
+
+        let mut reader = MemReader::new(writer.inner());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::decompress_init(&mut reader);
+        gzip.set_crc_task(true);
+        assert_eq!(gzip.decompress_all(&mut reader, &mut writer, MIN_SIZE_FACTOR), (1u, data.len() as u64));
+        assert!(( writer.inner() == data ));
+    }
+
+    #[test]
+    fn test_gzip_reader_read_to_end() {
+
+        let comp_data = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut gzip_reader = GZipReader::with_size_factor(MemReader::new(comp_data), MIN_SIZE_FACTOR);
+        assert!(( gzip_reader.read_to_end() == bytes!("ABCDEFGH\r\n").to_owned() ));
+    }
+
+    #[test]
+    fn test_gzip_reader_large_read() {
+
+        // Several internal buffers of data, read into one large buffer.
+        let mut original_data : ~[u8] = ~[];
+        while original_data.len() < calc_buf_size(MIN_SIZE_FACTOR) * 5 {
+            original_data.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+        let mut reader = MemReader::new(original_data.clone());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, original_data.len() as u32);
+        gzip.compress_stream(&mut reader, &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+
+        let mut gzip_reader = GZipReader::with_size_factor(MemReader::new(writer.inner()), MIN_SIZE_FACTOR);
+        let mut out_buf = vec::from_elem(original_data.len() * 2, 0u8);
+        assert_eq!(gzip_reader.read(out_buf), Some(original_data.len()));
+        assert!(( out_buf.slice(0, original_data.len()) == original_data.as_slice() ));
+        assert_eq!(gzip_reader.read(out_buf), None);
+        assert!(gzip_reader.gzip.checkCrc());
+    }
+
+    #[test]
+    fn test_gzip_reader_copy_to() {
+
+        let comp_data = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut gzip_reader = GZipReader::new(MemReader::new(comp_data));
+        let mut writer = MemWriter::new();
+        assert_eq!(gzip_reader.copy_to(&mut writer), Ok(10u64));
+        assert!(( writer.inner() == bytes!("ABCDEFGH\r\n").to_owned() ));
+
+        // Bad CRC
+        let comp_data = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD1, 0x0A, 0x00, 0x00, 0x00];
+        let mut gzip_reader = GZipReader::new(MemReader::new(comp_data));
+        assert!(gzip_reader.copy_to(&mut MemWriter::new()).is_err());
+    }
+
+    #[test]
+    fn test_gzip_salvage() {
+
+        // Bad CRC
+        let comp_data = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD1, 0x0A, 0x00, 0x00, 0x00];
+        let mut reader = MemReader::new(comp_data);
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::decompress_init(&mut reader);
+        gzip.set_salvage(true);
+        let (members, total_size) = gzip.decompress_all(&mut reader, &mut writer, DEFAULT_SIZE_FACTOR);
+        assert_eq!(members, 1);
+        assert_eq!(total_size, 10);
+        assert!(( writer.inner() == bytes!("ABCDEFGH\r\n").to_owned() ));
+
+        let mismatch = gzip.end_mismatch.unwrap();
+        assert_eq!(mismatch.expected_crc32, 0xD1D7A694);
+        assert_eq!(mismatch.computed_crc32, 0xD0D7A694);
+        assert_eq!(mismatch.expected_size, 10);
+        assert_eq!(mismatch.computed_size, 10);
+        assert!(mismatch.crc_mismatched());
+        assert!(!mismatch.size_mismatched());
+    }
+
+    #[test]
+    fn test_extra_subfields() {
+        let subfields = ~[OtherSubfield('A' as u8, 'p' as u8, ~[1u8, 2, 3]), BgzfBlockSize(0x1234)];
+        let xfield = pack_extra_subfields(subfields);
+        assert_eq!(xfield, ~['A' as u8, 'p' as u8, 3, 0, 1, 2, 3, 'B' as u8, 'C' as u8, 2, 0, 0x34, 0x12]);
+        assert_eq!(parse_extra_subfields(xfield), Ok(subfields.clone()));
+        assert!(parse_extra_subfields(xfield.slice(0, 6)).is_err());
+        assert!(parse_extra_subfields(xfield.slice(0, 9)).is_err());
+        assert_eq!(parse_extra_subfields([]), Ok(~[]));
+
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init_extra(&mut writer, bytes!("test1"), 0u32, 0u32, subfields);
+        gzip.compress_stream(&mut MemReader::new(bytes!("ABCDEFGH\r\n").to_owned()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+
+        let gzip = GZip::decompress_init(&mut MemReader::new(writer.inner()));
+        assert_eq!(gzip.extra_subfields(), Ok(subfields.clone()));
+        assert_eq!(gzip.bgzf_block_size(), Some(0x1235));
+        assert_eq!(gzip.file_name_as_str(""), ~"test1");
+    }
+
+    // Stream reading from the input and writing to the output, standing for the two directions of a socket.
+    struct PairStream {
+        input:      MemReader,
+        output:     MemWriter,
+    }
+
+    impl Reader for PairStream {
+        fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+            self.input.read(buf)
+        }
+
+        fn eof(&mut self) -> bool {
+            self.input.eof()
+        }
+    }
+
+    impl Writer for PairStream {
+        fn write(&mut self, buf: &[u8]) {
+            self.output.write(buf);
+        }
+    }
+
+    #[test]
+    fn test_gzip_stream() {
+        let mut stream = GZipStream::new(PairStream { input: MemReader::new(~[]), output: MemWriter::new() });
+        stream.write(bytes!("hello "));
+        stream.flush();
+        let flushed = stream.inner_ref().output.inner_ref().clone();
+        stream.write(bytes!("world"));
+        stream.finish();
+        let finished = stream.inner().output.inner();
+
+        // The flushed message decompresses in full without the rest of the data.
+        let mut stream = GZipStream::new(PairStream { input: MemReader::new(flushed), output: MemWriter::new() });
+        let mut buf = [0u8, ..100];
+        assert_eq!(stream.read(buf), Some(6));
+        assert_eq!(buf.slice(0, 6).to_owned(), bytes!("hello ").to_owned());
+
+        let mut stream = GZipStream::new(PairStream { input: MemReader::new(finished), output: MemWriter::new() });
+        assert_eq!(stream.read_to_end(), bytes!("hello world").to_owned());
+        assert!(stream.eof());
+    }
+
+    #[test]
+    fn test_header_limits() {
+        // FNAME set, with a file name running on without its terminating zero.
+        let mut header = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03];
+        header.grow(100000, &('a' as u8));
+        let mut errors = ~[];
+        io_error::cond.trap(|c| errors.push(c.desc)).inside(|| {
+            let gzip = GZip::decompress_init_limits(&mut MemReader::new(header.clone()), HeaderLimits::untrusted());
+            assert!(gzip.filename.is_none());
+        });
+        assert_eq!(errors, ~["The gzip file name is over the limit."]);
+
+        let gzip = GZip::decompress_init(&mut MemReader::new(header.clone()));
+        assert_eq!(gzip.filename.unwrap().len(), 100000);
+
+        // FEXTRA set with a 16-byte extra field.
+        let header = ~[0x1f, 0x8B, 0x08, 0x04, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x10, 0x00];
+        let mut limits = HeaderLimits::new();
+        limits.max_extra_len = Some(8);
+        let mut errors = ~[];
+        io_error::cond.trap(|c| errors.push(c.desc)).inside(|| {
+            GZip::decompress_init_limits(&mut MemReader::new(header.clone()), limits.clone());
+        });
+        assert_eq!(errors, ~["The gzip extra field is over the limit."]);
+    }
+
+    #[test]
+    fn test_gzip_cancel() {
+        let data = vec::from_fn(100000, |i| (i % 251) as u8);
+        let token = CancelToken::new();
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, 0u32);
+        gzip.compress_stream(&mut MemReader::new(data.clone()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+        let comp_data = writer.inner();
+
+        token.cancel();
+        let mut errors = ~[];
+        io_error::cond.trap(|c| errors.push(c.desc)).inside(|| {
+            let mut writer = MemWriter::new();
+            let mut gzip = GZip::compress_init(&mut writer, bytes!("test1"), 0u32, 0u32);
+            gzip.set_cancel_token(Some(token.clone()));
+            gzip.compress_stream(&mut MemReader::new(data.clone()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+            gzip.compress_parallel(&mut MemReader::new(data.clone()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR, 2);
+            gzip.compress_pipelined(MemReader::new(data.clone()), &mut writer, DEFAULT_COMPRESS_LEVEL, MIN_SIZE_FACTOR);
+
+            let mut reader = MemReader::new(comp_data.clone());
+            let mut gzip = GZip::decompress_init(&mut reader);
+            gzip.set_cancel_token(Some(token.clone()));
+            let (members, _) = gzip.decompress_all(&mut reader, &mut MemWriter::new(), MIN_SIZE_FACTOR);
+            assert_eq!(members, 0);
+        });
+        assert_eq!(errors, ~[CANCELLED, CANCELLED, CANCELLED, CANCELLED]);
+    }
+
+    #[test]
+    fn test_gzip_size_mismatch() {
+
+        // Bad size, good CRC
+        let comp_data = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0B, 0x00, 0x00, 0x00];
+        let mut errors = ~[];
+        io_error::cond.trap(|c| errors.push(c.detail.clone())).inside(|| {
+            let mut gzip_reader = GZipReader::new(MemReader::new(comp_data.clone()));
+            gzip_reader.read_to_end();
+        });
+        assert_eq!(errors, ~[Some(~"size mismatch (expected 11, got 10)")]);
+
+        // Tolerate the size mismatch but not the CRC mismatch.
+        let mut reader = MemReader::new(comp_data.clone());
+        let mut writer = MemWriter::new();
+        let mut gzip = GZip::decompress_init(&mut reader);
+        gzip.set_end_checks(true, false);
+        let (members, total_size) = gzip.decompress_all(&mut reader, &mut writer, DEFAULT_SIZE_FACTOR);
+        assert_eq!(members, 1);
+        assert_eq!(total_size, 10);
+
+        let mut reader = MemReader::new(comp_data);
+        let mut gzip = GZip::decompress_init(&mut reader);
+        gzip.set_salvage(true);
+        gzip.decompress_all(&mut reader, &mut MemWriter::new(), DEFAULT_SIZE_FACTOR);
+        let mismatch = gzip.end_mismatch.unwrap();
+        assert!(!mismatch.crc_mismatched());
+        assert!(mismatch.size_mismatched());
+        assert_eq!(mismatch.to_str(), ~"size mismatch (expected 11, got 10)");
+    }
+
+    #[test]
+    fn test_gzip_salvage_truncated() {
+
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut failed = false;
+        io_error::cond.trap(|_| {
+            failed = true;
+        }).inside(|| {
+            // The end section is cut off.
+            let mut reader = MemReader::new(member.slice(0, member.len() - 4).to_owned());
+            let mut writer = MemWriter::new();
+            let mut gzip = GZip::decompress_init(&mut reader);
+            gzip.set_salvage(true);
+            gzip.decompress_all(&mut reader, &mut writer, DEFAULT_SIZE_FACTOR);
+            assert!(( writer.inner() == bytes!("ABCDEFGH\r\n").to_owned() ));
+            let truncation = gzip.truncation.unwrap();
+            assert_eq!(truncation.recovered_size, 10);
+            assert!(!truncation.data_truncated);
+
+            // The compressed data are cut off.
+            let mut reader = MemReader::new(member.slice(0, 20).to_owned());
+            let mut writer = MemWriter::new();
+            let mut gzip = GZip::decompress_init(&mut reader);
+            gzip.set_salvage(true);
+            gzip.decompress_all(&mut reader, &mut writer, DEFAULT_SIZE_FACTOR);
+            let truncation = gzip.truncation.unwrap();
+            assert_eq!(truncation.recovered_size, writer.inner().len() as u64);
+            assert!(truncation.data_truncated);
+        });
+        assert!(!failed);
+    }
+
+    #[test]
+    fn test_decompress_file_to_vec() {
+
+        let member = ~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0A, 0x00, 0x00, 0x00];
+        let mut comp_data = member.clone();
+        comp_data.push_all(member);
+
+        let path = os::tmpdir().join("test_decompress_file_to_vec.gz");
+        File::create(&path).unwrap().write(comp_data);
+        let data = super::decompress_file_to_vec(&path);
+        assert!(( data == bytes!("ABCDEFGH\r\nABCDEFGH\r\n").to_owned() ));
+
+        // A bogus 4G uncompressed size at the end doesn't size the vector; the mismatch is raised.
+        let last = comp_data.len();
+        for i in range(last - 4, last) {
+            comp_data[i] = 0xFF;
+        }
+        File::create(&path).unwrap().write(comp_data);
+        let mut error_count = 0;
+        let mut data = ~[];
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            data = super::decompress_file_to_vec(&path);
+        });
+        fs::unlink(&path);
+        assert!(( error_count > 0 ));
+        assert!(( data.starts_with(bytes!("ABCDEFGH\r\n")) ));
+    }
+
+    #[test]
+    fn test_gzip_verify_bad_size() {
+
+        let mut expected_error = false;
+        io_error::cond.trap(|e| {
+            expected_error = true;
+            debug!("{:?}", e);
+        }).inside(|| {
+            let comp_reader = MemReader::new(~[0x1f, 0x8B, 0x08, 0x08, 0x54, 0x3C, 0x3D, 0x52, 0x00, 0x03, 0x74, 0x65, 0x73, 0x74, 0x31, 0x00, 0x73, 0x74, 0x72, 0x76, 0x71, 0x75, 0x73, 0xF7, 0xE0, 0xE5, 0x02, 0x00, 0x94, 0xA6, 0xD7, 0xD0, 0x0B, 0x00, 0x00, 0x00]);
+            GZip::verify(comp_reader, DEFAULT_SIZE_FACTOR);
+        });
+        assert!(expected_error);
+    }
+
+    #[test]
+    fn test_gzip_writer_write_from() {
+
+        let original_data = bytes!("ABCDEFGH\r\nABCDEFGH\r\n");
+        let mut gzip_writer = GZipWriter::new(MemWriter::new());
+        assert_eq!(gzip_writer.write_from(&mut MemReader::new(original_data.to_owned())), Ok(20u64));
+        let comp_data = gzip_writer.inner().inner();
+
+        let mut gzip_reader = GZipReader::new(MemReader::new(comp_data));
+        assert!(( gzip_reader.read_to_end() == original_data.to_owned() ));
+    }
+
+    #[test]
+    fn test_gzip_writer_flush_sync() {
+
+        let mut gzip_writer = GZipWriter::new(MemWriter::new());
+        gzip_writer.write(bytes!("event: 1\n"));
+        let header_len = gzip_writer.inner_ref().inner_ref().len();
+        gzip_writer.flush_sync();
+        let flushed_len = gzip_writer.inner_ref().inner_ref().len();
+        assert!(flushed_len > header_len);
+
+        // A sync flush ends on an empty stored block, 00 00 FF FF.
+        let flushed_data = gzip_writer.inner_ref().inner_ref().slice_from(flushed_len - 4).to_owned();
+        assert!(( flushed_data == ~[0x00u8, 0x00, 0xFF, 0xFF] ));
+
+        gzip_writer.write(bytes!("event: 2\n"));
+        gzip_writer.finalize();
+        let mut gzip_reader = GZipReader::new(MemReader::new(gzip_writer.inner().inner()));
+        assert!(( gzip_reader.read_to_end() == bytes!("event: 1\nevent: 2\n").to_owned() ));
+    }
+
     #[test]
     fn test_gzip_writer_new() {
 
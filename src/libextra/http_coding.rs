@@ -0,0 +1,468 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: http_coding.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The http_coding module negotiates the HTTP content-coding and picks the matching
+encoder or decoder, for the servers and clients using the gzip and deflate codings.
+
+The server passes the request's Accept-Encoding header to encoder_for(), which picks
+gzip, deflate, or identity, and returns the ContentEncoder writing to the response body
+and the Content-Encoding header value to send.  The response should also send
+"Vary: Accept-Encoding".  None means no coding is acceptable, for a 406 response.
+
+The client passes the response's Content-Encoding header to decoder_for() to get the
+ContentDecoder reading the decoded body.
+
+The deflate coding is the zlib format.  The decoder also takes the raw deflate data,
+which some servers send for it.
+
+Example:
+
+    match encoder_for(request_accept_encoding, response_body_writer) {
+        Some((mut encoder, content_encoding)) => {
+            // Send the Content-Encoding header if content_encoding is Some, then the body.
+            encoder.write(body);
+            encoder.finish();
+        },
+        None => // Respond with 406 Not Acceptable.
+    }
+
+    let mut decoder = decoder_for(response_content_encoding, response_body_reader).unwrap();
+    let body = decoder.read_to_end();
+
+*/
+
+
+use std::io::{Reader, Writer, Decorator};
+
+use super::deflate::{DeflateWriter, InflateReader, CompressParams, DEFAULT_SIZE_FACTOR};
+use super::gzip::{GZipReader, GZipWriter, DEFAULT_COMPRESS_LEVEL};
+use super::ioutil::BufferedPeekReader;
+
+
+/// The qvalue of a fully acceptable coding.  The qvalues are kept in thousandths, 0 to 1000.
+pub static MAX_QVALUE: uint = 1000;
+
+
+/// The content-codings supported.
+#[deriving(Clone, Eq)]
+pub enum ContentCoding {
+    /// The gzip format, also named x-gzip.
+    CodingGzip,
+    /// The zlib format.
+    CodingDeflate,
+    /// No encoding.
+    CodingIdentity,
+}
+
+impl ContentCoding {
+
+    /// The coding name used in the headers.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CodingGzip      => "gzip",
+            CodingDeflate   => "deflate",
+            CodingIdentity  => "identity",
+        }
+    }
+
+    /// Look up the coding by its name, ignoring case.  Return None for the unsupported codings.
+    pub fn from_name(name: &str) -> Option<ContentCoding> {
+        match name.trim().to_ascii_lower().as_slice() {
+            "gzip" | "x-gzip"   => Some(CodingGzip),
+            "deflate"           => Some(CodingDeflate),
+            "identity"          => Some(CodingIdentity),
+            _                   => None
+        }
+    }
+
+    /// The Content-Encoding header value to send for the coding.  None for identity, which sends no header.
+    pub fn header_value(&self) -> Option<&'static str> {
+        match *self {
+            CodingIdentity  => None,
+            _               => Some(self.name())
+        }
+    }
+
+}
+
+
+/// Parse an Accept-Encoding header into the list of the (coding, qvalue) pairs, in the header's order.
+/// The codings are lower-cased and the qvalues are in thousandths, MAX_QVALUE when not given.
+/// The entries with a malformed qvalue are dropped.
+pub fn parse_accept_encoding(header: &str) -> ~[(~str, uint)] {
+    let mut codings = ~[];
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lower();
+        if coding.len() == 0 {
+            continue;
+        }
+        let mut qvalue = Some(MAX_QVALUE);
+        for param in params {
+            match param.find('=') {
+                Some(pos) if param.slice_to(pos).trim().to_ascii_lower() == ~"q" =>
+                    qvalue = parse_qvalue(param.slice_from(pos + 1).trim()),
+                _ => ()
+            }
+        }
+        match qvalue {
+            Some(qvalue)    => codings.push((coding, qvalue)),
+            None            => ()
+        }
+    }
+    codings
+}
+
+// Parse a qvalue of up to 3 decimals, "0" to "1.000", into thousandths.
+fn parse_qvalue(value: &str) -> Option<uint> {
+    let (whole, fraction) = match value.find('.') {
+        Some(pos)   => (value.slice_to(pos), value.slice_from(pos + 1)),
+        None        => (value, "")
+    };
+    if fraction.len() > 3 {
+        return None;
+    }
+    let mut qvalue = match whole {
+        "0" => 0u,
+        "1" => MAX_QVALUE,
+        _   => return None
+    };
+    let mut scale = 100u;
+    for c in fraction.chars() {
+        match c.to_digit(10) {
+            Some(digit) => qvalue += digit * scale,
+            None        => return None
+        }
+        scale /= 10;
+    }
+    if qvalue > MAX_QVALUE { None } else { Some(qvalue) }
+}
+
+/// Pick the content-coding for the response by the request's Accept-Encoding header.  An absent header, passed
+/// as None, picks identity.  The coding with the highest qvalue wins, gzip first over deflate over identity on ties.  A coding not listed
+/// takes the qvalue of "*" if given.  Identity is acceptable unless refused by "identity;q=0" or by "*;q=0" without
+/// listing identity.  Return None when no coding is acceptable.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<ContentCoding> {
+    let accepted = match accept_encoding {
+        Some(header)    => parse_accept_encoding(header),
+        None            => return Some(CodingIdentity)
+    };
+    let qvalue_of = |coding: ContentCoding| -> Option<uint> {
+        let mut star = None;
+        for &(ref name, qvalue) in accepted.iter() {
+            if name.as_slice() == "*" {
+                star = Some(qvalue);
+            } else if ContentCoding::from_name(name.as_slice()) == Some(coding) {
+                return Some(qvalue);
+            }
+        }
+        star
+    };
+
+    let mut best = None;
+    let mut best_qvalue = 0u;
+    for &coding in [CodingGzip, CodingDeflate].iter() {
+        match qvalue_of(coding) {
+            Some(qvalue) if qvalue > best_qvalue => {
+                best = Some(coding);
+                best_qvalue = qvalue;
+            },
+            _ => ()
+        }
+    }
+    let identity_qvalue = match qvalue_of(CodingIdentity) {
+        Some(qvalue)    => qvalue,
+        None            => 1                // Acceptable but least preferred when not listed.
+    };
+    if identity_qvalue > best_qvalue {
+        best = Some(CodingIdentity);
+    }
+    best
+}
+
+
+/// The writer encoding the body with the negotiated content-coding.  finish() must be called at the end
+/// of the body to write out the end of the encoded data.
+pub enum ContentEncoder<W> {
+    /// Encoding with gzip.
+    GzipEncoder(GZipWriter<W>),
+    /// Encoding with deflate, into the zlib format.
+    DeflateEncoder(DeflateWriter<W>),
+    /// Passing the body through.
+    IdentityEncoder(W),
+}
+
+impl<W: Writer> ContentEncoder<W> {
+
+    /// Create the encoder of the coding on the writer.
+    pub fn new(coding: ContentCoding, writer: W) -> ContentEncoder<W> {
+        match coding {
+            CodingGzip      => GzipEncoder(GZipWriter::new(writer)),
+            CodingDeflate   => {
+                let params = CompressParams::new(DEFAULT_COMPRESS_LEVEL).zlib_header(true);
+                DeflateEncoder(DeflateWriter::with_params(writer, &params, DEFAULT_SIZE_FACTOR))
+            },
+            CodingIdentity  => IdentityEncoder(writer),
+        }
+    }
+
+    /// The content-coding of the encoder.
+    pub fn coding(&self) -> ContentCoding {
+        match *self {
+            GzipEncoder(_)      => CodingGzip,
+            DeflateEncoder(_)   => CodingDeflate,
+            IdentityEncoder(_)  => CodingIdentity,
+        }
+    }
+
+    /// Finish the encoded data and flush them to the writer.  No more writes can follow.
+    pub fn finish(&mut self) {
+        match *self {
+            GzipEncoder(ref mut writer)     => writer.finalize(),
+            DeflateEncoder(ref mut writer)  => writer.finalize(),
+            IdentityEncoder(ref mut writer) => writer.flush(),
+        }
+    }
+
+}
+
+impl<W: Writer> Writer for ContentEncoder<W> {
+
+    fn write(&mut self, buf: &[u8]) {
+        match *self {
+            GzipEncoder(ref mut writer)     => writer.write(buf),
+            DeflateEncoder(ref mut writer)  => writer.write(buf),
+            IdentityEncoder(ref mut writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) {
+        match *self {
+            GzipEncoder(ref mut writer)     => writer.flush(),
+            DeflateEncoder(ref mut writer)  => writer.flush(),
+            IdentityEncoder(ref mut writer) => writer.flush(),
+        }
+    }
+
+}
+
+/// Decorator to access the inner writer
+impl<W: Writer> Decorator<W> for ContentEncoder<W> {
+    fn inner(self) -> W {
+        match self {
+            GzipEncoder(writer)     => writer.inner(),
+            DeflateEncoder(writer)  => writer.inner(),
+            IdentityEncoder(writer) => writer,
+        }
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a W {
+        match *self {
+            GzipEncoder(ref writer)     => writer.inner_ref(),
+            DeflateEncoder(ref writer)  => writer.inner_ref(),
+            IdentityEncoder(ref writer) => writer,
+        }
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        match *self {
+            GzipEncoder(ref mut writer)     => writer.inner_mut_ref(),
+            DeflateEncoder(ref mut writer)  => writer.inner_mut_ref(),
+            IdentityEncoder(ref mut writer) => writer,
+        }
+    }
+}
+
+/// Negotiate the content-coding by the request's Accept-Encoding header and create its encoder on the response
+/// body writer.  Return the encoder and the Content-Encoding header value to send, None for no header.
+/// Return None when no coding is acceptable, for responding with 406 Not Acceptable.
+pub fn encoder_for<W: Writer>(accept_encoding: Option<&str>, writer: W) -> Option<(ContentEncoder<W>, Option<&'static str>)> {
+    negotiate(accept_encoding).map(|coding| (ContentEncoder::new(coding, writer), coding.header_value()))
+}
+
+
+/// The reader decoding the body by its content-coding.
+pub enum ContentDecoder<R> {
+    /// Decoding gzip.
+    GzipDecoder(GZipReader<R>),
+    /// Decoding deflate, the zlib format or the raw deflate data.
+    DeflateDecoder(InflateReader<BufferedPeekReader<R>>),
+    /// Passing the body through.
+    IdentityDecoder(R),
+}
+
+impl<R: Reader> ContentDecoder<R> {
+
+    /// Create the decoder of the coding on the reader.  The gzip and zlib headers are read right away.
+    pub fn new(coding: ContentCoding, reader: R) -> ContentDecoder<R> {
+        match coding {
+            CodingGzip      => GzipDecoder(GZipReader::new(reader)),
+            CodingDeflate   => DeflateDecoder(new_deflate_reader(reader)),
+            CodingIdentity  => IdentityDecoder(reader),
+        }
+    }
+
+    /// The content-coding of the decoder.
+    pub fn coding(&self) -> ContentCoding {
+        match *self {
+            GzipDecoder(_)      => CodingGzip,
+            DeflateDecoder(_)   => CodingDeflate,
+            IdentityDecoder(_)  => CodingIdentity,
+        }
+    }
+
+}
+
+impl<R: Reader> Reader for ContentDecoder<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        match *self {
+            GzipDecoder(ref mut reader)     => reader.read(buf),
+            DeflateDecoder(ref mut reader)  => reader.read(buf),
+            IdentityDecoder(ref mut reader) => reader.read(buf),
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        match *self {
+            GzipDecoder(ref mut reader)     => reader.eof(),
+            DeflateDecoder(ref mut reader)  => reader.eof(),
+            IdentityDecoder(ref mut reader) => reader.eof(),
+        }
+    }
+
+}
+
+/// Create the decoder on the response body reader by its Content-Encoding header, identity if the header is absent.
+/// The header can list several codings; the identity ones are skipped and at most one other is supported.
+/// Return the error for the unsupported codings.
+pub fn decoder_for<R: Reader>(content_encoding: Option<&str>, reader: R) -> Result<ContentDecoder<R>, ~str> {
+    let mut coding = CodingIdentity;
+    match content_encoding {
+        Some(header) => {
+            for name in header.split(',').map(|name| name.trim()).filter(|name| name.len() > 0) {
+                match ContentCoding::from_name(name) {
+                    Some(CodingIdentity)                        => (),
+                    Some(found) if coding == CodingIdentity     => coding = found,
+                    Some(_)                                     => return Err(format!("Unsupported stacked content-coding: {:s}", header)),
+                    None                                        => return Err(format!("Unsupported content-coding: {:s}", name))
+                }
+            }
+        },
+        None => ()
+    }
+    Ok(ContentDecoder::new(coding, reader))
+}
+
+/// Create the reader decompressing the deflate coding: the zlib format, with its Adler-32 checked at the end,
+/// or the raw deflate data without the zlib header, which some servers send for it.
+pub fn new_deflate_reader<R: Reader>(reader: R) -> InflateReader<BufferedPeekReader<R>> {
+    let mut reader = BufferedPeekReader::new(reader);
+    let has_header = is_zlib_header(reader.peek(2));
+    InflateReader::with_params(reader, has_header, DEFAULT_SIZE_FACTOR)
+}
+
+/// Check whether the two bytes are a zlib header: the deflate method with a window up to 32K and a valid check.
+pub fn is_zlib_header(header: &[u8]) -> bool {
+    header.len() >= 2 && header[0] & 0x0F == 8 && header[0] >> 4 <= 7 &&
+        ((header[0] as uint) * 256 + (header[1] as uint)) % 31 == 0
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Reader, Writer, Decorator};
+    use std::io::mem::MemReader;
+    use std::io::mem::MemWriter;
+    use std::io::io_error;
+    use super::super::deflate;
+    use super::super::gzip::GZipWriter;
+    use super::{ContentCoding, CodingGzip, CodingDeflate, CodingIdentity};
+    use super::{parse_accept_encoding, negotiate, encoder_for, decoder_for};
+    use super::new_deflate_reader;
+
+
+    #[test]
+    fn test_parse_accept_encoding() {
+        assert_eq!(parse_accept_encoding("gzip, Deflate;q=0.5 , br;q=1.0, *;q=0"),
+                   ~[(~"gzip", 1000), (~"deflate", 500), (~"br", 1000), (~"*", 0)]);
+        assert_eq!(parse_accept_encoding("gzip;q=0.125, identity; q=1, x;q=2, y;q=0.1234, , "),
+                   ~[(~"gzip", 125), (~"identity", 1000)]);
+        assert_eq!(ContentCoding::from_name("X-GZIP"), Some(CodingGzip));
+        assert_eq!(ContentCoding::from_name("br"), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), Some(CodingIdentity));
+        assert_eq!(negotiate(Some("")), Some(CodingIdentity));
+        assert_eq!(negotiate(Some("deflate, gzip")), Some(CodingGzip));
+        assert_eq!(negotiate(Some("gzip;q=0.5, deflate")), Some(CodingDeflate));
+        assert_eq!(negotiate(Some("br")), Some(CodingIdentity));
+        assert_eq!(negotiate(Some("*")), Some(CodingGzip));
+        assert_eq!(negotiate(Some("gzip;q=0, *")), Some(CodingDeflate));
+        assert_eq!(negotiate(Some("identity;q=1, gzip;q=0.5")), Some(CodingIdentity));
+        assert_eq!(negotiate(Some("br, identity;q=0")), None);
+        assert_eq!(negotiate(Some("*;q=0")), None);
+        assert_eq!(negotiate(Some("*;q=0, identity")), Some(CodingIdentity));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let body = bytes!("The quick brown fox jumps over the lazy dog.  The quick brown fox jumps over the lazy dog.");
+        for &(accept, header) in [("gzip", Some("gzip")), ("deflate", Some("deflate")), ("br", None)].iter() {
+            let (mut encoder, content_encoding) = encoder_for(Some(accept), MemWriter::new()).unwrap();
+            assert_eq!(content_encoding, header);
+            encoder.write(body);
+            encoder.finish();
+            let encoded = encoder.inner().inner();
+
+            let mut decoder = decoder_for(content_encoding, MemReader::new(encoded)).unwrap();
+            assert_eq!(decoder.read_to_end(), body.to_owned());
+        }
+        assert!(encoder_for(Some("*;q=0"), MemWriter::new()).is_none());
+
+        assert!(decoder_for(Some("br"), MemReader::new(~[])).is_err());
+        assert!(decoder_for(Some("gzip, deflate"), MemReader::new(~[])).is_err());
+        let mut writer = GZipWriter::new(MemWriter::new());
+        writer.write(body);
+        writer.finalize();
+        let mut decoder = decoder_for(Some("identity, x-gzip"), MemReader::new(writer.inner().inner())).unwrap();
+        assert_eq!(decoder.coding(), CodingGzip);
+        assert_eq!(decoder.read_to_end(), body.to_owned());
+    }
+
+    #[test]
+    fn test_deflate_reader() {
+        let body = bytes!("hello hello hello hello");
+
+        // The raw deflate data some servers send for the deflate coding.
+        let mut reader = new_deflate_reader(MemReader::new(deflate::deflate_bytes(body)));
+        assert_eq!(reader.read_to_end(), body.to_owned());
+
+        let mut zlib_data = deflate::deflate_bytes_zlib(body);
+        let last = zlib_data.len() - 1;
+        zlib_data[last] ^= 0xFF;
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            let mut reader = new_deflate_reader(MemReader::new(zlib_data.clone()));
+            assert_eq!(reader.read_to_end(), body.to_owned());
+        });
+        assert_eq!(error_count, 1);
+    }
+
+}
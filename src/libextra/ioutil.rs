@@ -0,0 +1,698 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: ioutil.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The ioutil module has the I/O helpers shared by the format implementations.
+
+*/
+
+
+use std::num;
+use std::vec;
+use std::util;
+use std::task;
+use std::comm::{Port, Chan};
+use std::io::{Reader, Seek, SeekCur, SeekEnd, Decorator};
+use std::io::{io_error, IoError, OtherIoError};
+use std::io::timer;
+use extra::arc::RWArc;
+
+
+/// Size of the scratch buffer used by skip().
+pub static SKIP_BUF_SIZE: uint = 64 * 1024;
+/// The longest sleep between the retries of ZeroReadRetry, in milliseconds.
+pub static MAX_BACKOFF_MS: u64 = 1000;
+/// The error of the operations stopped by a CancelToken, raised as the io_error desc or returned as the error string.
+pub static CANCELLED: &'static str = "Cancelled";
+
+
+/// Discard the next n bytes of the reader, reading them through a scratch buffer.  It works on the
+/// readers that can't seek, e.g. a pipe.  Return the number of bytes skipped, less than n at EOF.
+pub fn skip<R: Reader>(reader: &mut R, n: u64) -> u64 {
+    let mut buf = vec::from_elem(num::min(n, SKIP_BUF_SIZE as u64) as uint, 0u8);
+    let mut skipped = 0u64;
+    while skipped < n {
+        let len = num::min(n - skipped, buf.len() as u64) as uint;
+        match reader.read(buf.mut_slice(0, len)) {
+            Some(read_len)  => skipped += read_len as u64,
+            None            => break
+        }
+    }
+    skipped
+}
+
+/// Skip the next n bytes of a seekable reader by seeking forward, without reading them.
+/// Return the number of bytes skipped, less than n if it would go past the end.
+pub fn skip_seek<R: Reader + Seek>(reader: &mut R, n: u64) -> u64 {
+    let pos = reader.tell();
+    reader.seek(0, SeekEnd);
+    let end = reader.tell();
+    let skipped = if pos < end { num::min(n, end - pos) } else { 0 };
+    reader.seek((pos + skipped) as i64 - end as i64, SeekCur);
+    skipped
+}
+
+
+/// Read data upto the len_to_read into a new buffer, unless encounters EOF.  The buffer is truncated to the data read.
+pub fn read_upto<R: Reader>(reader: &mut R, len_to_read: uint) -> ~[u8] {
+    let mut buf = vec::from_elem(len_to_read, 0u8);
+    let read_len = read_buf_upto(reader, buf, 0, len_to_read);
+    buf.truncate(read_len);
+    buf
+}
+
+/// Read exactly the len_to_read bytes into a new buffer.  Raise io_error with the desc and return None if the data ends early.
+pub fn read_exact<R: Reader>(reader: &mut R, len_to_read: uint, desc: &'static str) -> Option<~[u8]> {
+    let mut buf = vec::from_elem(len_to_read, 0u8);
+    if read_buf_exact(reader, buf, 0, len_to_read, desc) {
+        Some(buf)
+    } else {
+        None
+    }
+}
+
+/// Read exactly the len_to_read bytes into the buffer.  Raise io_error with the desc and return false if the data ends early.
+pub fn read_buf_exact<R: Reader>(reader: &mut R, buf: &mut [u8], offset: uint, len_to_read: uint, desc: &'static str) -> bool {
+    let read_len = read_buf_upto(reader, buf, offset, len_to_read);
+    if read_len < len_to_read {
+        io_error::cond.raise(IoError {
+                kind:   OtherIoError,
+                desc:   desc,
+                detail: Some(format!("Read {:u} bytes out of {:u} bytes.", read_len, len_to_read))
+            });
+        return false;
+    }
+    true
+}
+
+/// Read data upto the len_to_read, unless encounters EOF.
+pub fn read_buf_upto<R: Reader>(reader: &mut R, buf: &mut [u8], offset: uint, len_to_read: uint) -> uint {
+    let mut total_read = 0u;
+    while total_read < len_to_read {
+        let remaining_len = len_to_read - total_read;
+        let begin = offset + total_read;
+        let end   = offset + total_read + remaining_len;
+        let slice_buf = buf.mut_slice(begin, end);
+        match reader.read(slice_buf) {
+            Some(read_len) => total_read = total_read + read_len,
+            None => break
+        }
+    }
+    return total_read;
+}
+
+
+/// Handle to stop a long running operation, like compressing a big file, from another task.  The operation checks
+/// it between the chunks of data and stops with the CANCELLED error.  The clones share the same state.
+/// Usage:
+///     let token = CancelToken::new();
+///     gzip.set_cancel_token(Some(token.clone()));
+///     ...
+///     token.cancel();     // on another task
+#[deriving(Clone)]
+pub struct CancelToken {
+    priv cancelled:     RWArc<bool>,
+}
+
+impl CancelToken {
+
+    /// Create a token not cancelled yet.
+    pub fn new() -> CancelToken {
+        CancelToken {
+            cancelled:  RWArc::new(false),
+        }
+    }
+
+    /// Ask the operations checking the token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.write(|cancelled| *cancelled = true);
+    }
+
+    /// Check whether the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.read(|cancelled| *cancelled)
+    }
+
+}
+
+/// Check whether the token, if there's one, is cancelled.
+pub fn is_cancelled(token: &Option<CancelToken>) -> bool {
+    match *token {
+        Some(ref token) => token.is_cancelled(),
+        None            => false
+    }
+}
+
+
+/// Reader decorator to examine the bytes ahead without consuming them, e.g. to detect a format by its magic bytes.
+/// peek() buffers the bytes ahead and push_back() returns the consumed bytes to the front of the stream.
+/// Usage:
+///     let mut reader = BufferedPeekReader::new(reader);
+///     let is_gzip = reader.peek(2) == [0x1F, 0x8B];
+///     reader.read(buf);
+pub struct BufferedPeekReader<R> {
+    priv inner_reader:  R,
+    priv buf:           ~[u8],
+    priv buf_offset:    uint,
+}
+
+impl<R: Reader> BufferedPeekReader<R> {
+
+    /// Create the reader on the inner reader.
+    pub fn new(inner_reader: R) -> BufferedPeekReader<R> {
+        BufferedPeekReader {
+            inner_reader:   inner_reader,
+            buf:            ~[],
+            buf_offset:     0,
+        }
+    }
+
+    /// Return the next n bytes without consuming them.  Fewer bytes are returned at EOF.
+    pub fn peek<'a>(&'a mut self, n: uint) -> &'a [u8] {
+        if self.buffered_len() < n {
+            let mut buf = self.buf.slice_from(self.buf_offset).to_owned();
+            let mut len = buf.len();
+            buf.grow(n - len, &0u8);
+            while len < n {
+                match self.inner_reader.read(buf.mut_slice(len, n)) {
+                    Some(read_len)  => len += read_len,
+                    None            => break
+                }
+            }
+            buf.truncate(len);
+            self.buf = buf;
+            self.buf_offset = 0;
+        }
+        let end = num::min(self.buf_offset + n, self.buf.len());
+        self.buf.slice(self.buf_offset, end)
+    }
+
+    /// Put the bytes back to the front of the stream, to be read again next.
+    pub fn push_back(&mut self, bytes: &[u8]) {
+        let mut buf = bytes.to_owned();
+        buf.push_all(self.buf.slice_from(self.buf_offset));
+        self.buf = buf;
+        self.buf_offset = 0;
+    }
+
+    /// The number of bytes buffered by peek() and push_back(), to be read before the inner reader's.
+    pub fn buffered_len(&self) -> uint {
+        self.buf.len() - self.buf_offset
+    }
+
+}
+
+impl<R: Reader> Reader for BufferedPeekReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        if buf.len() == 0 {
+            return Some(0);
+        }
+        if self.buffered_len() > 0 {
+            let len = num::min(buf.len(), self.buffered_len());
+            vec::bytes::copy_memory(buf, self.buf.slice(self.buf_offset, self.buf_offset + len), len);
+            self.buf_offset += len;
+            return Some(len);
+        }
+        self.inner_reader.read(buf)
+    }
+
+    fn eof(&mut self) -> bool {
+        self.buffered_len() == 0 && self.inner_reader.eof()
+    }
+
+}
+
+impl<R: Reader> Decorator<R> for BufferedPeekReader<R> {
+    fn inner(self) -> R {
+        self.inner_reader
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a R {
+        &self.inner_reader
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut R {
+        &mut self.inner_reader
+    }
+}
+
+
+/// Reader yielding the data of the inner readers in sequence, e.g. a header in memory followed by a body on disk.
+/// Usage:
+///     let mut reader = ChainedReader::new(~[~MemReader::new(header) as ~Reader, ~file as ~Reader]);
+///     let mut gzip_reader = GZipReader::new(reader);
+pub struct ChainedReader {
+    priv readers:       ~[~Reader],
+    priv current:       uint,
+}
+
+impl ChainedReader {
+
+    /// Create the reader on the inner readers, to be read in order.
+    pub fn new(readers: ~[~Reader]) -> ChainedReader {
+        ChainedReader {
+            readers:    readers,
+            current:    0,
+        }
+    }
+
+    /// Append another reader to the end of the chain.
+    pub fn push(&mut self, reader: ~Reader) {
+        self.readers.push(reader);
+    }
+
+    /// The index of the inner reader being read.  It's the number of readers when all of them are done.
+    pub fn current_index(&self) -> uint {
+        self.current
+    }
+
+    /// Return the inner readers.
+    pub fn unwrap(self) -> ~[~Reader] {
+        self.readers
+    }
+
+}
+
+impl Reader for ChainedReader {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        if buf.len() == 0 {
+            return Some(0);
+        }
+        while self.current < self.readers.len() {
+            match self.readers[self.current].read(buf) {
+                Some(read_len) if read_len > 0  => return Some(read_len),
+                _                               => self.current += 1
+            }
+        }
+        None
+    }
+
+    fn eof(&mut self) -> bool {
+        while self.current < self.readers.len() && self.readers[self.current].eof() {
+            self.current += 1;
+        }
+        self.current >= self.readers.len()
+    }
+
+}
+
+
+// The messages from the reading task of DoubleBufferedReader.
+enum ReadAheadMsg {
+    ReadAheadData(~[u8], uint),         // the buffer and the length of the data in it
+    ReadAheadEnd,
+}
+
+/// Reader decorator reading the inner reader ahead on a separate task with two buffers, so that the reading of
+/// the next buffer overlaps the processing of the current one, e.g. the compression in GZip::compress_stream().
+/// The buffers go back to the reading task when consumed; no more than two are allocated.
+/// Usage:
+///     let mut reader = DoubleBufferedReader::new(file, calc_buf_size(DEFAULT_SIZE_FACTOR));
+///     gzip.compress_stream(&mut reader, &mut writer, compress_level, buf_size_factor);
+pub struct DoubleBufferedReader {
+    priv data_port:     Port<ReadAheadMsg>,
+    priv free_chan:     Chan<~[u8]>,
+    priv buf:           ~[u8],
+    priv buf_len:       uint,
+    priv buf_offset:    uint,
+    priv is_eof:        bool,
+}
+
+impl DoubleBufferedReader {
+
+    /// Create the reader and start reading the inner reader on a new task, buf_size bytes at a time.
+    pub fn new<R: Reader + Send>(inner_reader: R, buf_size: uint) -> DoubleBufferedReader {
+        let (data_port, data_chan) = Chan::new();
+        let (free_port, free_chan) = Chan::new();
+        free_chan.send(vec::from_elem(num::max(buf_size, 1u), 0u8));
+        free_chan.send(vec::from_elem(num::max(buf_size, 1u), 0u8));
+
+        task::spawn(proc() {
+            let mut inner_reader = inner_reader;
+            loop {
+                let mut buf: ~[u8] = match free_port.recv_opt() {
+                    Some(buf)   => buf,
+                    None        => return               // the reader has been dropped
+                };
+                let mut len = 0u;
+                while len < buf.len() {
+                    match inner_reader.read(buf.mut_slice_from(len)) {
+                        Some(read_len)  => len += read_len,
+                        None            => break
+                    }
+                }
+                if len == 0 {
+                    data_chan.try_send(ReadAheadEnd);
+                    return;
+                }
+                if !data_chan.try_send(ReadAheadData(buf, len)) {
+                    return;
+                }
+            }
+        });
+
+        DoubleBufferedReader {
+            data_port:  data_port,
+            free_chan:  free_chan,
+            buf:        ~[],
+            buf_len:    0,
+            buf_offset: 0,
+            is_eof:     false,
+        }
+    }
+
+    // Hand the consumed buffer back to the reading task and take the next one.  Return false at the end.
+    fn next_buf(&mut self) -> bool {
+        let consumed = util::replace(&mut self.buf, ~[]);
+        if consumed.len() > 0 {
+            self.free_chan.try_send(consumed);
+        }
+        self.buf_len = 0;
+        self.buf_offset = 0;
+        match self.data_port.recv_opt() {
+            Some(ReadAheadData(buf, len)) => {
+                self.buf = buf;
+                self.buf_len = len;
+                true
+            },
+            Some(ReadAheadEnd) => {
+                self.is_eof = true;
+                false
+            },
+            None => {
+                self.is_eof = true;
+                io_error::cond.raise(IoError {
+                        kind:   OtherIoError,
+                        desc:   "Failed to read ahead",
+                        detail: Some(~"The reading task has failed.")
+                    });
+                false
+            }
+        }
+    }
+
+}
+
+impl Reader for DoubleBufferedReader {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        if self.is_eof {
+            return None;
+        }
+        if buf.len() == 0 {
+            return Some(0);
+        }
+        if self.buf_offset == self.buf_len && !self.next_buf() {
+            return None;
+        }
+        let len = num::min(buf.len(), self.buf_len - self.buf_offset);
+        vec::bytes::copy_memory(buf, self.buf.slice(self.buf_offset, self.buf_offset + len), len);
+        self.buf_offset += len;
+        Some(len)
+    }
+
+    fn eof(&mut self) -> bool {
+        self.is_eof
+    }
+
+}
+
+/// What to do when a read returns zero bytes.  The codecs' read loops take zero bytes as EOF, but the
+/// nonblocking or slow sources, like sockets, return zero bytes while they wait for more data.
+pub enum ZeroReadPolicy {
+    /// Take zero bytes as EOF, like the files.
+    ZeroReadEof,
+    /// Retry the read up to the number of times, sleeping the milliseconds before the first retry and
+    /// doubling the sleep for each one after, up to MAX_BACKOFF_MS.  EOF when the retries run out.
+    ZeroReadRetry(uint, u64),
+    /// Call the wait hook and retry the read.  EOF when the hook returns false.
+    ZeroReadWait(fn() -> bool),
+}
+
+/// Reader decorator applying the ZeroReadPolicy to the reads returning zero bytes, so the zero byte reads
+/// never reach the codecs' read loops.  None is returned as EOF when the policy gives up.
+/// Usage:
+///     let mut reader = ZeroReadReader::new(socket, ZeroReadRetry(10, 10));
+///     let mut gzip_reader = GZipReader::new(reader);
+pub struct ZeroReadReader<R> {
+    priv inner_reader:  R,
+    priv policy:        ZeroReadPolicy,
+}
+
+impl<R: Reader> ZeroReadReader<R> {
+
+    /// Create the reader on the inner reader with the policy.
+    pub fn new(inner_reader: R, policy: ZeroReadPolicy) -> ZeroReadReader<R> {
+        ZeroReadReader {
+            inner_reader:   inner_reader,
+            policy:         policy,
+        }
+    }
+
+}
+
+impl<R: Reader> Reader for ZeroReadReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        if buf.len() == 0 {
+            return Some(0);
+        }
+        let mut retries = 0u;
+        loop {
+            match self.inner_reader.read(buf) {
+                Some(0)     => (),
+                result      => return result
+            }
+            match self.policy {
+                ZeroReadEof                         => return None,
+                ZeroReadRetry(max_retries, backoff) => {
+                    if retries >= max_retries {
+                        return None;
+                    }
+                    timer::sleep(num::min(backoff << num::min(retries, 16u), MAX_BACKOFF_MS));
+                    retries += 1;
+                },
+                ZeroReadWait(wait_fn)               => {
+                    if !wait_fn() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        self.inner_reader.eof()
+    }
+
+}
+
+impl<R: Reader> Decorator<R> for ZeroReadReader<R> {
+    fn inner(self) -> R {
+        self.inner_reader
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a R {
+        &self.inner_reader
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut R {
+        &mut self.inner_reader
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use std::io::{Reader, Writer, Seek};
+    use std::io::{Open, Read, Truncate, Write};
+    use std::io::fs;
+    use std::io::fs::File;
+    use std::io::mem::MemReader;
+    use std::io::io_error;
+    use std::path::Path;
+    use super::{skip, skip_seek};
+    use super::{read_upto, read_exact, read_buf_exact, read_buf_upto};
+    use super::BufferedPeekReader;
+    use super::ChainedReader;
+    use super::DoubleBufferedReader;
+    use super::{ZeroReadReader, ZeroReadEof, ZeroReadRetry, ZeroReadWait};
+
+
+    #[test]
+    fn test_skip() {
+        let data = vec::from_fn(200000, |i| (i % 251) as u8);
+
+        let mut reader = MemReader::new(data.clone());
+        assert_eq!(skip(&mut reader, 0), 0);
+        assert_eq!(skip(&mut reader, 150001), 150001);
+        assert_eq!(reader.read_byte(), Some(data[150001]));
+        assert_eq!(skip(&mut reader, 100000), 200000 - 150002);
+        assert!(reader.eof());
+    }
+
+    #[test]
+    fn test_skip_seek() {
+        let data = vec::from_fn(200000, |i| (i % 251) as u8);
+        let file_path = Path::new("test_skip_seek.dat");
+        File::open_mode(&file_path, Truncate, Write).unwrap().write(data);
+
+        {
+            let mut reader = File::open_mode(&file_path, Open, Read).unwrap();
+            assert_eq!(skip_seek(&mut reader, 150001), 150001);
+            assert_eq!(reader.read_byte(), Some(data[150001]));
+            assert_eq!(skip_seek(&mut reader, 100000), 200000 - 150002);
+            assert_eq!(reader.tell(), 200000);
+        }
+        fs::unlink(&file_path);
+    }
+
+    #[test]
+    fn test_read_helpers() {
+        // The data come in short reads across the chained readers.
+        let mut reader = ChainedReader::new(~[~MemReader::new(~[1u8, 2, 3]) as ~Reader,
+                                              ~MemReader::new(~[4u8, 5, 6, 7]) as ~Reader]);
+        let mut buf = [0u8, ..6];
+        assert_eq!(read_buf_upto(&mut reader, buf, 1, 5), 5);
+        assert_eq!(buf.to_owned(), ~[0u8, 1, 2, 3, 4, 5]);
+        assert_eq!(read_upto(&mut reader, 10), ~[6u8, 7]);
+        assert_eq!(read_upto(&mut reader, 10), ~[]);
+
+        let mut reader = MemReader::new(~[1u8, 2, 3]);
+        assert_eq!(read_exact(&mut reader, 2, "Not enough data."), Some(~[1u8, 2]));
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            assert!(!read_buf_exact(&mut reader, buf, 0, 2, "Not enough data."));
+        });
+        assert_eq!(error_count, 1);
+    }
+
+    #[test]
+    fn test_buffered_peek_reader() {
+        let data = vec::from_fn(1000, |i| (i % 251) as u8);
+        let mut reader = BufferedPeekReader::new(MemReader::new(data.clone()));
+
+        assert_eq!(reader.peek(4).to_owned(), data.slice(0, 4).to_owned());
+        assert_eq!(reader.peek(2).to_owned(), data.slice(0, 2).to_owned());
+        assert_eq!(reader.buffered_len(), 4);
+
+        let mut buf = [0u8, ..10];
+        assert_eq!(reader.read(buf), Some(4));
+        assert_eq!(buf.slice(0, 4).to_owned(), data.slice(0, 4).to_owned());
+        assert_eq!(reader.read(buf), Some(10));
+        assert_eq!(buf.to_owned(), data.slice(4, 14).to_owned());
+
+        reader.push_back(buf.slice(6, 10));
+        reader.push_back([0xAA, 0xBB]);
+        assert_eq!(reader.peek(3).to_owned(), ~[0xAA, 0xBB, data[10]]);
+        let rest = reader.read_to_end();
+        assert_eq!(rest.len(), 2 + 1000 - 10);
+        assert_eq!(rest.slice_from(2).to_owned(), data.slice_from(10).to_owned());
+
+        assert_eq!(reader.peek(8).len(), 0);
+        assert!(reader.eof());
+    }
+
+    #[test]
+    fn test_chained_reader() {
+        let mut reader = ChainedReader::new(~[~MemReader::new(~[1u8, 2, 3]) as ~Reader,
+                                              ~MemReader::new(~[]) as ~Reader,
+                                              ~MemReader::new(~[4u8, 5]) as ~Reader]);
+        reader.push(~MemReader::new(~[6u8]) as ~Reader);
+
+        let mut buf = [0u8, ..10];
+        assert_eq!(reader.read(buf), Some(3));
+        assert_eq!(reader.current_index(), 0);
+        assert!(!reader.eof());
+        assert_eq!(reader.read_to_end(), ~[4u8, 5, 6]);
+        assert!(reader.eof());
+        assert_eq!(reader.current_index(), 4);
+        assert_eq!(reader.read(buf), None);
+    }
+
+    #[test]
+    fn test_double_buffered_reader() {
+        let data = vec::from_fn(200000, |i| (i % 251) as u8);
+        let mut reader = DoubleBufferedReader::new(MemReader::new(data.clone()), 4096);
+
+        let mut buf = [0u8, ..10];
+        assert_eq!(reader.read(buf), Some(10));
+        assert_eq!(buf.to_owned(), data.slice(0, 10).to_owned());
+        let rest = reader.read_to_end();
+        assert_eq!(rest, data.slice_from(10).to_owned());
+        assert!(reader.eof());
+        assert_eq!(reader.read(buf), None);
+
+        let mut reader = DoubleBufferedReader::new(MemReader::new(~[]), 4096);
+        assert_eq!(reader.read(buf), None);
+        assert!(reader.eof());
+    }
+
+    // Reader returning zero bytes the given times before each read of the data.
+    struct SlowReader {
+        zeros:      uint,
+        left:       uint,
+        data:       MemReader,
+    }
+
+    impl Reader for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+            if self.left > 0 {
+                self.left -= 1;
+                return Some(0);
+            }
+            self.left = self.zeros;
+            self.data.read(buf)
+        }
+
+        fn eof(&mut self) -> bool {
+            self.data.eof()
+        }
+    }
+
+    static mut wait_count: uint = 0;
+
+    fn count_wait() -> bool {
+        unsafe {
+            wait_count += 1;
+        }
+        true
+    }
+
+    #[test]
+    fn test_zero_read_reader() {
+        let slow_reader = || SlowReader { zeros: 2, left: 2, data: MemReader::new(~[1u8, 2, 3]) };
+        let mut buf = [0u8, ..10];
+
+        let mut reader = ZeroReadReader::new(slow_reader(), ZeroReadEof);
+        assert_eq!(reader.read(buf), None);
+
+        let mut reader = ZeroReadReader::new(slow_reader(), ZeroReadRetry(1, 1));
+        assert_eq!(reader.read(buf), None);
+
+        let mut reader = ZeroReadReader::new(slow_reader(), ZeroReadRetry(2, 1));
+        assert_eq!(reader.read(buf), Some(3));
+        assert_eq!(buf.slice(0, 3).to_owned(), ~[1u8, 2, 3]);
+
+        let mut reader = ZeroReadReader::new(slow_reader(), ZeroReadWait(count_wait));
+        assert_eq!(reader.read_to_end(), ~[1u8, 2, 3]);
+        assert!(unsafe { wait_count } >= 2);
+    }
+
+}
@@ -32,8 +32,22 @@
  ******************************************************************************/
 
 
+extern mod extra;
+
+
 /// The modules in this crate
 // make mod pub so that its pub names can be linked by the linker.
 pub mod deflate;
 pub mod gzip;
 pub mod zip;
+pub mod trace;
+pub mod arith;
+pub mod rangecoder;
+pub mod endian;
+pub mod ioutil;
+pub mod http_coding;
+pub mod bitstream;
+pub mod native_deflate;
+pub mod native_inflate;
+#[cfg(zlib)]
+mod zlib;
@@ -0,0 +1,909 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: native_deflate.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The native_deflate module is the DEFLATE compressor (RFC 1951) written in Rust, the
+BackendNative of the Deflator.  It takes the same tdefl flags as miniz, and has the
+tdefl functions used by deflate.rs on top of NativeCompressor, so the Deflator
+switches between the runtime library and this one at construction.
+
+The input is compressed in blocks of up to BLOCK_SIZE bytes.  The LZ77 matcher finds
+the matches in the hash chains of 3-byte prefixes over the block and the 32K window
+before it, with up to the number of probes of the compression level, and lazy or
+greedy parsing as the flags say.  Each block goes out in the stored, static Huffman,
+or dynamic Huffman format, whichever is the smallest.  The bits are packed with the
+BitWriter of the bitstream module.
+
+The compressed output is queued and handed out as the output buffer has room, so an
+output buffer of any size works; the compression only consumes more input when the
+queue is empty.
+
+*/
+
+
+use std::{cast, num, uint, vec};
+use std::libc::{c_void, size_t, c_int};
+use super::bitstream::{BitWriter, Lsb};
+use super::deflate::update_adler32;
+
+
+// The miniz flags, as defined in deflate.rs.
+static TDEFL_MAX_PROBES_MASK : uint                 = 0xFFF;
+static TDEFL_WRITE_ZLIB_HEADER : uint               = 0x01000;
+static TDEFL_GREEDY_PARSING_FLAG : uint             = 0x04000;
+static TDEFL_RLE_MATCHES : uint                     = 0x10000;
+static TDEFL_FILTER_MATCHES : uint                  = 0x20000;
+static TDEFL_FORCE_ALL_STATIC_BLOCKS : uint         = 0x40000;
+static TDEFL_FORCE_ALL_RAW_BLOCKS : uint            = 0x80000;
+
+/// The tdefl flush modes.
+pub static TDEFL_NO_FLUSH : c_int   = 0;
+/// Sync flush.
+pub static TDEFL_SYNC_FLUSH : c_int = 2;
+/// Full flush.
+pub static TDEFL_FULL_FLUSH : c_int = 3;
+/// Finish the stream.
+pub static TDEFL_FINISH : c_int     = 4;
+
+static TDEFL_STATUS_BAD_PARAM : c_int   = -2;
+static TDEFL_STATUS_OKAY : c_int        = 0;
+static TDEFL_STATUS_DONE : c_int        = 1;
+
+/// The maximum number of input bytes compressed in one block.
+pub static BLOCK_SIZE : uint = 65536;
+/// The size of the window the matches can refer back to.
+pub static WINDOW_SIZE : uint = 32768;
+
+static MIN_MATCH : uint = 3;
+static MAX_MATCH : uint = 258;
+static LAZY_MATCH_LIMIT : uint = 32;            // no lazy evaluation after a match this long
+static FAR_MIN_MATCH_DIST : uint = 8192;        // a minimum length match this far costs more than the literals
+static FILTER_MIN_MATCH : uint = 6;
+static HASH_BITS : uint = 15;
+static HASH_SIZE : uint = 1 << HASH_BITS;
+static MAX_STORED_LEN : uint = 65535;
+static MAX_CODE_BITS : uint = 15;
+static MAX_CODE_LENGTH_BITS : uint = 7;
+static NUM_LIT_CODES : uint = 286;
+static NUM_DIST_CODES : uint = 30;
+static NUM_CODE_LENGTH_CODES : uint = 19;
+static END_OF_BLOCK : uint = 256;
+
+static BLOCK_STORED : u32  = 0;
+static BLOCK_STATIC : u32  = 1;
+static BLOCK_DYNAMIC : u32 = 2;
+
+/// The base lengths of the length codes 257 to 285.
+pub static LEN_BASE : [uint, ..29] = [ 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                       35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258 ];
+/// The extra bits of the length codes 257 to 285.
+pub static LEN_EXTRA : [uint, ..29] = [ 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                        3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0 ];
+/// The base distances of the distance codes.
+pub static DIST_BASE : [uint, ..30] = [ 1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                        257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577 ];
+/// The extra bits of the distance codes.
+pub static DIST_EXTRA : [uint, ..30] = [ 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                                         7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13 ];
+/// The order of the code length code lengths in the dynamic block header.
+pub static CODE_LENGTH_ORDER : [uint, ..19] = [ 16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15 ];
+
+
+/// The DEFLATE compressor.  Call init() with the tdefl flags before compress().
+/// A clone carries on from the same point of the stream, e.g. from right after set_dictionary().
+#[deriving(Clone)]
+pub struct NativeCompressor {
+    priv max_probes:        uint,
+    priv greedy:            bool,
+    priv raw_blocks:        bool,
+    priv static_blocks:     bool,
+    priv rle_matches:       bool,
+    priv filter_matches:    bool,
+    priv zlib_header:       bool,
+    priv adler32:           u32,
+    priv dict_id:           Option<u32>,    // the ADLER32 of the preset dictionary
+    priv header_done:       bool,
+    priv history:           ~[u8],          // the last WINDOW_SIZE bytes compressed, referenced by the matches
+    priv pending:           ~[u8],          // the input not compressed yet
+    priv writer:            BitWriter<Lsb>,
+    priv out_queue:         ~[u8],          // the compressed data not handed out yet
+    priv out_begin:         uint,
+    priv flushed:           bool,           // no input since the last sync or full flush
+    priv finished:          bool,
+    priv initialized:       bool,
+}
+
+impl NativeCompressor {
+
+    /// Create the compressor.  It needs init() before use.
+    pub fn new() -> NativeCompressor {
+        NativeCompressor {
+            max_probes:         0,
+            greedy:             false,
+            raw_blocks:         false,
+            static_blocks:      false,
+            rle_matches:        false,
+            filter_matches:     false,
+            zlib_header:        false,
+            adler32:            1,
+            dict_id:            None,
+            header_done:        false,
+            history:            ~[],
+            pending:            ~[],
+            writer:             BitWriter::new(),
+            out_queue:          ~[],
+            out_begin:          0,
+            flushed:            false,
+            finished:           false,
+            initialized:        false,
+        }
+    }
+
+    /// Initialize or re-initialize the compressor for a new stream with the tdefl flags: the number of probes
+    /// in the low 12 bits, and the parsing, strategy and zlib header flags.  The ADLER32 is always computed
+    /// with the zlib header, and only written with it, as miniz does.
+    pub fn init(&mut self, compress_flags: uint) {
+        self.max_probes = num::max(1, compress_flags & TDEFL_MAX_PROBES_MASK);
+        self.greedy = compress_flags & TDEFL_GREEDY_PARSING_FLAG != 0;
+        self.raw_blocks = compress_flags & TDEFL_FORCE_ALL_RAW_BLOCKS != 0;
+        self.static_blocks = compress_flags & TDEFL_FORCE_ALL_STATIC_BLOCKS != 0;
+        self.rle_matches = compress_flags & TDEFL_RLE_MATCHES != 0;
+        self.filter_matches = compress_flags & TDEFL_FILTER_MATCHES != 0;
+        self.zlib_header = compress_flags & TDEFL_WRITE_ZLIB_HEADER != 0;
+        self.adler32 = 1;
+        self.dict_id = None;
+        self.header_done = false;
+        self.history = vec::with_capacity(WINDOW_SIZE);
+        self.pending = vec::with_capacity(BLOCK_SIZE);
+        self.writer = BitWriter::new();
+        self.out_queue = ~[];
+        self.out_begin = 0;
+        self.flushed = false;
+        self.finished = false;
+        self.initialized = true;
+    }
+
+    /// Set the preset dictionary after init() and before any input.  The matches can refer back into its last
+    /// WINDOW_SIZE bytes.  With the zlib header, the header has the FDICT flag and the ADLER32 of the dictionary
+    /// as the DICTID, as RFC 1950 specifies; the decompressor needs the same dictionary.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        let keep = num::min(WINDOW_SIZE, dictionary.len());
+        self.history = dictionary.slice_from(dictionary.len() - keep).to_owned();
+        self.dict_id = Some(update_adler32(1, dictionary));
+    }
+
+    /// Whether init() has been called.
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    /// Compress the input into the output with the tdefl flush mode.  Return the number of input bytes consumed,
+    /// the number of bytes written to output, and whether the stream has finished and all of it is written out.
+    /// When the output fills up, the rest of the compressed data come out in the next call.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8], tdefl_flush: c_int) -> (uint, uint, bool) {
+        let mut in_used = 0u;
+        let mut out_used = self.drain(output, 0);
+
+        while !self.finished && self.out_begin == self.out_queue.len() {
+            if in_used < input.len() {
+                let take = num::min(BLOCK_SIZE - self.pending.len(), input.len() - in_used);
+                let chunk = input.slice(in_used, in_used + take);
+                if self.zlib_header {
+                    self.adler32 = update_adler32(self.adler32, chunk);
+                }
+                self.pending.push_all(chunk);
+                self.flushed = false;
+                in_used += take;
+                if self.pending.len() == BLOCK_SIZE {
+                    self.compress_block(false);
+                    out_used = self.drain(output, out_used);
+                }
+                continue;
+            }
+
+            // All the input is in.  Flush as asked.
+            match tdefl_flush {
+                TDEFL_FINISH => {
+                    self.compress_block(true);
+                    self.finish_stream();
+                },
+                TDEFL_SYNC_FLUSH | TDEFL_FULL_FLUSH if !self.flushed => {
+                    self.compress_block(false);
+                    self.write_sync_marker();
+                    if tdefl_flush == TDEFL_FULL_FLUSH {
+                        self.history.truncate(0);
+                    }
+                    self.flushed = true;
+                },
+                _ => ()
+            }
+            out_used = self.drain(output, out_used);
+            break;
+        }
+
+        (in_used, out_used, self.finished && self.out_begin == self.out_queue.len())
+    }
+
+    // Copy the queued output into output from out_offset.  Return the new out_offset.
+    fn drain(&mut self, output: &mut [u8], out_offset: uint) -> uint {
+        let copy_len = num::min(output.len() - out_offset, self.out_queue.len() - self.out_begin);
+        vec::bytes::copy_memory(output.mut_slice_from(out_offset),
+                                self.out_queue.slice(self.out_begin, self.out_begin + copy_len), copy_len);
+        self.out_begin += copy_len;
+        if self.out_begin == self.out_queue.len() {
+            self.out_queue.truncate(0);
+            self.out_begin = 0;
+        }
+        out_offset + copy_len
+    }
+
+    // Move the completed bytes of the BitWriter to the output queue.
+    fn queue_output(&mut self) {
+        let bytes = self.writer.take_bytes();
+        self.out_queue.push_all(bytes);
+    }
+
+    fn write_header(&mut self) {
+        if !self.header_done {
+            self.header_done = true;
+            if self.zlib_header {
+                // CMF of deflate with the 32K window, and FLG with the level hint, the FDICT flag and the check bits.
+                let level = if self.raw_blocks || self.max_probes <= 2 { 0 }
+                            else if self.max_probes < 128 { 1 }
+                            else if self.max_probes < 2048 { 2 }
+                            else { 3 };
+                let flg = (level << 6) | (if self.dict_id.is_some() { 0x20 } else { 0 });
+                let flg = flg + (31 - (0x78 * 256 + flg) % 31) % 31;
+                self.writer.write_bits(0x78, 8);
+                self.writer.write_bits(flg, 8);
+                match self.dict_id {
+                    Some(dict_id) => self.writer.write_bytes([(dict_id >> 24) as u8, (dict_id >> 16) as u8,
+                                                              (dict_id >> 8) as u8, dict_id as u8]),
+                    None => ()
+                }
+            }
+        }
+    }
+
+    // Compress the pending input as one block, or as several stored blocks.  An empty non-final block is skipped.
+    fn compress_block(&mut self, is_final: bool) {
+        self.write_header();
+        if self.pending.len() == 0 && !is_final {
+            return;
+        }
+
+        let start = self.history.len();
+        let mut buf = vec::with_capacity(start + self.pending.len());
+        buf.push_all(self.history);
+        buf.push_all(self.pending);
+
+        if self.raw_blocks {
+            self.write_stored(buf.slice_from(start), is_final);
+        } else {
+            let tokens = self.find_matches(buf, start);
+            self.write_block(buf.slice_from(start), tokens, is_final);
+        }
+
+        let keep = num::min(WINDOW_SIZE, buf.len());
+        self.history = buf.slice_from(buf.len() - keep).to_owned();
+        self.pending.truncate(0);
+        self.queue_output();
+    }
+
+    // An empty stored block, ending at a byte boundary with 00 00 FF FF.
+    fn write_sync_marker(&mut self) {
+        self.write_header();
+        self.writer.write_bits(BLOCK_STORED << 1, 3);
+        self.writer.align_byte();
+        self.writer.write_bytes([0x00u8, 0x00, 0xFF, 0xFF]);
+        self.queue_output();
+    }
+
+    fn finish_stream(&mut self) {
+        self.writer.align_byte();
+        if self.zlib_header {
+            let adler32 = self.adler32;
+            self.writer.write_bytes([(adler32 >> 24) as u8, (adler32 >> 16) as u8, (adler32 >> 8) as u8, adler32 as u8]);
+        }
+        self.queue_output();
+        self.finished = true;
+    }
+
+    // Find the LZ77 matches of buf from start to the end, with buf before start as the window.
+    // A token is (literal, 0) or (length, distance).
+    fn find_matches(&self, buf: &[u8], start: uint) -> ~[(u16, u16)] {
+        let end = buf.len();
+        let mut head = vec::from_elem(HASH_SIZE, -1i32);
+        let mut prev = vec::from_elem(end, -1i32);
+        let mut inserted = 0u;
+        let mut tokens = vec::with_capacity(end - start);
+
+        let mut pos = start;
+        while pos < end {
+            insert_hashes(buf, head, prev, &mut inserted, pos);
+            let (len, dist) = self.longest_match(buf, pos, head, prev);
+            if len >= MIN_MATCH {
+                if !self.greedy && len < LAZY_MATCH_LIMIT && pos + 1 < end {
+                    // Lazy evaluation: take a literal if the match at the next byte is longer.
+                    insert_hashes(buf, head, prev, &mut inserted, pos + 1);
+                    let (next_len, _) = self.longest_match(buf, pos + 1, head, prev);
+                    if next_len > len {
+                        tokens.push((buf[pos] as u16, 0u16));
+                        pos += 1;
+                        continue;
+                    }
+                }
+                tokens.push((len as u16, dist as u16));
+                pos += len;
+            } else {
+                tokens.push((buf[pos] as u16, 0u16));
+                pos += 1;
+            }
+        }
+        tokens
+    }
+
+    // The longest match at pos.  Return (length, distance), or (0, 0) for none.
+    fn longest_match(&self, buf: &[u8], pos: uint, head: &[i32], prev: &[i32]) -> (uint, uint) {
+        let max_len = num::min(MAX_MATCH, buf.len() - pos);
+        if max_len < MIN_MATCH {
+            return (0, 0);
+        }
+
+        let mut best_len = 0u;
+        let mut best_dist = 0u;
+        if self.rle_matches {
+            if pos > 0 {
+                best_len = match_len(buf, pos - 1, pos, max_len);
+                best_dist = 1;
+            }
+        } else {
+            let min_pos = if pos > WINDOW_SIZE { pos - WINDOW_SIZE } else { 0 };
+            let mut probes = self.max_probes;
+            let mut cand = head[hash(buf, pos)];
+            while cand >= 0 && cand as uint >= min_pos && probes > 0 {
+                let cand_pos = cand as uint;
+                if buf[cand_pos + best_len] == buf[pos + best_len] {
+                    let len = match_len(buf, cand_pos, pos, max_len);
+                    if len > best_len {
+                        best_len = len;
+                        best_dist = pos - cand_pos;
+                        if len == max_len {
+                            break;
+                        }
+                    }
+                }
+                cand = prev[cand_pos];
+                probes -= 1;
+            }
+        }
+
+        if best_len < MIN_MATCH || (best_len == MIN_MATCH && best_dist >= FAR_MIN_MATCH_DIST) ||
+            (self.filter_matches && best_len < FILTER_MIN_MATCH) {
+            return (0, 0);
+        }
+        (best_len, best_dist)
+    }
+
+    // Write the data in stored blocks of up to MAX_STORED_LEN bytes.
+    fn write_stored(&mut self, data: &[u8], is_final: bool) {
+        let mut offset = 0u;
+        loop {
+            let len = num::min(MAX_STORED_LEN, data.len() - offset);
+            let last = offset + len == data.len();
+            self.writer.write_bits((BLOCK_STORED << 1) | (if is_final && last { 1 } else { 0 }), 3);
+            self.writer.align_byte();
+            self.writer.write_bytes([len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+            self.writer.write_bytes(data.slice(offset, offset + len));
+            offset += len;
+            if last {
+                break;
+            }
+        }
+    }
+
+    // Write the block in the smallest of the stored, static and dynamic formats.
+    fn write_block(&mut self, data: &[u8], tokens: &[(u16, u16)], is_final: bool) {
+        let mut lit_freq = vec::from_elem(NUM_LIT_CODES, 0u);
+        let mut dist_freq = vec::from_elem(NUM_DIST_CODES, 0u);
+        for &(value, dist) in tokens.iter() {
+            if dist == 0 {
+                lit_freq[value as uint] += 1;
+            } else {
+                lit_freq[257 + len_code(value as uint)] += 1;
+                dist_freq[dist_code(dist as uint)] += 1;
+            }
+        }
+        lit_freq[END_OF_BLOCK] = 1;
+
+        let (static_lit_lens, static_dist_lens) = static_lengths();
+        let static_bits = 3 + data_bits(lit_freq, dist_freq, static_lit_lens, static_dist_lens);
+        let stored_bits = stored_block_bits(data.len(), self.writer.pending_bits());
+
+        let dyn_lit_lens = huffman_lengths(lit_freq, MAX_CODE_BITS);
+        let mut dyn_dist_lens = huffman_lengths(dist_freq, MAX_CODE_BITS);
+        if dyn_dist_lens.iter().all(|&len| len == 0) {
+            // No matches; a complete code of two unused distances.
+            dyn_dist_lens[0] = 1;
+            dyn_dist_lens[1] = 1;
+        }
+        let header = DynamicHeader::new(dyn_lit_lens, dyn_dist_lens);
+        let dyn_bits = if self.static_blocks { uint::max_value }
+                       else { 3 + header.bits() + data_bits(lit_freq, dist_freq, dyn_lit_lens, dyn_dist_lens) };
+
+        if stored_bits < static_bits && stored_bits < dyn_bits {
+            self.write_stored(data, is_final);
+            return;
+        }
+
+        let final_bit = if is_final { 1 } else { 0 };
+        if static_bits <= dyn_bits {
+            self.writer.write_bits((BLOCK_STATIC << 1) | final_bit, 3);
+            self.write_tokens(tokens, static_lit_lens, static_dist_lens);
+        } else {
+            self.writer.write_bits((BLOCK_DYNAMIC << 1) | final_bit, 3);
+            header.write(&mut self.writer);
+            self.write_tokens(tokens, dyn_lit_lens, dyn_dist_lens);
+        }
+    }
+
+    fn write_tokens(&mut self, tokens: &[(u16, u16)], lit_lens: &[u8], dist_lens: &[u8]) {
+        let lit_codes = huffman_codes(lit_lens);
+        let dist_codes = huffman_codes(dist_lens);
+        let writer = &mut self.writer;
+        for &(value, dist) in tokens.iter() {
+            if dist == 0 {
+                writer.write_bits(lit_codes[value as uint] as u32, lit_lens[value as uint] as uint);
+            } else {
+                let len = value as uint;
+                let lc = len_code(len);
+                writer.write_bits(lit_codes[257 + lc] as u32, lit_lens[257 + lc] as uint);
+                writer.write_bits((len - LEN_BASE[lc]) as u32, LEN_EXTRA[lc]);
+                let dist = dist as uint;
+                let dc = dist_code(dist);
+                writer.write_bits(dist_codes[dc] as u32, dist_lens[dc] as uint);
+                writer.write_bits((dist - DIST_BASE[dc]) as u32, DIST_EXTRA[dc]);
+            }
+        }
+        writer.write_bits(lit_codes[END_OF_BLOCK] as u32, lit_lens[END_OF_BLOCK] as uint);
+    }
+
+}
+
+
+// The code length codes of a dynamic block header.
+struct DynamicHeader {
+    num_lit:        uint,
+    num_dist:       uint,
+    num_cl:         uint,
+    cl_lens:        ~[u8],
+    cl_symbols:     ~[(u8, u8)],        // (code length symbol, extra bits value)
+}
+
+impl DynamicHeader {
+
+    fn new(lit_lens: &[u8], dist_lens: &[u8]) -> DynamicHeader {
+        let num_lit = num::max(257, last_used(lit_lens));
+        let num_dist = num::max(1, last_used(dist_lens));
+        let mut lens = vec::with_capacity(num_lit + num_dist);
+        lens.push_all(lit_lens.slice_to(num_lit));
+        lens.push_all(dist_lens.slice_to(num_dist));
+
+        // Run-length code the lengths with the repeat codes 16, 17 and 18.
+        let mut cl_symbols = ~[];
+        let mut i = 0u;
+        while i < lens.len() {
+            let len = lens[i];
+            let mut run = 1u;
+            while i + run < lens.len() && lens[i + run] == len {
+                run += 1;
+            }
+            if len == 0 && run >= 11 {
+                let r = num::min(run, 138);
+                cl_symbols.push((18u8, (r - 11) as u8));
+                i += r;
+            } else if len == 0 && run >= 3 {
+                cl_symbols.push((17u8, (run - 3) as u8));
+                i += run;
+            } else if len != 0 && run >= 4 {
+                cl_symbols.push((len, 0u8));
+                let r = num::min(run - 1, 6);
+                cl_symbols.push((16u8, (r - 3) as u8));
+                i += 1 + r;
+            } else {
+                cl_symbols.push((len, 0u8));
+                i += 1;
+            }
+        }
+
+        let mut cl_freq = vec::from_elem(NUM_CODE_LENGTH_CODES, 0u);
+        for &(sym, _) in cl_symbols.iter() {
+            cl_freq[sym as uint] += 1;
+        }
+        let cl_lens = huffman_lengths(cl_freq, MAX_CODE_LENGTH_BITS);
+        let mut num_cl = NUM_CODE_LENGTH_CODES;
+        while num_cl > 4 && cl_lens[CODE_LENGTH_ORDER[num_cl - 1]] == 0 {
+            num_cl -= 1;
+        }
+
+        DynamicHeader {
+            num_lit:    num_lit,
+            num_dist:   num_dist,
+            num_cl:     num_cl,
+            cl_lens:    cl_lens,
+            cl_symbols: cl_symbols,
+        }
+    }
+
+    fn bits(&self) -> uint {
+        self.cl_symbols.iter().fold(5 + 5 + 4 + 3 * self.num_cl, |total, &(sym, _)| {
+            total + self.cl_lens[sym as uint] as uint + extra_cl_bits(sym)
+        })
+    }
+
+    fn write(&self, writer: &mut BitWriter<Lsb>) {
+        writer.write_bits((self.num_lit - 257) as u32, 5);
+        writer.write_bits((self.num_dist - 1) as u32, 5);
+        writer.write_bits((self.num_cl - 4) as u32, 4);
+        for i in range(0, self.num_cl) {
+            writer.write_bits(self.cl_lens[CODE_LENGTH_ORDER[i]] as u32, 3);
+        }
+        let cl_codes = huffman_codes(self.cl_lens);
+        for &(sym, extra) in self.cl_symbols.iter() {
+            writer.write_bits(cl_codes[sym as uint] as u32, self.cl_lens[sym as uint] as uint);
+            writer.write_bits(extra as u32, extra_cl_bits(sym));
+        }
+    }
+
+}
+
+fn extra_cl_bits(sym: u8) -> uint {
+    match sym {
+        16  => 2,
+        17  => 3,
+        18  => 7,
+        _   => 0
+    }
+}
+
+// The number of lengths up to the last non-zero one.
+fn last_used(lens: &[u8]) -> uint {
+    let mut count = lens.len();
+    while count > 0 && lens[count - 1] == 0 {
+        count -= 1;
+    }
+    count
+}
+
+fn hash(buf: &[u8], pos: uint) -> uint {
+    (((buf[pos] as uint) << 10) ^ ((buf[pos + 1] as uint) << 5) ^ (buf[pos + 2] as uint)) & (HASH_SIZE - 1)
+}
+
+// Add the positions up to pos to the hash chains.
+fn insert_hashes(buf: &[u8], head: &mut [i32], prev: &mut [i32], inserted: &mut uint, pos: uint) {
+    while *inserted < pos {
+        let i = *inserted;
+        if i + MIN_MATCH <= buf.len() {
+            let h = hash(buf, i);
+            prev[i] = head[h];
+            head[h] = i as i32;
+        }
+        *inserted += 1;
+    }
+}
+
+fn match_len(buf: &[u8], from: uint, pos: uint, max_len: uint) -> uint {
+    let mut len = 0u;
+    while len < max_len && buf[from + len] == buf[pos + len] {
+        len += 1;
+    }
+    len
+}
+
+fn len_code(len: uint) -> uint {
+    let mut code = LEN_BASE.len() - 1;
+    while LEN_BASE[code] > len {
+        code -= 1;
+    }
+    code
+}
+
+fn dist_code(dist: uint) -> uint {
+    let mut code = DIST_BASE.len() - 1;
+    while DIST_BASE[code] > dist {
+        code -= 1;
+    }
+    code
+}
+
+/// The code lengths of the static Huffman codes, of the literal/length codes and of the distance codes.
+pub fn static_lengths() -> (~[u8], ~[u8]) {
+    let lit_lens = vec::from_fn(288, |i| if i < 144 { 8u8 } else if i < 256 { 9 } else if i < 280 { 7 } else { 8 });
+    let dist_lens = vec::from_elem(NUM_DIST_CODES, 5u8);
+    (lit_lens, dist_lens)
+}
+
+// The number of bits of the block data coded with the lengths, excluding the header.
+fn data_bits(lit_freq: &[uint], dist_freq: &[uint], lit_lens: &[u8], dist_lens: &[u8]) -> uint {
+    let mut bits = 0u;
+    for i in range(0, lit_freq.len()) {
+        bits += lit_freq[i] * lit_lens[i] as uint;
+        if i > END_OF_BLOCK {
+            bits += lit_freq[i] * LEN_EXTRA[i - 257];
+        }
+    }
+    for i in range(0, dist_freq.len()) {
+        bits += dist_freq[i] * (dist_lens[i] as uint + DIST_EXTRA[i]);
+    }
+    bits
+}
+
+// The number of bits of the data in stored blocks, starting after pending_bits bits of a partial byte.
+fn stored_block_bits(data_len: uint, pending_bits: uint) -> uint {
+    let blocks = num::max(1, (data_len + MAX_STORED_LEN - 1) / MAX_STORED_LEN);
+    let first_pad = (8 - (pending_bits + 3) % 8) % 8;
+    3 + first_pad + (blocks - 1) * (3 + 5) + blocks * 32 + data_len * 8
+}
+
+/// Compute the length-limited Huffman code lengths of the symbols with the frequencies.  The unused symbols
+/// get 0.  A lone used symbol gets a partner of length 1, so the code is always complete.
+pub fn huffman_lengths(freqs: &[uint], max_len: uint) -> ~[u8] {
+    let mut lens = vec::from_elem(freqs.len(), 0u8);
+    let mut syms = range(0, freqs.len()).filter(|&i| freqs[i] > 0).collect::<~[uint]>();
+    if syms.len() == 0 {
+        return lens;
+    }
+    if syms.len() == 1 {
+        lens[syms[0]] = 1;
+        lens[if syms[0] == 0 { 1 } else { 0 }] = 1;
+        return lens;
+    }
+    syms.sort_by(|a, b| freqs[*a].cmp(&freqs[*b]));
+
+    // Build the tree with the sorted leaves and the internal nodes in the order they're made, both ascending.
+    let leaf_count = syms.len();
+    let node_count = 2 * leaf_count - 1;
+    let mut weight = vec::from_elem(node_count, 0u);
+    let mut parent = vec::from_elem(node_count, 0u);
+    for i in range(0, leaf_count) {
+        weight[i] = freqs[syms[i]];
+    }
+    let mut next_leaf = 0u;
+    let mut next_node = leaf_count;
+    for node in range(leaf_count, node_count) {
+        for _ in range(0, 2) {
+            let child = if next_leaf < leaf_count && (next_node >= node || weight[next_leaf] <= weight[next_node]) {
+                next_leaf += 1;
+                next_leaf - 1
+            } else {
+                next_node += 1;
+                next_node - 1
+            };
+            weight[node] += weight[child];
+            parent[child] = node;
+        }
+    }
+
+    // The depths of the leaves, counted per length with the ones too long moved to max_len.
+    let mut depth = vec::from_elem(node_count, 0u);
+    let mut count = vec::from_elem(max_len + 1, 0u);
+    let mut i = node_count - 1;
+    while i > 0 {
+        i -= 1;
+        depth[i] = depth[parent[i]] + 1;
+        if i < leaf_count {
+            count[num::min(depth[i], max_len)] += 1;
+        }
+    }
+
+    // Shorten the code until it fits, moving a leaf down from the longest length that has room each time.
+    let mut total = 0u;
+    for len in range(1, max_len + 1) {
+        total += count[len] << (max_len - len);
+    }
+    while total > (1 << max_len) {
+        count[max_len] -= 1;
+        let mut len = max_len - 1;
+        while len > 0 {
+            if count[len] != 0 {
+                count[len] -= 1;
+                count[len + 1] += 2;
+                break;
+            }
+            len -= 1;
+        }
+        total -= 1;
+    }
+
+    // The least frequent symbols get the longest codes.
+    let mut next_sym = 0u;
+    let mut len = max_len;
+    while len > 0 {
+        for _ in range(0, count[len]) {
+            lens[syms[next_sym]] = len as u8;
+            next_sym += 1;
+        }
+        len -= 1;
+    }
+    lens
+}
+
+/// Compute the canonical Huffman codes of the code lengths, bit-reversed to be written least significant bit first.
+pub fn huffman_codes(lens: &[u8]) -> ~[u16] {
+    let mut len_count = [0u, ..16];
+    for &len in lens.iter() {
+        if len > 0 {
+            len_count[len as uint] += 1;
+        }
+    }
+    let mut next_code = [0u, ..16];
+    let mut code = 0u;
+    for len in range(1, MAX_CODE_BITS + 1) {
+        code = (code + len_count[len - 1]) << 1;
+        next_code[len] = code;
+    }
+
+    let mut codes = vec::from_elem(lens.len(), 0u16);
+    for i in range(0, lens.len()) {
+        let len = lens[i] as uint;
+        if len > 0 {
+            codes[i] = reverse_bits(next_code[len], len) as u16;
+            next_code[len] += 1;
+        }
+    }
+    codes
+}
+
+fn reverse_bits(code: uint, len: uint) -> uint {
+    let mut reversed = 0u;
+    for i in range(0, len) {
+        reversed |= ((code >> i) & 1) << (len - 1 - i);
+    }
+    reversed
+}
+
+
+/// Allocate the compressor handle, to be initialized by tdefl_init().
+pub unsafe fn tdefl_compressor_alloc() -> *c_void {
+    cast::transmute(~NativeCompressor::new())
+}
+
+/// Free the compressor handle.
+pub unsafe fn tdefl_compressor_free(tdefl_compressor: *c_void) {
+    let _compressor: ~NativeCompressor = cast::transmute(tdefl_compressor);
+}
+
+/// Initialize or re-initialize the compressor with the tdefl flags.
+pub unsafe fn tdefl_init(tdefl_compressor: *c_void, compress_flags: c_int) -> c_int {
+    let compressor: &mut NativeCompressor = cast::transmute(tdefl_compressor);
+    compressor.init(compress_flags as uint);
+    TDEFL_STATUS_OKAY
+}
+
+/// Compress the input buffer into the output buffer with the tdefl flush mode.
+/// The sizes are updated to the bytes consumed and the bytes written.
+pub unsafe fn tdefl_compress(tdefl_compressor: *c_void,
+                             pIn_buf: *c_void,
+                             pIn_buf_size: *mut size_t,
+                             pOut_buf: *c_void,
+                             pOut_buf_size: *mut size_t,
+                             tdefl_flush: c_int) -> c_int {
+    let compressor: &mut NativeCompressor = cast::transmute(tdefl_compressor);
+    if !compressor.is_initialized() {
+        return TDEFL_STATUS_BAD_PARAM;
+    }
+    vec::raw::buf_as_slice(pIn_buf as *u8, *pIn_buf_size as uint, |input| {
+        vec::raw::mut_buf_as_slice(pOut_buf as *mut u8, *pOut_buf_size as uint, |output| {
+            let (in_used, out_used, done) = compressor.compress(input, output, tdefl_flush);
+            *pIn_buf_size = in_used as size_t;
+            *pOut_buf_size = out_used as size_t;
+            if done { TDEFL_STATUS_DONE } else { TDEFL_STATUS_OKAY }
+        })
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use super::{NativeCompressor, huffman_lengths, huffman_codes};
+    use super::{TDEFL_NO_FLUSH, TDEFL_SYNC_FLUSH, TDEFL_FINISH};
+    use super::super::deflate::{inflate_bytes, inflate_bytes_zlib};
+
+
+    // Compress all the data with a small output buffer, as the Deflator loops do.
+    fn compress_all(compressor: &mut NativeCompressor, data: &[u8]) -> ~[u8] {
+        let mut output = ~[];
+        let mut out_buf = [0u8, ..1000];
+        let mut in_offset = 0u;
+        loop {
+            let (in_used, out_used, done) = compressor.compress(data.slice_from(in_offset), out_buf, TDEFL_FINISH);
+            in_offset += in_used;
+            output.push_all(out_buf.slice_to(out_used));
+            if done {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_huffman_lengths() {
+        let lens = huffman_lengths([10u, 0, 1, 1, 2, 4], 15);
+        assert_eq!(lens, ~[1u8, 0, 4, 4, 3, 2]);
+
+        // Fibonacci frequencies make a deep tree; limited to 7 bits, the code is still complete.
+        let freqs = [1u, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 377];
+        let lens = huffman_lengths(freqs, 7);
+        assert!(( lens.iter().all(|&len| len > 0 && len <= 7) ));
+        assert_eq!(lens.iter().fold(0u, |total, &len| total + (1 << (7 - len))), 1 << 7);
+
+        assert_eq!(huffman_lengths([0u, 0, 5], 15), ~[1u8, 0, 1]);
+
+        // RFC 1951 example: lengths (3, 3, 3, 3, 3, 2, 4, 4) give the codes 010, 011, ... , 00, 1110, 1111.
+        let codes = huffman_codes([3u8, 3, 3, 3, 3, 2, 4, 4]);
+        assert_eq!(codes, ~[0x2u16, 0x6, 0x1, 0x5, 0x3, 0x0, 0x7, 0xF]);
+    }
+
+    #[test]
+    fn test_native_compress() {
+        let text = bytes!("The quick brown fox jumps over the lazy dog.  ").to_owned();
+        let mut data = ~[];
+        for i in range(0u, 4000) {
+            data.push_all(text);
+            data.push((i % 251) as u8);
+        }
+        let random = vec::from_fn(70000, |i| ((i * 7919 + (i >> 3) * 104729) % 251) as u8);
+
+        for &probes in [0x001u, 0x020, 0xFFF].iter() {
+            for &flags in [0u, 0x04000, 0x10000, 0x20000, 0x40000, 0x80000].iter() {
+                let mut compressor = NativeCompressor::new();
+                compressor.init(probes | flags);
+                let comp_buf = compress_all(&mut compressor, data);
+                assert!(( inflate_bytes(comp_buf) == data ));
+                if flags != 0x10000 && flags != 0x80000 {
+                    assert!(( comp_buf.len() < data.len() / 4 ));
+                }
+
+                compressor.init(probes | flags | 0x01000);
+                let comp_buf = compress_all(&mut compressor, random);
+                assert!(( inflate_bytes_zlib(comp_buf) == random ));
+                assert!(( comp_buf.len() < random.len() + 64 ));
+
+                compressor.init(probes | flags);
+                assert!(( inflate_bytes(compress_all(&mut compressor, [])).len() == 0 ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_sync_flush() {
+        let data = bytes!("ABCDEFGHABCDEFGHABCDEFGH").to_owned();
+        let mut compressor = NativeCompressor::new();
+        compressor.init(0x020);
+        let mut out_buf = [0u8, ..256];
+        let (in_used, out_used, done) = compressor.compress(data, out_buf, TDEFL_NO_FLUSH);
+        assert!(( in_used == data.len() && out_used == 0 && !done ));
+        let (_, out_used, _) = compressor.compress([], out_buf, TDEFL_SYNC_FLUSH);
+        assert!(( out_buf.slice(out_used - 4, out_used).to_owned() == ~[0x00u8, 0x00, 0xFF, 0xFF] ));
+        // A repeated flush without new input adds nothing.
+        let (_, out_used2, _) = compressor.compress([], out_buf.mut_slice_from(out_used), TDEFL_SYNC_FLUSH);
+        assert_eq!(out_used2, 0);
+        let (_, out_used3, done) = compressor.compress([], out_buf.mut_slice_from(out_used), TDEFL_FINISH);
+        assert!(( done ));
+        assert!(( inflate_bytes(out_buf.slice_to(out_used + out_used3)) == data ));
+    }
+
+}
@@ -0,0 +1,675 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: native_inflate.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The native_inflate module is the DEFLATE decompressor (RFC 1951) written in Rust, the
+BackendNative of the Inflator.  It takes the same tinfl flags and returns the same
+tinfl status codes as miniz.  The Inflator calls NativeDecompressor on its buffers
+directly; the tinfl functions on top of it are the runtime backend of deflate.rs
+when built with --cfg native, without the miniz of the runtime.
+
+The decompressor is a state machine that stops wherever the input runs out or the
+output fills up, and picks up from there on the next call.  The bits are read with
+the BitReader of the bitstream module, one whole field at a time: a field cut off
+at the end of the input is read again after more input comes in.  The input is
+pulled in only as far as the fields go, so at the end of the compressed data the
+bytes after it are left unconsumed for the outer stream.
+
+The decompressor keeps the last 32K of output in its own window for the matches to
+copy from, so the output buffer of a call can be of any size and it needn't hold
+the earlier output.
+
+*/
+
+
+use std::{cast, num, vec};
+use std::libc::{c_void, size_t, c_int, c_uint};
+use super::bitstream::{BitReader, Lsb};
+use super::deflate::update_adler32;
+use super::native_deflate::{huffman_codes, static_lengths, WINDOW_SIZE};
+use super::native_deflate::{LEN_BASE, LEN_EXTRA, DIST_BASE, DIST_EXTRA, CODE_LENGTH_ORDER};
+
+
+// The tinfl flags and status codes of miniz.
+static TINFL_FLAG_PARSE_ZLIB_HEADER : uint = 1;
+static TINFL_FLAG_HAS_MORE_INPUT : uint = 2;
+
+static TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS : c_int = -4;
+static TINFL_STATUS_ADLER32_MISMATCH : c_int            = -2;
+static TINFL_STATUS_FAILED : c_int                      = -1;
+static TINFL_STATUS_DONE : c_int                        = 0;
+static TINFL_STATUS_NEEDS_MORE_INPUT : c_int            = 1;
+static TINFL_STATUS_HAS_MORE_OUTPUT : c_int             = 2;
+
+static END_OF_BLOCK : uint = 256;
+static MAX_LIT_CODES : uint = 286;
+static MAX_DIST_CODES : uint = 30;
+
+
+// Where the decompression is in the stream.
+#[deriving(Clone)]
+enum State {
+    StateStart,
+    StateZlibHeader,
+    StateBlockHeader,
+    StateStoredLen,
+    StateStored(uint),                  // the bytes left in the stored block
+    StateTableCounts,
+    StateCodeLengthLens(uint),          // the number of the code length code lengths read
+    StateCodeLens,
+    StateCodes,
+    StateCopy(uint, uint),              // the length left and the distance of the match being copied
+    StateTrailer,
+    StateDone,
+    StateFailed,
+}
+
+// Why a step of the decompression stops.
+enum Stop {
+    StopNeedsInput,
+    StopOutputFull,
+    StopFailed,
+    StopAdler32Mismatch,
+}
+
+
+// The decoding table of a Huffman code, indexed by the next max_len bits of the input.  The entries are
+// the symbol << 4 | the code length, or 0 where no code matches.
+#[deriving(Clone)]
+struct HuffmanTable {
+    entries:    ~[u16],
+    max_len:    uint,
+}
+
+impl HuffmanTable {
+
+    fn empty() -> HuffmanTable {
+        HuffmanTable { entries: ~[0u16], max_len: 0 }
+    }
+
+    // Build the table of the code lengths.  None if the lengths are over-subscribed.
+    fn new(lens: &[u8]) -> Option<HuffmanTable> {
+        let max_len = lens.iter().fold(0u, |max_len, &len| num::max(max_len, len as uint));
+        let total = lens.iter().fold(0u, |total, &len| if len > 0 { total + (1 << (max_len - len as uint)) } else { total });
+        if total > (1 << max_len) {
+            return None;
+        }
+
+        let codes = huffman_codes(lens);
+        let mut entries = vec::from_elem(1 << max_len, 0u16);
+        for sym in range(0, lens.len()) {
+            let len = lens[sym] as uint;
+            if len > 0 {
+                // All the indexes starting with the code bits
+                let mut index = codes[sym] as uint;
+                while index < entries.len() {
+                    entries[index] = (sym << 4 | len) as u16;
+                    index += 1 << len;
+                }
+            }
+        }
+        Some(HuffmanTable { entries: entries, max_len: max_len })
+    }
+
+}
+
+
+/// The DEFLATE decompressor of one stream.
+/// A clone carries on from the same point of the stream, e.g. from right after set_dictionary().
+#[deriving(Clone)]
+pub struct NativeDecompressor {
+    priv state:         State,
+    priv reader:        BitReader<Lsb>,
+    priv final_block:   bool,
+    priv zlib_header:   bool,
+    priv adler32:       u32,
+    priv dict_id:       Option<u32>,    // the ADLER32 of the preset dictionary
+    priv window:        ~[u8],          // the last WINDOW_SIZE bytes decompressed, as a ring buffer
+    priv out_count:     uint,           // the number of bytes decompressed, the position in the window
+    priv lit_table:     HuffmanTable,
+    priv dist_table:    HuffmanTable,
+    priv cl_table:      HuffmanTable,
+    priv lit_count:     uint,           // the number of literal/length codes of the dynamic block
+    priv dist_count:    uint,
+    priv cl_count:      uint,
+    priv lens:          ~[u8],          // the code lengths of the dynamic block header read so far
+}
+
+impl NativeDecompressor {
+
+    /// Create the decompressor for a new stream.
+    pub fn new() -> NativeDecompressor {
+        NativeDecompressor {
+            state:          StateStart,
+            reader:         BitReader::new(),
+            final_block:    false,
+            zlib_header:    false,
+            adler32:        1,
+            dict_id:        None,
+            window:         vec::from_elem(WINDOW_SIZE, 0u8),
+            out_count:      0,
+            lit_table:      HuffmanTable::empty(),
+            dist_table:     HuffmanTable::empty(),
+            cl_table:       HuffmanTable::empty(),
+            lit_count:      0,
+            dist_count:     0,
+            cl_count:       0,
+            lens:           ~[],
+        }
+    }
+
+    /// Set the preset dictionary before the first decompress() call.  The matches of the stream can refer back
+    /// into its last WINDOW_SIZE bytes.  The zlib data with the FDICT flag must have the ADLER32 of the dictionary
+    /// as the DICTID; without a dictionary, they fail.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        let keep = num::min(WINDOW_SIZE, dictionary.len());
+        for &byte in dictionary.slice_from(dictionary.len() - keep).iter() {
+            self.window[self.out_count & (WINDOW_SIZE - 1)] = byte;
+            self.out_count += 1;
+        }
+        self.dict_id = Some(update_adler32(1, dictionary));
+    }
+
+    /// Decompress the input into the output with the tinfl flags.  Return the number of input bytes consumed,
+    /// the number of bytes written to output, and the tinfl status.  The zlib header flag is taken from the first
+    /// call.  At the end of the compressed data, the input bytes after it are not consumed.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8], decompress_flags: uint) -> (uint, uint, c_int) {
+        let mut in_pos = 0u;
+        let mut out_pos = 0u;
+        let mut adler_begin = 0u;
+        let mut status = TINFL_STATUS_DONE;
+
+        match self.state {
+            StateStart => {
+                self.zlib_header = decompress_flags & TINFL_FLAG_PARSE_ZLIB_HEADER != 0;
+                self.state = if self.zlib_header { StateZlibHeader } else { StateBlockHeader };
+            },
+            _ => ()
+        }
+
+        loop {
+            match self.state {
+                StateDone => break,
+                StateTrailer => {
+                    // The ADLER32 of the output so far is needed for checking.
+                    self.update_adler32(output.slice(adler_begin, out_pos));
+                    adler_begin = out_pos;
+                },
+                _ => ()
+            }
+            match self.step(input, &mut in_pos, output, &mut out_pos) {
+                Ok(()) => (),
+                Err(stop) => {
+                    status = match stop {
+                        StopNeedsInput if decompress_flags & TINFL_FLAG_HAS_MORE_INPUT != 0 => TINFL_STATUS_NEEDS_MORE_INPUT,
+                        StopNeedsInput      => TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS,
+                        StopOutputFull      => TINFL_STATUS_HAS_MORE_OUTPUT,
+                        StopFailed          => TINFL_STATUS_FAILED,
+                        StopAdler32Mismatch => TINFL_STATUS_ADLER32_MISMATCH,
+                    };
+                    break;
+                }
+            }
+        }
+        self.update_adler32(output.slice(adler_begin, out_pos));
+
+        match self.state {
+            StateDone => {
+                // Hand back the whole bytes pulled in past the end.
+                self.reader.align_byte();
+                in_pos -= num::min(self.reader.available() / 8, in_pos);
+                self.reader.reset();
+            },
+            _ => ()
+        }
+        (in_pos, out_pos, status)
+    }
+
+    // Decompress one field of the stream, or a run of the data of a stored block or a match, and move on to the
+    // next state.  The bits are consumed only after the whole field is in.
+    fn step(&mut self, input: &[u8], in_pos: &mut uint, output: &mut [u8], out_pos: &mut uint) -> Result<(), Stop> {
+        match self.state {
+            StateZlibHeader => {
+                if !self.reader.fill(input, in_pos, 16) {
+                    return Err(StopNeedsInput);
+                }
+                let cmf = self.reader.peek_bits(0, 8);
+                let flg = self.reader.peek_bits(8, 8);
+                // The DEFLATE method with up to 32K window.
+                if (cmf * 256 + flg) % 31 != 0 || cmf & 0x0F != 8 || cmf >> 4 > 7 {
+                    return self.fail();
+                }
+                if flg & 0x20 != 0 {
+                    // The DICTID of the preset dictionary follows, the ADLER32 of it in big-endian.
+                    if !self.reader.fill(input, in_pos, 48) {
+                        return Err(StopNeedsInput);
+                    }
+                    let dict_id = (self.reader.peek_bits(16, 8) << 24) | (self.reader.peek_bits(24, 8) << 16) |
+                                  (self.reader.peek_bits(32, 8) << 8) | self.reader.peek_bits(40, 8);
+                    self.reader.consume(48);
+                    if self.dict_id != Some(dict_id) {
+                        return self.fail();
+                    }
+                } else {
+                    self.reader.consume(16);
+                }
+                self.state = StateBlockHeader;
+            },
+            StateBlockHeader => {
+                if !self.reader.fill(input, in_pos, 3) {
+                    return Err(StopNeedsInput);
+                }
+                let header = self.reader.peek_bits(0, 3);
+                self.reader.consume(3);
+                self.final_block = header & 1 != 0;
+                match header >> 1 {
+                    0 => {
+                        self.reader.align_byte();
+                        self.state = StateStoredLen;
+                    },
+                    1 => {
+                        let (lit_lens, dist_lens) = static_lengths();
+                        self.lit_table = HuffmanTable::new(lit_lens).unwrap();
+                        self.dist_table = HuffmanTable::new(dist_lens).unwrap();
+                        self.state = StateCodes;
+                    },
+                    2 => self.state = StateTableCounts,
+                    _ => return self.fail()
+                }
+            },
+            StateStoredLen => {
+                if !self.reader.fill(input, in_pos, 32) {
+                    return Err(StopNeedsInput);
+                }
+                let len = self.reader.peek_bits(0, 16);
+                let nlen = self.reader.peek_bits(16, 16);
+                self.reader.consume(32);
+                if len != !nlen & 0xFFFF {
+                    return self.fail();
+                }
+                self.state = StateStored(len as uint);
+            },
+            StateStored(left) => {
+                if left == 0 {
+                    self.state = self.end_block_state();
+                } else if *out_pos == output.len() {
+                    return Err(StopOutputFull);
+                } else if self.reader.available() >= 8 {
+                    let byte = self.reader.peek_bits(0, 8) as u8;
+                    self.reader.consume(8);
+                    self.put(output, out_pos, byte);
+                    self.state = StateStored(left - 1);
+                } else if *in_pos == input.len() {
+                    return Err(StopNeedsInput);
+                } else {
+                    let copy_len = num::min(left, num::min(input.len() - *in_pos, output.len() - *out_pos));
+                    for i in range(*in_pos, *in_pos + copy_len) {
+                        self.put(output, out_pos, input[i]);
+                    }
+                    *in_pos += copy_len;
+                    self.state = StateStored(left - copy_len);
+                }
+            },
+            StateTableCounts => {
+                if !self.reader.fill(input, in_pos, 14) {
+                    return Err(StopNeedsInput);
+                }
+                self.lit_count = self.reader.peek_bits(0, 5) as uint + 257;
+                self.dist_count = self.reader.peek_bits(5, 5) as uint + 1;
+                self.cl_count = self.reader.peek_bits(10, 4) as uint + 4;
+                self.reader.consume(14);
+                if self.lit_count > MAX_LIT_CODES || self.dist_count > MAX_DIST_CODES {
+                    return self.fail();
+                }
+                self.lens = vec::from_elem(CODE_LENGTH_ORDER.len(), 0u8);
+                self.state = StateCodeLengthLens(0);
+            },
+            StateCodeLengthLens(count) => {
+                if count < self.cl_count {
+                    if !self.reader.fill(input, in_pos, 3) {
+                        return Err(StopNeedsInput);
+                    }
+                    self.lens[CODE_LENGTH_ORDER[count]] = self.reader.peek_bits(0, 3) as u8;
+                    self.reader.consume(3);
+                    self.state = StateCodeLengthLens(count + 1);
+                } else {
+                    match HuffmanTable::new(self.lens) {
+                        Some(table) => self.cl_table = table,
+                        None => return self.fail()
+                    }
+                    self.lens = vec::with_capacity(self.lit_count + self.dist_count);
+                    self.state = StateCodeLens;
+                }
+            },
+            StateCodeLens => {
+                if self.lens.len() < self.lit_count + self.dist_count {
+                    return self.read_code_len(input, in_pos);
+                }
+                if self.lens[END_OF_BLOCK] == 0 {
+                    return self.fail();
+                }
+                match (HuffmanTable::new(self.lens.slice_to(self.lit_count)),
+                       HuffmanTable::new(self.lens.slice_from(self.lit_count))) {
+                    (Some(lit_table), Some(dist_table)) => {
+                        self.lit_table = lit_table;
+                        self.dist_table = dist_table;
+                    },
+                    _ => return self.fail()
+                }
+                self.state = StateCodes;
+            },
+            StateCodes => {
+                if *out_pos == output.len() {
+                    return Err(StopOutputFull);
+                }
+                return self.read_code(input, in_pos, output, out_pos);
+            },
+            StateCopy(len, dist) => {
+                let copy_len = num::min(len, output.len() - *out_pos);
+                if copy_len == 0 {
+                    return Err(StopOutputFull);
+                }
+                for _ in range(0, copy_len) {
+                    let byte = self.window[(self.out_count - dist) & (WINDOW_SIZE - 1)];
+                    self.put(output, out_pos, byte);
+                }
+                self.state = if copy_len == len { StateCodes } else { StateCopy(len - copy_len, dist) };
+            },
+            StateTrailer => {
+                self.reader.align_byte();
+                if !self.reader.fill(input, in_pos, 32) {
+                    return Err(StopNeedsInput);
+                }
+                // The ADLER32 is in the big-endian order.
+                let expected = (self.reader.peek_bits(0, 8) << 24) | (self.reader.peek_bits(8, 8) << 16) |
+                               (self.reader.peek_bits(16, 8) << 8) | self.reader.peek_bits(24, 8);
+                self.reader.consume(32);
+                self.state = StateDone;
+                if expected != self.adler32 {
+                    return Err(StopAdler32Mismatch);
+                }
+            },
+            StateFailed => return Err(StopFailed),
+            StateStart | StateDone => ()
+        }
+        Ok(())
+    }
+
+    // Read one code length of the dynamic block header, or a run of them.
+    fn read_code_len(&mut self, input: &[u8], in_pos: &mut uint) -> Result<(), Stop> {
+        let (sym, sym_len) = match decode(&mut self.reader, input, in_pos, &self.cl_table, 0) {
+            Ok(decoded) => decoded,
+            Err(stop) => return self.stop(stop)
+        };
+        if sym < 16 {
+            self.reader.consume(sym_len);
+            self.lens.push(sym as u8);
+            return Ok(());
+        }
+
+        // The repeat of the previous length or of 0.
+        let (extra_bits, base) = match sym {
+            16  => (2, 3),
+            17  => (3, 3),
+            _   => (7, 11)
+        };
+        if !self.reader.fill(input, in_pos, sym_len + extra_bits) {
+            return Err(StopNeedsInput);
+        }
+        let repeat = base + self.reader.peek_bits(sym_len, extra_bits) as uint;
+        self.reader.consume(sym_len + extra_bits);
+        let len = match sym {
+            16 if self.lens.len() == 0  => return self.fail(),
+            16                          => self.lens[self.lens.len() - 1],
+            _                           => 0
+        };
+        if self.lens.len() + repeat > self.lit_count + self.dist_count {
+            return self.fail();
+        }
+        for _ in range(0, repeat) {
+            self.lens.push(len);
+        }
+        Ok(())
+    }
+
+    // Read one literal, a match, or the end of the block.  A match is read with its length and distance as one field.
+    fn read_code(&mut self, input: &[u8], in_pos: &mut uint, output: &mut [u8], out_pos: &mut uint) -> Result<(), Stop> {
+        let (sym, sym_len) = match decode(&mut self.reader, input, in_pos, &self.lit_table, 0) {
+            Ok(decoded) => decoded,
+            Err(stop) => return self.stop(stop)
+        };
+        if sym < END_OF_BLOCK {
+            self.reader.consume(sym_len);
+            self.put(output, out_pos, sym as u8);
+            return Ok(());
+        }
+        if sym == END_OF_BLOCK {
+            self.reader.consume(sym_len);
+            self.state = self.end_block_state();
+            return Ok(());
+        }
+        if sym - 257 >= LEN_BASE.len() {
+            return self.fail();
+        }
+
+        let len_bits = LEN_EXTRA[sym - 257];
+        if !self.reader.fill(input, in_pos, sym_len + len_bits) {
+            return Err(StopNeedsInput);
+        }
+        let len = LEN_BASE[sym - 257] + self.reader.peek_bits(sym_len, len_bits) as uint;
+        let skip = sym_len + len_bits;
+
+        let (dist_sym, dist_sym_len) = match decode(&mut self.reader, input, in_pos, &self.dist_table, skip) {
+            Ok(decoded) => decoded,
+            Err(stop) => return self.stop(stop)
+        };
+        if dist_sym >= DIST_BASE.len() {
+            return self.fail();
+        }
+        let dist_bits = DIST_EXTRA[dist_sym];
+        if !self.reader.fill(input, in_pos, skip + dist_sym_len + dist_bits) {
+            return Err(StopNeedsInput);
+        }
+        let dist = DIST_BASE[dist_sym] + self.reader.peek_bits(skip + dist_sym_len, dist_bits) as uint;
+        self.reader.consume(skip + dist_sym_len + dist_bits);
+        if dist > self.out_count {
+            return self.fail();
+        }
+        self.state = StateCopy(len, dist);
+        Ok(())
+    }
+
+    fn end_block_state(&self) -> State {
+        if !self.final_block {
+            StateBlockHeader
+        } else if self.zlib_header {
+            StateTrailer
+        } else {
+            StateDone
+        }
+    }
+
+    // Write one byte of output, keeping it in the window.
+    fn put(&mut self, output: &mut [u8], out_pos: &mut uint, byte: u8) {
+        output[*out_pos] = byte;
+        *out_pos += 1;
+        self.window[self.out_count & (WINDOW_SIZE - 1)] = byte;
+        self.out_count += 1;
+    }
+
+    fn update_adler32(&mut self, output: &[u8]) {
+        if self.zlib_header && output.len() > 0 {
+            self.adler32 = update_adler32(self.adler32, output);
+        }
+    }
+
+    fn stop(&mut self, stop: Stop) -> Result<(), Stop> {
+        match stop {
+            StopFailed  => self.fail(),
+            _           => Err(stop)
+        }
+    }
+
+    // The data are corrupted.  The decompressor stays failed.
+    fn fail(&mut self) -> Result<(), Stop> {
+        self.state = StateFailed;
+        Err(StopFailed)
+    }
+
+}
+
+// Decode the next symbol of the table after the first skip bits, pulling in the input as needed.
+// Return the symbol and its code length, without consuming it.
+fn decode(reader: &mut BitReader<Lsb>, input: &[u8], in_pos: &mut uint, table: &HuffmanTable, skip: uint) -> Result<(uint, uint), Stop> {
+    loop {
+        // The bits not pulled in yet read as 0.  The entry is right if its code is within the bits pulled in.
+        let entry = table.entries[reader.peek_bits(skip, table.max_len) as uint] as uint;
+        let len = entry & 0xF;
+        if len > 0 && skip + len <= reader.available() {
+            return Ok((entry >> 4, len));
+        }
+        if len == 0 && skip + table.max_len <= reader.available() {
+            return Err(StopFailed);
+        }
+        if !reader.fill(input, in_pos, reader.available() + 1) {
+            return Err(StopNeedsInput);
+        }
+    }
+}
+
+
+/// Allocate the decompressor handle.
+pub unsafe fn tinfl_decompressor_alloc() -> *c_void {
+    cast::transmute(~NativeDecompressor::new())
+}
+
+/// Free the decompressor handle.
+pub unsafe fn tinfl_decompressor_free(tinfl_decompressor: *c_void) {
+    let _decompressor: ~NativeDecompressor = cast::transmute(tinfl_decompressor);
+}
+
+/// Decompress the input buffer into the output buffer at pOut_buf_next with the tinfl flags.
+/// The sizes are updated to the bytes consumed and the bytes written.  The output before pOut_buf_next
+/// isn't used; the decompressor has its own window.
+pub unsafe fn tinfl_decompress(tinfl_decompressor: *c_void,
+                               pIn_buf_next: *c_void,
+                               pIn_buf_size: *mut size_t,
+                               _pOut_buf_start: *c_void,
+                               pOut_buf_next: *c_void,
+                               pOut_buf_size: *mut size_t,
+                               decompress_flags: c_uint) -> c_int {
+    let decompressor: &mut NativeDecompressor = cast::transmute(tinfl_decompressor);
+    vec::raw::buf_as_slice(pIn_buf_next as *u8, *pIn_buf_size as uint, |input| {
+        vec::raw::mut_buf_as_slice(pOut_buf_next as *mut u8, *pOut_buf_size as uint, |output| {
+            let (in_used, out_used, status) = decompressor.decompress(input, output, decompress_flags as uint);
+            *pIn_buf_size = in_used as size_t;
+            *pOut_buf_size = out_used as size_t;
+            status
+        })
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use super::NativeDecompressor;
+    use super::{TINFL_FLAG_PARSE_ZLIB_HEADER, TINFL_FLAG_HAS_MORE_INPUT};
+    use super::{TINFL_STATUS_DONE, TINFL_STATUS_NEEDS_MORE_INPUT, TINFL_STATUS_HAS_MORE_OUTPUT};
+    use super::{TINFL_STATUS_FAILED, TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS, TINFL_STATUS_ADLER32_MISMATCH};
+    use super::super::deflate::{deflate_bytes, deflate_bytes_zlib};
+
+
+    // Decompress all the data, in_size bytes of input and out_size bytes of output at a time.
+    // Return the output, the input consumed, and the last status.
+    fn decompress_all(data: &[u8], flags: uint, in_size: uint, out_size: uint) -> (~[u8], uint, int) {
+        let mut decompressor = NativeDecompressor::new();
+        let mut output = ~[];
+        let mut out_buf = vec::from_elem(out_size, 0u8);
+        let mut in_offset = 0u;
+        loop {
+            let in_end = if in_offset + in_size < data.len() { in_offset + in_size } else { data.len() };
+            let more_input = if in_end < data.len() { TINFL_FLAG_HAS_MORE_INPUT } else { 0 };
+            let (in_used, out_used, status) = decompressor.decompress(data.slice(in_offset, in_end), out_buf, flags | more_input);
+            in_offset += in_used;
+            output.push_all(out_buf.slice_to(out_used));
+            if status != TINFL_STATUS_NEEDS_MORE_INPUT && status != TINFL_STATUS_HAS_MORE_OUTPUT {
+                return (output, in_offset, status as int);
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_decompress() {
+        let mut data = ~[];
+        for i in range(0u, 3000) {
+            data.push_all(bytes!("The quick brown fox jumps over the lazy dog.  "));
+            data.push((i % 251) as u8);
+        }
+        let random = vec::from_fn(70000, |i| ((i * 7919 + (i >> 3) * 104729) % 251) as u8);
+        let comp_buf = deflate_bytes(data);
+        let comp_random = deflate_bytes(random);
+
+        for &(in_size, out_size) in [(1000000u, 1000000u), (1, 1000000), (1000000, 1), (7, 13), (4096, 65536)].iter() {
+            let (output, in_used, status) = decompress_all(comp_buf, 0, in_size, out_size);
+            assert!(( status == TINFL_STATUS_DONE as int && output == data && in_used == comp_buf.len() ));
+            let (output, _, status) = decompress_all(comp_random, 0, in_size, out_size);
+            assert!(( status == TINFL_STATUS_DONE as int && output == random ));
+        }
+
+        // The bytes after the compressed data are left.
+        let mut padded = comp_buf.clone();
+        padded.push_all([1u8, 2, 3]);
+        let (output, in_used, status) = decompress_all(padded, 0, 1000000, 1000000);
+        assert!(( status == TINFL_STATUS_DONE as int && output == data && in_used == comp_buf.len() ));
+
+        // The zlib header and the ADLER32.
+        let mut zlib_buf = deflate_bytes_zlib(data);
+        let (output, in_used, status) = decompress_all(zlib_buf, TINFL_FLAG_PARSE_ZLIB_HEADER, 100, 100);
+        assert!(( status == TINFL_STATUS_DONE as int && output == data && in_used == zlib_buf.len() ));
+        let last = zlib_buf.len() - 1;
+        zlib_buf[last] ^= 0xFF;
+        let (output, _, status) = decompress_all(zlib_buf, TINFL_FLAG_PARSE_ZLIB_HEADER, 100, 100);
+        assert!(( status == TINFL_STATUS_ADLER32_MISMATCH as int && output == data ));
+        let (_, _, status) = decompress_all(zlib_buf, 0, 100, 100);
+        assert_eq!(status, TINFL_STATUS_FAILED as int);
+
+        // The cut-off data.
+        let (output, _, status) = decompress_all(comp_buf.slice_to(comp_buf.len() / 2), 0, 100, 100);
+        assert!(( status == TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS as int && output.len() < data.len() ));
+    }
+
+    #[test]
+    fn test_native_decompress_blocks() {
+        // A stored block of "abc", then the final static block of the match of length 6 at distance 3.
+        let mut stream = ~[0x00u8, 0x03, 0x00, 0xFC, 0xFF, 0x61, 0x62, 0x63];
+        stream.push_all([0x83u8, 0x20, 0x00]);
+        let (output, in_used, status) = decompress_all(stream, 0, 1000, 1000);
+        assert_eq!(status, TINFL_STATUS_DONE as int);
+        assert_eq!(output, bytes!("abcabcabc").to_owned());
+        assert_eq!(in_used, stream.len());
+
+        // The reserved block type and the bad stored length fail.
+        let (_, _, status) = decompress_all([0x07u8, 0x00], 0, 1000, 1000);
+        assert_eq!(status, TINFL_STATUS_FAILED as int);
+        let (_, _, status) = decompress_all([0x01u8, 0x03, 0x00, 0xFC, 0xFE, 0x61, 0x62, 0x63], 0, 1000, 1000);
+        assert_eq!(status, TINFL_STATUS_FAILED as int);
+        // The match reaching before the start of the output fails.
+        let (_, _, status) = decompress_all([0x83u8, 0x20, 0x00], 0, 1000, 1000);
+        assert_eq!(status, TINFL_STATUS_FAILED as int);
+    }
+
+}
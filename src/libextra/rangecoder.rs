@@ -0,0 +1,454 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: rangecoder.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The rangecoder module provides the byte-oriented range coder used as the entropy
+stage of LZMA.  It's the building block for the LZMA/7z support and can be used
+standalone by the format implementers.
+
+The bits are coded with the adaptive probabilities of Prob, or as the direct bits
+with the fixed probability 0.5.  BitTree codes the n-bit symbols with a tree of
+Probs, from the most significant bit or in reverse as LZMA does for some fields.
+The encoder and the decoder must use the same sequence of Probs.
+
+RangeEncoder writes to a Writer and needs finish() at the end to flush the pending
+bytes.  RangeDecoder reads from a Reader; the truncated input raises io_error.
+
+Example:
+
+    let mut prob = Prob::new();
+    let mut encoder = RangeEncoder::new(MemWriter::new());
+    encoder.encode_bit(&mut prob, 1);
+    encoder.finish();
+    let buf = encoder.inner().inner();
+
+    let mut prob = Prob::new();
+    let mut decoder = RangeDecoder::new(MemReader::new(buf));
+    let bit = decoder.decode_bit(&mut prob);
+
+*/
+
+
+use std::vec;
+use std::io::{Reader, Writer, Decorator};
+use std::io::{io_error, IoError, OtherIoError};
+
+
+/// The number of bits of the probabilities.
+pub static PROB_BITS: uint = 11;
+/// The initial probability, 0.5.
+pub static PROB_INIT: u16 = (1 << PROB_BITS) / 2;
+/// The adaptation rate of the probabilities.
+pub static PROB_MOVE_BITS: uint = 5;
+
+static TOP_VALUE: u32 = 1 << 24;
+
+
+/// Adaptive probability of a bit being 0, as LZMA keeps them.
+#[deriving(Clone, Eq)]
+pub struct Prob {
+    priv value:     u16,
+}
+
+impl Prob {
+
+    /// Create a probability at 0.5.
+    pub fn new() -> Prob {
+        Prob { value: PROB_INIT }
+    }
+
+    /// The probability of the next bit being 0, scaled by 1 << PROB_BITS.
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    /// Create count probabilities at 0.5, for the models indexed by the context.
+    pub fn new_vec(count: uint) -> ~[Prob] {
+        vec::from_elem(count, Prob::new())
+    }
+
+    fn update(&mut self, bit: uint) {
+        if bit == 0 {
+            self.value += ((1 << PROB_BITS) - self.value) >> PROB_MOVE_BITS;
+        } else {
+            self.value -= self.value >> PROB_MOVE_BITS;
+        }
+    }
+
+}
+
+
+/// Tree of probabilities coding the symbols of num_bits bits.
+#[deriving(Clone)]
+pub struct BitTree {
+    priv num_bits:  uint,
+    priv probs:     ~[Prob],
+}
+
+impl BitTree {
+
+    /// Create a tree for the symbols of num_bits bits.
+    pub fn new(num_bits: uint) -> BitTree {
+        BitTree {
+            num_bits:   num_bits,
+            probs:      Prob::new_vec(1 << num_bits),
+        }
+    }
+
+    /// The number of bits of the symbols.
+    pub fn num_bits(&self) -> uint {
+        self.num_bits
+    }
+
+    /// Encode the symbol from its most significant bit.
+    pub fn encode<W: Writer>(&mut self, encoder: &mut RangeEncoder<W>, symbol: uint) {
+        let mut m = 1u;
+        let mut i = self.num_bits;
+        while i > 0 {
+            i -= 1;
+            let bit = (symbol >> i) & 1;
+            encoder.encode_bit(&mut self.probs[m], bit);
+            m = (m << 1) | bit;
+        }
+    }
+
+    /// Decode a symbol coded by encode().
+    pub fn decode<R: Reader>(&mut self, decoder: &mut RangeDecoder<R>) -> uint {
+        let mut m = 1u;
+        for _ in range(0, self.num_bits) {
+            m = (m << 1) | decoder.decode_bit(&mut self.probs[m]);
+        }
+        m - (1 << self.num_bits)
+    }
+
+    /// Encode the symbol from its least significant bit.
+    pub fn reverse_encode<W: Writer>(&mut self, encoder: &mut RangeEncoder<W>, symbol: uint) {
+        let mut m = 1u;
+        let mut sym = symbol;
+        for _ in range(0, self.num_bits) {
+            let bit = sym & 1;
+            sym = sym >> 1;
+            encoder.encode_bit(&mut self.probs[m], bit);
+            m = (m << 1) | bit;
+        }
+    }
+
+    /// Decode a symbol coded by reverse_encode().
+    pub fn reverse_decode<R: Reader>(&mut self, decoder: &mut RangeDecoder<R>) -> uint {
+        let mut m = 1u;
+        let mut symbol = 0u;
+        for i in range(0, self.num_bits) {
+            let bit = decoder.decode_bit(&mut self.probs[m]);
+            m = (m << 1) | bit;
+            symbol = symbol | (bit << i);
+        }
+        symbol
+    }
+
+}
+
+
+/// Range encoder writing the coded bytes to the inner writer.
+pub struct RangeEncoder<W> {
+    priv inner_writer:  W,
+    priv low:           u64,
+    priv range:         u32,
+    priv cache:         u8,
+    priv cache_size:    u64,
+    priv out_total:     u64,
+}
+
+impl<W: Writer> Decorator<W> for RangeEncoder<W> {
+    fn inner(self) -> W {
+        self.inner_writer
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a W {
+        &self.inner_writer
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        &mut self.inner_writer
+    }
+}
+
+impl<W: Writer> RangeEncoder<W> {
+
+    /// Create an encoder writing to the inner writer.
+    pub fn new(inner_writer: W) -> RangeEncoder<W> {
+        RangeEncoder {
+            inner_writer:   inner_writer,
+            low:            0,
+            range:          0xFFFFFFFF,
+            cache:          0,
+            cache_size:     1,
+            out_total:      0,
+        }
+    }
+
+    /// Encode the bit, 0 or 1, with the probability and adapt the probability to it.
+    pub fn encode_bit(&mut self, prob: &mut Prob, bit: uint) {
+        let bound = (self.range >> PROB_BITS) * (prob.value as u32);
+        if bit == 0 {
+            self.range = bound;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+        }
+        prob.update(bit);
+        while self.range < TOP_VALUE {
+            self.range = self.range << 8;
+            self.shift_low();
+        }
+    }
+
+    /// Encode the low count bits of value from the most significant one, with the fixed probability 0.5.
+    pub fn encode_direct_bits(&mut self, value: u32, count: uint) {
+        let mut i = count;
+        while i > 0 {
+            i -= 1;
+            self.range = self.range >> 1;
+            if (value >> i) & 1 == 1 {
+                self.low += self.range as u64;
+            }
+            while self.range < TOP_VALUE {
+                self.range = self.range << 8;
+                self.shift_low();
+            }
+        }
+    }
+
+    /// Flush the pending bytes.  Call it once after the last bit.
+    pub fn finish(&mut self) {
+        for _ in range(0, 5) {
+            self.shift_low();
+        }
+    }
+
+    /// The number of bytes written to the inner writer so far.
+    pub fn out_total(&self) -> u64 {
+        self.out_total
+    }
+
+    // Hold the 0xFF bytes in the cache until the carry from the low is known.
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF000000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut temp = self.cache;
+            loop {
+                self.inner_writer.write([temp + carry]);
+                self.out_total += 1;
+                temp = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = ((self.low >> 24) & 0xFF) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FFFFFF) << 8;
+    }
+
+}
+
+
+/// Range decoder reading the coded bytes from the inner reader.
+pub struct RangeDecoder<R> {
+    priv inner_reader:  R,
+    priv range:         u32,
+    priv code:          u32,
+}
+
+impl<R: Reader> Decorator<R> for RangeDecoder<R> {
+    fn inner(self) -> R {
+        self.inner_reader
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a R {
+        &self.inner_reader
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut R {
+        &mut self.inner_reader
+    }
+}
+
+impl<R: Reader> RangeDecoder<R> {
+
+    /// Create a decoder reading from the inner reader.  It reads the 5 initial bytes of the coded data.
+    pub fn new(inner_reader: R) -> RangeDecoder<R> {
+        let mut decoder = RangeDecoder {
+            inner_reader:   inner_reader,
+            range:          0xFFFFFFFF,
+            code:           0,
+        };
+        for _ in range(0, 5) {
+            decoder.code = (decoder.code << 8) | (decoder.next_byte() as u32);
+        }
+        decoder
+    }
+
+    /// Decode a bit, 0 or 1, with the probability and adapt the probability to it.
+    pub fn decode_bit(&mut self, prob: &mut Prob) -> uint {
+        let bound = (self.range >> PROB_BITS) * (prob.value as u32);
+        let bit = if self.code < bound {
+            self.range = bound;
+            0
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        prob.update(bit);
+        self.normalize();
+        bit
+    }
+
+    /// Decode count bits coded by encode_direct_bits(), returning them as the low bits of the value.
+    pub fn decode_direct_bits(&mut self, count: uint) -> u32 {
+        let mut value = 0u32;
+        for _ in range(0, count) {
+            self.range = self.range >> 1;
+            let bit = if self.code >= self.range {
+                self.code -= self.range;
+                1
+            } else {
+                0
+            };
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        value
+    }
+
+    /// Check whether the decoder has consumed the coded data to the end cleanly, as after the encoder's finish().
+    pub fn is_finished_ok(&self) -> bool {
+        self.code == 0
+    }
+
+    fn normalize(&mut self) {
+        if self.range < TOP_VALUE {
+            self.range = self.range << 8;
+            self.code = (self.code << 8) | (self.next_byte() as u32);
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.inner_reader.read_byte() {
+            Some(byte)  => byte,
+            None        => {
+                io_error::cond.raise(IoError {
+                        kind:   OtherIoError,
+                        desc:   "Range coded data truncated",
+                        detail: None
+                    });
+                0
+            }
+        }
+    }
+
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec;
+    use std::io::Decorator;
+    use std::io::mem::MemReader;
+    use std::io::mem::MemWriter;
+    use std::io::io_error;
+    use super::Prob;
+    use super::BitTree;
+    use super::RangeEncoder;
+    use super::RangeDecoder;
+
+
+    #[test]
+    fn test_encode_decode_bits() {
+        let bits = vec::from_fn(10000, |i| if (i * 7919) % 11 < 2 { 1u } else { 0u });
+
+        let mut probs = Prob::new_vec(2);
+        let mut encoder = RangeEncoder::new(MemWriter::new());
+        let mut prev = 0u;
+        for &bit in bits.iter() {
+            encoder.encode_bit(&mut probs[prev], bit);
+            prev = bit;
+        }
+        encoder.finish();
+        let out_total = encoder.out_total();
+        let buf = encoder.inner().inner();
+        assert_eq!(buf.len() as u64, out_total);
+        assert!(buf.len() < bits.len() / 8);
+
+        let mut probs = Prob::new_vec(2);
+        let mut decoder = RangeDecoder::new(MemReader::new(buf));
+        let mut prev = 0u;
+        for &bit in bits.iter() {
+            assert_eq!(decoder.decode_bit(&mut probs[prev]), bit);
+            prev = bit;
+        }
+        assert!(decoder.is_finished_ok());
+    }
+
+    #[test]
+    fn test_bit_tree_and_direct_bits() {
+        let symbols = vec::from_fn(3000, |i| if i % 3 == 0 { (i * 37) % 64 } else { 5 + i % 3 });
+
+        let mut tree = BitTree::new(6);
+        let mut rev_tree = BitTree::new(4);
+        let mut encoder = RangeEncoder::new(MemWriter::new());
+        for &symbol in symbols.iter() {
+            tree.encode(&mut encoder, symbol);
+            rev_tree.reverse_encode(&mut encoder, symbol & 0xF);
+            encoder.encode_direct_bits(symbol as u32 * 0x10001, 26);
+        }
+        encoder.finish();
+        let buf = encoder.inner().inner();
+
+        let mut tree = BitTree::new(6);
+        let mut rev_tree = BitTree::new(4);
+        let mut decoder = RangeDecoder::new(MemReader::new(buf));
+        for &symbol in symbols.iter() {
+            assert_eq!(tree.decode(&mut decoder), symbol);
+            assert_eq!(rev_tree.reverse_decode(&mut decoder), symbol & 0xF);
+            assert_eq!(decoder.decode_direct_bits(26), (symbol as u32 * 0x10001) & 0x3FFFFFF);
+        }
+        assert!(decoder.is_finished_ok());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut prob = Prob::new();
+        let mut encoder = RangeEncoder::new(MemWriter::new());
+        for _ in range(0, 1000) {
+            encoder.encode_direct_bits(0x5A, 8);
+        }
+        encoder.finish();
+        let buf = encoder.inner().inner();
+
+        let mut error_count = 0;
+        io_error::cond.trap(|_| error_count += 1).inside(|| {
+            let mut decoder = RangeDecoder::new(MemReader::new(buf.slice(0, buf.len() / 2).to_owned()));
+            for _ in range(0, 1000) {
+                decoder.decode_direct_bits(8);
+            }
+            decoder.decode_bit(&mut prob);
+        });
+        assert!(error_count > 0);
+    }
+
+}
@@ -0,0 +1,49 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: trace.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The trace module is the logging hook of the library.  The library sends its trace
+messages to the trace function set by the application, e.g. for rzip -vv.
+Tracing is off until a trace function is set.
+
+*/
+
+
+static mut TRACE_FN: Option<fn(&str)> = None;
+
+
+/// Set the function to receive the trace messages of the library.  None turns tracing off.
+pub fn set_trace_fn(trace_fn: Option<fn(&str)>) {
+    unsafe {
+        TRACE_FN = trace_fn;
+    }
+}
+
+/// Check whether tracing is on.  Check it before formatting a trace message to skip the work when it's off.
+pub fn is_on() -> bool {
+    unsafe {
+        TRACE_FN.is_some()
+    }
+}
+
+/// Send the trace message to the trace function, if it's set.
+pub fn trace(msg: &str) {
+    unsafe {
+        match TRACE_FN {
+            Some(trace_fn)  => trace_fn(msg),
+            None            => ()
+        }
+    }
+}
+
@@ -33,14 +33,31 @@ use std::str;
 use std::num;
 use std::vec;
 use std::iter::{Iterator};
-use std::io::{Reader, Writer};
+use std::path::Path;
+use std::hashmap::HashSet;
+use std::os;
+use std::char;
+use std::ascii::AsciiCast;
+use std::io;
+use std::io::{Reader, Writer, Decorator, Open, Read, Truncate, Write, ReadWrite};
 use std::io::{io_error, IoError, OtherIoError};
-use std::io::{SeekSet, SeekEnd};
+use std::io::{SeekSet, SeekEnd, FileStat};
+use std::io::fs;
 use std::io::fs::File;
+use std::io::mem::{MemReader, MemWriter};
+use extra::time;
 
 use super::deflate;
 use super::deflate::Deflator;
 use super::deflate::Inflator;
+use super::gzip::update_crc;
+use super::gzip::DEFAULT_COMPRESS_LEVEL;
+use super::gzip::GZipReader;
+use super::endian::{pack_num, unpack_num, unpack_uint, LittleEndian};
+use super::ioutil;
+use super::ioutil::CancelToken;
+use super::ioutil::{read_upto, read_exact, read_buf_exact, read_buf_upto};
+use super::trace;
 
 
 static CD_METADATA_MAGIC: u32   = 0x06054B50u32;
@@ -50,6 +67,9 @@ static LOCAL_DESC_MAGIC: u32    = 0x08074B50u32;
 
 // #define VERSION_MADE            0xB17       // 0xB00 is win32 os-code. 0x17 is 23 in decimal: zip 2.3
 // #define VERSION_NEEDED          20          // Needs PKUNZIP 2.0 to unzip it
+static VERSION_MADE_BY: u16         = 0x31E;    // 0x300 is Unix os-code.  0x1E is 30 in decimal: zip 3.0
+static VERSION_NEEDED_STORE: u16    = 10;       // Needs zip 1.0 to extract a stored entry
+static VERSION_NEEDED_DEFLATE: u16  = 20;       // Needs zip 2.0 to extract a deflated entry
 
 // internal file attribute
 // #define UNKNOWN (-1)
@@ -62,10 +82,48 @@ static MAX_CD_METADATA_SEARCH: uint = CD_METADATA_SIZE + MAX_COMMENT_SIZE;
 static CD_FILE_HEADER_SIZE: uint    = 46u;      // leading size for central directory header, before variable size fields.
 static LOCAL_FILE_HEADER_SIZE: uint = 30u;      // leading size for local header, before variable size fields.
 static DATA_DESCRIPTOR_SIZE: uint   = 12u;      
+static CRYPT_HEADER_SIZE: uint      = 12u;      // encryption header in front of the data of a ZipCrypto encrypted entry
+static LOCAL_CRC_OFFSET: uint       = 14u;      // offset of the crc-32 and the sizes in the local header
+static MAX_ENTRY_COUNT: uint        = 0xFFFFu;
+static MAX_ZIP32_SIZE: u64          = 0xFFFFFFFFu64;
 
 
-static METHOD_STORE: u16 = 0;       // Store method
-static METHOD_DEFLATE: u16 = 8;     // Deflation method
+/// Store method, no compression.
+pub static METHOD_STORE: u16 = 0;
+/// Deflate method.
+pub static METHOD_DEFLATE: u16 = 8;
+static METHOD_AES: u16 = 99;        // WinZip AES encryption, with the actual method in the extra field
+
+// General purpose flags
+static FLAG_ENCRYPTED: u16 = 0x0001;        // The entry data is encrypted
+static FLAG_DATA_DESCRIPTOR: u16 = 0x0008;  // The CRC and sizes follow the entry data in a data descriptor
+static FLAG_UTF8: u16 = 0x0800;             // The file name and comment are in UTF-8
+static FLAG_PATCH_DATA: u16 = 0x0020;       // The entry is compressed patch data
+static FLAG_STRONG_ENCRYPTION: u16 = 0x0040; // The entry uses PKWARE strong encryption
+
+// External file attributes
+static UNIX_FILE_MODE: u32 = 0x8000;        // S_IFREG
+static UNIX_DIR_MODE: u32 = 0x4000;         // S_IFDIR
+static UNIX_LINK_MODE: u32 = 0xA000;        // S_IFLNK
+static UNIX_TYPE_MASK: u32 = 0xF000;        // S_IFMT
+static DOS_DIR_ATTR: u32 = 0x10;            // MS-DOS directory attribute
+
+// Extra fields
+static EXTRA_ZIP64: u16 = 0x0001;           // Zip64 extended information
+static EXTRA_NTFS: u16 = 0x000A;            // NTFS timestamps
+static NTFS_TIMES_TAG: u16 = 0x0001;        // attribute of the modified, accessed and created times in the NTFS extra field
+static NTFS_TIMES_SIZE: u16 = 24;           // three 8-byte times
+static NTFS_EPOCH_SECS: u64 = 11644473600;  // seconds from 1601-01-01, the epoch of the NTFS time, to 1970-01-01
+static EXTRA_UNIX_OWNER: u16 = 0x7875;      // Info-ZIP Unix uid/gid, "ux"
+static UNIX_OWNER_VERSION: u8 = 1;
+static EXTRA_ALIGNMENT: u16 = 0xD935;       // Android zipalign padding, with the alignment
+static ALIGNMENT_FIELD_MIN: uint = 6;       // header id, data size, and the alignment
+
+/// Maximum levels of the zip files nested in zip files, opened by ZipFile::open_nested_zip().
+pub static MAX_NESTED_DEPTH: uint = 8;
+
+/// Minimum interval between the calls of the progress function within an entry, in nanoseconds.
+pub static PROGRESS_INTERVAL_NS: u64 = 500000000;
 
 
 
@@ -75,6 +133,15 @@ pub struct ZipFile {
     /// Zip file's metadata for central directories.
     cd_metadata:        CDMetaData,
     priv inner_file:    File,
+    priv password:      Option<~[u8]>,
+    priv limits:        ExtractLimits,
+    priv extracted_total: u64,
+    priv depth:         uint,
+    priv lenient:       bool,
+    priv progress_fn:   Option<fn(&ExtractProgress)>,
+    priv progress:      ExtractProgress,
+    priv cancel:        Option<CancelToken>,
+    priv header_limits: HeaderLimits,
 }
 
 
@@ -85,6 +152,15 @@ impl ZipFile {
         let mut zip_file = ZipFile {
             cd_metadata:    CDMetaData::new(),
             inner_file:     file,
+            password:       None,
+            limits:         ExtractLimits::new(),
+            extracted_total: 0u64,
+            depth:          0u,
+            lenient:        false,
+            progress_fn:    None,
+            progress:       ExtractProgress::new(0, 0),
+            cancel:         None,
+            header_limits:  HeaderLimits::new(),
         };
         match zip_file.cd_metadata.read_cd_metadata(&mut zip_file.inner_file) {
             Ok(_)   => Ok(zip_file),
@@ -92,6 +168,68 @@ impl ZipFile {
         }
     }
 
+    /// Set the password for reading the encrypted entries.  Only the traditional PKWARE encryption (ZipCrypto) is supported.
+    pub fn set_password(&mut self, password: &str) {
+        self.password = Some(password.as_bytes().to_owned());
+    }
+
+    /// Set the limits on extracting the entries, enforced by extract_all() and the entry readers.
+    /// The total size counts the bytes read by the readers from now on.
+    pub fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+        self.extracted_total = 0;
+    }
+
+    /// Set whether get_zip_entries() reads the entries until the central directory runs out or an entry signature
+    /// mismatches, instead of reading the entry count in the end record.  The count is wrong in some archives,
+    /// e.g. in the fragments of a spanned archive.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Set the limits on the central directory and the entry headers, enforced by get_zip_entries() and
+    /// get_zip_entry_table() before the entries are read in.
+    pub fn set_header_limits(&mut self, header_limits: HeaderLimits) {
+        self.header_limits = header_limits;
+    }
+
+    /// Set the function called with the progress of extract_to_writer(), and so extract_entry() and extract_all(),
+    /// at most every PROGRESS_INTERVAL_NS within an entry and at the end of each entry.  None turns it off.
+    pub fn set_progress_fn(&mut self, progress_fn: Option<fn(&ExtractProgress)>) {
+        self.progress_fn = progress_fn;
+    }
+
+    /// Set the token to stop extract_to_writer(), and so extract_entry() and extract_all().  It's checked between
+    /// the buffers of data and between the entries.  The stopped extraction fails with the ioutil::CANCELLED error.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Start counting the progress toward the total number of entries and bytes to extract.
+    /// extract_all() starts it on the entries it extracts.
+    pub fn start_progress(&mut self, entries_total: uint, bytes_total: u64) {
+        self.progress = ExtractProgress::new(entries_total, bytes_total);
+    }
+
+    // Count the bytes extracted and call the progress function, if it's due or the entry is done.
+    fn update_progress(&mut self, len: u64, entry_done: bool) {
+        self.progress.entry_done += len;
+        self.progress.bytes_done += len;
+        if entry_done {
+            self.progress.entries_done += 1;
+        }
+        match self.progress_fn {
+            Some(progress_fn) => {
+                let now = time::precise_time_ns();
+                if entry_done || now - self.progress.last_ns >= PROGRESS_INTERVAL_NS {
+                    self.progress.last_ns = now;
+                    progress_fn(&self.progress);
+                }
+            },
+            None => ()
+        }
+    }
+
     /// Return an iterator ready to read each ZipEntry from the zip file.
     pub fn zip_entry_iter<'a>(&'a mut self) -> ZipEntry32Iterator<'a> {
         // Seek to file position at the beginning of cd directories.
@@ -107,10 +245,17 @@ impl ZipFile {
 
     /// Return the list of all ZipEntries of the zip file.
     pub fn get_zip_entries(&mut self) -> Result<~[ZipEntry32], ~str> {
+        match self.header_limits.check_cd_size(self.cd_metadata.cd_size as u64) {
+            Ok(_)   => (),
+            Err(s)  => return Err(s)
+        }
         // Seek to file position at the beginning of cd directories.
         self.inner_file.seek(self.cd_metadata.cd_entry_begin_offset as i64, SeekSet);
         // Read all the entries in one shot.
         let buf = read_upto(&mut self.inner_file, self.cd_metadata.cd_size as uint);
+        if self.lenient {
+            return Ok(read_entries_lenient(buf, &self.header_limits));
+        }
         if buf.len() != self.cd_metadata.cd_size as uint {
             return Err(format!("Fail to read all the zip entries.  Only read {:u} bytes out of {:u} total bytes.", buf.len(), self.cd_metadata.cd_size));
         }
@@ -118,9 +263,18 @@ impl ZipFile {
         let mut entries = ~[];
         let mut offset = 0;
         for _ in range(0, self.cd_metadata.cd_entry_count) {
+            let valid = offset + CD_FILE_HEADER_SIZE <= buf.len();
+            let end = if valid { offset + CD_FILE_HEADER_SIZE + cd_variable_len(buf, offset) } else { 0 };
+            if !valid || end > buf.len() {
+                return Err(format!("Zip file entry at offset {:u} overruns the central directory of {:u} bytes.", offset, buf.len()));
+            }
             let mut entry = ZipEntry32::new();
             match entry.unpack_zip_entry(buf, offset) {
                 Ok(offset2) => {
+                    match self.header_limits.check_entry(&entry) {
+                        Ok(_)   => (),
+                        Err(s)  => return Err(s)
+                    }
                     offset = entry.unpack_zip_entry_extra(buf, offset2);
                 },
                 Err(s) => return Err(s)
@@ -130,226 +284,1634 @@ impl ZipFile {
         Ok(entries)
     }
 
-    fn zip_entry_reader<'a>(&'a mut self, entry: &ZipEntry32) -> ZipReader<'a> {
-        let mut reader = ZipReader {
+    /// Return the table of the entries on the raw central directory, parsing the entries only when they are asked for.
+    /// It saves the allocations of get_zip_entries() on the archives of many entries.  See ZipEntryTable.
+    pub fn get_zip_entry_table(&mut self) -> Result<ZipEntryTable, ~str> {
+        match self.header_limits.check_cd_size(self.cd_metadata.cd_size as u64) {
+            Ok(_)   => (),
+            Err(s)  => return Err(s)
+        }
+        self.inner_file.seek(self.cd_metadata.cd_entry_begin_offset as i64, SeekSet);
+        let buf = read_upto(&mut self.inner_file, self.cd_metadata.cd_size as uint);
+        if !self.lenient && buf.len() != self.cd_metadata.cd_size as uint {
+            return Err(format!("Fail to read all the zip entries.  Only read {:u} bytes out of {:u} total bytes.", buf.len(), self.cd_metadata.cd_size));
+        }
+        let max_count = if self.lenient { MAX_ENTRY_COUNT } else { self.cd_metadata.cd_entry_count as uint };
+        let mut offsets = ~[];
+        let mut offset = 0;
+        while offsets.len() < max_count {
+            let valid = offset + CD_FILE_HEADER_SIZE <= buf.len() && unpack_num::<u32>(buf, offset, LittleEndian) == CD_HEADER_MAGIC;
+            let end = if valid { offset + CD_FILE_HEADER_SIZE + cd_variable_len(buf, offset) } else { 0 };
+            if !valid || end > buf.len() {
+                if self.lenient {
+                    break;
+                }
+                return Err(~"Zip file entry signature mismatched.");
+            }
+            let lengths = self.header_limits.check_lengths(unpack_num::<u16>(buf, offset + 28, LittleEndian) as uint,
+                                                           unpack_num::<u16>(buf, offset + 30, LittleEndian) as uint,
+                                                           unpack_num::<u16>(buf, offset + 32, LittleEndian) as uint);
+            match lengths {
+                Ok(_)                   => (),
+                Err(_) if self.lenient  => break,
+                Err(s)                  => return Err(s)
+            }
+            offsets.push(offset);
+            offset = end;
+        }
+        Ok(ZipEntryTable {
+            cd_buf:     buf,
+            offsets:    offsets,
+        })
+    }
+
+    /// Return a reader to read the decompressed content of the zip entry.
+    pub fn zip_entry_reader<'a>(&'a mut self, entry: &ZipEntry32) -> ZipReader<'a> {
+        let mut decoder = EntryDecoder::new(entry);
+        decoder.limit = self.limits.output_limit(entry, self.extracted_total);
+        decoder.init(&mut self.inner_file, &self.password);
+        ZipReader {
             zip_file:   self,
-            zip_entry:  entry.clone(),
-            read_total: 0u64,
-            cmp_crc32:  0u32,
-            is_eof:     false,
-            inflator:   None,
-        };
-        reader.init();
-        reader
+            decoder:    decoder,
+        }
     }
 
-}
+    /// Return a reader to read the decompressed content of the zip entry, opening its own handle of the zip file.
+    /// The reader doesn't borrow the ZipFile; it can be kept or sent to another task while other entries are read.
+    pub fn open_entry_reader(&self, entry: &ZipEntry32) -> Result<ZipEntryReader, ~str> {
+        open_entry_reader(self.inner_file.path(), &self.password, &self.limits, entry)
+    }
 
+    /// Open the zip entry that is itself a zip file, e.g. a .jar in a .zip.  A zip file needs seeking, so the entry is
+    /// extracted to a temporary file, removed when the NestedZipFile is dropped.  The nested zip file gets the password
+    /// and the limits of this one.  Fail if the nesting is deeper than MAX_NESTED_DEPTH.
+    pub fn open_nested_zip(&mut self, entry: &ZipEntry32) -> Result<NestedZipFile, ~str> {
+        if self.depth >= MAX_NESTED_DEPTH {
+            return Err(format!("Zip file {:s} is nested more than {:u} levels.", entry.file_name_as_str(), MAX_NESTED_DEPTH));
+        }
+        let tmp_path = unique_path(&os::tmpdir().join("rustyzip-nested.zip"));
+        let mut result = Ok(());
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            match File::open_mode(&tmp_path, Truncate, Write) {
+                Some(tmp_file) => {
+                    let mut tmp_file = tmp_file;
+                    self.extract_to_writer(entry, &mut tmp_file);
+                },
+                None =>
+                    result = Err(format!("Failed to open file {:s} for write.", tmp_path.as_str().unwrap_or("")))
+            }
+        });
+        let result = result.and_then(|_| {
+            match io::result(|| File::open_mode(&tmp_path, Open, Read)) {
+                Ok(Some(tmp_file))  => ZipFile::open(tmp_file),
+                _                   => Err(format!("Failed to open file {:s}", tmp_path.as_str().unwrap_or("")))
+            }
+        });
+        match result {
+            Ok(zip_file) => {
+                let mut zip_file = zip_file;
+                zip_file.password = self.password.clone();
+                zip_file.limits = self.limits.clone();
+                zip_file.depth = self.depth + 1;
+                Ok(NestedZipFile {
+                    zip_file:   Some(zip_file),
+                    tmp_path:   tmp_path,
+                })
+            },
+            Err(s) => {
+                io::result(|| fs::unlink(&tmp_path));
+                Err(s)
+            }
+        }
+    }
 
-/// A zip file's central directory metadata, located at the end of the file.
-pub struct CDMetaData {
-    /// number of this disk
-    disk_number:            u16,
-    /// number of the disk with the start of the central directory
-    cd_disk_number:         u16,
-    /// total number of entries in the central directory on this disk
-    cd_entry_count_on_disk: u16,
-    /// total number of entries in the central directory
-    cd_entry_count:         u16,
-    /// size of the central directory
-    cd_size:                u32,
-    /// offset of start of central directory
-    cd_entry_begin_offset:  u32,
-    /// ZIP file comment length
-    comment_length:         u16,
-    /// file comment
-    comment:                Option<~str>,
+    /// Open the zip entry that is a gzip file, e.g. a .tar.gz in a .zip, as a GZipReader decompressing the entry data
+    /// as they are read.
+    pub fn open_nested_gzip(&self, entry: &ZipEntry32) -> Result<GZipReader<ZipEntryReader>, ~str> {
+        match self.open_entry_reader(entry) {
+            Ok(reader)  => Ok(GZipReader::new(reader)),
+            Err(s)      => Err(s)
+        }
+    }
 
-    // Additional info
+    /// Return the names that appear in more than one entry.
+    pub fn duplicate_names(&mut self) -> Result<~[~str], ~str> {
+        match self.get_zip_entries() {
+            Ok(entries) => Ok(find_duplicates(entries)),
+            Err(s)      => Err(s)
+        }
+    }
 
-    /// size of the zip file
-    file_size:              u64,
-}
+    /// Return the entry of the name, picking the one by the policy if there are several entries of the name.
+    pub fn find_entry(&mut self, name: &str, policy: DuplicatePolicy) -> Result<Option<ZipEntry32>, ~str> {
+        let entries = match self.get_zip_entries() {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        let mut found = entries.move_iter().filter(|entry| entry.name_eq(name));
+        match policy {
+            DuplicateFirst  => Ok(found.next()),
+            DuplicateLast   => Ok(found.last()),
+            DuplicateError  => {
+                let first = found.next();
+                match found.next() {
+                    Some(_) => Err(format!("Duplicate entries of name {:s}.", name)),
+                    None    => Ok(first)
+                }
+            }
+        }
+    }
 
-impl CDMetaData {
+    /// Return the statistics of all the entries.
+    pub fn stats(&mut self) -> Result<ZipStats, ~str> {
+        match self.get_zip_entries() {
+            Ok(entries) => Ok(ZipStats::from_entries(entries.iter())),
+            Err(s)      => Err(s)
+        }
+    }
 
-    fn new() -> CDMetaData {
-        CDMetaData {
-            disk_number:            0u16,
-            cd_disk_number:         0u16,
-            cd_entry_count_on_disk: 0u16,
-            cd_entry_count:         0u16,
-            cd_size:                0u32,
-            cd_entry_begin_offset:  0u32,
-            comment_length:         0u16,
-            comment:                None,
-            file_size:              0u64,
+    /// Return the features needed to extract the zip file.  See required_features().
+    pub fn required_features(&mut self) -> Result<~[ZipFeature], ~str> {
+        let mut features = match self.get_zip_entries() {
+            Ok(entries) => required_features(entries),
+            Err(s)      => return Err(s)
+        };
+        if self.cd_metadata.disk_number != 0 || self.cd_metadata.cd_disk_number != 0 {
+            add_feature(&mut features, FeatureSplit);
         }
+        if self.cd_metadata.cd_entry_count == 0xFFFF || self.cd_metadata.cd_size == 0xFFFFFFFF ||
+            self.cd_metadata.cd_entry_begin_offset == 0xFFFFFFFF {
+            add_feature(&mut features, FeatureZip64);
+        }
+        Ok(features)
     }
 
-    fn read_cd_metadata(&mut self, file: &mut File) -> Result<uint, ~str> {
-        // Go to the end of the file and start searching for central directory metadata
-        file.seek(0i64, SeekEnd);
-        self.file_size = file.tell();
-        if self.file_size < CD_METADATA_SIZE as u64{
-            return Err(~"File too small to be a valid zip file.");
+    /// Check that this build of the library supports all the features needed to extract the zip file.
+    /// Return the error naming the unsupported features otherwise.
+    pub fn check_supported(&mut self) -> Result<(), ~str> {
+        let features = match self.required_features() {
+            Ok(features)    => features,
+            Err(s)          => return Err(s)
+        };
+        let unsupported = features.iter().filter(|feature| !feature.is_supported()).map(|feature| feature.to_str()).collect::<~[~str]>();
+        if unsupported.len() == 0 {
+            Ok(())
+        } else {
+            Err(format!("The zip file needs the unsupported features: {:s}.", unsupported.connect(", ")))
         }
+    }
 
-        let max_search_size = num::min(self.file_size, MAX_CD_METADATA_SEARCH as u64) as uint;
-        file.seek(-(max_search_size as i64), SeekEnd);
-        let mut buf = vec::from_elem(max_search_size, 0u8);
-        let read_len = read_buf_upto(file, buf, 0, max_search_size);
+    /// Return the index of the entries for the sorted and grouped views.  See ZipEntryIndex.
+    pub fn entry_index(&mut self) -> Result<ZipEntryIndex, ~str> {
+        match self.get_zip_entries() {
+            Ok(entries) => Ok(ZipEntryIndex::new(entries)),
+            Err(s)      => Err(s)
+        }
+    }
 
-        for mut offset in range(0, read_len - 4) {
+    /// Return a shared handle of the zip file for reading its entries concurrently.  See SharedZipFile.
+    pub fn share(&mut self) -> Result<SharedZipFile, ~str> {
+        match self.get_zip_entries() {
+            Ok(entries) => Ok(SharedZipFile {
+                path:       self.inner_file.path().clone(),
+                password:   self.password.clone(),
+                limits:     self.limits.clone(),
+                entries:    entries,
+            }),
+            Err(s)      => Err(s)
+        }
+    }
 
-            if unpack_u32_le(buf, offset) == CD_METADATA_MAGIC {
-                // Got to the beginning of the central directory metadata section.
-                offset += 4;
-                self.disk_number = unpack_u16_le(buf, offset);
-                offset += 2;
-                self.cd_disk_number = unpack_u16_le(buf, offset);
-                offset += 2;
-                self.cd_entry_count_on_disk = unpack_u16_le(buf, offset);
-                offset += 2;
-                self.cd_entry_count = unpack_u16_le(buf, offset);
-                offset += 2;
-                self.cd_size = unpack_u32_le(buf, offset);
-                offset += 4;
-                self.cd_entry_begin_offset = unpack_u32_le(buf, offset);
-                offset += 4;
-                self.comment_length = unpack_u16_le(buf, offset);
-                offset += 2;
-                if self.comment_length > 0 {
-                    self.comment = Some(str::from_utf8(buf.slice(offset, offset + self.comment_length as uint)));
+    /// Extract the content of the zip entry to the writer.  Return the number of bytes extracted.
+    /// Any error is raised as io_error, including a CRC mismatch.
+    pub fn extract_to_writer<W: Writer>(&mut self, entry: &ZipEntry32, writer: &mut W) -> u64 {
+        self.progress.entry_name = entry.file_name_as_str();
+        self.progress.entry_size = entry.uncompressed_size as u64;
+        self.progress.entry_done = 0;
+        let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+        let mut total = 0u64;
+        {
+            let mut reader = self.zip_entry_reader(entry);
+            loop {
+                if ioutil::is_cancelled(&reader.zip_file.cancel) {
+                    io_error::cond.raise(IoError {
+                            kind:   OtherIoError,
+                            desc:   ioutil::CANCELLED,
+                            detail: Some(format!("The extraction of {:s} is cancelled.", entry.file_name_as_str()))
+                        });
+                    break;
+                }
+                match reader.read(buf) {
+                    Some(read_len) => {
+                        writer.write(buf.slice(0, read_len));
+                        total += read_len as u64;
+                        reader.zip_file.update_progress(read_len as u64, false);
+                    },
+                    None => break
                 }
-
-                println(format!("{:?}", self));
-
-                return Ok(0);
             }
         }
-        Err(~"Zip file central directory signature missing.")
+        writer.flush();
+        self.update_progress(0, true);
+        total
     }
 
-}
-
-/// The local file header of a file item in a zip file
-#[deriving(Clone)]
-pub struct LocalFileHeader {
-    /// version needed to extract
-    version_needed:             u16,
-    ///general purpose bit flag
-    general_flag:               u16,
-    /// compression method
-    compression_method:         u16,
-    /// last mod file time
-    modified_time:              u16,
-    /// last mod file date
-    modified_date:              u16,
-    /// crc-32
-    crc32:                      u32,
-    /// compressed size
-    compressed_size:            u32,
-    /// uncompressed size
-    uncompressed_size:          u32,
-    /// file name length
-    file_name_length:           u16,
-    /// extra field length
-    extra_field_length:         u16,
-    /// file name
-    file_name:                  Option<~[u8]>,
-    /// extra field
-    extra_field:                Option<~[u8]>,
-}
+    /// Verify the zip entry by decompressing it and checking its CRC and size, without writing the data out.
+    /// Return the decompressed size, or the error on the entry, including the encrypted entry and the unsupported method.
+    pub fn verify_entry(&mut self, entry: &ZipEntry32) -> Result<u64, ~str> {
+        if entry.compression_method == METHOD_AES {
+            return Err(~"AES encrypted entry is not supported.");
+        }
+        if entry.is_encrypted() && self.password.is_none() {
+            return Err(~"Encrypted entry needs a password.");
+        }
+        if entry.compression_method != METHOD_STORE && entry.compression_method != METHOD_DEFLATE {
+            return Err(format!("Unsupported compression method: {:u}", entry.compression_method as uint));
+        }
 
-impl LocalFileHeader {
+        let mut result = Ok(0u64);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let mut reader = self.zip_entry_reader(entry);
+            let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+            let mut total = 0u64;
+            loop {
+                match reader.read(buf) {
+                    Some(read_len)  => total += read_len as u64,
+                    None            => break
+                }
+            }
+            if result.is_ok() {
+                result = if total == entry.uncompressed_size as u64 {
+                    Ok(total)
+                } else {
+                    Err(format!("Size mismatched.  Expected {:u} bytes, decompressed {:u} bytes.", entry.uncompressed_size as uint, total as uint))
+                };
+            }
+        });
+        result
+    }
 
-    fn new() -> LocalFileHeader {
-        LocalFileHeader {
-            version_needed:             0u16,
-            general_flag:               0u16,
-            compression_method:         0u16,
-            modified_time:              0u16,
-            modified_date:              0u16,
-            crc32:                      0u32,
-            compressed_size:            0u32,
-            uncompressed_size:          0u32,
-            file_name_length:           0u16,
-            extra_field_length:         0u16,
-            file_name:                  None,
-            extra_field:                None,
+    /// Check that the data of the stored entries start at the alignment, as ZipWriter::set_alignment() writes them.
+    /// Return the names of the misaligned entries.
+    pub fn check_alignment(&mut self, alignment: uint) -> Result<~[~str], ~str> {
+        let entries = match self.get_zip_entries() {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        if alignment <= 1 {
+            return Ok(~[]);
         }
+        let mut result = Ok(~[]);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let mut misaligned = ~[];
+            for entry in entries.iter().filter(|entry| entry.compression_method == METHOD_STORE) {
+                let mut entry = entry.clone();
+                entry.read_local_file_header(&mut self.inner_file);
+                if entry.get_file_data_offset() as u64 % alignment as u64 != 0 {
+                    misaligned.push(entry.file_name_as_str());
+                }
+            }
+            if result.is_ok() {
+                result = Ok(misaligned);
+            }
+        });
+        result
     }
 
-    fn unpack_header(&mut self, buf: &[u8], mut offset: uint) -> uint {
-
-        if unpack_u32_le(buf, offset) != LOCAL_HEADER_MAGIC {
-            io_error::cond.raise(IoError { kind: OtherIoError, desc: "Zip local file header signature mismatched", detail: None });
+    /// Verify all the zip entries.
+    /// Return the name of each entry with its result, the decompressed size or the error on the entry.
+    pub fn verify_all(&mut self) -> Result<~[(~str, Result<u64, ~str>)], ~str> {
+        let entries = match self.get_zip_entries() {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        let mut results = ~[];
+        for entry in entries.iter() {
+            let result = self.verify_entry(entry);
+            results.push((entry.file_name_as_str(), result));
         }
-        offset += 4;
+        Ok(results)
+    }
 
-        self.version_needed = unpack_u16_le(buf, offset);           offset += 2;
-        self.general_flag = unpack_u16_le(buf, offset);             offset += 2;
-        self.compression_method = unpack_u16_le(buf, offset);       offset += 2;
-        self.modified_time = unpack_u16_le(buf, offset);            offset += 2;
-        self.modified_date = unpack_u16_le(buf, offset);            offset += 2;
-        self.crc32 = unpack_u32_le(buf, offset);                    offset += 4;
-        self.compressed_size = unpack_u32_le(buf, offset);          offset += 4;
-        self.uncompressed_size = unpack_u32_le(buf, offset);        offset += 4;
-        self.file_name_length = unpack_u16_le(buf, offset);         offset += 2;
-        self.extra_field_length = unpack_u16_le(buf, offset);       offset += 2;
+    /// Extract the zip entry under the dest_dir directory, creating its parent directories as needed.
+    /// Return the number of bytes extracted, or the error on the entry.
+    pub fn extract_entry(&mut self, entry: &ZipEntry32, dest_dir: &Path, options: &ExtractOptions) -> Result<u64, ~str> {
+        let name = entry.file_name_as_str();
+        let out_path = match options.dest_path(dest_dir, name) {
+            Some(out_path)  => out_path,
+            None            => return Err(format!("Unsafe path {:s} in zip entry.  Entry skipped.", name))
+        };
+        if through_symlink(dest_dir, &out_path) {
+            return Err(format!("Path {:s} goes through a symlink.  Entry skipped.", name));
+        }
+        if trace::is_on() {
+            trace::trace(format!("zip: extracting {:s}, method {:u}, flags 0x{:04x}, local header at {:u}, to {:s}",
+                                 name, entry.compression_method as uint, entry.general_flag as uint,
+                                 entry.local_header_offset as uint, out_path.as_str().unwrap_or("")));
+        }
 
-        return offset;
+        let mut result = Ok(0u64);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            if entry.is_dir() {
+                if !options.junk_paths && !out_path.is_dir() {
+                    fs::mkdir_recursive(&out_path, io::UserRWX);
+                }
+            } else if entry.is_symlink() {
+                result = self.extract_symlink(entry, dest_dir, &out_path, options);
+            } else {
+                match options.write_path(&out_path) {
+                    None =>
+                        result = Err(format!("File {:s} already exists.  Entry skipped.", out_path.as_str().unwrap_or(""))),
+                    Some(write_path) => {
+                        let parent_dir = write_path.dir_path();
+                        if !parent_dir.is_dir() {
+                            fs::mkdir_recursive(&parent_dir, io::UserRWX);
+                        }
+                        match File::open_mode(&write_path, Truncate, Write) {
+                            Some(writer) => {
+                                let mut writer = writer;
+                                result = Ok(self.extract_to_writer(entry, &mut writer));
+                            },
+                            None =>
+                                result = Err(format!("Failed to open file {:s} for write.", write_path.as_str().unwrap_or("")))
+                        }
+                        if result.is_ok() {
+                            restore_file_times(&write_path, entry.ntfs_times(), entry.modified_time, entry.modified_date);
+                            if options.owner {
+                                restore_file_owner(&write_path, entry.unix_owner());
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        result
     }
 
-    // Unpack the variable length header of the zip entry.
-    fn unpack_header_rest(&mut self, buf: &[u8], mut offset: uint) -> uint {
-        if self.file_name_length > 0 {
-            self.file_name = Some(buf.slice(offset, offset + self.file_name_length as uint).to_owned());
-            offset += self.file_name_length as uint;
+    // Recreate the symlink entry at out_path.  The symlinks are skipped unless the options allow them,
+    // and the link target must be a relative path that stays under dest_dir.  The .. in the target must all
+    // come first: past a link already extracted, or extracted later, a .. would climb from where that link
+    // points to, not from where it is.
+    fn extract_symlink(&mut self, entry: &ZipEntry32, dest_dir: &Path, out_path: &Path, options: &ExtractOptions) -> Result<u64, ~str> {
+        let name = entry.file_name_as_str();
+        if !options.symlinks {
+            return Err(format!("Symlink {:s} skipped.", name));
         }
-        if self.extra_field_length > 0 {
-            self.extra_field = Some(buf.slice(offset, offset + self.extra_field_length as uint).to_owned());
-            offset += self.extra_field_length as uint;
+        let mut writer = MemWriter::new();
+        let size = self.extract_to_writer(entry, &mut writer);
+        let target = str::from_utf8(writer.inner());
+        let rel_dir = match out_path.dir_path().path_relative_from(dest_dir) {
+            Some(rel_dir)   => rel_dir,
+            None            => Path::new(".")
+        };
+        let target_rel = rel_dir.join(target.as_slice());
+        if target.starts_with("/") || !is_descending_target(target) ||
+            entry_dest_path(dest_dir, target_rel.as_str().unwrap_or("..")).is_none() {
+            return Err(format!("Unsafe target {:s} in symlink {:s}.  Entry skipped.", target, name));
+        }
+        match options.write_path(out_path) {
+            None =>
+                Err(format!("File {:s} already exists.  Entry skipped.", out_path.as_str().unwrap_or(""))),
+            Some(write_path) => {
+                if write_path.exists() {
+                    fs::unlink(&write_path);
+                }
+                let parent_dir = write_path.dir_path();
+                if !parent_dir.is_dir() {
+                    fs::mkdir_recursive(&parent_dir, io::UserRWX);
+                }
+                fs::symlink(&Path::new(target.as_slice()), &write_path);
+                Ok(size)
+            }
         }
-        offset
     }
 
-    fn get_rest_length(&self) -> uint {
-        return self.file_name_length as uint + self.extra_field_length as uint;
+    /// Extract all the zip entries wanted by the extract mode under the dest_dir directory.
+    /// Return the name of each entry with its result, the number of bytes extracted or the error on the entry.
+    pub fn extract_all(&mut self, dest_dir: &Path, options: &ExtractOptions) -> Result<~[(~str, Result<u64, ~str>)], ~str> {
+        let entries = match self.get_zip_entries() {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        let entries = match resolve_duplicates(entries, options.duplicates) {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        // The sizes in the central directory are checked up front; the readers check the actual data.
+        match self.limits.check_entries(entries, self.extracted_total) {
+            Some(exceeded)  => return Err(exceeded.to_str()),
+            None            => ()
+        }
+        // Extract in the order of the entry data in the file for sequential reads.
+        let index = ZipEntryIndex::new(entries);
+        let wanted = index.sorted_by_offset().move_iter().filter(|entry| options.is_wanted(dest_dir, entry.file_name_as_str(), entry.modified_date, entry.modified_time)).collect::<~[&ZipEntry32]>();
+        self.start_progress(wanted.len(), wanted.iter().fold(0u64, |total, entry| total + entry.uncompressed_size as u64));
+        let mut results = ~[];
+        for &entry in wanted.iter() {
+            if ioutil::is_cancelled(&self.cancel) {
+                return Err(ioutil::CANCELLED.to_owned());
+            }
+            let result = self.extract_entry(entry, dest_dir, options);
+            results.push((entry.file_name_as_str(), result));
+        }
+        Ok(results)
     }
 
-    fn get_total_length(&self) -> uint {
-        return LOCAL_FILE_HEADER_SIZE + self.get_rest_length();
-    }
+}
 
-    fn read_header(&mut self, file: &mut File) {
-        let mut buf = [0u8, ..LOCAL_FILE_HEADER_SIZE];
-        let read_len = read_buf_upto(file, buf, 0, LOCAL_FILE_HEADER_SIZE);
-        if read_len < LOCAL_FILE_HEADER_SIZE {
-            io_error::cond.raise(IoError { kind: OtherIoError, desc: "Zip local file header does not have enough data", detail: None });
-        }
 
-        let mut header = LocalFileHeader::new();
-        header.unpack_header(buf, 0);
-        let buf = read_upto(file, header.get_rest_length());
-        header.unpack_header_rest(buf, 0);
+/// A zip file in an entry of another zip file, opened by ZipFile::open_nested_zip().
+pub struct NestedZipFile {
+    priv zip_file:  Option<ZipFile>,
+    priv tmp_path:  Path,
+}
+
+impl NestedZipFile {
+
+    /// Return the nested zip file.
+    pub fn zip_file<'a>(&'a mut self) -> &'a mut ZipFile {
+        self.zip_file.get_mut_ref()
     }
+}
 
+impl Drop for NestedZipFile {
+    fn drop(&mut self) {
+        // Close the file before removing it.
+        self.zip_file = None;
+        io::result(|| fs::unlink(&self.tmp_path));
+    }
 }
 
 
-/// A file item entry for a file item embedded in a zip file.
+/// Shared handle of a zip file for reading its entries concurrently, e.g. by a multi-task extractor
+/// or by a server streaming several entries at the same time.  Clone it to each task.  Each reader
+/// opens its own handle of the zip file, with its own seek position; the readers don't wait on each other.
 #[deriving(Clone)]
-pub struct ZipEntry32 {
-    /// version of zip format created this entry
-    version_made_by:            u16,
-    /// version needed to extract
-    version_needed:             u16,
-    ///general purpose bit flag
-    general_flag:               u16,
-    /// compression method
-    compression_method:         u16,
+pub struct SharedZipFile {
+    priv path:      Path,
+    priv password:  Option<~[u8]>,
+    priv limits:    ExtractLimits,
+    priv entries:   ~[ZipEntry32],
+}
+
+impl SharedZipFile {
+
+    /// Return the entries of the zip file.
+    pub fn entries<'a>(&'a self) -> &'a [ZipEntry32] {
+        self.entries.as_slice()
+    }
+
+    /// Return the entry with the name.
+    pub fn find_entry<'a>(&'a self, name: &str) -> Option<&'a ZipEntry32> {
+        self.entries.iter().find(|entry| entry.name_eq(name))
+    }
+
+    /// Return a reader to read the decompressed content of the zip entry.
+    pub fn entry_reader(&self, entry: &ZipEntry32) -> Result<ZipEntryReader, ~str> {
+        open_entry_reader(&self.path, &self.password, &self.limits, entry)
+    }
+}
+
+// Read the entries in the central directory buffer until it runs out or an entry signature mismatches.
+fn read_entries_lenient(buf: &[u8], header_limits: &HeaderLimits) -> ~[ZipEntry32] {
+    let mut entries = ~[];
+    let mut offset = 0;
+    while offset + CD_FILE_HEADER_SIZE <= buf.len() && entries.len() < MAX_ENTRY_COUNT {
+        let mut entry = ZipEntry32::new();
+        let fixed_end = match entry.unpack_zip_entry(buf, offset) {
+            Ok(fixed_end)   => fixed_end,
+            Err(_)          => break
+        };
+        let end = fixed_end + entry.file_name_length as uint + entry.extra_field_length as uint + entry.file_comment_length as uint;
+        if end > buf.len() || header_limits.check_entry(&entry).is_err() {
+            break;
+        }
+        offset = entry.unpack_zip_entry_extra(buf, fixed_end);
+        entries.push(entry);
+    }
+    if trace::is_on() {
+        trace::trace(format!("zip: read {:u} entries leniently from {:u} bytes of central directory", entries.len(), buf.len()));
+    }
+    entries
+}
+
+/// Return the names that appear in more than one entry, in the order of their first entries.
+pub fn find_duplicates(entries: &[ZipEntry32]) -> ~[~str] {
+    let mut seen = HashSet::new();
+    let mut duplicates : ~[~str] = ~[];
+    for entry in entries.iter() {
+        let name = entry.file_name_as_str();
+        if !seen.insert(name.clone()) && !duplicates.contains(&name) {
+            duplicates.push(name);
+        }
+    }
+    duplicates
+}
+
+/// Keep one entry for each name by the policy, in the order of the central directory.
+/// Return the error listing the duplicate names with DuplicateError.
+pub fn resolve_duplicates(entries: ~[ZipEntry32], policy: DuplicatePolicy) -> Result<~[ZipEntry32], ~str> {
+    let duplicates = find_duplicates(entries);
+    if duplicates.len() == 0 {
+        return Ok(entries);
+    }
+    match policy {
+        DuplicateError  => Err(format!("Duplicate entry names: {:s}", duplicates.connect(", "))),
+        DuplicateFirst  => {
+            let mut seen = HashSet::new();
+            Ok(entries.move_iter().filter(|entry| seen.insert(entry.file_name_as_str())).collect())
+        },
+        DuplicateLast   => {
+            let mut seen = HashSet::new();
+            let mut kept : ~[ZipEntry32] = entries.move_rev_iter().filter(|entry| seen.insert(entry.file_name_as_str())).collect();
+            kept.reverse();
+            Ok(kept)
+        }
+    }
+}
+
+
+/// The entries of the central directory kept as the raw bytes read in one buffer, with the offset of each entry.
+/// The names are compared on the raw bytes and the entries are parsed into ZipEntry32 only on demand.
+/// Usage:
+///     let table = zip_file.get_zip_entry_table().unwrap();
+///     match table.find(bytes!("META-INF/MANIFEST.MF")) {
+///         Some(i) => zip_file.extract_to_writer(&table.entry(i), &mut writer),
+///         None    => ...
+///     }
+pub struct ZipEntryTable {
+    priv cd_buf:    ~[u8],
+    priv offsets:   ~[uint],
+}
+
+impl ZipEntryTable {
+
+    /// The number of entries.
+    pub fn len(&self) -> uint {
+        self.offsets.len()
+    }
+
+    /// The raw bytes of the file name of the entry at the index.
+    pub fn name_bytes<'a>(&'a self, index: uint) -> &'a [u8] {
+        let offset = self.offsets[index];
+        let name_len = unpack_num::<u16>(self.cd_buf, offset + 28, LittleEndian) as uint;
+        self.cd_buf.slice(offset + CD_FILE_HEADER_SIZE, offset + CD_FILE_HEADER_SIZE + name_len)
+    }
+
+    /// The file name of the entry at the index.
+    pub fn name(&self, index: uint) -> ~str {
+        str::from_utf8(self.name_bytes(index))
+    }
+
+    /// The uncompressed size of the entry at the index, without parsing the entry.
+    pub fn uncompressed_size(&self, index: uint) -> u32 {
+        unpack_num::<u32>(self.cd_buf, self.offsets[index] + 24, LittleEndian)
+    }
+
+    /// Return the index of the first entry of the name.
+    pub fn find(&self, name: &[u8]) -> Option<uint> {
+        range(0, self.len()).find(|&i| self.name_bytes(i) == name)
+    }
+
+    /// Parse the entry at the index.
+    pub fn entry(&self, index: uint) -> ZipEntry32 {
+        let mut entry = ZipEntry32::new();
+        match entry.unpack_zip_entry(self.cd_buf, self.offsets[index]) {
+            Ok(fixed_end)   => { entry.unpack_zip_entry_extra(self.cd_buf, fixed_end); },
+            Err(_)          => ()           // the signatures are checked by get_zip_entry_table()
+        }
+        entry
+    }
+
+    /// Parse the entries whose raw names are selected by the filter.
+    pub fn filter_entries(&self, filter: |&[u8]| -> bool) -> ~[ZipEntry32] {
+        range(0, self.len()).filter(|&i| filter(self.name_bytes(i))).map(|i| self.entry(i)).collect()
+    }
+
+}
+
+// The total length of the name, the extra field and the comment of the central directory header at the offset.
+fn cd_variable_len(buf: &[u8], offset: uint) -> uint {
+    unpack_num::<u16>(buf, offset + 28, LittleEndian) as uint + 
+        unpack_num::<u16>(buf, offset + 30, LittleEndian) as uint + 
+        unpack_num::<u16>(buf, offset + 32, LittleEndian) as uint
+}
+
+
+/// Index of the zip entries read from the central directory, with the entries sorted by name,
+/// sorted by the offset of their data, and grouped by directory.  The orders are computed once
+/// when the index is built.
+pub struct ZipEntryIndex {
+    priv entries:   ~[ZipEntry32],
+    priv names:     ~[~str],
+    priv by_name:   ~[uint],
+    priv by_offset: ~[uint],
+    priv groups:    ~[(~str, ~[uint])],
+    priv folded:    Option<~[(~str, uint)]>,
+}
+
+impl ZipEntryIndex {
+
+    /// Build the index of the entries.
+    pub fn new(entries: ~[ZipEntry32]) -> ZipEntryIndex {
+        let names = entries.map(|entry| entry.file_name_as_str());
+        let mut by_name = vec::from_fn(entries.len(), |i| i);
+        by_name.sort_by(|a, b| names[*a].cmp(&names[*b]));
+        let mut by_offset = vec::from_fn(entries.len(), |i| i);
+        by_offset.sort_by(|a, b| entries[*a].local_header_offset.cmp(&entries[*b].local_header_offset));
+
+        // Entries are added to their groups in the name order.  A group is not contiguous in the name
+        // order, e.g. "dir/sub/file" comes between "dir/a" and "dir/z".
+        let mut groups : ~[(~str, ~[uint])] = ~[];
+        for &i in by_name.iter() {
+            let dir = entry_dir_prefix(names[i]);
+            match groups.iter().position(|&(ref group_dir, _)| *group_dir == dir) {
+                Some(pos)   => match groups[pos] { (_, ref mut indices) => indices.push(i) },
+                None        => groups.push((dir, ~[i]))
+            }
+        }
+        groups.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+        ZipEntryIndex {
+            entries:    entries,
+            names:      names,
+            by_name:    by_name,
+            by_offset:  by_offset,
+            groups:     groups,
+            folded:     None,
+        }
+    }
+
+    /// Set whether find() matches the names case-insensitively, with the names folded by fold_name().
+    pub fn set_ignore_case(&mut self, ignore_case: bool) {
+        self.folded = if ignore_case {
+            let mut folded = self.by_name.map(|i| (fold_name(self.names[*i]), *i));
+            folded.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+            Some(folded)
+        } else {
+            None
+        };
+    }
+
+    /// Return the entry of the name.  With several matching entries, return the first one by name.
+    pub fn find<'a>(&'a self, name: &str) -> Option<&'a ZipEntry32> {
+        match self.folded {
+            Some(ref folded) => {
+                let folded_name = fold_name(name);
+                let k = lower_bound(folded.len(), |k| match folded[k] { (ref key, _) => *key < folded_name });
+                match folded.get_opt(k) {
+                    Some(&(ref key, i)) if *key == folded_name  => Some(&self.entries[i]),
+                    _                                           => None
+                }
+            },
+            None => {
+                let k = lower_bound(self.by_name.len(), |k| self.names[self.by_name[k]].as_slice() < name);
+                match self.by_name.get_opt(k) {
+                    Some(&i) if self.names[i].as_slice() == name    => Some(&self.entries[i]),
+                    _                                               => None
+                }
+            }
+        }
+    }
+
+    /// Return the entries in the order of the central directory.
+    pub fn entries<'a>(&'a self) -> &'a [ZipEntry32] {
+        self.entries.as_slice()
+    }
+
+    /// Return the entries sorted by name.
+    pub fn sorted_by_name<'a>(&'a self) -> ~[&'a ZipEntry32] {
+        self.by_name.map(|i| &self.entries[*i])
+    }
+
+    /// Return the entries sorted by the offset of their data in the zip file, for reading the file sequentially.
+    pub fn sorted_by_offset<'a>(&'a self) -> ~[&'a ZipEntry32] {
+        self.by_offset.map(|i| &self.entries[*i])
+    }
+
+    /// Return the entries grouped by their directory prefix, e.g. "dir/sub/" for "dir/sub/file.txt" and "dir/" for "dir/sub/".
+    /// The entries at the top level are in the "" group.  The groups and the entries in each group are sorted by name.
+    pub fn grouped_by_dir<'a>(&'a self) -> ~[(&'a str, ~[&'a ZipEntry32])] {
+        self.groups.map(|&(ref dir, ref indices)| (dir.as_slice(), indices.map(|i| &self.entries[*i])))
+    }
+}
+
+/// Statistics of the zip entries, e.g. for the footer of a listing.
+pub struct ZipStats {
+    /// Number of entries
+    entry_count:        uint,
+    /// Total compressed size
+    compressed_size:    u64,
+    /// Total uncompressed size
+    uncompressed_size:  u64,
+    /// Number of entries of each compression method, in the order of first appearance
+    method_counts:      ~[(u16, uint)],
+    /// Number of encrypted entries
+    encrypted_count:    uint,
+    /// Modified time of the oldest entry in milliseconds, None without entries
+    oldest_mtime:       Option<u64>,
+    /// Modified time of the newest entry in milliseconds, None without entries
+    newest_mtime:       Option<u64>,
+}
+
+impl ZipStats {
+
+    /// Collect the statistics of the entries.
+    pub fn from_entries<'a, T: Iterator<&'a ZipEntry32>>(mut entries: T) -> ZipStats {
+        let mut stats = ZipStats {
+            entry_count:        0,
+            compressed_size:    0,
+            uncompressed_size:  0,
+            method_counts:      ~[],
+            encrypted_count:    0,
+            oldest_mtime:       None,
+            newest_mtime:       None,
+        };
+        let mut oldest : Option<(u16, u16)> = None;
+        let mut newest : Option<(u16, u16)> = None;
+        for entry in entries {
+            stats.entry_count += 1;
+            stats.compressed_size += entry.compressed_size as u64;
+            stats.uncompressed_size += entry.uncompressed_size as u64;
+            match stats.method_counts.iter().position(|&(method, _)| method == entry.compression_method) {
+                Some(pos)   => match stats.method_counts[pos] { (_, ref mut count) => *count += 1 },
+                None        => stats.method_counts.push((entry.compression_method, 1))
+            }
+            if entry.is_encrypted() {
+                stats.encrypted_count += 1;
+            }
+            let modified = (entry.modified_date, entry.modified_time);
+            oldest = Some(oldest.map_default(modified, |oldest| if modified < oldest { modified } else { oldest }));
+            newest = Some(newest.map_default(modified, |newest| if modified > newest { modified } else { newest }));
+        }
+        // The MS-DOS times are compared as is, and converted once.
+        stats.oldest_mtime = oldest.map(|(dos_date, dos_time)| from_dos_time(dos_time, dos_date) * 1000);
+        stats.newest_mtime = newest.map(|(dos_date, dos_time)| from_dos_time(dos_time, dos_date) * 1000);
+        stats
+    }
+
+    /// Return the percentage of the size saved by compression over all the entries.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.uncompressed_size > 0 {
+            100f64 - (self.compressed_size as f64 * 100f64 / self.uncompressed_size as f64)
+        } else {
+            0f64
+        }
+    }
+
+    /// Return the number of entries of the compression method.
+    pub fn method_count(&self, method: u16) -> uint {
+        match self.method_counts.iter().find(|&&(m, _)| m == method) {
+            Some(&(_, count))   => count,
+            None                => 0
+        }
+    }
+}
+
+/// A feature of the zip format needed to extract an archive.  See ZipFile::required_features().
+#[deriving(Clone, Eq)]
+pub enum ZipFeature {
+    /// Stored entries
+    FeatureStore,
+    /// Deflated entries
+    FeatureDeflate,
+    /// Deflate64 (enhanced deflate) entries
+    FeatureDeflate64,
+    /// Bzip2 compressed entries
+    FeatureBzip2,
+    /// LZMA compressed entries
+    FeatureLzma,
+    /// Xz compressed entries
+    FeatureXz,
+    /// PPMd compressed entries
+    FeaturePpmd,
+    /// Entries of another compression method
+    FeatureMethod(u16),
+    /// Traditional PKWARE (ZipCrypto) encryption
+    FeatureZipCrypto,
+    /// WinZip AES encryption
+    FeatureAes,
+    /// PKWARE strong encryption
+    FeatureStrongEncryption,
+    /// Compressed patch data
+    FeaturePatchData,
+    /// Zip64 sizes, offsets or counts beyond the 32-bit limits
+    FeatureZip64,
+    /// Archive split or spanned over several files
+    FeatureSplit,
+    /// A zip version newer than the features above, from version_needed
+    FeatureVersion(u16),
+}
+
+impl ZipFeature {
+
+    /// Describe the feature.
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            FeatureStore            => ~"store",
+            FeatureDeflate          => ~"deflate",
+            FeatureDeflate64        => ~"Deflate64",
+            FeatureBzip2            => ~"bzip2",
+            FeatureLzma             => ~"LZMA",
+            FeatureXz               => ~"xz",
+            FeaturePpmd             => ~"PPMd",
+            FeatureMethod(method)   => format!("compression method {:u}", method as uint),
+            FeatureZipCrypto        => ~"ZipCrypto encryption",
+            FeatureAes              => ~"AES encryption",
+            FeatureStrongEncryption => ~"strong encryption",
+            FeaturePatchData        => ~"patch data",
+            FeatureZip64            => ~"Zip64",
+            FeatureSplit            => ~"split archive",
+            FeatureVersion(version) => format!("zip version {:u}.{:u}", (version / 10) as uint, (version % 10) as uint),
+        }
+    }
+
+    /// Check whether this build of the library implements the feature.
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            FeatureStore | FeatureDeflate | FeatureZipCrypto    => true,
+            _                                                   => false
+        }
+    }
+
+}
+
+/// Return the features needed to extract the entries, by their compression methods, flags, version needed,
+/// sizes and extra fields.  The split archive is detected by ZipFile::required_features() only.
+pub fn required_features(entries: &[ZipEntry32]) -> ~[ZipFeature] {
+    let mut features = ~[];
+    for entry in entries.iter() {
+        let method_feature = match entry.compression_method {
+            METHOD_STORE    => FeatureStore,
+            METHOD_DEFLATE  => FeatureDeflate,
+            9               => FeatureDeflate64,
+            12              => FeatureBzip2,
+            14              => FeatureLzma,
+            95              => FeatureXz,
+            98              => FeaturePpmd,
+            METHOD_AES      => FeatureAes,
+            method          => FeatureMethod(method)
+        };
+        add_feature(&mut features, method_feature);
+        if (entry.general_flag & FLAG_STRONG_ENCRYPTION) == FLAG_STRONG_ENCRYPTION {
+            add_feature(&mut features, FeatureStrongEncryption);
+        } else if entry.is_encrypted() && entry.compression_method != METHOD_AES {
+            add_feature(&mut features, FeatureZipCrypto);
+        }
+        if (entry.general_flag & FLAG_PATCH_DATA) == FLAG_PATCH_DATA {
+            add_feature(&mut features, FeaturePatchData);
+        }
+        let has_zip64_field = entry.extra_field.as_ref().map_default(false, |extra_field| {
+            split_extra_fields(*extra_field).iter().any(|&(id, _)| id == EXTRA_ZIP64)
+        });
+        let version_needed = entry.version_needed & 0xFF;
+        if has_zip64_field || version_needed == 45 || entry.compressed_size == 0xFFFFFFFF ||
+            entry.uncompressed_size == 0xFFFFFFFF || entry.local_header_offset == 0xFFFFFFFF {
+            add_feature(&mut features, FeatureZip64);
+        }
+        if entry.disk_number_start != 0 {
+            add_feature(&mut features, FeatureSplit);
+        }
+        // 6.3 is the newest version of the features above.
+        if version_needed > 63 {
+            add_feature(&mut features, FeatureVersion(version_needed));
+        }
+    }
+    features
+}
+
+fn add_feature(features: &mut ~[ZipFeature], feature: ZipFeature) {
+    if !features.contains(&feature) {
+        features.push(feature);
+    }
+}
+
+/// Fold the entry name for the case-insensitive lookup.  The name is decomposed to the canonical form (NFD), so
+/// the precomposed letters written on Windows match the decomposed ones written on macOS, and the ASCII letters
+/// are lowercased.  The accented Latin letters match across cases by their base letters, e.g. "É" and "é".
+pub fn fold_name(name: &str) -> ~str {
+    let mut folded = str::with_capacity(name.len());
+    for c in name.chars() {
+        char::decompose_canonical(c, |d| folded.push_char(d.to_ascii_opt().map_default(d, |a| a.to_lower().to_char())));
+    }
+    folded
+}
+
+// Return the first index in 0..len where less() is false, with less() true before and false after it.
+fn lower_bound(len: uint, less: |uint| -> bool) -> uint {
+    let (mut low, mut high) = (0u, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if less(mid) { low = mid + 1 } else { high = mid }
+    }
+    low
+}
+
+// Return the directory prefix of the entry name, including the trailing '/'.
+fn entry_dir_prefix(name: &str) -> ~str {
+    let name = if name.ends_with("/") { name.slice_to(name.len() - 1) } else { name };
+    match name.rfind('/') {
+        Some(pos)   => name.slice_to(pos + 1).to_owned(),
+        None        => ~""
+    }
+}
+
+// Open a new handle of the zip file for reading the entry.
+// The limit on the total size applies to each reader alone.
+fn open_entry_reader(path: &Path, password: &Option<~[u8]>, limits: &ExtractLimits, entry: &ZipEntry32) -> Result<ZipEntryReader, ~str> {
+    let file = match io::result(|| File::open_mode(path, Open, Read)) {
+        Ok(Some(file))  => file,
+        _               => return Err(format!("Failed to open file {:s}", path.as_str().unwrap_or("")))
+    };
+    let mut reader = ZipEntryReader {
+        inner_file: file,
+        decoder:    EntryDecoder::new(entry),
+    };
+    reader.decoder.limit = limits.output_limit(entry, 0);
+    reader.decoder.init(&mut reader.inner_file, password);
+    Ok(reader)
+}
+
+
+/// Options on extracting the zip entries.
+pub struct ExtractOptions {
+    /// What to do when the file of an entry already exists.
+    overwrite:      OverwritePolicy,
+    /// Strip the directories of the entry names and write all the files directly under dest_dir.
+    junk_paths:     bool,
+    /// Which entries to extract, by comparing them with the existing files.
+    mode:           ExtractMode,
+    /// Recreate the symlink entries as symlinks.  Otherwise they are skipped.
+    symlinks:       bool,
+    /// Apply the uid and gid in the Unix owner extra field to the extracted files.  It takes the privilege to
+    /// change the owner, usually root; the files keep the owner of the process when it fails.
+    owner:          bool,
+    /// Which entry to extract when several entries have the same name.
+    duplicates:     DuplicatePolicy,
+    /// The rules to map the entry names to the paths under dest_dir.
+    path_policy:    PathPolicy,
+}
+
+impl ExtractOptions {
+
+    /// The default options, which keep the entry paths, never overwrite the existing files, and fail on the duplicate names.
+    pub fn new() -> ExtractOptions {
+        ExtractOptions {
+            overwrite:  OverwriteNever,
+            junk_paths: false,
+            mode:       ExtractAll,
+            symlinks:   false,
+            owner:      false,
+            duplicates: DuplicateError,
+            path_policy: PathPolicy::new(),
+        }
+    }
+
+    /// Check whether the extract mode wants the entry of the name, modified at the MS-DOS date and time.
+    /// Like Info-ZIP, the entry is wanted if it is newer than the existing file, to the 2-second resolution.
+    pub fn is_wanted(&self, dest_dir: &Path, name: &str, dos_date: u16, dos_time: u16) -> bool {
+        let out_path = match self.dest_path(dest_dir, name) {
+            Some(out_path)  => out_path,
+            None            => return true      // let the extraction report the unsafe path
+        };
+        let is_newer = || {
+            let (file_time, file_date) = to_dos_time(fs::stat(&out_path).modified / 1000);
+            (file_date, file_time) < (dos_date, dos_time)
+        };
+        match self.mode {
+            ExtractAll      => true,
+            ExtractFreshen  => out_path.is_file() && is_newer(),
+            ExtractUpdate   => !out_path.exists() || (out_path.is_file() && is_newer())
+        }
+    }
+
+    /// Map the name of a zip entry to its path under dest_dir, keeping only the file name with junk_paths.
+    /// Return None for an unsafe name.
+    pub fn dest_path(&self, dest_dir: &Path, name: &str) -> Option<Path> {
+        if self.junk_paths {
+            let name = name.trim_right_chars(&'/');
+            match name.rfind('/') {
+                Some(pos)   => self.path_policy.dest_path(dest_dir, name.slice_from(pos + 1)),
+                None        => self.path_policy.dest_path(dest_dir, name)
+            }
+        } else {
+            self.path_policy.dest_path(dest_dir, name)
+        }
+    }
+
+    /// Return the path to write the file of an entry, applying the overwrite policy if out_path exists.
+    /// Return None if the entry should be skipped.
+    pub fn write_path(&self, out_path: &Path) -> Option<Path> {
+        if !out_path.exists() {
+            return Some(out_path.clone());
+        }
+        match self.overwrite {
+            OverwriteNever          => None,
+            OverwriteAlways         => Some(out_path.clone()),
+            OverwriteRename         => Some(unique_path(out_path)),
+            OverwritePrompt(prompt) => if prompt(out_path) { Some(out_path.clone()) } else { None }
+        }
+    }
+
+}
+
+/// The rules to map the name of an archive entry to a path under the destination directory.
+/// The names are split on '/'; the empty and "." parts are dropped.
+#[deriving(Clone)]
+pub struct PathPolicy {
+    /// Map an absolute name under the destination directory, dropping the leading '/'.  Otherwise reject it.
+    allow_absolute: bool,
+    /// Drop the drive letter, e.g. "C:", and take backslash as a separator, for the names written on Windows.
+    strip_drive:    bool,
+    /// Resolve ".." against the earlier parts of the name, rejecting only the names that would go above the
+    /// destination directory.  Otherwise reject any name with "..".
+    resolve_dot_dot: bool,
+    /// Accept only the names under the directory prefix, e.g. "site/", and reject the others.
+    jail:           Option<~str>,
+}
+
+impl PathPolicy {
+
+    /// The default rules, which map the absolute names under the destination directory and reject any "..".
+    pub fn new() -> PathPolicy {
+        PathPolicy {
+            allow_absolute:     true,
+            strip_drive:        false,
+            resolve_dot_dot:    false,
+            jail:               None,
+        }
+    }
+
+    /// Apply the rules to the entry name, and return the relative path parts.  Return None for a rejected name.
+    pub fn sanitize(&self, name: &str) -> Option<~[~str]> {
+        let mut name = name.to_owned();
+        if self.strip_drive {
+            name = name.replace("\\", "/");
+            if name.len() >= 2 && name.char_at(1) == ':' && name.char_at(0).is_alphabetic() {
+                name = name.slice_from(2).to_owned();
+            }
+        }
+        if name.starts_with("/") && !self.allow_absolute {
+            return None;
+        }
+        let mut parts : ~[~str] = ~[];
+        for part in name.split('/') {
+            match part {
+                "" | "."                        => (),
+                ".." if !self.resolve_dot_dot   => return None,
+                ".."                            => if parts.pop_opt().is_none() { return None },
+                part                            => parts.push(part.to_owned())
+            }
+        }
+        if parts.len() == 0 {
+            return None;
+        }
+        match self.jail {
+            Some(ref jail) => {
+                let jail_parts : ~[&str] = jail.split('/').filter(|part| part.len() > 0).collect();
+                if parts.len() <= jail_parts.len() || parts.iter().zip(jail_parts.iter()).any(|(part, jail_part)| part.as_slice() != *jail_part) {
+                    return None;
+                }
+            },
+            None => ()
+        }
+        Some(parts)
+    }
+
+    /// Map the entry name to its path under dest_dir.  Return None for a rejected name.
+    pub fn dest_path(&self, dest_dir: &Path, name: &str) -> Option<Path> {
+        self.sanitize(name).map(|parts| dest_dir.join(parts.connect("/")))
+    }
+}
+
+/// The extract modes for incremental restores, like unzip -f and -u.
+pub enum ExtractMode {
+    /// Extract all the entries.
+    ExtractAll,
+    /// Extract only the entries whose files exist and are older.
+    ExtractFreshen,
+    /// Extract the entries whose files are older, and the entries whose files don't exist yet.
+    ExtractUpdate,
+}
+
+/// The overwrite policy on extracting an entry whose file already exists.
+pub enum OverwritePolicy {
+    /// Skip the entries of the existing files.
+    OverwriteNever,
+    /// Overwrite the existing files.
+    OverwriteAlways,
+    /// Keep the existing files and write to a new name, e.g. file(1).txt.
+    OverwriteRename,
+    /// Call the prompt function with the path of each existing file; overwrite it if the function returns true.
+    OverwritePrompt(fn(&Path) -> bool),
+}
+
+/// The policy on the entries of the same name.  The zip format permits duplicate names, and most unzip tools
+/// let the last one win, which can be used to hide an entry behind an innocent-looking one of the same name.
+pub enum DuplicatePolicy {
+    /// Use the first entry of the name in the central directory.
+    DuplicateFirst,
+    /// Use the last entry of the name in the central directory.
+    DuplicateLast,
+    /// Fail on any duplicate name.
+    DuplicateError,
+}
+
+/// The progress of extracting the entries, passed to the progress function set by ZipFile::set_progress_fn().
+#[deriving(Clone)]
+pub struct ExtractProgress {
+    /// Name of the entry being extracted
+    entry_name:     ~str,
+    /// Uncompressed size of the entry being extracted
+    entry_size:     u64,
+    /// Bytes of the entry extracted so far
+    entry_done:     u64,
+    /// Number of entries to extract, as given to ZipFile::start_progress()
+    entries_total:  uint,
+    /// Number of entries extracted so far
+    entries_done:   uint,
+    /// Uncompressed bytes to extract, as given to ZipFile::start_progress()
+    bytes_total:    u64,
+    /// Bytes extracted so far
+    bytes_done:     u64,
+    priv start_ns:  u64,
+    priv last_ns:   u64,
+}
+
+impl ExtractProgress {
+
+    /// Start the progress toward the totals.
+    pub fn new(entries_total: uint, bytes_total: u64) -> ExtractProgress {
+        let now = time::precise_time_ns();
+        ExtractProgress {
+            entry_name:     ~"",
+            entry_size:     0,
+            entry_done:     0,
+            entries_total:  entries_total,
+            entries_done:   0,
+            bytes_total:    bytes_total,
+            bytes_done:     0,
+            start_ns:       now,
+            last_ns:        now,
+        }
+    }
+
+    /// Seconds since the progress started.
+    pub fn elapsed_secs(&self) -> f64 {
+        (time::precise_time_ns() - self.start_ns) as f64 / 1000000000f64
+    }
+
+    /// Percentage of the total bytes extracted.
+    pub fn percent(&self) -> f64 {
+        if self.bytes_total > 0 { self.bytes_done as f64 * 100f64 / self.bytes_total as f64 } else { 100f64 }
+    }
+
+    /// Estimated seconds remaining at the throughput so far.  None before any byte is extracted.
+    pub fn eta_secs(&self) -> Option<u64> {
+        if self.bytes_done == 0 {
+            None
+        } else if self.bytes_done >= self.bytes_total {
+            Some(0)
+        } else {
+            Some(((self.bytes_total - self.bytes_done) as f64 * self.elapsed_secs() / self.bytes_done as f64) as u64)
+        }
+    }
+
+}
+
+/// The limits on extracting the zip entries, against the zip bombs.  None is unlimited.
+#[deriving(Clone)]
+pub struct ExtractLimits {
+    /// Maximum number of entries.
+    max_entries:    Option<uint>,
+    /// Maximum number of uncompressed bytes of all the entries.
+    max_total_size: Option<u64>,
+    /// Maximum ratio of the uncompressed size to the compressed size of an entry.
+    max_ratio:      Option<f64>,
+}
+
+impl ExtractLimits {
+
+    /// No limit.
+    pub fn new() -> ExtractLimits {
+        ExtractLimits {
+            max_entries:    None,
+            max_total_size: None,
+            max_ratio:      None,
+        }
+    }
+
+    /// Check the entries and their sizes in the central directory against the limits, with extracted_total bytes
+    /// extracted already.  Return the limit exceeded, if any.
+    pub fn check_entries(&self, entries: &[ZipEntry32], extracted_total: u64) -> Option<LimitExceeded> {
+        match self.max_entries {
+            Some(max_entries) if entries.len() > max_entries => return Some(TooManyEntries(max_entries)),
+            _ => ()
+        }
+        let total = entries.iter().fold(extracted_total, |total, entry| total + entry.uncompressed_size as u64);
+        match self.max_total_size {
+            Some(max_total_size) if total > max_total_size => return Some(TooLargeTotal(max_total_size)),
+            _ => ()
+        }
+        for entry in entries.iter() {
+            match self.max_ratio {
+                Some(max_ratio) if entry.uncompressed_size as u64 > max_ratio_size(entry, max_ratio) =>
+                    return Some(TooHighRatio(entry.file_name_as_str(), max_ratio)),
+                _ => ()
+            }
+        }
+        None
+    }
+
+    // Return the maximum number of bytes to read from the entry, and the limit that sets it.
+    fn output_limit(&self, entry: &ZipEntry32, extracted_total: u64) -> Option<(u64, LimitExceeded)> {
+        let by_total = self.max_total_size.map(|max_total_size| {
+            (if max_total_size > extracted_total { max_total_size - extracted_total } else { 0 }, TooLargeTotal(max_total_size))
+        });
+        let by_ratio = self.max_ratio.map(|max_ratio| (max_ratio_size(entry, max_ratio), TooHighRatio(entry.file_name_as_str(), max_ratio)));
+        match (by_total, by_ratio) {
+            (Some((total_size, total_limit)), Some((ratio_size, ratio_limit))) =>
+                Some(if total_size <= ratio_size { (total_size, total_limit) } else { (ratio_size, ratio_limit) }),
+            (by_total, None)                    => by_total,
+            (None, by_ratio)                    => by_ratio
+        }
+    }
+}
+
+/// The caps on the size of the central directory and the lengths of the entry header fields, against the malicious
+/// headers.  They are checked before the central directory and the fields are read in.  None is unlimited.
+#[deriving(Clone)]
+pub struct HeaderLimits {
+    /// Maximum size of the central directory, read in whole by get_zip_entries().
+    max_cd_size:        Option<u64>,
+    /// Maximum length of the file name of an entry.
+    max_name_len:       Option<uint>,
+    /// Maximum length of the extra field of an entry.
+    max_extra_len:      Option<uint>,
+    /// Maximum length of the comment of an entry.
+    max_comment_len:    Option<uint>,
+}
+
+impl HeaderLimits {
+
+    /// No limit.
+    pub fn new() -> HeaderLimits {
+        HeaderLimits {
+            max_cd_size:        None,
+            max_name_len:       None,
+            max_extra_len:      None,
+            max_comment_len:    None,
+        }
+    }
+
+    /// The limits for the untrusted input: 64M bytes of central directory, 4K bytes of file name,
+    /// 8K bytes of extra field and 4K bytes of comment.
+    pub fn untrusted() -> HeaderLimits {
+        HeaderLimits {
+            max_cd_size:        Some(64 * 1024 * 1024),
+            max_name_len:       Some(4096),
+            max_extra_len:      Some(8192),
+            max_comment_len:    Some(4096),
+        }
+    }
+
+    /// Check the size of the central directory against the limit.
+    pub fn check_cd_size(&self, cd_size: u64) -> Result<(), ~str> {
+        match self.max_cd_size {
+            Some(max_size) if cd_size > max_size =>
+                Err(format!("The central directory of {:u} bytes is over the limit of {:u} bytes.", cd_size, max_size)),
+            _ => Ok(())
+        }
+    }
+
+    /// Check the lengths of the file name, the extra field and the comment of an entry header against the limits.
+    pub fn check_lengths(&self, name_len: uint, extra_len: uint, comment_len: uint) -> Result<(), ~str> {
+        let checks = [("file name", name_len, self.max_name_len),
+                      ("extra field", extra_len, self.max_extra_len),
+                      ("comment", comment_len, self.max_comment_len)];
+        for &(field, len, max_len) in checks.iter() {
+            match max_len {
+                Some(max_len) if len > max_len =>
+                    return Err(format!("The {:s} of {:u} bytes of an entry is over the limit of {:u} bytes.", field, len, max_len)),
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    // Check the lengths in the fixed header of the entry, before its variable length fields are read.
+    fn check_entry(&self, entry: &ZipEntry32) -> Result<(), ~str> {
+        self.check_lengths(entry.file_name_length as uint, entry.extra_field_length as uint, entry.file_comment_length as uint)
+    }
+}
+
+
+// The largest uncompressed size of the entry within the ratio, counting an empty compressed data as one byte.
+fn max_ratio_size(entry: &ZipEntry32, max_ratio: f64) -> u64 {
+    (num::max(entry.compressed_size, 1) as f64 * max_ratio) as u64
+}
+
+/// The limit exceeded on extracting the zip entries.
+#[deriving(Clone)]
+pub enum LimitExceeded {
+    /// More entries than the maximum number.
+    TooManyEntries(uint),
+    /// More uncompressed bytes in total than the maximum size.
+    TooLargeTotal(u64),
+    /// The entry of the name expands more than the maximum ratio.
+    TooHighRatio(~str, f64),
+}
+
+impl LimitExceeded {
+    /// Describe the limit exceeded.
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            TooManyEntries(max_entries)     => format!("Limit exceeded: more than {:u} entries.", max_entries),
+            TooLargeTotal(max_total_size)   => format!("Limit exceeded: more than {:u} bytes in total.", max_total_size),
+            TooHighRatio(ref name, max_ratio) => format!("Limit exceeded: {:s} expands more than {:.1f} times.", *name, max_ratio)
+        }
+    }
+}
+
+
+/// A zip file's central directory metadata, located at the end of the file.
+pub struct CDMetaData {
+    /// number of this disk
+    disk_number:            u16,
+    /// number of the disk with the start of the central directory
+    cd_disk_number:         u16,
+    /// total number of entries in the central directory on this disk
+    cd_entry_count_on_disk: u16,
+    /// total number of entries in the central directory
+    cd_entry_count:         u16,
+    /// size of the central directory
+    cd_size:                u32,
+    /// offset of start of central directory
+    cd_entry_begin_offset:  u32,
+    /// ZIP file comment length
+    comment_length:         u16,
+    /// file comment
+    comment:                Option<~str>,
+
+    // Additional info
+
+    /// size of the zip file
+    file_size:              u64,
+}
+
+impl CDMetaData {
+
+    fn new() -> CDMetaData {
+        CDMetaData {
+            disk_number:            0u16,
+            cd_disk_number:         0u16,
+            cd_entry_count_on_disk: 0u16,
+            cd_entry_count:         0u16,
+            cd_size:                0u32,
+            cd_entry_begin_offset:  0u32,
+            comment_length:         0u16,
+            comment:                None,
+            file_size:              0u64,
+        }
+    }
+
+    fn read_cd_metadata(&mut self, file: &mut File) -> Result<uint, ~str> {
+        // Go to the end of the file and start searching for central directory metadata
+        file.seek(0i64, SeekEnd);
+        self.file_size = file.tell();
+        if self.file_size < CD_METADATA_SIZE as u64{
+            return Err(~"File too small to be a valid zip file.");
+        }
+
+        let max_search_size = num::min(self.file_size, MAX_CD_METADATA_SEARCH as u64) as uint;
+        file.seek(-(max_search_size as i64), SeekEnd);
+        let mut buf = vec::from_elem(max_search_size, 0u8);
+        let read_len = read_buf_upto(file, buf, 0, max_search_size);
+
+        for mut offset in range(0, read_len - 4) {
+
+            if unpack_num::<u32>(buf, offset, LittleEndian) == CD_METADATA_MAGIC {
+                // Got to the beginning of the central directory metadata section.
+                offset += 4;
+                self.disk_number = unpack_num::<u16>(buf, offset, LittleEndian);
+                offset += 2;
+                self.cd_disk_number = unpack_num::<u16>(buf, offset, LittleEndian);
+                offset += 2;
+                self.cd_entry_count_on_disk = unpack_num::<u16>(buf, offset, LittleEndian);
+                offset += 2;
+                self.cd_entry_count = unpack_num::<u16>(buf, offset, LittleEndian);
+                offset += 2;
+                self.cd_size = unpack_num::<u32>(buf, offset, LittleEndian);
+                offset += 4;
+                self.cd_entry_begin_offset = unpack_num::<u32>(buf, offset, LittleEndian);
+                offset += 4;
+                self.comment_length = unpack_num::<u16>(buf, offset, LittleEndian);
+                offset += 2;
+                if self.comment_length > 0 {
+                    self.comment = Some(str::from_utf8(buf.slice(offset, offset + self.comment_length as uint)));
+                }
+                return Ok(0);
+            }
+        }
+        Err(~"Zip file central directory signature missing.")
+    }
+
+    fn pack_cd_metadata(&self) -> ~[u8] {
+        let comment = self.comment.clone().unwrap_or(~"");
+        let mut buf = vec::from_elem(CD_METADATA_SIZE + comment.len(), 0u8);
+        let mut offset = pack_num::<u32>(buf, 0, CD_METADATA_MAGIC, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.disk_number, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.cd_disk_number, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.cd_entry_count_on_disk, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.cd_entry_count, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.cd_size, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.cd_entry_begin_offset, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, comment.len() as u16, LittleEndian);
+        pack_bytes(buf, offset, comment.as_bytes());
+        buf
+    }
+
+}
+
+/// The local file header of a file item in a zip file
+#[deriving(Clone)]
+pub struct LocalFileHeader {
+    /// version needed to extract
+    version_needed:             u16,
+    ///general purpose bit flag
+    general_flag:               u16,
+    /// compression method
+    compression_method:         u16,
+    /// last mod file time
+    modified_time:              u16,
+    /// last mod file date
+    modified_date:              u16,
+    /// crc-32
+    crc32:                      u32,
+    /// compressed size
+    compressed_size:            u32,
+    /// uncompressed size
+    uncompressed_size:          u32,
+    /// file name length
+    file_name_length:           u16,
+    /// extra field length
+    extra_field_length:         u16,
+    /// file name
+    file_name:                  Option<~[u8]>,
+    /// extra field
+    extra_field:                Option<~[u8]>,
+}
+
+impl LocalFileHeader {
+
+    fn new() -> LocalFileHeader {
+        LocalFileHeader {
+            version_needed:             0u16,
+            general_flag:               0u16,
+            compression_method:         0u16,
+            modified_time:              0u16,
+            modified_date:              0u16,
+            crc32:                      0u32,
+            compressed_size:            0u32,
+            uncompressed_size:          0u32,
+            file_name_length:           0u16,
+            extra_field_length:         0u16,
+            file_name:                  None,
+            extra_field:                None,
+        }
+    }
+
+    fn unpack_header(&mut self, buf: &[u8], mut offset: uint) -> uint {
+
+        if unpack_num::<u32>(buf, offset, LittleEndian) != LOCAL_HEADER_MAGIC {
+            io_error::cond.raise(IoError { kind: OtherIoError, desc: "Zip local file header signature mismatched", detail: None });
+        }
+        offset += 4;
+
+        self.version_needed = unpack_num::<u16>(buf, offset, LittleEndian);           offset += 2;
+        self.general_flag = unpack_num::<u16>(buf, offset, LittleEndian);             offset += 2;
+        self.compression_method = unpack_num::<u16>(buf, offset, LittleEndian);       offset += 2;
+        self.modified_time = unpack_num::<u16>(buf, offset, LittleEndian);            offset += 2;
+        self.modified_date = unpack_num::<u16>(buf, offset, LittleEndian);            offset += 2;
+        self.crc32 = unpack_num::<u32>(buf, offset, LittleEndian);                    offset += 4;
+        self.compressed_size = unpack_num::<u32>(buf, offset, LittleEndian);          offset += 4;
+        self.uncompressed_size = unpack_num::<u32>(buf, offset, LittleEndian);        offset += 4;
+        self.file_name_length = unpack_num::<u16>(buf, offset, LittleEndian);         offset += 2;
+        self.extra_field_length = unpack_num::<u16>(buf, offset, LittleEndian);       offset += 2;
+
+        return offset;
+    }
+
+    // Unpack the variable length header of the zip entry.
+    fn unpack_header_rest(&mut self, buf: &[u8], mut offset: uint) -> uint {
+        if self.file_name_length > 0 {
+            self.file_name = Some(buf.slice(offset, offset + self.file_name_length as uint).to_owned());
+            offset += self.file_name_length as uint;
+        }
+        if self.extra_field_length > 0 {
+            self.extra_field = Some(buf.slice(offset, offset + self.extra_field_length as uint).to_owned());
+            offset += self.extra_field_length as uint;
+        }
+        offset
+    }
+
+    fn pack_header(&self) -> ~[u8] {
+        let mut buf = vec::from_elem(self.get_total_length(), 0u8);
+        let mut offset = pack_num::<u32>(buf, 0, LOCAL_HEADER_MAGIC, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.version_needed, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.general_flag, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.compression_method, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.modified_time, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.modified_date, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.crc32, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.compressed_size, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.uncompressed_size, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.file_name_length, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.extra_field_length, LittleEndian);
+        offset = pack_bytes(buf, offset, self.file_name.clone().unwrap_or(~[]));
+        pack_bytes(buf, offset, self.extra_field.clone().unwrap_or(~[]));
+        buf
+    }
+
+    /// Return the file name of the entry.
+    pub fn file_name_as_str(&self) -> ~str {
+        match self.file_name {
+            Some(ref file_name) => str::from_utf8(*file_name),
+            None                => ~""
+        }
+    }
+
+    /// Check whether the entry is a directory, whose name ends with /.
+    pub fn is_dir(&self) -> bool {
+        self.file_name_as_str().ends_with("/")
+    }
+
+    /// Return the modified, accessed and created times in milliseconds from the NTFS extra field, if it's there.
+    pub fn ntfs_times(&self) -> Option<(u64, u64, u64)> {
+        find_ntfs_times(self.extra_field.clone().unwrap_or(~[]))
+    }
+
+    /// Return the uid and the gid from the Unix owner extra field, if it's there.
+    pub fn unix_owner(&self) -> Option<(u32, u32)> {
+        find_unix_owner(self.extra_field.clone().unwrap_or(~[]))
+    }
+
+    fn get_rest_length(&self) -> uint {
+        return self.file_name_length as uint + self.extra_field_length as uint;
+    }
+
+    fn get_total_length(&self) -> uint {
+        return LOCAL_FILE_HEADER_SIZE + self.get_rest_length();
+    }
+
+    // Append the alignment extra field, padded so that the data after the header, at data_offset now, is aligned.
+    fn add_alignment_padding(&mut self, data_offset: uint, alignment: uint) {
+        let pad_len = (alignment - (data_offset + ALIGNMENT_FIELD_MIN) % alignment) % alignment;
+        let mut field = vec::from_elem(ALIGNMENT_FIELD_MIN + pad_len, 0u8);
+        let mut offset = pack_num::<u16>(field, 0, EXTRA_ALIGNMENT, LittleEndian);
+        offset = pack_num::<u16>(field, offset, (ALIGNMENT_FIELD_MIN - 4 + pad_len) as u16, LittleEndian);
+        pack_num::<u16>(field, offset, alignment as u16, LittleEndian);
+        let mut extra_field = self.extra_field.clone().unwrap_or(~[]);
+        extra_field.push_all(field);
+        self.extra_field_length = extra_field.len() as u16;
+        self.extra_field = Some(extra_field);
+    }
+
+    fn read_header(&mut self, file: &mut File) {
+        let mut buf = [0u8, ..LOCAL_FILE_HEADER_SIZE];
+        if !read_buf_exact(file, buf, 0, LOCAL_FILE_HEADER_SIZE, "Zip local file header does not have enough data") {
+            return;
+        }
+
+        self.unpack_header(buf, 0);
+        match read_exact(file, self.get_rest_length(), "Zip local file header does not have enough data for the name and extra field") {
+            Some(buf)   => self.unpack_header_rest(buf, 0),
+            None        => ()
+        }
+    }
+
+}
+
+
+/// A file item entry for a file item embedded in a zip file.
+#[deriving(Clone)]
+pub struct ZipEntry32 {
+    /// version of zip format created this entry
+    version_made_by:            u16,
+    /// version needed to extract
+    version_needed:             u16,
+    ///general purpose bit flag
+    general_flag:               u16,
+    /// compression method
+    compression_method:         u16,
     /// last mod file time
     modified_time:              u16,
     /// last mod file date
@@ -381,385 +1943,3508 @@ pub struct ZipEntry32 {
     /// file comment
     file_comment:               Option<~str>,
 
-    /// local file header
-    local_header:               LocalFileHeader,
+    /// local file header
+    local_header:               LocalFileHeader,
+
+    priv file_name_str:         Option<~str>,       // the file name decoded on the first name_str()
+}
+
+impl ZipEntry32 {
+
+    fn new() -> ZipEntry32 {
+        ZipEntry32 {
+            version_made_by:            0u16,
+            version_needed:             0u16,
+            general_flag:               0u16,
+            compression_method:         0u16,
+            modified_time:              0u16,
+            modified_date:              0u16,
+            crc32:                      0u32,
+            compressed_size:            0u32,
+            uncompressed_size:          0u32,
+            file_name_length:           0u16,
+            extra_field_length:         0u16,
+            file_comment_length:        0u16,
+            disk_number_start:          0u16,
+            internal_file_attributes:   0u16,
+            external_file_attributes:   0u32,
+            local_header_offset:        0u32,
+            file_name:                  None,
+            extra_field:                None,
+            file_comment:               None,
+            local_header:               LocalFileHeader::new(),
+            file_name_str:              None,
+        }
+    }
+
+    // Unpack the fixed header of the zip entry.
+    fn unpack_zip_entry(&mut self, buf: &[u8], mut offset: uint) -> Result<uint, ~str> {
+
+        if unpack_num::<u32>(buf, offset, LittleEndian) != CD_HEADER_MAGIC {
+            return Err(~"Zip file entry signature mismatched.");
+        }
+        offset += 4;
+
+        self.version_made_by = unpack_num::<u16>(buf, offset, LittleEndian);          offset += 2;
+        self.version_needed = unpack_num::<u16>(buf, offset, LittleEndian);           offset += 2;
+        self.general_flag = unpack_num::<u16>(buf, offset, LittleEndian);             offset += 2;
+        self.compression_method = unpack_num::<u16>(buf, offset, LittleEndian);       offset += 2;
+        self.modified_time = unpack_num::<u16>(buf, offset, LittleEndian);            offset += 2;
+        self.modified_date = unpack_num::<u16>(buf, offset, LittleEndian);            offset += 2;
+        self.crc32 = unpack_num::<u32>(buf, offset, LittleEndian);                    offset += 4;
+        self.compressed_size = unpack_num::<u32>(buf, offset, LittleEndian);          offset += 4;
+        self.uncompressed_size = unpack_num::<u32>(buf, offset, LittleEndian);        offset += 4;
+        self.file_name_length = unpack_num::<u16>(buf, offset, LittleEndian);         offset += 2;
+        self.extra_field_length = unpack_num::<u16>(buf, offset, LittleEndian);       offset += 2;
+        self.file_comment_length = unpack_num::<u16>(buf, offset, LittleEndian);      offset += 2;
+        self.disk_number_start = unpack_num::<u16>(buf, offset, LittleEndian);        offset += 2;
+        self.internal_file_attributes = unpack_num::<u16>(buf, offset, LittleEndian); offset += 2;
+        self.external_file_attributes = unpack_num::<u32>(buf, offset, LittleEndian); offset += 4;
+        self.local_header_offset = unpack_num::<u32>(buf, offset, LittleEndian);      offset += 4;
+
+        return Ok(offset);
+    }
+
+    /// Return the file name of the entry.  Use name_str() to decode the name once for repeated uses.
+    pub fn file_name_as_str(&self) -> ~str {
+        match self.file_name_str {
+            Some(ref name)  => name.clone(),
+            None            => decode_name(self.file_name_bytes())
+        }
+    }
+
+    /// Return the raw bytes of the file name, for comparing the names without decoding them.
+    pub fn file_name_bytes<'a>(&'a self) -> &'a [u8] {
+        match self.file_name {
+            Some(ref file_name) => file_name.as_slice(),
+            None                => &[]
+        }
+    }
+
+    /// Return the file name of the entry, decoding it on the first call and keeping it for the later calls.
+    pub fn name_str<'a>(&'a mut self) -> &'a str {
+        if self.file_name_str.is_none() {
+            self.file_name_str = Some(decode_name(self.file_name_bytes()));
+        }
+        self.file_name_str.get_ref().as_slice()
+    }
+
+    /// Check whether the file name is the name, comparing the raw bytes.
+    pub fn name_eq(&self, name: &str) -> bool {
+        self.file_name_bytes() == name.as_bytes()
+    }
+
+    /// Check whether the entry is a directory, whose name ends with /.
+    pub fn is_dir(&self) -> bool {
+        self.file_name_bytes().ends_with(bytes!("/"))
+    }
+
+    /// Return the zip version made by, as major.minor.
+    pub fn version_made_by_as_str(&self) -> ~str {
+        let version = (self.version_made_by & 0xFF) as uint;
+        format!("{:u}.{:u}", version / 10, version % 10)
+    }
+
+    /// Return the zip version needed to extract, as major.minor.
+    pub fn version_needed_as_str(&self) -> ~str {
+        let version = (self.version_needed & 0xFF) as uint;
+        format!("{:u}.{:u}", version / 10, version % 10)
+    }
+
+    /// Return the name of the host OS creating the entry, from the version made by.
+    pub fn host_os_as_str(&self) -> ~str {
+        match self.version_made_by >> 8 {
+            0   => ~"MS-DOS",
+            1   => ~"Amiga",
+            2   => ~"OpenVMS",
+            3   => ~"Unix",
+            6   => ~"OS/2",
+            7   => ~"Macintosh",
+            10  => ~"Windows NTFS",
+            11  => ~"MVS",
+            14  => ~"VFAT",
+            19  => ~"OS X",
+            os  => format!("OS {:u}", os as uint)
+        }
+    }
+
+    /// Return the Unix mode in the external file attributes, if the entry has one.
+    pub fn unix_mode(&self) -> Option<u32> {
+        let mode = self.external_file_attributes >> 16;
+        if mode != 0 { Some(mode) } else { None }
+    }
+
+    /// Return the names of the general purpose flags set.
+    pub fn flags_as_str(&self) -> ~str {
+        let mut names = ~[];
+        if (self.general_flag & FLAG_ENCRYPTED) == FLAG_ENCRYPTED {
+            names.push("encrypted");
+        }
+        if (self.general_flag & FLAG_DATA_DESCRIPTOR) == FLAG_DATA_DESCRIPTOR {
+            names.push("data descriptor");
+        }
+        if (self.general_flag & FLAG_UTF8) == FLAG_UTF8 {
+            names.push("UTF-8");
+        }
+        names.connect(", ")
+    }
+
+    /// Return the id and the data of each field in the extra field.
+    pub fn extra_fields(&self) -> ~[(u16, ~[u8])] {
+        match self.extra_field {
+            Some(ref extra_field)   => split_extra_fields(*extra_field),
+            None                    => ~[]
+        }
+    }
+
+    /// Return the modified, accessed and created times in milliseconds from the NTFS extra field, if it's there.
+    pub fn ntfs_times(&self) -> Option<(u64, u64, u64)> {
+        find_ntfs_times(self.extra_field.clone().unwrap_or(~[]))
+    }
+
+    /// Return the uid and the gid from the Unix owner extra field, if it's there.
+    pub fn unix_owner(&self) -> Option<(u32, u32)> {
+        find_unix_owner(self.extra_field.clone().unwrap_or(~[]))
+    }
+
+    /// Return the name of the compression method.
+    pub fn method_as_str(&self) -> ~str {
+        match self.compression_method {
+            METHOD_STORE    => ~"Stored",
+            METHOD_DEFLATE  => ~"Defl",
+            method          => format!("M{:u}", method as uint)
+        }
+    }
+
+    /// Return the modified time decoded from the MS-DOS time and date, as (year, month, day, hour, minute, second).
+    pub fn modified_datetime(&self) -> (uint, uint, uint, uint, uint, uint) {
+        let date = self.modified_date as uint;
+        let time = self.modified_time as uint;
+        ((date >> 9) + 1980, (date >> 5) & 0x0F, date & 0x1F, time >> 11, (time >> 5) & 0x3F, (time & 0x1F) * 2)
+    }
+
+    /// Return the compression ratio as the percentage of space saved.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.uncompressed_size > 0 {
+            100f64 - (self.compressed_size as f64 * 100f64 / self.uncompressed_size as f64)
+        } else {
+            0f64
+        }
+    }
+
+    /// Check whether the entry is older than the modified time in milliseconds, to the 2-second resolution of MS-DOS time.
+    pub fn is_older_than(&self, mtime: u64) -> bool {
+        let (dos_time, dos_date) = to_dos_time(mtime / 1000);
+        (self.modified_date, self.modified_time) < (dos_date, dos_time)
+    }
+
+    /// Check whether the entry is a symlink, by the Unix mode in the external file attributes.
+    pub fn is_symlink(&self) -> bool {
+        (self.external_file_attributes >> 16) & UNIX_TYPE_MASK == UNIX_LINK_MODE
+    }
+
+    /// Check whether the entry data is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        (self.general_flag & FLAG_ENCRYPTED) == FLAG_ENCRYPTED
+    }
+
+    fn get_extra_length(&self) -> uint {
+        return self.file_name_length as uint + self.extra_field_length as uint + self.file_comment_length as uint;
+    }
+
+    fn pack_zip_entry(&self) -> ~[u8] {
+        let mut buf = vec::from_elem(CD_FILE_HEADER_SIZE + self.get_extra_length(), 0u8);
+        let mut offset = pack_num::<u32>(buf, 0, CD_HEADER_MAGIC, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.version_made_by, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.version_needed, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.general_flag, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.compression_method, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.modified_time, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.modified_date, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.crc32, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.compressed_size, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.uncompressed_size, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.file_name_length, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.extra_field_length, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.file_comment_length, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.disk_number_start, LittleEndian);
+        offset = pack_num::<u16>(buf, offset, self.internal_file_attributes, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.external_file_attributes, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.local_header_offset, LittleEndian);
+        offset = pack_bytes(buf, offset, self.file_name.clone().unwrap_or(~[]));
+        offset = pack_bytes(buf, offset, self.extra_field.clone().unwrap_or(~[]));
+        pack_bytes(buf, offset, self.file_comment.clone().unwrap_or(~"").as_bytes());
+        buf
+    }
+
+    // The local header has the same fields as the central directory entry.
+    fn to_local_header(&self) -> LocalFileHeader {
+        LocalFileHeader {
+            version_needed:             self.version_needed,
+            general_flag:               self.general_flag,
+            compression_method:         self.compression_method,
+            modified_time:              self.modified_time,
+            modified_date:              self.modified_date,
+            crc32:                      self.crc32,
+            compressed_size:            self.compressed_size,
+            uncompressed_size:          self.uncompressed_size,
+            file_name_length:           self.file_name_length,
+            extra_field_length:         self.extra_field_length,
+            file_name:                  self.file_name.clone(),
+            extra_field:                self.extra_field.clone(),
+        }
+    }
+
+    // The data descriptor after the entry data, with its signature.
+    fn pack_data_descriptor(&self) -> ~[u8] {
+        let mut buf = vec::from_elem(DATA_DESCRIPTOR_SIZE + 4, 0u8);
+        let mut offset = pack_num::<u32>(buf, 0, LOCAL_DESC_MAGIC, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.crc32, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, self.compressed_size, LittleEndian);
+        pack_num::<u32>(buf, offset, self.uncompressed_size, LittleEndian);
+        buf
+    }
+
+    // Unpack the variable length header of the zip entry.
+    fn unpack_zip_entry_extra(&mut self, buf: &[u8], mut offset: uint) -> uint {
+        if self.file_name_length > 0 {
+            self.file_name = Some(buf.slice(offset, offset + self.file_name_length as uint).to_owned());
+            self.file_name_str = None;
+            offset += self.file_name_length as uint;
+        }
+        if self.extra_field_length > 0 {
+            self.extra_field = Some(buf.slice(offset, offset + self.extra_field_length as uint).to_owned());
+            offset += self.extra_field_length as uint;
+        }
+        if self.file_comment_length > 0 {
+            self.file_comment = Some(str::from_utf8( buf.slice(offset, offset + self.file_comment_length as uint) ));
+            offset += self.file_comment_length as uint;
+        }
+        offset
+    }
+
+    fn read_zip_entry(file: &mut File) -> Result<ZipEntry32, ~str> {
+        let mut buf = [0u8, ..CD_FILE_HEADER_SIZE];
+        let read_len = read_buf_upto(file, buf, 0, CD_FILE_HEADER_SIZE);
+        if read_len < CD_FILE_HEADER_SIZE {
+            return Err(~"Zip file entry does not have enough data.");
+        }
+        
+        let mut entry = ZipEntry32::new();
+        match entry.unpack_zip_entry(buf, 0) {
+            Err(s) => return Err(s),
+            Ok(_) => {
+                let buf = read_upto(file, entry.get_extra_length());
+                if buf.len() < entry.get_extra_length() {
+                    return Err(~"Zip file entry does not have enough data for the name, extra field and comment.");
+                }
+                entry.unpack_zip_entry_extra(buf, 0);
+            }
+        }
+        Ok(entry)
+    }
+
+    fn read_local_file_header(&mut self, file: &mut File) {
+        file.seek(self.local_header_offset as i64, SeekSet);
+        self.local_header.read_header(file)
+    }
+
+    fn get_file_data_offset(&self) -> i64 {
+        self.local_header_offset as i64 + self.local_header.get_total_length() as i64
+    }
+
+    fn read_file_data(&mut self, file: &mut File, read_offset: u64, output_buf: &mut [u8]) -> uint {
+        let remaining_len = self.compressed_size as u64 - read_offset;
+        if remaining_len == 0 {
+            return 0;
+        }
+        file.seek(self.get_file_data_offset() + read_offset as i64, SeekSet);
+        let bytes_to_read = num::min(remaining_len, output_buf.len() as u64) as uint;
+        match file.read(output_buf.mut_slice(0, bytes_to_read)) {
+            Some(read_len)  => read_len,
+            None            => 0
+        }
+    }
+
+    fn has_data_descriptor(&self) -> bool {
+        (self.general_flag & FLAG_DATA_DESCRIPTOR) == FLAG_DATA_DESCRIPTOR
+    }
+
+    fn checkCrc(&self, cmp_crc32: u32) -> bool {
+        self.crc32 == cmp_crc32
+    }
+
+}
+
+/// Writer to create a new zip file.  Add the file items one at a time, then call finish() to write the central directory.
+/// The file needs to be seekable, since the CRC and the sizes of each entry are filled in after its data are written.
+pub struct ZipWriter {
+    priv inner_file:    File,
+    priv entries:       ~[ZipEntry32],
+    priv finished:      bool,
+    priv auto_store:    bool,
+    priv ntfs_times:    bool,
+    priv unix_owner:    bool,
+    priv alignment:     uint,
+    priv plain_names:   ~[~str],
+    priv cancel:        Option<CancelToken>,
+}
+
+impl ZipWriter {
+
+    /// Create a zip writer writing to the file.
+    pub fn new(file: File) -> ZipWriter {
+        ZipWriter {
+            inner_file: file,
+            entries:    ~[],
+            finished:   false,
+            auto_store: false,
+            ntfs_times: false,
+            unix_owner: true,
+            alignment:  0,
+            plain_names: ~[],
+            cancel:     None,
+        }
+    }
+
+    /// Set whether add_file() adds the Unix owner extra field with the uid and the gid of the file.
+    /// It's on by default, and only takes effect on Unix.
+    pub fn set_unix_owner(&mut self, unix_owner: bool) {
+        self.unix_owner = unix_owner;
+    }
+
+    /// Set whether to add the NTFS extra field to the entries, with the modified, accessed and created times
+    /// at the 100ns resolution of NTFS, so that the Windows tools restore the full-resolution times.
+    pub fn set_ntfs_times(&mut self, ntfs_times: bool) {
+        self.ntfs_times = ntfs_times;
+    }
+
+    /// Set the alignment of the data of the stored entries, e.g. 4 as Android zipalign requires, by padding
+    /// the extra field of their local headers.  0 or 1 turns it off, which is the default.
+    pub fn set_alignment(&mut self, alignment: uint) {
+        self.alignment = alignment;
+    }
+
+    /// Set the names of the entries to write without any extra field, e.g. the mimetype entry of EPUB and ODF.
+    /// They are not padded for the alignment either.
+    pub fn set_plain_entries(&mut self, names: ~[~str]) {
+        self.plain_names = names;
+    }
+
+    /// Set the token to stop adding the entries with add_file() and add_reader().  It's checked before each entry
+    /// and between the buffers of data.  The stopped entry fails with the ioutil::CANCELLED error and is left out
+    /// of the central directory.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Set whether add_file() stores the files not worth deflating, like already-compressed media,
+    /// when asked for the deflate method.  It's decided with is_file_worth_deflating().
+    pub fn set_auto_store(&mut self, auto_store: bool) {
+        self.auto_store = auto_store;
+    }
+
+    /// Add a directory entry.  A / is appended to the name if it doesn't have one.
+    /// mtime is the modified time in milliseconds.  perm is the Unix permission bits.
+    pub fn add_dir(&mut self, name: &str, mtime: u64, perm: u32) -> Result<ZipEntry32, ~str> {
+        if self.entries.len() >= MAX_ENTRY_COUNT {
+            return Err(format!("Too many entries in zip file.  {:s} is not added.", name));
+        }
+        let dir_name = if name.ends_with("/") { name.to_owned() } else { name + "/" };
+        let mut entry = new_entry(dir_name, METHOD_STORE, mtime, (UNIX_DIR_MODE | perm) << 16 | DOS_DIR_ATTR);
+        self.add_ntfs_times(&mut entry, (mtime, mtime, mtime));
+        self.write_local_header(&mut entry);
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// Add the file at the path as an entry with the name, compressed with the method.
+    /// With set_auto_store(), the deflate method falls back to store for the file not worth deflating.
+    pub fn add_file(&mut self, path: &Path, name: &str, method: u16, compress_level: uint) -> Result<ZipEntry32, ~str> {
+        let stat = fs::stat(path);
+        match File::open_mode(path, Open, Read) {
+            Some(file) => {
+                let mut file = file;
+                let method = if method == METHOD_DEFLATE && self.auto_store && 
+                                !deflate::is_worth_deflating(read_samples(&mut file, stat.size)) {
+                    METHOD_STORE
+                } else {
+                    method
+                };
+                let owner = if self.unix_owner { file_owner(&stat) } else { None };
+                self.add_reader_mode(&mut file, name, (stat.modified, stat.accessed, stat.created), owner,
+                                     UNIX_FILE_MODE | stat.perm as u32, method, compress_level)
+            },
+            None => Err(format!("Failed to open file {:s}", path.as_str().unwrap_or("")))
+        }
+    }
+
+    /// Add all the data read from the reader as an entry with the name, compressed with the method.
+    /// mtime is the modified time in milliseconds.  perm is the Unix permission bits.
+    /// compress_level is 0-9 for the deflate method.
+    pub fn add_reader<R: Reader>(&mut self, reader: &mut R, name: &str, mtime: u64, perm: u32, 
+                                 method: u16, compress_level: uint) -> Result<ZipEntry32, ~str> {
+        self.add_reader_mode(reader, name, (mtime, mtime, mtime), None, UNIX_FILE_MODE | perm, method, compress_level)
+    }
+
+    /// Add a symlink entry with the name, storing the link target as its data like Info-ZIP does.
+    pub fn add_symlink(&mut self, name: &str, target: &str, mtime: u64) -> Result<ZipEntry32, ~str> {
+        let mut reader = MemReader::new(target.as_bytes().to_owned());
+        self.add_reader_mode(&mut reader, name, (mtime, mtime, mtime), None, UNIX_LINK_MODE | 0x1FF, METHOD_STORE, 0)
+    }
+
+    // Add the entry with the data from the reader.  times are the modified, accessed and created times in milliseconds.
+    // owner is the uid and the gid for the Unix owner extra field.
+    fn add_reader_mode<R: Reader>(&mut self, reader: &mut R, name: &str, times: (u64, u64, u64), owner: Option<(u32, u32)>,
+                                  unix_mode: u32, method: u16, compress_level: uint) -> Result<ZipEntry32, ~str> {
+        if self.entries.len() >= MAX_ENTRY_COUNT {
+            return Err(format!("Too many entries in zip file.  {:s} is not added.", name));
+        }
+        if ioutil::is_cancelled(&self.cancel) {
+            return Err(ioutil::CANCELLED.to_owned());
+        }
+        let (mtime, _, _) = times;
+        let mut entry = new_entry(name, method, mtime, unix_mode << 16);
+        self.add_ntfs_times(&mut entry, times);
+        match owner {
+            Some(owner) => append_extra_field(&mut entry, unix_owner_extra_field(owner)),
+            None        => ()
+        }
+        self.write_local_header(&mut entry);
+
+        let mut crc32 = 0u32;
+        let mut read_total = 0u64;
+        let mut write_total = 0u64;
+        match method {
+            METHOD_STORE => {
+                let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+                loop {
+                    if ioutil::is_cancelled(&self.cancel) {
+                        return Err(ioutil::CANCELLED.to_owned());
+                    }
+                    match reader.read(buf) {
+                        Some(read_len) => {
+                            crc32 = update_crc(crc32, buf, 0, read_len);
+                            read_total += read_len as u64;
+                            self.inner_file.write(buf.slice(0, read_len));
+                        },
+                        None => break
+                    }
+                }
+                write_total = read_total;
+            },
+            METHOD_DEFLATE => {
+                let mut deflator = Deflator::new();
+                match deflator.init(compress_level, false, false) {
+                    Ok(_) => (),
+                    Err(err) => return Err(format!("Failed to initialize deflator.  {:s}", err.to_str()))
+                }
+                let inner_file = &mut self.inner_file;
+                let cancel = &self.cancel;
+                let result = deflator.compress_stream(
+                    // upcall function to read input data for compression
+                    |in_buf| {
+                        if ioutil::is_cancelled(cancel) {
+                            return 0;       // stop reading; the cancellation is returned below
+                        }
+                        match reader.read(in_buf) {
+                            Some(read_len) => {
+                                crc32 = update_crc(crc32, in_buf, 0, read_len);
+                                read_total += read_len as u64;
+                                read_len
+                            },
+                            None => 0       // EOF
+                        }
+                    },
+                    // upcall function to write the compressed data
+                    |out_buf, _| {
+                        inner_file.write(out_buf);
+                        write_total += out_buf.len() as u64;
+                        false               // don't abort
+                    });
+                match result {
+                    Ok(_) if ioutil::is_cancelled(cancel) => return Err(ioutil::CANCELLED.to_owned()),
+                    Ok(_) => (),
+                    Err(err) => return Err(format!("Failed to compress data.  {:s}", err.to_str()))
+                }
+            },
+            _ => return Err(format!("Unsupported compression method: {:u}", method as uint))
+        }
+
+        self.finish_entry(entry, crc32, read_total, write_total)
+    }
+
+    /// Add the entry with the data from the reader, with the metadata and the compression in the options.
+    pub fn add_reader_with_options<R: Reader>(&mut self, reader: &mut R, name: &str, options: &EntryOptions) -> Result<ZipEntry32, ~str> {
+        let mut entry_writer = match self.start_entry(name, options) {
+            Ok(entry_writer)    => entry_writer,
+            Err(s)              => return Err(s)
+        };
+        let mut result = Ok(());
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+            loop {
+                match reader.read(buf) {
+                    Some(read_len)  => entry_writer.write(buf.slice(0, read_len)),
+                    None            => break
+                }
+            }
+        });
+        match result {
+            Ok(_)   => entry_writer.finish(),
+            Err(s)  => Err(s)
+        }
+    }
+
+    /// Start a new entry with the name, and return the writer for its data.  The data don't need to be known
+    /// up front, e.g. for zipping the data generated on the fly.  The entry is done when the writer is finished
+    /// or dropped; the CRC and the sizes are filled in its local header then.
+    pub fn start_entry<'a>(&'a mut self, name: &str, options: &EntryOptions) -> Result<ZipEntryWriter<'a>, ~str> {
+        if self.entries.len() >= MAX_ENTRY_COUNT {
+            return Err(format!("Too many entries in zip file.  {:s} is not added.", name));
+        }
+        let deflator = match options.method {
+            METHOD_STORE    => None,
+            METHOD_DEFLATE  => {
+                let mut deflator = Deflator::new();
+                match deflator.init(options.compress_level, false, false) {
+                    Ok(_) => Some(deflator),
+                    Err(err) => return Err(format!("Failed to initialize deflator.  {:s}", err.to_str()))
+                }
+            },
+            method => return Err(format!("Unsupported compression method: {:u}", method as uint))
+        };
+        let external_attributes = options.external_attributes.unwrap_or(options.unix_mode << 16);
+        let mut entry = new_entry(name, options.method, options.mtime, external_attributes);
+        entry.internal_file_attributes = options.internal_attributes;
+        self.add_ntfs_times(&mut entry, (options.mtime, options.mtime, options.mtime));
+        self.write_local_header(&mut entry);
+
+        Ok(ZipEntryWriter {
+            zip_writer:     self,
+            entry:          entry,
+            deflator:       deflator,
+            crc32:          0u32,
+            read_total:     0u64,
+            write_total:    0u64,
+            finished:       false,
+        })
+    }
+
+    // Fill in the CRC and the sizes of the entry whose data have been written, and add it to the central directory.
+    fn finish_entry(&mut self, entry: ZipEntry32, crc32: u32, read_total: u64, write_total: u64) -> Result<ZipEntry32, ~str> {
+        let mut entry = entry;
+        let name = entry.file_name_as_str();
+        let method = entry.compression_method;
+
+        // The entry data written so far are left out of the central directory on error.
+        if read_total > MAX_ZIP32_SIZE || write_total > MAX_ZIP32_SIZE {
+            return Err(format!("File {:s} is too big for a zip file.", name));
+        }
+        entry.crc32 = crc32;
+        entry.compressed_size = write_total as u32;
+        entry.uncompressed_size = read_total as u32;
+        // Keep the local header as written, which may have the alignment padding.
+        entry.local_header.crc32 = entry.crc32;
+        entry.local_header.compressed_size = entry.compressed_size;
+        entry.local_header.uncompressed_size = entry.uncompressed_size;
+        if trace::is_on() {
+            trace::trace(format!("zip: added {:s}, method {:u}, {:u} -> {:u} bytes, crc {:08x}, local header at {:u}",
+                                 name, method as uint, read_total as uint, write_total as uint, crc32 as uint, entry.local_header_offset as uint));
+        }
+
+        // Fill in the CRC and the sizes in the local header.
+        let mut buf = [0u8, ..12];
+        let mut offset = pack_num::<u32>(buf, 0, entry.crc32, LittleEndian);
+        offset = pack_num::<u32>(buf, offset, entry.compressed_size, LittleEndian);
+        pack_num::<u32>(buf, offset, entry.uncompressed_size, LittleEndian);
+        let end_offset = self.inner_file.tell();
+        self.inner_file.seek(entry.local_header_offset as i64 + LOCAL_CRC_OFFSET as i64, SeekSet);
+        self.inner_file.write(buf);
+        self.inner_file.seek(end_offset as i64, SeekSet);
+
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// Copy the entry of another zip file as is, without decompressing and recompressing its data.
+    pub fn copy_entry(&mut self, zip_file: &mut ZipFile, entry: &ZipEntry32) -> Result<ZipEntry32, ~str> {
+        if self.entries.len() >= MAX_ENTRY_COUNT {
+            return Err(format!("Too many entries in zip file.  {:s} is not added.", entry.file_name_as_str()));
+        }
+        let mut src_entry = entry.clone();
+        src_entry.read_local_file_header(&mut zip_file.inner_file);
+
+        // The CRC and the sizes are in the local header of the copy; it doesn't need the data descriptor, except
+        // for an encrypted entry, whose password check byte depends on the flag.
+        let mut new_entry = entry.clone();
+        if !entry.is_encrypted() {
+            new_entry.general_flag &= !FLAG_DATA_DESCRIPTOR;
+        }
+        self.write_local_header(&mut new_entry);
+
+        let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+        let mut read_offset = 0u64;
+        loop {
+            let read_len = src_entry.read_file_data(&mut zip_file.inner_file, read_offset, buf);
+            if read_len == 0 {
+                break;
+            }
+            self.inner_file.write(buf.slice(0, read_len));
+            read_offset += read_len as u64;
+        }
+        if read_offset != entry.compressed_size as u64 {
+            return Err(format!("Failed to copy all the data of {:s}.", entry.file_name_as_str()));
+        }
+        if new_entry.has_data_descriptor() {
+            self.inner_file.write(new_entry.pack_data_descriptor());
+        }
+
+        self.entries.push(new_entry.clone());
+        Ok(new_entry)
+    }
+
+    /// Write the central directory and its metadata to finish the zip file.
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        let cd_begin = self.inner_file.tell();
+        for entry in self.entries.iter() {
+            self.inner_file.write(entry.pack_zip_entry());
+        }
+        let cd_end = self.inner_file.tell();
+
+        let mut cd_metadata = CDMetaData::new();
+        cd_metadata.cd_entry_count_on_disk = self.entries.len() as u16;
+        cd_metadata.cd_entry_count = self.entries.len() as u16;
+        cd_metadata.cd_size = (cd_end - cd_begin) as u32;
+        cd_metadata.cd_entry_begin_offset = cd_begin as u32;
+        self.inner_file.write(cd_metadata.pack_cd_metadata());
+        self.inner_file.flush();
+    }
+
+    // Add the NTFS extra field with the times to the new entry, if it's set.
+    fn add_ntfs_times(&self, entry: &mut ZipEntry32, times: (u64, u64, u64)) {
+        if self.ntfs_times {
+            append_extra_field(entry, ntfs_extra_field(times));
+        }
+    }
+
+    fn write_local_header(&mut self, entry: &mut ZipEntry32) {
+        entry.local_header_offset = self.inner_file.tell() as u32;
+        let plain = self.plain_names.iter().any(|name| *name == entry.file_name_as_str());
+        if plain {
+            entry.extra_field = None;
+            entry.extra_field_length = 0;
+        }
+        entry.local_header = entry.to_local_header();
+        if self.alignment > 1 && entry.compression_method == METHOD_STORE && !plain {
+            let data_offset = entry.local_header_offset as uint + entry.local_header.get_total_length();
+            entry.local_header.add_alignment_padding(data_offset, self.alignment);
+        }
+        self.inner_file.write(entry.local_header.pack_header());
+    }
+
+}
+
+/// Options on the entries started with ZipWriter::start_entry().
+pub struct EntryOptions {
+    /// Compression method, METHOD_STORE or METHOD_DEFLATE.
+    method:         u16,
+    /// Compression level, 0-9, for the deflate method.
+    compress_level: uint,
+    /// Modified time in milliseconds.
+    mtime:          u64,
+    /// Unix mode, with the file type and the permission bits.
+    unix_mode:      u32,
+    /// Internal attributes, e.g. bit 0 set for a text file.
+    internal_attributes: u16,
+    /// External attributes as is, instead of the ones from unix_mode, e.g. to keep those of a copied entry.
+    external_attributes: Option<u32>,
+}
+
+impl EntryOptions {
+
+    /// The default options, for a regular file deflated at the default level, modified now, and readable by all.
+    pub fn new() -> EntryOptions {
+        let now = time::get_time();
+        EntryOptions {
+            method:         METHOD_DEFLATE,
+            compress_level: DEFAULT_COMPRESS_LEVEL,
+            mtime:          now.sec as u64 * 1000 + now.nsec as u64 / 1000000,
+            unix_mode:      UNIX_FILE_MODE | 0x1A4,     // -rw-r--r--
+            internal_attributes: 0u16,
+            external_attributes: None,
+        }
+    }
+
+    /// The options with the metadata of the entry, e.g. to copy it with the data recompressed.
+    pub fn from_entry(entry: &ZipEntry32) -> EntryOptions {
+        let mut options = EntryOptions::new();
+        options.method = entry.compression_method;
+        options.mtime = match entry.ntfs_times() {
+            Some((mtime, _, _)) => mtime,
+            None                => from_dos_time(entry.modified_time, entry.modified_date) * 1000
+        };
+        options.unix_mode = entry.unix_mode().unwrap_or(options.unix_mode);
+        options.internal_attributes = entry.internal_file_attributes;
+        options.external_attributes = Some(entry.external_file_attributes);
+        options
+    }
+}
+
+/// Writer of the data of an entry started with ZipWriter::start_entry().  The data are compressed as they are written.
+/// Call finish() to complete the entry and get its result.  Dropping the writer finishes the entry too, ignoring any error.
+pub struct ZipEntryWriter<'a> {
+    priv zip_writer:    &'a mut ZipWriter,
+    priv entry:         ZipEntry32,
+    priv deflator:      Option<Deflator>,
+    priv crc32:         u32,
+    priv read_total:    u64,
+    priv write_total:   u64,
+    priv finished:      bool,
+}
+
+impl<'a> ZipEntryWriter<'a> {
+
+    /// Finish the entry, compressing the rest of the data and filling in the CRC and the sizes.
+    /// Return the entry added, or the error on the entry.
+    pub fn finish(&mut self) -> Result<ZipEntry32, ~str> {
+        if self.finished {
+            return Err(format!("Entry {:s} has been finished.", self.entry.file_name_as_str()));
+        }
+        self.finished = true;
+        match self.deflator {
+            Some(ref mut deflator) => {
+                let inner_file = &mut self.zip_writer.inner_file;
+                let mut write_len = 0u64;
+                let result = deflator.compress_write([], true, |out_buf, _| {
+                    inner_file.write(out_buf);
+                    write_len += out_buf.len() as u64;
+                });
+                self.write_total += write_len;
+                match result {
+                    Ok(_) => (),
+                    Err(err) => return Err(format!("Failed to compress data.  {:s}", err.to_str()))
+                }
+            },
+            None => ()
+        }
+        self.zip_writer.finish_entry(self.entry.clone(), self.crc32, self.read_total, self.write_total)
+    }
+}
+
+impl<'a> Writer for ZipEntryWriter<'a> {
+
+    fn write(&mut self, buf: &[u8]) {
+        if self.finished {
+            io_error::cond.raise(IoError {
+                    kind: OtherIoError,
+                    desc: "Zip entry has been finished",
+                    detail: Some(self.entry.file_name_as_str())
+                });
+            return;
+        }
+        self.crc32 = update_crc(self.crc32, buf, 0, buf.len());
+        self.read_total += buf.len() as u64;
+        let inner_file = &mut self.zip_writer.inner_file;
+        match self.deflator {
+            Some(ref mut deflator) => {
+                let mut write_len = 0u64;
+                let result = deflator.compress_write(buf, false, |out_buf, _| {
+                    inner_file.write(out_buf);
+                    write_len += out_buf.len() as u64;
+                });
+                self.write_total += write_len;
+                match result {
+                    Ok(_) => (),
+                    Err(err) => {
+                        io_error::cond.raise(IoError {
+                                kind: OtherIoError,
+                                desc: "Failed to compress data",
+                                detail: Some(err.to_str())
+                            });
+                    }
+                }
+            },
+            None => {
+                inner_file.write(buf);
+                self.write_total += buf.len() as u64;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.zip_writer.inner_file.flush();
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for ZipEntryWriter<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish();
+        }
+    }
+}
+
+/// Modify the zip file in place.  The entries for which remove_fn returns true are dropped, 
+/// the rest are copied as is, then add_fn adds the new entries.  The zip file is rewritten to a temporary file 
+/// next to it, which replaces the zip file when everything is done.  The zip file is untouched on error.
+/// Return the number of entries copied.
+pub fn modify_zip_file(zip_path: &Path, remove_fn: |&ZipEntry32| -> bool, add_fn: |&mut ZipWriter|) -> Result<uint, ~str> {
+    let mut zip_file = match File::open_mode(zip_path, Open, Read) {
+        Some(file)  => match ZipFile::open(file) {
+            Ok(zip_file)    => zip_file,
+            Err(s)          => return Err(s)
+        },
+        None        => return Err(format!("Failed to open file {:s}", zip_path.as_str().unwrap_or("")))
+    };
+    let entries = match zip_file.get_zip_entries() {
+        Ok(entries) => entries,
+        Err(s)      => return Err(s)
+    };
+
+    let tmp_path = Path::new(zip_path.as_str().unwrap_or("") + ".tmp");
+    let mut copied = 0u;
+    let mut result = Ok(0u);
+    io_error::cond.trap(|c| {
+        result = Err(c.to_str());
+    }).inside(|| {
+        match File::open_mode(&tmp_path, Truncate, Write) {
+            Some(tmp_file) => {
+                let mut zip_writer = ZipWriter::new(tmp_file);
+                for entry in entries.iter() {
+                    if remove_fn(entry) {
+                        continue;
+                    }
+                    match zip_writer.copy_entry(&mut zip_file, entry) {
+                        Ok(_)   => copied += 1,
+                        Err(s)  => {
+                            result = Err(s);
+                            break;
+                        }
+                    }
+                }
+                if result.is_ok() {
+                    add_fn(&mut zip_writer);
+                    zip_writer.finish();
+                }
+            },
+            None =>
+                result = Err(format!("Failed to open file {:s} for write.", tmp_path.as_str().unwrap_or("")))
+        }
+    });
+
+    match result {
+        Ok(_) => {
+            fs::rename(&tmp_path, zip_path);
+            Ok(copied)
+        },
+        Err(s) => {
+            if tmp_path.exists() {
+                fs::unlink(&tmp_path);
+            }
+            Err(s)
+        }
+    }
+}
+
+/// Preset of the entry order and the compression methods of an archive format built on zip, like JAR and APK.
+/// Usage:
+///     let preset = ArchivePreset::apk();
+///     preset.apply(&mut zip_writer);
+///     for name in preset.order(names, |name| name.clone()).iter() {
+///         zip_writer.add_file(&Path::new(name.clone()), *name, preset.method_for(*name), DEFAULT_COMPRESS_LEVEL);
+///     }
+#[deriving(Clone)]
+pub struct ArchivePreset {
+    /// Names of the entries to write first, in this order, e.g. META-INF/MANIFEST.MF for JAR.
+    first_entries:      ~[~str],
+    /// Patterns of the entries to store uncompressed, matched with glob_match().  The rest are deflated.
+    stored_patterns:    ~[~str],
+    /// Alignment of the data of the stored entries.  See ZipWriter::set_alignment().
+    alignment:          uint,
+    /// Names of the entries written without any extra field.  See ZipWriter::set_plain_entries().
+    plain_entries:      ~[~str],
+}
+
+impl ArchivePreset {
+
+    /// The JAR preset, with the META-INF/ directory and its MANIFEST.MF first, and all entries deflated.
+    pub fn jar() -> ArchivePreset {
+        ArchivePreset {
+            first_entries:      ~[~"META-INF/", ~"META-INF/MANIFEST.MF"],
+            stored_patterns:    ~[],
+            alignment:          0,
+            plain_entries:      ~[],
+        }
+    }
+
+    /// The APK preset, like JAR but with resources.arsc and the native libraries stored and aligned to 4 bytes.
+    pub fn apk() -> ArchivePreset {
+        ArchivePreset {
+            first_entries:      ~[~"META-INF/", ~"META-INF/MANIFEST.MF"],
+            stored_patterns:    ~[~"resources.arsc", ~"*.so"],
+            alignment:          4,
+            plain_entries:      ~[],
+        }
+    }
+
+    /// The EPUB and ODF preset, with the mimetype entry first, stored and without any extra field, as their validators require.
+    pub fn mimetype_first() -> ArchivePreset {
+        ArchivePreset {
+            first_entries:      ~[~"mimetype"],
+            stored_patterns:    ~[~"mimetype"],
+            alignment:          0,
+            plain_entries:      ~[~"mimetype"],
+        }
+    }
+
+    /// The compression method of the entry by its name.
+    pub fn method_for(&self, name: &str) -> u16 {
+        if self.stored_patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes())) {
+            METHOD_STORE
+        } else {
+            METHOD_DEFLATE
+        }
+    }
+
+    /// Order the items by their entry names, with the first entries in front in their order.
+    /// The rest keep their order.
+    pub fn order<T>(&self, items: ~[T], name_of: |&T| -> ~str) -> ~[T] {
+        let ranks = items.iter().map(|item| {
+            let name = name_of(item);
+            self.first_entries.iter().position(|first| *first == name).unwrap_or(self.first_entries.len())
+        }).collect::<~[uint]>();
+        let mut buckets = vec::from_fn(self.first_entries.len() + 1, |_| ~[]);
+        for (item, &rank) in items.move_iter().zip(ranks.iter()) {
+            buckets[rank].push(item);
+        }
+        let mut ordered = ~[];
+        for bucket in buckets.move_iter() {
+            ordered.push_all_move(bucket);
+        }
+        ordered
+    }
+
+    /// Set up the zip writer for the preset: the alignment, the plain entries, and the methods left to method_for().
+    pub fn apply(&self, zip_writer: &mut ZipWriter) {
+        zip_writer.set_alignment(self.alignment);
+        zip_writer.set_plain_entries(self.plain_entries.clone());
+        zip_writer.set_auto_store(false);
+    }
+
+    /// Check the existing zip file against the preset: the order of the first entries, the methods of the stored
+    /// patterns, the plain entries without extra field, and the alignment.  Return the problems found.
+    pub fn check(&self, zip_file: &mut ZipFile) -> Result<~[~str], ~str> {
+        let entries = match zip_file.get_zip_entries() {
+            Ok(entries) => entries,
+            Err(s)      => return Err(s)
+        };
+        let mut problems = ~[];
+
+        // The first entries present in the archive must lead it, in their order.
+        let present = self.first_entries.iter().filter(|first| entries.iter().any(|entry| entry.file_name_as_str() == **first)).collect::<~[&~str]>();
+        for (i, first) in present.iter().enumerate() {
+            if i >= entries.len() || entries[i].file_name_as_str() != **first {
+                problems.push(format!("{:s} is not entry {:u} of the archive.", **first, i + 1));
+            }
+        }
+
+        for entry in entries.iter() {
+            let name = entry.file_name_as_str();
+            if self.method_for(name) == METHOD_STORE && entry.compression_method != METHOD_STORE {
+                problems.push(format!("{:s} is not stored uncompressed.", name));
+            }
+        }
+
+        let mut result = Ok(());
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            for entry in entries.iter().filter(|entry| self.plain_entries.contains(&entry.file_name_as_str())) {
+                let mut entry = entry.clone();
+                entry.read_local_file_header(&mut zip_file.inner_file);
+                if entry.extra_field_length > 0 || entry.local_header.extra_field_length > 0 {
+                    problems.push(format!("{:s} has an extra field.", entry.file_name_as_str()));
+                }
+            }
+        });
+        match result {
+            Ok(_)   => (),
+            Err(s)  => return Err(s)
+        }
+
+        if self.alignment > 1 {
+            match zip_file.check_alignment(self.alignment) {
+                Ok(misaligned) => {
+                    for name in misaligned.iter() {
+                        problems.push(format!("{:s} is not aligned to {:u} bytes.", *name, self.alignment));
+                    }
+                },
+                Err(s) => return Err(s)
+            }
+        }
+        Ok(problems)
+    }
+
+}
+
+/// Match the name against the wildcard pattern, as Info-ZIP does.
+/// * matches any sequence of characters including /, ? matches one character, [abc] and [a-z] match one in the set.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    if pattern.len() == 0 {
+        return name.len() == 0;
+    }
+    match pattern[0] {
+        '*' as u8 => {
+            range(0, name.len() + 1).any(|i| glob_match(pattern.slice_from(1), name.slice_from(i)))
+        },
+        '?' as u8 => {
+            name.len() > 0 && glob_match(pattern.slice_from(1), name.slice_from(1))
+        },
+        '[' as u8 => {
+            match pattern.iter().skip(1).position(|&c| c == ']' as u8) {
+                Some(end) if name.len() > 0 => {
+                    let set = pattern.slice(1, end + 1);
+                    let mut matched = false;
+                    let mut i = 0;
+                    while i < set.len() {
+                        if i + 2 < set.len() && set[i + 1] == '-' as u8 {
+                            matched = matched || (set[i] <= name[0] && name[0] <= set[i + 2]);
+                            i += 3;
+                        } else {
+                            matched = matched || set[i] == name[0];
+                            i += 1;
+                        }
+                    }
+                    matched && glob_match(pattern.slice_from(end + 2), name.slice_from(1))
+                },
+                _ => name.len() > 0 && name[0] == pattern[0] && glob_match(pattern.slice_from(1), name.slice_from(1))
+            }
+        },
+        c => {
+            name.len() > 0 && name[0] == c && glob_match(pattern.slice_from(1), name.slice_from(1))
+        }
+    }
+}
+
+/// Make a new entry for writing, with the sizes and CRC to be filled in later.
+/// Check whether the file is worth deflating, by compressing a few blocks sampled from it.
+/// See deflate::estimate_ratio().  Return false for the file that can't be read.
+pub fn is_file_worth_deflating(path: &Path) -> bool {
+    let result = io::result(|| {
+        match File::open_mode(path, Open, Read) {
+            Some(file) => {
+                let mut file = file;
+                let size = fs::stat(path).size;
+                deflate::is_worth_deflating(read_samples(&mut file, size))
+            },
+            None => false
+        }
+    });
+    result.unwrap_or(false)
+}
+
+// Read up to deflate::SAMPLE_BLOCK_COUNT blocks sampled evenly across the file, for deflate::estimate_ratio().
+// The file is positioned back at the beginning.
+fn read_samples(file: &mut File, file_size: u64) -> ~[u8] {
+    let block_size = deflate::SAMPLE_BLOCK_SIZE as u64;
+    let samples_size = block_size * deflate::SAMPLE_BLOCK_COUNT as u64;
+    let samples = if file_size <= samples_size {
+        read_upto(file, file_size as uint)
+    } else {
+        let stride = (file_size - block_size) / (deflate::SAMPLE_BLOCK_COUNT as u64 - 1);
+        let mut samples = vec::with_capacity(samples_size as uint);
+        for i in range(0, deflate::SAMPLE_BLOCK_COUNT) {
+            file.seek((i as u64 * stride) as i64, SeekSet);
+            samples.push_all(read_upto(file, deflate::SAMPLE_BLOCK_SIZE));
+        }
+        samples
+    };
+    file.seek(0, SeekSet);
+    samples
+}
+
+fn new_entry(name: &str, method: u16, mtime: u64, external_attributes: u32) -> ZipEntry32 {
+    let (dos_time, dos_date) = to_dos_time(mtime / 1000);
+    let mut entry = ZipEntry32::new();
+    entry.version_made_by = VERSION_MADE_BY;
+    entry.version_needed = if method == METHOD_DEFLATE { VERSION_NEEDED_DEFLATE } else { VERSION_NEEDED_STORE };
+    entry.general_flag = if name.is_ascii() { 0 } else { FLAG_UTF8 };
+    entry.compression_method = method;
+    entry.modified_time = dos_time;
+    entry.modified_date = dos_date;
+    entry.file_name_length = name.len() as u16;
+    entry.file_name = Some(name.as_bytes().to_owned());
+    entry.file_name_str = Some(name.to_owned());
+    entry.external_file_attributes = external_attributes;
+    entry
+}
+
+// Decode the raw file name.  The names without the UTF-8 flag are in the DOS code page by the spec,
+// but most tools write them in UTF-8 anyway.  The names not in UTF-8 are taken as Latin-1.
+fn decode_name(name: &[u8]) -> ~str {
+    if str::is_utf8(name) {
+        str::from_utf8(name)
+    } else {
+        name.iter().map(|&b| b as char).collect()
+    }
+}
+
+/// Convert the MS-DOS time and date in local time to the Unix time in seconds.
+fn from_dos_time(dos_time: u16, dos_date: u16) -> u64 {
+    let mut tm = time::empty_tm();
+    tm.tm_year = ((dos_date >> 9) + 80) as i32;
+    tm.tm_mon = ((dos_date >> 5) & 0x0F) as i32 - 1;
+    tm.tm_mday = (dos_date & 0x1F) as i32;
+    tm.tm_hour = (dos_time >> 11) as i32;
+    tm.tm_min = ((dos_time >> 5) & 0x3F) as i32;
+    tm.tm_sec = ((dos_time & 0x1F) * 2) as i32;
+    tm.tm_isdst = -1;
+    tm.tm_gmtoff = time::now().tm_gmtoff;      // to_timespec() takes a zero offset as UTC, otherwise as local time
+    let secs = tm.to_timespec().sec;
+    if secs > 0 { secs as u64 } else { 0 }
+}
+
+/// Convert the Unix time in milliseconds to the NTFS time, in 100ns since 1601-01-01.
+fn to_ntfs_time(ms: u64) -> u64 {
+    (ms / 1000 + NTFS_EPOCH_SECS) * 10000000 + (ms % 1000) * 10000
+}
+
+/// Convert the NTFS time, in 100ns since 1601-01-01, to the Unix time in milliseconds.  Times before 1970 become 0.
+fn from_ntfs_time(ntfs_time: u64) -> u64 {
+    let ms = ntfs_time / 10000;
+    if ms > NTFS_EPOCH_SECS * 1000 { ms - NTFS_EPOCH_SECS * 1000 } else { 0 }
+}
+
+/// Build the NTFS extra field with the modified, accessed and created times in milliseconds.
+fn ntfs_extra_field(times: (u64, u64, u64)) -> ~[u8] {
+    let (mtime, atime, ctime) = times;
+    let mut buf = vec::from_elem(12 + NTFS_TIMES_SIZE as uint, 0u8);
+    let mut offset = pack_num::<u16>(buf, 0, EXTRA_NTFS, LittleEndian);
+    offset = pack_num::<u16>(buf, offset, 8 + NTFS_TIMES_SIZE, LittleEndian);
+    offset = pack_num::<u32>(buf, offset, 0, LittleEndian);                   // reserved
+    offset = pack_num::<u16>(buf, offset, NTFS_TIMES_TAG, LittleEndian);
+    offset = pack_num::<u16>(buf, offset, NTFS_TIMES_SIZE, LittleEndian);
+    offset = pack_num::<u64>(buf, offset, to_ntfs_time(mtime), LittleEndian);
+    offset = pack_num::<u64>(buf, offset, to_ntfs_time(atime), LittleEndian);
+    pack_num::<u64>(buf, offset, to_ntfs_time(ctime), LittleEndian);
+    buf
+}
+
+/// Find the modified, accessed and created times in milliseconds in the NTFS field of the extra field.
+fn find_ntfs_times(extra_field: &[u8]) -> Option<(u64, u64, u64)> {
+    for &(id, ref data) in split_extra_fields(extra_field).iter() {
+        if id != EXTRA_NTFS {
+            continue;
+        }
+        let mut offset = 4u;                                // skip the reserved
+        while offset + 4 <= data.len() {
+            let tag = unpack_num::<u16>(*data, offset, LittleEndian);
+            let size = unpack_num::<u16>(*data, offset + 2, LittleEndian);
+            offset += 4;
+            if tag == NTFS_TIMES_TAG && size >= NTFS_TIMES_SIZE && offset + NTFS_TIMES_SIZE as uint <= data.len() {
+                return Some((from_ntfs_time(unpack_num::<u64>(*data, offset, LittleEndian)),
+                             from_ntfs_time(unpack_num::<u64>(*data, offset + 8, LittleEndian)),
+                             from_ntfs_time(unpack_num::<u64>(*data, offset + 16, LittleEndian))));
+            }
+            offset += size as uint;
+        }
+    }
+    None
+}
+
+/// Build the Unix owner extra field with the uid and the gid, each in 4 bytes.
+fn unix_owner_extra_field(owner: (u32, u32)) -> ~[u8] {
+    let (uid, gid) = owner;
+    let mut buf = vec::from_elem(15, 0u8);
+    let mut offset = pack_num::<u16>(buf, 0, EXTRA_UNIX_OWNER, LittleEndian);
+    offset = pack_num::<u16>(buf, offset, 11, LittleEndian);
+    buf[offset] = UNIX_OWNER_VERSION;
+    buf[offset + 1] = 4;
+    offset = pack_num::<u32>(buf, offset + 2, uid, LittleEndian);
+    buf[offset] = 4;
+    pack_num::<u32>(buf, offset + 1, gid, LittleEndian);
+    buf
+}
+
+/// Find the uid and the gid in the Unix owner field of the extra field.  Each can be 1 to 8 bytes long.
+fn find_unix_owner(extra_field: &[u8]) -> Option<(u32, u32)> {
+    for &(id, ref data) in split_extra_fields(extra_field).iter() {
+        if id != EXTRA_UNIX_OWNER || data.len() < 2 || data[0] != UNIX_OWNER_VERSION {
+            continue;
+        }
+        let uid_size = data[1] as uint;
+        if uid_size > 8 || data.len() < 2 + uid_size + 1 {
+            continue;
+        }
+        let gid_size = data[2 + uid_size] as uint;
+        if gid_size > 8 || data.len() < 3 + uid_size + gid_size {
+            continue;
+        }
+        return Some((unpack_uint(data.slice(2, 2 + uid_size), LittleEndian) as u32,
+                     unpack_uint(data.slice(3 + uid_size, 3 + uid_size + gid_size), LittleEndian) as u32));
+    }
+    None
+}
+
+/// Append the field to the extra field of the entry.
+fn append_extra_field(entry: &mut ZipEntry32, field: ~[u8]) {
+    let mut extra_field = entry.extra_field.clone().unwrap_or(~[]);
+    extra_field.push_all(field);
+    entry.extra_field_length = extra_field.len() as u16;
+    entry.extra_field = Some(extra_field);
+}
+
+/// The uid and the gid of the file, for the Unix owner extra field.
+#[cfg(unix)]
+fn file_owner(stat: &FileStat) -> Option<(u32, u32)> {
+    Some((stat.unstable.uid as u32, stat.unstable.gid as u32))
+}
+
+/// The file has no Unix owner on the other platforms.
+#[cfg(not(unix))]
+fn file_owner(_stat: &FileStat) -> Option<(u32, u32)> {
+    None
+}
+
+/// Change the owner of the extracted file to the uid and the gid of the entry.  It's left as is on failure,
+/// as changing the owner takes the privilege.
+fn restore_file_owner(path: &Path, owner: Option<(u32, u32)>) {
+    match owner {
+        Some((uid, gid)) => {
+            io::result(|| fs::chown(path, uid as int, gid as int));
+        },
+        None => ()
+    }
+}
+
+/// Split the extra field into the id and the data of each field.
+fn split_extra_fields(extra_field: &[u8]) -> ~[(u16, ~[u8])] {
+    let mut fields = ~[];
+    let mut offset = 0u;
+    while offset + 4 <= extra_field.len() {
+        let id = unpack_num::<u16>(extra_field, offset, LittleEndian);
+        let len = unpack_num::<u16>(extra_field, offset + 2, LittleEndian) as uint;
+        let end = num::min(offset + 4 + len, extra_field.len());
+        fields.push((id, extra_field.slice(offset + 4, end).to_owned()));
+        offset = end;
+    }
+    fields
+}
+
+/// Set the times of the extracted file, from the NTFS times when they are there, or else from the MS-DOS time.
+fn restore_file_times(path: &Path, ntfs_times: Option<(u64, u64, u64)>, dos_time: u16, dos_date: u16) {
+    match ntfs_times {
+        Some((mtime, atime, _)) => fs::change_file_times(path, atime, mtime),
+        None => {
+            let mtime = from_dos_time(dos_time, dos_date) * 1000;
+            fs::change_file_times(path, mtime, mtime)
+        }
+    }
+}
+
+/// Convert the Unix time in seconds to the MS-DOS time and date in local time.  Times before 1980 become 1980-01-01.
+fn to_dos_time(secs: u64) -> (u16, u16) {
+    let tm = time::at(time::Timespec::new(secs as i64, 0));
+    if tm.tm_year < 80 {
+        return (0u16, (1 << 5) | 1);
+    }
+    let dos_time = (tm.tm_hour << 11) | (tm.tm_min << 5) | (tm.tm_sec / 2);
+    let dos_date = ((tm.tm_year - 80) << 9) | ((tm.tm_mon + 1) << 5) | tm.tm_mday;
+    (dos_time as u16, dos_date as u16)
+}
+
+
+/// An iterator over the list of ZipEntry read from the zip file.
+pub struct ZipEntry32Iterator<'self> {
+    priv zip_file:  &'self mut ZipFile,
+    priv index:     u16,
+    priv file_pos:  u64,
+    priv finished:  bool,
+}
+
+
+impl<'self> Iterator<ZipEntry32> for ZipEntry32Iterator<'self> {
+
+    fn next(&mut self) -> Option<ZipEntry32> {
+        if self.finished {
+            return None;
+        }
+        if self.index >= self.zip_file.cd_metadata.cd_entry_count {
+            self.finished = true;
+            return None;
+        }
+
+        self.index += 1;
+        self.finished = (self.index == self.zip_file.cd_metadata.cd_entry_count);
+
+        match ZipEntry32::read_zip_entry(&mut self.zip_file.inner_file) {
+            Ok(entry) => Some(entry),
+            Err(s) => fail!(s)      // TODO: return error
+        }
+    }
+
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        if self.finished {
+            (0u, Some(0u))
+        } else {
+            (self.index as uint, Some(self.zip_file.cd_metadata.cd_entry_count as uint))
+        }
+    }
+}
+
+/// Reader for reading the content of the file item at the zip entry.
+pub struct ZipReader<'self> {
+    priv zip_file:      &'self mut ZipFile,
+    priv decoder:       EntryDecoder,
+}
+
+impl<'self> Reader for ZipReader<'self> {
+
+    /// Read the decompressed data from the file item inside the zip file.
+    fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
+        let result = self.decoder.read(&mut self.zip_file.inner_file, output_buf);
+        self.zip_file.extracted_total += result.unwrap_or(0) as u64;
+        result
+    }
+
+    fn eof(&mut self) -> bool {
+        return self.decoder.is_eof;
+    }
+}
+
+/// Reader for reading the content of the file item at the zip entry, with its own handle of the zip file.
+/// It doesn't borrow the ZipFile; it can be stored or sent to another task.
+pub struct ZipEntryReader {
+    priv inner_file:    File,
+    priv decoder:       EntryDecoder,
+}
+
+impl ZipEntryReader {
+
+    /// Return the zip entry being read.
+    pub fn zip_entry<'a>(&'a self) -> &'a ZipEntry32 {
+        &self.decoder.zip_entry
+    }
+}
+
+impl Reader for ZipEntryReader {
+
+    /// Read the decompressed data from the file item inside the zip file.
+    fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
+        self.decoder.read(&mut self.inner_file, output_buf)
+    }
+
+    fn eof(&mut self) -> bool {
+        return self.decoder.is_eof;
+    }
+}
+
+// Decoding states of the data of a zip entry, shared by ZipReader and ZipEntryReader.
+struct EntryDecoder {
+    zip_entry:      ZipEntry32,
+    read_total:     u64,
+    cmp_crc32:      u32,
+    is_eof:         bool,
+    inflator:       Option<Inflator>,
+    crypto:         Option<ZipCrypto>,
+    output_total:   u64,
+    limit:          Option<(u64, LimitExceeded)>,
+}
+
+impl EntryDecoder {
+
+    fn new(entry: &ZipEntry32) -> EntryDecoder {
+        EntryDecoder {
+            zip_entry:  entry.clone(),
+            read_total: 0u64,
+            cmp_crc32:  0u32,
+            is_eof:     false,
+            inflator:   None,
+            crypto:     None,
+            output_total: 0u64,
+            limit:      None,
+        }
+    }
+
+    fn init(&mut self, file: &mut File, password: &Option<~[u8]>) {
+        self.zip_entry.read_local_file_header(file);
+        if self.zip_entry.is_encrypted() {
+            self.init_crypto(file, password);
+        }
+        match self.zip_entry.compression_method {
+            METHOD_STORE => (),
+            METHOD_DEFLATE => {
+                self.inflator = Some(Inflator::with_size_factor(deflate::DEFAULT_SIZE_FACTOR));
+            },
+            _ => {
+                io_error::cond.raise(IoError {
+                        kind: OtherIoError,
+                        desc: "Unsupported compression method",
+                        detail: Some(format!("Unsupported compression method: {:u}", self.zip_entry.compression_method as uint))
+                    });
+            }
+        }
+    }
+
+    fn init_crypto(&mut self, file: &mut File, password: &Option<~[u8]>) {
+        if self.zip_entry.compression_method == METHOD_AES {
+            io_error::cond.raise(IoError {
+                    kind: OtherIoError,
+                    desc: "AES encrypted zip entry is not supported",
+                    detail: Some(self.zip_entry.file_name_as_str())
+                });
+            return;
+        }
+        let mut crypto = match *password {
+            Some(ref password)  => ZipCrypto::new(*password),
+            None                => {
+                io_error::cond.raise(IoError {
+                        kind: OtherIoError,
+                        desc: "Encrypted zip entry needs a password",
+                        detail: Some(self.zip_entry.file_name_as_str())
+                    });
+                return;
+            }
+        };
+
+        // The last byte of the decrypted header is the high byte of the CRC, 
+        // or of the modified time when the CRC comes later in the data descriptor.
+        let mut header = [0u8, ..CRYPT_HEADER_SIZE];
+        let read_len = self.zip_entry.read_file_data(file, 0, header);
+        self.read_total += read_len as u64;
+        crypto.decrypt(header);
+        let check_byte = if self.zip_entry.has_data_descriptor() { (self.zip_entry.modified_time >> 8) as u8 } else { (self.zip_entry.crc32 >> 24) as u8 };
+        if read_len < CRYPT_HEADER_SIZE || header[CRYPT_HEADER_SIZE - 1] != check_byte {
+            io_error::cond.raise(IoError {
+                    kind: OtherIoError,
+                    desc: "Incorrect password for the encrypted zip entry",
+                    detail: Some(self.zip_entry.file_name_as_str())
+                });
+        }
+        self.crypto = Some(crypto);
+    }
+
+    fn read(&mut self, file: &mut File, output_buf: &mut [u8]) -> Option<uint> {
+        let result = match self.zip_entry.compression_method {
+            METHOD_STORE    => self.store_read(file, output_buf),
+            METHOD_DEFLATE  => self.deflate_read(file, output_buf),
+            _               => {
+                io_error::cond.raise(IoError {
+                        kind: OtherIoError,
+                        desc: "Unsupported compression method",
+                        detail: Some(format!("Unsupported compression method: {:u}", self.zip_entry.compression_method as uint))
+                    });
+                None
+            }
+        };
+        match result {
+            Some(read_len) => {
+                // The data can be more than the sizes in the central directory say; the limit is on the actual data.
+                self.output_total += read_len as u64;
+                match self.limit {
+                    Some((max_output, ref exceeded)) if self.output_total > max_output => {
+                        self.is_eof = true;
+                        io_error::cond.raise(IoError {
+                                kind: OtherIoError,
+                                desc: "Zip extraction limit exceeded",
+                                detail: Some(exceeded.to_str())
+                            });
+                        None
+                    },
+                    _ => result
+                }
+            },
+            None => None
+        }
+    }
+
+    // Read the raw entry data, decrypting them if the entry is encrypted.
+    fn read_raw(&mut self, file: &mut File, buf: &mut [u8]) -> uint {
+        let read_len = self.zip_entry.read_file_data(file, self.read_total, buf);
+        self.read_total += read_len as u64;
+        match self.crypto {
+            Some(ref mut crypto)    => crypto.decrypt(buf.mut_slice(0, read_len)),
+            None                    => ()
+        }
+        read_len
+    }
+
+    fn store_read(&mut self, file: &mut File, output_buf: &mut [u8]) -> Option<uint> {
+        if self.is_eof {
+            return None;
+        }
+        let read_len = self.read_raw(file, output_buf);
+        if read_len > 0 {
+            self.cmp_crc32 = update_crc(self.cmp_crc32, output_buf, 0, read_len);
+            Some(read_len)
+        } else {
+            self.is_eof = true;
+            self.raiseCrcError();
+            None
+        }
+    }
+
+    fn raiseCrcError(&self) {
+        if !self.zip_entry.checkCrc(self.cmp_crc32) {
+            io_error::cond.raise(IoError {
+                    kind: OtherIoError,
+                    desc: "CRC mismatched in zip entry",
+                    detail: Some(format!("{:s}: expected crc {:08x}, computed crc {:08x}", 
+                                         self.zip_entry.file_name_as_str(), self.zip_entry.crc32 as uint, self.cmp_crc32 as uint))
+                });
+        }
+    }
+
+    fn deflate_read(&mut self, file: &mut File, output_buf: &mut [u8]) -> Option<uint> {
+        let mut inflator = self.inflator.get_mut_ref();
+        let status = inflator.decompress_read(
+            // Callback to read input data.
+            |in_buf| {
+                if self.is_eof {
+                    0
+                } else {
+                    self.read_raw(file, in_buf)     // Return number of bytes read, including 0 for EOF
+                }
+            },
+            output_buf);
+
+        match status {
+            Ok(0) => {
+                self.is_eof = true;
+                // The CRC and sizes of the central directory entry are valid even when the entry has a data descriptor.
+                self.raiseCrcError();
+                None
+            },
+            Ok(output_len) => {
+                self.cmp_crc32 = update_crc(self.cmp_crc32, output_buf, 0, output_len);
+                return Some(output_len);
+            },
+            _ => {
+                // Clean up states before raising error.
+                self.is_eof = true;
+                io_error::cond.raise(IoError {
+                        kind: OtherIoError,
+                        desc: "Read failure in decompression",
+                        detail: Some(format!("Read failure in deflate_read().  status: {:?}", status))
+                    });
+                None
+            }
+        }
+    }
+
+}
+
+
+
+/// Pack the bytes into byte buffer
+fn pack_bytes(buf: &mut [u8], offset: uint, value: &[u8]) -> uint {
+    vec::bytes::copy_memory(buf.mut_slice(offset, offset + value.len()), value, value.len());
+    offset + value.len()
+}
+
+/// Pack a string into a zero-terminated buffer.
+fn to_strz(str_value: &str) -> ~[u8] {
+    let str_bytes = str_value.as_bytes();
+    let mut buf = vec::from_elem(str_bytes.len() + 1, 0u8);
+    vec::bytes::copy_memory(buf, str_bytes, str_bytes.len());
+    buf[buf.len() - 1] = 0;
+    return buf;
+}
+
+/// Read a zero-terminated str.  Read until encountering the terminating 0.
+fn read_strz<R: Reader>(reader: &mut R) -> ~str {
+    let mut buf = ~[];
+    loop {
+        match reader.read_byte() {
+            Some(0)     => break,
+            Some(ch)    => buf.push(ch),
+            None        => break
+        }
+    }
+    return str::from_utf8(buf);
+}
+
+/// Reader for reading the zip entries sequentially from a non-seekable stream, like funzip.
+/// It parses the local file header of each entry as it arrives, without the central directory at the end.
+/// A stored entry with the sizes in the data descriptor can't be read, since its data length is unknown.
+/// Usage:
+///     let mut zip_stream = ZipStreamReader::new(reader);
+///     loop {
+///         match zip_stream.next_header() {
+///             Some(header) => zip_stream.read_data(&header, &mut writer),
+///             None => break
+///         }
+///     }
+pub struct ZipStreamReader<R> {
+    priv inner_reader:      R,
+    priv pending:           ~[u8],
+    priv pending_offset:    uint,
+}
+
+impl<R: Reader> ZipStreamReader<R> {
+
+    /// Create the stream reader on the inner reader.
+    pub fn new(inner_reader: R) -> ZipStreamReader<R> {
+        ZipStreamReader {
+            inner_reader:   inner_reader,
+            pending:        ~[],
+            pending_offset: 0u,
+        }
+    }
+
+    /// Read the local file header of the next entry.  Return None at the central directory or the end of the stream.
+    pub fn next_header(&mut self) -> Option<LocalFileHeader> {
+        let buf = self.read_exact(LOCAL_FILE_HEADER_SIZE);
+        if buf.len() < 4 || unpack_num::<u32>(buf, 0, LittleEndian) != LOCAL_HEADER_MAGIC {
+            return None;
+        }
+        if buf.len() < LOCAL_FILE_HEADER_SIZE {
+            io_error::cond.raise(IoError { kind: OtherIoError, desc: "Zip local file header does not have enough data", detail: None });
+            return None;
+        }
+        let mut header = LocalFileHeader::new();
+        header.unpack_header(buf, 0);
+        let rest_buf = self.read_exact(header.get_rest_length());
+        header.unpack_header_rest(rest_buf, 0);
+        Some(header)
+    }
+
+    /// Read the data of the entry of the header just read, writing the decompressed data to writer.
+    /// Must be called after each next_header(), to move on to the next entry.  Pass a sink writer to skip the entry.
+    /// Return the decompressed size, or the error on the entry.
+    pub fn read_data<W: Writer>(&mut self, header: &LocalFileHeader, writer: &mut W) -> Result<u64, ~str> {
+        let has_descriptor = (header.general_flag & FLAG_DATA_DESCRIPTOR) == FLAG_DATA_DESCRIPTOR;
+        if (header.general_flag & FLAG_ENCRYPTED) == FLAG_ENCRYPTED {
+            return Err(~"Encrypted entry is not supported in a stream.");
+        }
+
+        let mut crc32 = 0u32;
+        let mut total = 0u64;
+        match header.compression_method {
+            METHOD_STORE if has_descriptor => 
+                return Err(~"Stored entry with a data descriptor is not supported in a stream."),
+            METHOD_STORE => {
+                let mut remaining = header.compressed_size as uint;
+                let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+                while remaining > 0 {
+                    let read_len = self.read_some(buf.mut_slice(0, num::min(remaining, buf.len())));
+                    if read_len == 0 {
+                        return Err(~"Unexpected end of stream.");
+                    }
+                    crc32 = update_crc(crc32, buf, 0, read_len);
+                    writer.write(buf.slice(0, read_len));
+                    total += read_len as u64;
+                    remaining -= read_len;
+                }
+            },
+            METHOD_DEFLATE => {
+                // Without the data descriptor, only read up to the compressed size.  Otherwise the deflate stream tells the end.
+                let mut remaining = if has_descriptor { MAX_ZIP32_SIZE } else { header.compressed_size as u64 };
+                let mut rest = ~[];
+                let mut inflator = Inflator::with_size_factor(deflate::DEFAULT_SIZE_FACTOR);
+                let result = inflator.decompress_stream(
+                    |in_buf| {
+                        let len = num::min(remaining, in_buf.len() as u64) as uint;
+                        let read_len = self.read_some(in_buf.mut_slice(0, len));
+                        remaining -= read_len as u64;
+                        read_len
+                    },
+                    |out_buf, _| {
+                        crc32 = update_crc(crc32, out_buf, 0, out_buf.len());
+                        writer.write(out_buf);
+                        total += out_buf.len() as u64;
+                        false
+                    },
+                    |rest_buf| {
+                        rest.push_all(rest_buf);
+                    });
+                self.push_back(rest);
+                match result {
+                    Ok(_) => (),
+                    Err(err) => return Err(format!("Failed to decompress data.  {:s}", err.to_str()))
+                }
+            },
+            method => return Err(format!("Unsupported compression method: {:u}", method as uint))
+        }
+        writer.flush();
+
+        let expected_crc32 = if has_descriptor { self.read_data_descriptor() } else { header.crc32 };
+        if crc32 != expected_crc32 {
+            return Err(format!("CRC mismatched.  Expected crc {:08x}, computed crc {:08x}", expected_crc32 as uint, crc32 as uint));
+        }
+        Ok(total)
+    }
+
+    /// Extract the entry of the header just read under the dest_dir directory, like ZipFile::extract_entry() does.
+    /// The local header has no file attributes, so a symlink entry is extracted as a file of its link target.
+    /// Return the number of bytes extracted, or the error on the entry.
+    pub fn extract_entry(&mut self, header: &LocalFileHeader, dest_dir: &Path, options: &ExtractOptions) -> Result<u64, ~str> {
+        let name = header.file_name_as_str();
+        let mut result = Ok(0u64);
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let out_path = options.dest_path(dest_dir, name);
+            match out_path {
+                None => {
+                    self.read_data(header, &mut SinkWriter);
+                    result = Err(format!("Unsafe path {:s} in zip entry.  Entry skipped.", name));
+                },
+                Some(ref out_path) if through_symlink(dest_dir, out_path) => {
+                    self.read_data(header, &mut SinkWriter);
+                    result = Err(format!("Path {:s} goes through a symlink.  Entry skipped.", name));
+                },
+                Some(ref out_path) if header.is_dir() => {
+                    if !options.junk_paths && !out_path.is_dir() {
+                        fs::mkdir_recursive(out_path, io::UserRWX);
+                    }
+                    result = self.read_data(header, &mut SinkWriter);
+                },
+                Some(ref out_path) => {
+                    match options.write_path(out_path) {
+                        None => {
+                            self.read_data(header, &mut SinkWriter);
+                            result = Err(format!("File {:s} already exists.  Entry skipped.", out_path.as_str().unwrap_or("")));
+                        },
+                        Some(write_path) => {
+                            let parent_dir = write_path.dir_path();
+                            if !parent_dir.is_dir() {
+                                fs::mkdir_recursive(&parent_dir, io::UserRWX);
+                            }
+                            match File::open_mode(&write_path, Truncate, Write) {
+                                Some(writer) => {
+                                    let mut writer = writer;
+                                    result = self.read_data(header, &mut writer);
+                                },
+                                None =>
+                                    result = Err(format!("Failed to open file {:s} for write.", write_path.as_str().unwrap_or("")))
+                            }
+                            if result.is_ok() {
+                                restore_file_times(&write_path, header.ntfs_times(), header.modified_time, header.modified_date);
+                                if options.owner {
+                                    restore_file_owner(&write_path, header.unix_owner());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        result
+    }
+
+    /// Skip the data of the entry of the header just read.  The data of known size is skipped without
+    /// decompressing or checking it; otherwise it's decompressed to find its end.
+    /// Return the decompressed size, or the error on the entry.
+    pub fn skip_entry(&mut self, header: &LocalFileHeader) -> Result<u64, ~str> {
+        if (header.general_flag & FLAG_DATA_DESCRIPTOR) == FLAG_DATA_DESCRIPTOR {
+            return self.read_data(header, &mut SinkWriter);
+        }
+        let size = header.compressed_size as u64;
+        if self.skip_bytes(size) < size {
+            return Err(~"Unexpected end of stream.");
+        }
+        Ok(header.uncompressed_size as u64)
+    }
+
+    // Skip the pending data first, then the inner reader's.
+    fn skip_bytes(&mut self, n: u64) -> u64 {
+        let pending_len = num::min(n, (self.pending.len() - self.pending_offset) as u64);
+        self.pending_offset += pending_len as uint;
+        pending_len + ioutil::skip(&mut self.inner_reader, n - pending_len)
+    }
+
+    // Read the data descriptor after the entry data.  Its signature is optional.  Return its CRC.
+    fn read_data_descriptor(&mut self) -> u32 {
+        let buf = self.read_exact(4);
+        let crc_buf = if buf.len() == 4 && unpack_num::<u32>(buf, 0, LittleEndian) == LOCAL_DESC_MAGIC { self.read_exact(4) } else { buf };
+        self.read_exact(DATA_DESCRIPTOR_SIZE - 4);     // compressed and uncompressed sizes
+        if crc_buf.len() == 4 { unpack_num::<u32>(crc_buf, 0, LittleEndian) } else { 0 }
+    }
+
+    fn push_back(&mut self, data: &[u8]) {
+        let mut pending = data.to_owned();
+        pending.push_all(self.pending.slice_from(self.pending_offset));
+        self.pending = pending;
+        self.pending_offset = 0;
+    }
+
+    fn read_some(&mut self, buf: &mut [u8]) -> uint {
+        if buf.len() == 0 {
+            return 0;
+        }
+        if self.pending_offset < self.pending.len() {
+            let len = num::min(buf.len(), self.pending.len() - self.pending_offset);
+            vec::bytes::copy_memory(buf, self.pending.slice(self.pending_offset, self.pending_offset + len), len);
+            self.pending_offset += len;
+            return len;
+        }
+        match self.inner_reader.read(buf) {
+            Some(read_len)  => read_len,
+            None            => 0
+        }
+    }
+
+    fn read_exact(&mut self, len: uint) -> ~[u8] {
+        let mut buf = vec::from_elem(len, 0u8);
+        let mut total = 0u;
+        while total < len {
+            let read_len = self.read_some(buf.mut_slice(total, len));
+            if read_len == 0 {
+                break;
+            }
+            total += read_len;
+        }
+        buf.truncate(total);
+        buf
+    }
+
 }
 
-impl ZipEntry32 {
 
-    fn new() -> ZipEntry32 {
-        ZipEntry32 {
-            version_made_by:            0u16,
-            version_needed:             0u16,
-            general_flag:               0u16,
-            compression_method:         0u16,
-            modified_time:              0u16,
-            modified_date:              0u16,
-            crc32:                      0u32,
-            compressed_size:            0u32,
-            uncompressed_size:          0u32,
-            file_name_length:           0u16,
-            extra_field_length:         0u16,
-            file_comment_length:        0u16,
-            disk_number_start:          0u16,
-            internal_file_attributes:   0u16,
-            external_file_attributes:   0u32,
-            local_header_offset:        0u32,
-            file_name:                  None,
-            extra_field:                None,
-            file_comment:               None,
-            local_header:               LocalFileHeader::new(),
+/// Default size of the central directory kept in memory by ZipStreamWriter before spilling to a temporary file.
+pub static CD_SPILL_THRESHOLD: uint = 1024 * 1024;
+
+/// Writer of a zip file in a single pass, to a writer that can't seek, e.g. a pipe.  The CRC and the sizes of a
+/// deflated entry go to the data descriptor after its data.  The central directory records are kept in memory,
+/// spilled to a temporary file above a threshold, and written out with the end record by finish().  The temporary
+/// file is removed when the ZipStreamWriter is finished or dropped.
+pub struct ZipStreamWriter<W> {
+    priv inner_writer:      W,
+    priv offset:            u64,
+    priv cd_buf:            ~[u8],
+    priv cd_count:          uint,
+    priv cd_size:           u64,
+    priv spill:             Option<SpillFile>,
+    priv spill_failed:      bool,
+    priv spill_threshold:   uint,
+    priv finished:          bool,
+}
+
+impl<W: Writer> ZipStreamWriter<W> {
+
+    /// Create a ZipStreamWriter writing to the inner_writer.
+    pub fn new(inner_writer: W) -> ZipStreamWriter<W> {
+        ZipStreamWriter {
+            inner_writer:       inner_writer,
+            offset:             0u64,
+            cd_buf:             ~[],
+            cd_count:           0u,
+            cd_size:            0u64,
+            spill:              None,
+            spill_failed:       false,
+            spill_threshold:    CD_SPILL_THRESHOLD,
+            finished:           false,
+        }
+    }
+
+    /// Set the size of the central directory kept in memory before spilling it to a temporary file.
+    pub fn set_spill_threshold(&mut self, spill_threshold: uint) {
+        self.spill_threshold = spill_threshold;
+    }
+
+    /// Add the entry with the data from the reader, with the metadata and the compression in the options.
+    /// A stored entry is read into memory first, since its sizes must be in the local header.
+    pub fn add_reader<R: Reader>(&mut self, reader: &mut R, name: &str, options: &EntryOptions) -> Result<ZipEntry32, ~str> {
+        if self.finished {
+            return Err(format!("Zip stream is finished.  {:s} is not added.", name));
+        }
+        if self.cd_count >= MAX_ENTRY_COUNT {
+            return Err(format!("Too many entries in zip file.  {:s} is not added.", name));
+        }
+        if self.offset > MAX_ZIP32_SIZE {
+            return Err(format!("Zip file is too big.  {:s} is not added.", name));
+        }
+        let external_attributes = options.external_attributes.unwrap_or(options.unix_mode << 16);
+        let mut entry = new_entry(name, options.method, options.mtime, external_attributes);
+        entry.internal_file_attributes = options.internal_attributes;
+        entry.local_header_offset = self.offset as u32;
+
+        let mut result = Ok(());
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            result = match options.method {
+                METHOD_STORE    => self.write_stored(&mut entry, reader),
+                METHOD_DEFLATE  => self.write_deflated(&mut entry, reader, options.compress_level),
+                method          => Err(format!("Unsupported compression method: {:u}", method as uint))
+            };
+        });
+        match result {
+            Ok(_) => {
+                entry.local_header = entry.to_local_header();
+                self.add_cd_record(entry.pack_zip_entry());
+                Ok(entry)
+            },
+            Err(s) => Err(s)
+        }
+    }
+
+    /// Write the central directory and the end record.  The ZipStreamWriter can't take any more entry.
+    pub fn finish(&mut self) -> Result<(), ~str> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let mut result = Ok(());
+        io_error::cond.trap(|c| {
+            result = Err(c.to_str());
+        }).inside(|| {
+            let cd_begin = self.offset;
+            match self.spill.take() {
+                Some(spill) => {
+                    let mut spill = spill;          // removes the temporary file when dropped, even on error
+                    let spill_file = spill.file.get_mut_ref();
+                    spill_file.write(self.cd_buf);
+                    spill_file.seek(0, SeekSet);
+                    let mut buf = vec::from_elem(deflate::calc_buf_size(deflate::DEFAULT_SIZE_FACTOR), 0u8);
+                    loop {
+                        match spill_file.read(buf) {
+                            Some(read_len)  => self.inner_writer.write(buf.slice(0, read_len)),
+                            None            => break
+                        }
+                    }
+                },
+                None => self.inner_writer.write(self.cd_buf)
+            }
+            self.cd_buf = ~[];
+
+            if cd_begin > MAX_ZIP32_SIZE || self.cd_size > MAX_ZIP32_SIZE {
+                result = Err(~"Zip file is too big.");
+                return;
+            }
+            let mut cd_metadata = CDMetaData::new();
+            cd_metadata.cd_entry_count_on_disk = self.cd_count as u16;
+            cd_metadata.cd_entry_count = self.cd_count as u16;
+            cd_metadata.cd_size = self.cd_size as u32;
+            cd_metadata.cd_entry_begin_offset = cd_begin as u32;
+            self.inner_writer.write(cd_metadata.pack_cd_metadata());
+            self.inner_writer.flush();
+        });
+        result
+    }
+
+    // Write a stored entry, with the CRC and the sizes in its local header.
+    fn write_stored<R: Reader>(&mut self, entry: &mut ZipEntry32, reader: &mut R) -> Result<(), ~str> {
+        let data = reader.read_to_end();
+        if data.len() as u64 > MAX_ZIP32_SIZE {
+            return Err(format!("File {:s} is too big for a zip file.", entry.file_name_as_str()));
+        }
+        entry.crc32 = update_crc(0u32, data, 0, data.len());
+        entry.compressed_size = data.len() as u32;
+        entry.uncompressed_size = data.len() as u32;
+        self.write_out(entry.to_local_header().pack_header());
+        self.write_out(data);
+        Ok(())
+    }
+
+    // Write a deflated entry, with the CRC and the sizes in the data descriptor after its data.
+    fn write_deflated<R: Reader>(&mut self, entry: &mut ZipEntry32, reader: &mut R, compress_level: uint) -> Result<(), ~str> {
+        let mut deflator = Deflator::new();
+        match deflator.init(compress_level, false, false) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("Failed to initialize deflator.  {:s}", err.to_str()))
+        }
+        entry.general_flag |= FLAG_DATA_DESCRIPTOR;
+        self.write_out(entry.to_local_header().pack_header());
+
+        let mut crc32 = 0u32;
+        let mut read_total = 0u64;
+        let mut write_total = 0u64;
+        let result = {
+            let inner_writer = &mut self.inner_writer;
+            deflator.compress_stream(
+                |in_buf| {
+                    match reader.read(in_buf) {
+                        Some(read_len) => {
+                            crc32 = update_crc(crc32, in_buf, 0, read_len);
+                            read_total += read_len as u64;
+                            read_len
+                        },
+                        None => 0       // EOF
+                    }
+                },
+                |out_buf, _| {
+                    inner_writer.write(out_buf);
+                    write_total += out_buf.len() as u64;
+                    false               // don't abort
+                })
+        };
+        self.offset += write_total;
+        match result {
+            Ok(_) => (),
+            Err(err) => return Err(format!("Failed to compress data.  {:s}", err.to_str()))
+        }
+        if read_total > MAX_ZIP32_SIZE || write_total > MAX_ZIP32_SIZE {
+            return Err(format!("File {:s} is too big for a zip file.", entry.file_name_as_str()));
+        }
+        entry.crc32 = crc32;
+        entry.compressed_size = write_total as u32;
+        entry.uncompressed_size = read_total as u32;
+
+        self.write_out(entry.pack_data_descriptor());
+        Ok(())
+    }
+
+    fn write_out(&mut self, buf: &[u8]) {
+        self.inner_writer.write(buf);
+        self.offset += buf.len() as u64;
+    }
+
+    // Keep the central directory record, spilling the records to the temporary file above the threshold.
+    fn add_cd_record(&mut self, record: ~[u8]) {
+        self.cd_count += 1;
+        self.cd_size += record.len() as u64;
+        self.cd_buf.push_all_move(record);
+        if self.cd_buf.len() <= self.spill_threshold {
+            return;
+        }
+        if self.spill.is_none() {
+            if self.spill_failed {
+                return;                                 // keep the records in memory
+            }
+            let spill_path = unique_path(&os::tmpdir().join("rustyzip-cd.tmp"));
+            match io::result(|| File::open_mode(&spill_path, Truncate, ReadWrite)) {
+                Ok(Some(spill_file))    => self.spill = Some(SpillFile { path: spill_path, file: Some(spill_file) }),
+                _                       => {
+                    self.spill_failed = true;           // don't retry the open on every record
+                    return;
+                }
+            }
+        }
+        match self.spill {
+            Some(SpillFile { file: Some(ref mut spill_file), .. }) => {
+                spill_file.write(self.cd_buf);
+                self.cd_buf = ~[];
+            },
+            _ => ()
+        }
+    }
+}
+
+// The temporary file of the central directory records spilled by ZipStreamWriter, removed when dropped.
+struct SpillFile {
+    path:   Path,
+    file:   Option<File>,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // Close the file before removing it.
+        self.file = None;
+        io::result(|| fs::unlink(&self.path));
+    }
+}
+
+/// Decorator to access the inner writer
+impl<W: Writer> Decorator<W> for ZipStreamWriter<W> {
+    fn inner(self) -> W {
+        self.inner_writer
+    }
+
+    fn inner_ref<'a>(&'a self) -> &'a W {
+        &self.inner_writer
+    }
+
+    fn inner_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        &mut self.inner_writer
+    }
+}
+
+
+/// Writer discarding all the data written, for skipping the entry data.
+struct SinkWriter;
+
+impl Writer for SinkWriter {
+
+    fn write(&mut self, _buf: &[u8]) {
+    }
+
+    fn flush(&mut self) {
+    }
+
+}
+
+
+/// The traditional PKWARE encryption (ZipCrypto) for decrypting an encrypted entry.
+/// The keys are initialized with the password and updated with each byte of the data.
+struct ZipCrypto {
+    priv keys:  [u32, ..3],
+}
+
+impl ZipCrypto {
+
+    fn new(password: &[u8]) -> ZipCrypto {
+        let mut crypto = ZipCrypto { keys: [0x12345678u32, 0x23456789u32, 0x34567890u32] };
+        for &c in password.iter() {
+            crypto.update_keys(c);
+        }
+        crypto
+    }
+
+    fn update_keys(&mut self, c: u8) {
+        self.keys[0] = crc32_byte(self.keys[0], c);
+        self.keys[1] = (self.keys[1] + (self.keys[0] & 0xFF)) * 134775813 + 1;
+        self.keys[2] = crc32_byte(self.keys[2], (self.keys[1] >> 24) as u8);
+    }
+
+    fn stream_byte(&self) -> u8 {
+        let temp = (self.keys[2] | 2) & 0xFFFF;
+        ((temp * (temp ^ 1)) >> 8) as u8
+    }
+
+    fn decrypt(&mut self, buf: &mut [u8]) {
+        for b in buf.mut_iter() {
+            let c = *b ^ self.stream_byte();
+            self.update_keys(c);
+            *b = c;
+        }
+    }
+
+}
+
+/// The raw CRC32 update of one byte, without the pre and post one's complement of update_crc().
+fn crc32_byte(crc: u32, b: u8) -> u32 {
+    !update_crc(!crc, [b], 0, 1)
+}
+
+/// Return the name of the extra field id.
+pub fn extra_field_name(id: u16) -> &'static str {
+    match id {
+        0x0001  => "Zip64 extended information",
+        0x000A  => "NTFS timestamps",
+        0x000D  => "Unix",
+        0x5455  => "extended timestamp",
+        0x5855  => "Info-ZIP Unix (old)",
+        0x7075  => "Info-ZIP Unicode path",
+        0x7855  => "Info-ZIP Unix UID/GID (old)",
+        0x7875  => "Info-ZIP Unix UID/GID",
+        0x9901  => "WinZip AES",
+        _       => "unknown"
+    }
+}
+
+/// Map the name of a zip entry to a path under dest_dir by the default PathPolicy.  The leading / of an absolute
+/// name is dropped.  Return None for a name with .. that would escape dest_dir.
+fn entry_dest_path(dest_dir: &Path, name: &str) -> Option<Path> {
+    PathPolicy::new().dest_path(dest_dir, name)
+}
+
+/// Check whether a parent directory of the path under dest_dir is a symlink, e.g. one extracted earlier,
+/// through which the path could end up outside dest_dir.
+fn through_symlink(dest_dir: &Path, path: &Path) -> bool {
+    let rel_dir = match path.dir_path().path_relative_from(dest_dir) {
+        Some(rel_dir)   => rel_dir,
+        None            => return false
+    };
+    let mut dir = dest_dir.clone();
+    for component in rel_dir.components() {
+        if component == bytes!(".") {
+            continue;
+        }
+        dir.push(component);
+        if is_symlink(&dir) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Check whether the path is a symlink, without following it.  A missing path is not.
+fn is_symlink(path: &Path) -> bool {
+    let mut is_link = false;
+    io_error::cond.trap(|_| ()).inside(|| {
+        is_link = match fs::lstat(path).kind {
+            io::TypeSymlink => true,
+            _               => false
+        };
+    });
+    is_link
+}
+
+/// Check whether all the .. of the relative symlink target come before its other components.
+fn is_descending_target(target: &str) -> bool {
+    let mut descending = false;
+    for component in target.split('/') {
+        match component {
+            "" | "."    => (),
+            ".."        => if descending { return false },
+            _           => descending = true
+        }
+    }
+    true
+}
+
+/// Return the first path of the form stem(n).ext that doesn't exist yet.
+fn unique_path(path: &Path) -> Path {
+    let stem = path.filestem_str().unwrap_or("");
+    let ext = match path.extension_str() {
+        Some(ext)   => format!(".{:s}", ext),
+        None        => ~""
+    };
+    let mut n = 1u;
+    loop {
+        let new_path = path.with_filename(format!("{:s}({:u}){:s}", stem, n, ext));
+        if !new_path.exists() {
+            return new_path;
+        }
+        n += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::io;
+    use std::os;
+    use std::vec;
+    use std::io::{Reader, Writer, Open, Read, Truncate, Write};
+    use std::io::fs;
+    use std::io::fs::File;
+    use std::io::mem::{MemReader, MemWriter};
+    use super::{ZipFile, ZipWriter, ZipStreamReader, ZipEntry32, ZipCrypto, METHOD_STORE, METHOD_DEFLATE};
+    use super::{ExtractOptions, OverwriteNever, OverwriteAlways, OverwriteRename, OverwritePrompt};
+    use super::{ExtractAll, ExtractFreshen, ExtractUpdate};
+    use super::entry_dest_path;
+    use super::PathPolicy;
+    use super::fold_name;
+    use super::is_file_worth_deflating;
+    use super::EntryOptions;
+    use super::{DuplicateFirst, DuplicateLast, DuplicateError, find_duplicates};
+    use super::ExtractLimits;
+    use super::MAX_NESTED_DEPTH;
+    use super::ZipStats;
+    use super::ZipStreamWriter;
+    use super::{ArchivePreset, glob_match};
+    use super::{required_features, FeatureStore, FeatureDeflate, FeatureZipCrypto, FeatureAes, FeatureBzip2, FeatureZip64, FeatureVersion};
+    use super::{METHOD_AES, FLAG_ENCRYPTED};
+    use super::ExtractProgress;
+    use super::super::gzip::GZipWriter;
+    use super::super::ioutil::{CancelToken, CANCELLED};
+    use super::HeaderLimits;
+    use std::io::Decorator;
+    use std::comm::{Chan, SharedChan};
+    use std::task;
+
+    #[test]
+    fn test_zip_writer_roundtrip() {
+        let zip_path = super::unique_path(&os::tmpdir().join("test_zip_writer_roundtrip.zip"));
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_dir("dir", 0, 0x1ED);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "dir/stored.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "dir/deflated.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].is_dir());
+        for entry in entries.slice_from(1).iter() {
+            let mut writer = MemWriter::new();
+            zip_file.extract_to_writer(entry, &mut writer);
+            assert!(( writer.inner() == data.to_owned() ));
+        }
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_zip_stream_reader() {
+        let zip_path = Path::new("test_zip_stream_reader.zip");
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "stored.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "deflated.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
+        }
+
+        let mut zip_stream = ZipStreamReader::new(File::open_mode(&zip_path, Open, Read).unwrap());
+        let mut count = 0;
+        loop {
+            match zip_stream.next_header() {
+                Some(header) => {
+                    let mut writer = MemWriter::new();
+                    assert!(zip_stream.read_data(&header, &mut writer).is_ok());
+                    assert!(( writer.inner() == data.to_owned() ));
+                    count += 1;
+                },
+                None => break
+            }
+        }
+        assert_eq!(count, 2);
+        fs::unlink(&zip_path);
+    }
+
+    fn refuse_overwrite(_: &Path) -> bool { false }
+
+    #[test]
+    fn test_extract_overwrite_policy() {
+        let zip_path = Path::new("test_extract_overwrite_policy.zip");
+        let dest_dir = Path::new("test_extract_overwrite_policy");
+        let data = bytes!("ABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "file.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let mut options = ExtractOptions::new();
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_ok());
+        options.overwrite = OverwriteNever;
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_err());
+        options.overwrite = OverwritePrompt(refuse_overwrite);
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_err());
+        options.overwrite = OverwriteAlways;
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_ok());
+        options.overwrite = OverwriteRename;
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_ok());
+        assert!(dest_dir.join("file(1).txt").exists());
+
+        fs::unlink(&dest_dir.join("file.txt"));
+        fs::unlink(&dest_dir.join("file(1).txt"));
+        fs::rmdir(&dest_dir);
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let zip_path = Path::new("test_header_limits.zip");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(~[1u8, 2, 3]), "a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(~[1u8, 2, 3]), "long_name.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
+        }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        zip_file.set_header_limits(HeaderLimits::untrusted());
+        assert_eq!(zip_file.get_zip_entries().unwrap().len(), 2);
+
+        let mut limits = HeaderLimits::new();
+        limits.max_name_len = Some(8);
+        zip_file.set_header_limits(limits.clone());
+        assert!(zip_file.get_zip_entries().is_err());
+        assert!(zip_file.get_zip_entry_table().is_err());
+        zip_file.set_lenient(true);
+        assert_eq!(zip_file.get_zip_entries().unwrap().len(), 1);
+        assert_eq!(zip_file.get_zip_entry_table().unwrap().len(), 1);
+
+        limits.max_cd_size = Some(10);
+        zip_file.set_header_limits(limits);
+        assert!(zip_file.get_zip_entries().is_err());
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let zip_path = Path::new("test_cancel_token.zip");
+        let dest_dir = Path::new("test_cancel_token");
+        let data = bytes!("ABCDEFGH\r\n");
+        let token = CancelToken::new();
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.set_cancel_token(Some(token.clone()));
+            assert!(zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "file.txt", 0, 0x1A4, METHOD_STORE, 0).is_ok());
+            token.cancel();
+            match zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "file2.txt", 0, 0x1A4, METHOD_DEFLATE, 6) {
+                Ok(_)   => fail!("The cancelled entry is added."),
+                Err(s)  => assert_eq!(s, CANCELLED.to_owned())
+            }
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        assert_eq!(zip_file.get_zip_entries().unwrap().len(), 1);
+        zip_file.set_cancel_token(Some(token.clone()));
+        assert_eq!(zip_file.extract_all(&dest_dir, &ExtractOptions::new()), Err(CANCELLED.to_owned()));
+        assert!(!dest_dir.join("file.txt").exists());
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_modified_datetime() {
+        let mut entry = ZipEntry32::new();
+        entry.modified_date = ((2013 - 1980) << 9) | (10 << 5) | 21;
+        entry.modified_time = (14 << 11) | (35 << 5) | (58 / 2);
+        assert_eq!(entry.modified_datetime(), (2013, 10, 21, 14, 35, 58));
+    }
+
+    #[test]
+    fn test_zip_crypto() {
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\n");
+
+        // Encrypt with the same keys; the keys are updated with the plain bytes.
+        let mut crypto = ZipCrypto::new(bytes!("secret"));
+        let mut encrypted = data.to_owned();
+        for b in encrypted.mut_iter() {
+            let c = *b;
+            *b = c ^ crypto.stream_byte();
+            crypto.update_keys(c);
+        }
+        assert!(( encrypted.as_slice() != data ));
+
+        let mut crypto = ZipCrypto::new(bytes!("secret"));
+        crypto.decrypt(encrypted);
+        assert!(( encrypted.as_slice() == data ));
+    }
+
+    // An archive made by Info-ZIP Zip 3.0 with the password "secret":
+    //   zip -X -P secret -0 kat.zip hello.txt
+    //   zip -X -P secret -9 kat.zip fox.txt
+    // hello.txt is "Hello, ZipCrypto!\n"; fox.txt is "The quick brown fox jumps over the lazy dog. " 8 times and "\n".
+    // Both entries have the data descriptor flag, so the password check byte is the high byte of the modified time.
+    static INFO_ZIP_CRYPTO: &'static [u8] = &[
+        0x50, 0x4B, 0x03, 0x04, 0x0A, 0x00, 0x09, 0x00, 0x00, 0x00, 0x7D, 0x74, 0x55, 0x43, 0xEC, 0x9C,
+        0x5E, 0xBA, 0x1E, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x68, 0x65,
+        0x6C, 0x6C, 0x6F, 0x2E, 0x74, 0x78, 0x74, 0x60, 0x72, 0x94, 0x16, 0x9A, 0x85, 0x1E, 0xBA, 0x31,
+        0x0A, 0x33, 0xCD, 0xDA, 0x89, 0xD5, 0x1A, 0x0A, 0xC4, 0x79, 0x49, 0x70, 0xAB, 0xA8, 0xF3, 0x2A,
+        0xFF, 0x50, 0x1E, 0xBD, 0x9D, 0x50, 0x4B, 0x07, 0x08, 0xEC, 0x9C, 0x5E, 0xBA, 0x1E, 0x00, 0x00,
+        0x00, 0x12, 0x00, 0x00, 0x00, 0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x0B, 0x00, 0x08, 0x00, 0x7D,
+        0x74, 0x55, 0x43, 0x81, 0x63, 0x87, 0x6E, 0x40, 0x00, 0x00, 0x00, 0x69, 0x01, 0x00, 0x00, 0x07,
+        0x00, 0x00, 0x00, 0x66, 0x6F, 0x78, 0x2E, 0x74, 0x78, 0x74, 0xB8, 0xEB, 0x6D, 0x9A, 0xFB, 0x57,
+        0xFC, 0x1D, 0x18, 0x1A, 0x5D, 0x07, 0x63, 0xE9, 0x2C, 0xEC, 0x18, 0x89, 0x54, 0x7F, 0xC1, 0xBE,
+        0x10, 0x0E, 0x9A, 0xAF, 0x7B, 0x75, 0x54, 0x1E, 0x6D, 0xF1, 0x18, 0x16, 0xC1, 0xB1, 0x89, 0xA6,
+        0xBC, 0x40, 0x7B, 0x7F, 0x54, 0xFB, 0x10, 0x19, 0xD9, 0xA9, 0xB0, 0x4C, 0x7E, 0x1E, 0x02, 0x0D,
+        0x84, 0x47, 0x28, 0x16, 0x0A, 0x4A, 0x18, 0xA4, 0x91, 0xF8, 0x50, 0x4B, 0x07, 0x08, 0x81, 0x63,
+        0x87, 0x6E, 0x40, 0x00, 0x00, 0x00, 0x69, 0x01, 0x00, 0x00, 0x50, 0x4B, 0x01, 0x02, 0x1E, 0x03,
+        0x0A, 0x00, 0x09, 0x00, 0x00, 0x00, 0x7D, 0x74, 0x55, 0x43, 0xEC, 0x9C, 0x5E, 0xBA, 0x1E, 0x00,
+        0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xA4, 0x81, 0x00, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2E, 0x74, 0x78,
+        0x74, 0x50, 0x4B, 0x01, 0x02, 0x1E, 0x03, 0x14, 0x00, 0x0B, 0x00, 0x08, 0x00, 0x7D, 0x74, 0x55,
+        0x43, 0x81, 0x63, 0x87, 0x6E, 0x40, 0x00, 0x00, 0x00, 0x69, 0x01, 0x00, 0x00, 0x07, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xA4, 0x81, 0x55, 0x00, 0x00, 0x00, 0x66,
+        0x6F, 0x78, 0x2E, 0x74, 0x78, 0x74, 0x50, 0x4B, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+        0x02, 0x00, 0x6C, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_zip_crypto_info_zip() {
+        let zip_path = super::unique_path(&os::tmpdir().join("rustyzip-crypto.zip"));
+        File::open_mode(&zip_path, Truncate, Write).unwrap().write(INFO_ZIP_CRYPTO);
+
+        let mut fox = ~[];
+        for _ in range(0, 8) {
+            fox.push_all(bytes!("The quick brown fox jumps over the lazy dog. "));
+        }
+        fox.push_all(bytes!("\n"));
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_encrypted() && entries[0].compression_method == METHOD_STORE);
+        assert!(entries[1].is_encrypted() && entries[1].compression_method == METHOD_DEFLATE);
+
+        zip_file.set_password("secret");
+        assert_eq!(zip_file.zip_entry_reader(&entries[0]).read_to_end(), bytes!("Hello, ZipCrypto!\n").to_owned());
+        assert_eq!(zip_file.zip_entry_reader(&entries[1]).read_to_end(), fox);
+
+        zip_file.set_password("wrong");
+        let mut error = None;
+        io::io_error::cond.trap(|e| error = Some(e.desc)).inside(|| {
+            zip_file.zip_entry_reader(&entries[0]).read_to_end();
+        });
+        assert_eq!(error, Some("Incorrect password for the encrypted zip entry"));
+
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_copy_encrypted_entry() {
+        let src_path = super::unique_path(&os::tmpdir().join("rustyzip-crypto-src.zip"));
+        let copy_path = super::unique_path(&os::tmpdir().join("rustyzip-crypto-copy.zip"));
+        File::open_mode(&src_path, Truncate, Write).unwrap().write(INFO_ZIP_CRYPTO);
+
+        let mut src_file = ZipFile::open(File::open_mode(&src_path, Open, Read).unwrap()).unwrap();
+        let src_entries = src_file.get_zip_entries().unwrap();
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&copy_path, Truncate, Write).unwrap());
+            for entry in src_entries.iter() {
+                assert!(( zip_writer.copy_entry(&mut src_file, entry).is_ok() ));
+            }
+            zip_writer.finish();
+        }
+
+        // The copies keep the data descriptor flag their password check byte goes by.
+        let mut zip_file = ZipFile::open(File::open_mode(&copy_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.is_encrypted() && entry.has_data_descriptor()));
+        zip_file.set_password("secret");
+        src_file.set_password("secret");
+        for i in range(0, 2) {
+            assert_eq!(zip_file.zip_entry_reader(&entries[i]).read_to_end(), src_file.zip_entry_reader(&src_entries[i]).read_to_end());
+        }
+        assert_eq!(zip_file.zip_entry_reader(&entries[0]).read_to_end(), bytes!("Hello, ZipCrypto!\n").to_owned());
+
+        fs::unlink(&src_path);
+        fs::unlink(&copy_path);
+    }
+
+    #[test]
+    fn test_extra_fields() {
+        let mut entry = ZipEntry32::new();
+        entry.extra_field = Some(~[0x55, 0x54, 0x05, 0x00, 0x03, 0x01, 0x02, 0x03, 0x04, 0x75, 0x78, 0x00, 0x00]);
+        let fields = entry.extra_fields();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0], (0x5455u16, ~[0x03u8, 0x01, 0x02, 0x03, 0x04]));
+        assert_eq!(fields[1], (0x7875u16, ~[]));
+    }
+
+    #[test]
+    fn test_extract_symlink() {
+        let zip_path = Path::new("test_extract_symlink.zip");
+        let dest_dir = Path::new("test_extract_symlink");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_symlink("dir/link", "../file.txt", 0);
+            zip_writer.add_symlink("unsafe", "../file.txt", 0);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert!(entries[0].is_symlink());
+        let mut options = ExtractOptions::new();
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_err());
+        options.symlinks = true;
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_ok());
+        assert_eq!(fs::readlink(&dest_dir.join("dir/link")), Some(Path::new("../file.txt")));
+        assert!(zip_file.extract_entry(&entries[1], &dest_dir, &options).is_err());
+
+        fs::unlink(&dest_dir.join("dir/link"));
+        fs::rmdir(&dest_dir.join("dir"));
+        fs::rmdir(&dest_dir);
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_extract_symlink_chain() {
+        let zip_path = Path::new("test_extract_symlink_chain.zip");
+        let dest_dir = Path::new("test_extract_symlink_chain");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            // d/e/y points back to d.  Through it, d/e/x would point two levels above dest_dir.
+            zip_writer.add_symlink("d/e/y", "../../d", 0);
+            zip_writer.add_symlink("d/e/x", "y/../..", 0);
+            zip_writer.add_reader(&mut MemReader::new(bytes!("data").to_owned()), "d/e/y/e/file.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_symlink("d/e/z", "../d2/../..", 0);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let mut options = ExtractOptions::new();
+        options.symlinks = true;
+        assert!(zip_file.extract_entry(&entries[0], &dest_dir, &options).is_ok());
+        assert!(zip_file.extract_entry(&entries[1], &dest_dir, &options).is_err());
+        assert!(!dest_dir.join("d/e/x").exists());
+        assert!(zip_file.extract_entry(&entries[2], &dest_dir, &options).is_err());
+        assert!(!dest_dir.join("d/e/file.txt").exists());
+        assert!(zip_file.extract_entry(&entries[3], &dest_dir, &options).is_err());
+
+        fs::unlink(&dest_dir.join("d/e/y"));
+        fs::rmdir(&dest_dir.join("d/e"));
+        fs::rmdir(&dest_dir.join("d"));
+        fs::rmdir(&dest_dir);
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_extract_mode() {
+        let dest_dir = Path::new("test_extract_mode");
+        let file_path = dest_dir.join("file.txt");
+        fs::mkdir_recursive(&dest_dir, io::UserRWX);
+        File::create(&file_path).unwrap().write(bytes!("ABCDEFGH"));
+        let (file_time, file_date) = super::to_dos_time(fs::stat(&file_path).modified / 1000);
+
+        let mut options = ExtractOptions::new();
+        options.mode = ExtractAll;
+        assert!(options.is_wanted(&dest_dir, "file.txt", file_date, file_time));
+        options.mode = ExtractFreshen;
+        assert!(!options.is_wanted(&dest_dir, "file.txt", file_date, file_time));
+        assert!(options.is_wanted(&dest_dir, "file.txt", file_date + 1, file_time));
+        assert!(!options.is_wanted(&dest_dir, "new.txt", file_date + 1, file_time));
+        options.mode = ExtractUpdate;
+        assert!(!options.is_wanted(&dest_dir, "file.txt", file_date, file_time));
+        assert!(options.is_wanted(&dest_dir, "file.txt", file_date + 1, file_time));
+        assert!(options.is_wanted(&dest_dir, "new.txt", file_date, file_time));
+
+        fs::unlink(&file_path);
+        fs::rmdir(&dest_dir);
+    }
+
+    #[test]
+    fn test_auto_store() {
+        let zip_path = Path::new("test_auto_store.zip");
+        let text_path = Path::new("test_auto_store.txt");
+        let random_path = Path::new("test_auto_store.bin");
+        let mut text : ~[u8] = ~[];
+        let mut random : ~[u8] = ~[];
+        let mut seed = 12345u32;
+        while text.len() < 100000 {
+            text.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+        while random.len() < 100000 {
+            seed = seed * 1103515245 + 12345;
+            random.push((seed >> 16) as u8);
+        }
+        File::create(&text_path).unwrap().write(text);
+        File::create(&random_path).unwrap().write(random);
+        assert!(is_file_worth_deflating(&text_path));
+        assert!(!is_file_worth_deflating(&random_path));
+        assert!(!is_file_worth_deflating(&Path::new("test_auto_store.none")));
+
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.set_auto_store(true);
+            assert_eq!(zip_writer.add_file(&text_path, "text.txt", METHOD_DEFLATE, 6).unwrap().compression_method, METHOD_DEFLATE);
+            assert_eq!(zip_writer.add_file(&random_path, "random.bin", METHOD_DEFLATE, 6).unwrap().compression_method, METHOD_STORE);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[1], &mut writer);
+        assert_eq!(writer.inner(), random);
+
+        fs::unlink(&zip_path);
+        fs::unlink(&text_path);
+        fs::unlink(&random_path);
+    }
+
+    #[test]
+    fn test_start_entry() {
+        let zip_path = Path::new("test_start_entry.zip");
+        let mut text : ~[u8] = ~[];
+        while text.len() < 100000 {
+            text.push_all(bytes!("ABCDEFGH\r\n0123456789"));
+        }
+
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            {
+                let mut entry_writer = zip_writer.start_entry("deflated.txt", &EntryOptions::new()).unwrap();
+                for chunk in text.chunks(7000) {
+                    entry_writer.write(chunk);
+                }
+                let entry = entry_writer.finish().unwrap();
+                assert_eq!(entry.uncompressed_size as uint, text.len());
+                assert!((entry.compressed_size as uint) < text.len());
+                assert!(entry_writer.finish().is_err());
+            }
+            {
+                // Finished on drop.
+                let mut options = EntryOptions::new();
+                options.method = METHOD_STORE;
+                let mut entry_writer = zip_writer.start_entry("stored.txt", &options).unwrap();
+                entry_writer.write(text.slice(0, 1000));
+            }
+            zip_writer.finish();
         }
-    }
 
-    // Unpack the fixed header of the zip entry.
-    fn unpack_zip_entry(&mut self, buf: &[u8], mut offset: uint) -> Result<uint, ~str> {
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].compression_method, METHOD_STORE);
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[0], &mut writer);
+        assert_eq!(writer.inner(), text.clone());
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[1], &mut writer);
+        assert_eq!(writer.inner(), text.slice(0, 1000).to_owned());
 
-        if unpack_u32_le(buf, offset) != CD_HEADER_MAGIC {
-            return Err(~"Zip file entry signature mismatched.");
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_open_entry_reader() {
+        let zip_path = Path::new("test_open_entry_reader.zip");
+        let data1 = bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n");
+        let data2 = bytes!("0123456789\r\n0123456789\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data1.to_owned()), "one.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.add_reader(&mut MemReader::new(data2.to_owned()), "two.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
         }
-        offset += 4;
 
-        self.version_made_by = unpack_u16_le(buf, offset);          offset += 2;
-        self.version_needed = unpack_u16_le(buf, offset);           offset += 2;
-        self.general_flag = unpack_u16_le(buf, offset);             offset += 2;
-        self.compression_method = unpack_u16_le(buf, offset);       offset += 2;
-        self.modified_time = unpack_u16_le(buf, offset);            offset += 2;
-        self.modified_date = unpack_u16_le(buf, offset);            offset += 2;
-        self.crc32 = unpack_u32_le(buf, offset);                    offset += 4;
-        self.compressed_size = unpack_u32_le(buf, offset);          offset += 4;
-        self.uncompressed_size = unpack_u32_le(buf, offset);        offset += 4;
-        self.file_name_length = unpack_u16_le(buf, offset);         offset += 2;
-        self.extra_field_length = unpack_u16_le(buf, offset);       offset += 2;
-        self.file_comment_length = unpack_u16_le(buf, offset);      offset += 2;
-        self.disk_number_start = unpack_u16_le(buf, offset);        offset += 2;
-        self.internal_file_attributes = unpack_u16_le(buf, offset); offset += 2;
-        self.external_file_attributes = unpack_u32_le(buf, offset); offset += 4;
-        self.local_header_offset = unpack_u32_le(buf, offset);      offset += 4;
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let mut reader1 = zip_file.open_entry_reader(&entries[0]).unwrap();
+        let reader2 = zip_file.open_entry_reader(&entries[1]).unwrap();
+        assert_eq!(reader2.zip_entry().file_name_as_str(), ~"two.txt");
 
-        return Ok(offset);
-    }
+        // Read the other entry through the ZipFile in between.
+        let mut buf = [0u8, ..10];
+        assert_eq!(reader1.read(buf), Some(10));
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[1], &mut writer);
+        assert_eq!(writer.inner(), data2.to_owned());
+        let mut data = buf.to_owned();
+        data.push_all(reader1.read_to_end());
+        assert_eq!(data, data1.to_owned());
 
-    fn get_extra_length(&self) -> uint {
-        return self.file_name_length as uint + self.extra_field_length as uint + self.file_comment_length as uint;
+        // Read in another task.
+        let (port, chan) = Chan::new();
+        task::spawn(proc() {
+            let mut reader2 = reader2;
+            chan.send(reader2.read_to_end());
+        });
+        assert_eq!(port.recv(), data2.to_owned());
+
+        fs::unlink(&zip_path);
     }
 
-    // Unpack the variable length header of the zip entry.
-    fn unpack_zip_entry_extra(&mut self, buf: &[u8], mut offset: uint) -> uint {
-        if self.file_name_length > 0 {
-            self.file_name = Some(buf.slice(offset, offset + self.file_name_length as uint).to_owned());
-            offset += self.file_name_length as uint;
+    #[test]
+    fn test_shared_zip_file() {
+        let zip_path = Path::new("test_shared_zip_file.zip");
+        let mut data : ~[~[u8]] = ~[];
+        for i in range(0u, 4) {
+            let mut text : ~[u8] = ~[];
+            while text.len() < 50000 {
+                text.push_all(format!("{:u} ABCDEFGH\r\n", i).as_bytes());
+            }
+            data.push(text);
         }
-        if self.extra_field_length > 0 {
-            self.extra_field = Some(buf.slice(offset, offset + self.extra_field_length as uint).to_owned());
-            offset += self.extra_field_length as uint;
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            for (i, text) in data.iter().enumerate() {
+                zip_writer.add_reader(&mut MemReader::new(text.clone()), format!("{:u}.txt", i), 0, 0x1A4, METHOD_DEFLATE, 6);
+            }
+            zip_writer.finish();
         }
-        if self.file_comment_length > 0 {
-            self.file_comment = Some(str::from_utf8( buf.slice(offset, offset + self.file_comment_length as uint) ));
-            offset += self.file_comment_length as uint;
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let shared = zip_file.share().unwrap();
+        assert_eq!(shared.entries().len(), 4);
+        assert!(shared.find_entry("2.txt").is_some());
+        assert!(shared.find_entry("5.txt").is_none());
+
+        let (port, chan) = SharedChan::new();
+        for i in range(0u, 4) {
+            let shared = shared.clone();
+            let chan = chan.clone();
+            task::spawn(proc() {
+                let entry = shared.find_entry(format!("{:u}.txt", i)).unwrap().clone();
+                let mut reader = shared.entry_reader(&entry).unwrap();
+                chan.send((i, reader.read_to_end()));
+            });
+        }
+        for _ in range(0u, 4) {
+            let (i, text) = port.recv();
+            assert_eq!(text, data[i].clone());
         }
-        offset
-    }
 
-    fn unpack_data_descriptor(&mut self, buf: &[u8]) {
-        // TODO
+        fs::unlink(&zip_path);
     }
 
-    fn read_zip_entry(file: &mut File) -> Result<ZipEntry32, ~str> {
-        let mut buf = [0u8, ..CD_FILE_HEADER_SIZE];
-        let read_len = read_buf_upto(file, buf, 0, CD_FILE_HEADER_SIZE);
-        if read_len < CD_FILE_HEADER_SIZE {
-            return Err(~"Zip file entry does not have enough data.");
+    #[test]
+    fn test_entry_index() {
+        let zip_path = Path::new("test_entry_index.zip");
+        let data = bytes!("ABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "b/z.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "top.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_dir("b/c", 0, 0x1ED);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "b/a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "b/c/d.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
         }
-        
-        let mut entry = ZipEntry32::new();
-        match entry.unpack_zip_entry(buf, 0) {
-            Err(s) => return Err(s),
-            Ok(_) => {
-                let buf = read_upto(file, entry.get_extra_length());
-                entry.unpack_zip_entry_extra(buf, 0);
-            }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let index = zip_file.entry_index().unwrap();
+        let names = index.sorted_by_name().map(|entry| entry.file_name_as_str());
+        assert_eq!(names, ~[~"b/a.txt", ~"b/c/", ~"b/c/d.txt", ~"b/z.txt", ~"top.txt"]);
+        let names = index.sorted_by_offset().map(|entry| entry.file_name_as_str());
+        assert_eq!(names, ~[~"b/z.txt", ~"top.txt", ~"b/c/", ~"b/a.txt", ~"b/c/d.txt"]);
+
+        {
+            let groups = index.grouped_by_dir();
+            let dirs = groups.map(|&(dir, _)| dir.to_owned());
+            assert_eq!(dirs, ~[~"", ~"b/", ~"b/c/"]);
+            let (_, ref b_entries) = groups[1];
+            assert_eq!(b_entries.map(|entry| entry.file_name_as_str()), ~[~"b/a.txt", ~"b/c/", ~"b/z.txt"]);
         }
-        Ok(entry)
+
+        let mut index = index;
+        assert_eq!(index.find("b/c/d.txt").unwrap().file_name_as_str(), ~"b/c/d.txt");
+        assert!(index.find("B/C/D.TXT").is_none());
+        assert!(index.find("b/c/e.txt").is_none());
+        index.set_ignore_case(true);
+        assert_eq!(index.find("B/C/D.TXT").unwrap().file_name_as_str(), ~"b/c/d.txt");
+        assert_eq!(index.find("Top.txt").unwrap().file_name_as_str(), ~"top.txt");
+        assert!(index.find("b/c/e.txt").is_none());
+        index.set_ignore_case(false);
+        assert!(index.find("Top.txt").is_none());
+
+        assert_eq!(fold_name("Caf\u00c9.TXT"), fold_name("cafe\u0301.txt"));
+        assert!(fold_name("a.txt") != fold_name("b.txt"));
+
+        fs::unlink(&zip_path);
     }
 
-    fn read_local_file_header(&mut self, file: &mut File) {
-        file.seek(self.local_header_offset as i64, SeekSet);
-        self.local_header.read_header(file)
+    #[test]
+    fn test_duplicates() {
+        let zip_path = Path::new("test_duplicates.zip");
+        let dest_dir = Path::new("test_duplicates");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(bytes!("first").to_owned()), "a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(bytes!("other").to_owned()), "b.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(bytes!("last").to_owned()), "a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        assert_eq!(zip_file.duplicate_names().unwrap(), ~[~"a.txt"]);
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(find_duplicates(entries.slice(0, 2)).len(), 0);
+
+        let first = zip_file.find_entry("a.txt", DuplicateFirst).unwrap().unwrap();
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&first, &mut writer);
+        assert_eq!(writer.inner(), bytes!("first").to_owned());
+        let last = zip_file.find_entry("a.txt", DuplicateLast).unwrap().unwrap();
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&last, &mut writer);
+        assert_eq!(writer.inner(), bytes!("last").to_owned());
+        assert!(zip_file.find_entry("a.txt", DuplicateError).is_err());
+        assert!(zip_file.find_entry("b.txt", DuplicateError).unwrap().is_some());
+        assert!(zip_file.find_entry("c.txt", DuplicateError).unwrap().is_none());
+
+        let mut options = ExtractOptions::new();
+        assert!(zip_file.extract_all(&dest_dir, &options).is_err());
+        assert!(!dest_dir.join("b.txt").exists());
+        options.duplicates = DuplicateLast;
+        assert_eq!(zip_file.extract_all(&dest_dir, &options).unwrap().len(), 2);
+        assert_eq!(File::open_mode(&dest_dir.join("a.txt"), Open, Read).unwrap().read_to_end(), bytes!("last").to_owned());
+
+        fs::unlink(&dest_dir.join("a.txt"));
+        fs::unlink(&dest_dir.join("b.txt"));
+        fs::rmdir(&dest_dir);
+        fs::unlink(&zip_path);
     }
 
-    fn get_file_data_offset(&self) -> i64 {
-        self.local_header_offset as i64 + self.local_header.get_total_length() as i64
+    #[test]
+    fn test_extract_limits() {
+        let zip_path = Path::new("test_extract_limits.zip");
+        let dest_dir = Path::new("test_extract_limits");
+        let zeros = vec::from_elem(100000, 0u8);
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(bytes!("ABCDEFGH").to_owned()), "small.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(zeros.clone()), "zeros.bin", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
+        }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let options = ExtractOptions::new();
+
+        let mut limits = ExtractLimits::new();
+        limits.max_entries = Some(1);
+        assert!(limits.check_entries(entries, 0).is_some());
+        zip_file.set_limits(limits.clone());
+        assert!(zip_file.extract_all(&dest_dir, &options).is_err());
+        assert!(!dest_dir.exists());
+
+        let mut limits = ExtractLimits::new();
+        limits.max_total_size = Some(50000);
+        assert!(limits.check_entries(entries, 0).is_some());
+        assert!(limits.check_entries(entries.slice(0, 1), 0).is_none());
+
+        // The readers stop at the limit on the actual data, even without checking the sizes up front.
+        let mut limits = ExtractLimits::new();
+        limits.max_ratio = Some(10.0);
+        assert!(limits.check_entries(entries, 0).is_some());
+        zip_file.set_limits(limits);
+        let mut writer = MemWriter::new();
+        let mut error = None;
+        io::io_error::cond.trap(|e| error = Some(e.desc)).inside(|| {
+            zip_file.extract_to_writer(&entries[1], &mut writer);
+        });
+        assert_eq!(error, Some("Zip extraction limit exceeded"));
+        assert!(writer.inner().len() < zeros.len());
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[0], &mut writer);
+        assert_eq!(writer.inner(), bytes!("ABCDEFGH").to_owned());
+
+        fs::unlink(&zip_path);
     }
 
-    fn read_file_data(&mut self, file: &mut File, read_offset: u64, output_buf: &mut [u8]) -> uint {
-        let remaining_len = self.compressed_size as u64 - read_offset;
-        if remaining_len == 0 {
-            return 0;
+    #[test]
+    fn test_nested_archives() {
+        let inner_path = Path::new("test_nested_inner.zip");
+        let zip_path = Path::new("test_nested_archives.zip");
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&inner_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "inner.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
         }
-        file.seek(self.get_file_data_offset() + read_offset as i64, SeekSet);
-        let bytes_to_read = num::min(remaining_len, output_buf.len() as u64) as uint;
-        match file.read(output_buf.mut_slice(0, bytes_to_read)) {
-            Some(read_len)  => read_len,
-            None            => 0
+        let mut gzip_writer = GZipWriter::new(MemWriter::new());
+        gzip_writer.write(data);
+        gzip_writer.finalize();
+        let gzip_data = gzip_writer.inner().inner();
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_file(&inner_path, "lib/inner.jar", METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(gzip_data), "data.txt.gz", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
         }
-    }
+        fs::unlink(&inner_path);
 
-    fn has_data_descriptor(&self) -> bool {
-        // TODO
-        true
-    }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        {
+            let mut nested = zip_file.open_nested_zip(&entries[0]).unwrap();
+            let nested_entries = nested.zip_file().get_zip_entries().unwrap();
+            assert_eq!(nested_entries[0].file_name_as_str(), ~"inner.txt");
+            let mut writer = MemWriter::new();
+            nested.zip_file().extract_to_writer(&nested_entries[0], &mut writer);
+            assert_eq!(writer.inner(), data.to_owned());
+            assert!(nested.zip_file().open_nested_zip(&nested_entries[0]).is_err());    // not a zip file
+        }
 
-    fn checkCrc(&self) {
-        // TODO
+        let mut gzip_reader = zip_file.open_nested_gzip(&entries[1]).unwrap();
+        assert_eq!(gzip_reader.read_to_end(), data.to_owned());
+
+        zip_file.depth = MAX_NESTED_DEPTH;
+        assert!(zip_file.open_nested_zip(&entries[0]).is_err());
+
+        fs::unlink(&zip_path);
     }
 
-}
+    #[test]
+    fn test_stats() {
+        let zip_path = Path::new("test_stats.zip");
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n");
+        let old_mtime = 1000000000000u64;
+        let new_mtime = 1300000000000u64;
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "new.txt", new_mtime, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "old.txt", old_mtime, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "mid.txt", 1100000000000u64, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
+        }
 
-/// An iterator over the list of ZipEntry read from the zip file.
-pub struct ZipEntry32Iterator<'self> {
-    priv zip_file:  &'self mut ZipFile,
-    priv index:     u16,
-    priv file_pos:  u64,
-    priv finished:  bool,
-}
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let stats = zip_file.stats().unwrap();
+        assert_eq!(stats.entry_count, 3);
+        assert_eq!(stats.uncompressed_size, 3 * data.len() as u64);
+        assert!(stats.compressed_size < stats.uncompressed_size);
+        assert!(stats.compression_ratio() > 0f64);
+        assert_eq!(stats.method_count(METHOD_DEFLATE), 2);
+        assert_eq!(stats.method_count(METHOD_STORE), 1);
+        assert_eq!(stats.encrypted_count, 0);
+        assert_eq!(stats.oldest_mtime, Some(old_mtime));
+        assert_eq!(stats.newest_mtime, Some(new_mtime));
 
+        let empty : ~[ZipEntry32] = ~[];
+        let stats = ZipStats::from_entries(empty.iter());
+        assert_eq!(stats.entry_count, 0);
+        assert_eq!(stats.oldest_mtime, None);
 
-impl<'self> Iterator<ZipEntry32> for ZipEntry32Iterator<'self> {
+        fs::unlink(&zip_path);
+    }
 
-    fn next(&mut self) -> Option<ZipEntry32> {
-        if self.finished {
-            return None;
+    static mut progress_calls: uint = 0;
+    static mut progress_last_done: u64 = 0;
+
+    fn count_progress(progress: &ExtractProgress) {
+        unsafe {
+            progress_calls += 1;
+            progress_last_done = progress.bytes_done;
         }
-        if self.index >= self.zip_file.cd_metadata.cd_entry_count {
-            self.finished = true;
-            return None;
+        assert!(progress.entries_done <= progress.entries_total);
+    }
+
+    #[test]
+    fn test_entry_name_lazy() {
+        let mut entry = ZipEntry32::new();
+        entry.file_name = Some(bytes!("dir/caf", 0xE9, ".txt").to_owned());
+        assert!(entry.name_eq("dir/caf\xe9.txt") == false);
+        assert_eq!(entry.file_name_as_str(), ~"dir/caf\xe9.txt");
+        assert_eq!(entry.name_str().to_owned(), ~"dir/caf\xe9.txt");
+        assert!(!entry.is_dir());
+
+        entry.file_name = Some(bytes!("dir/").to_owned());
+        entry.file_name_str = None;
+        assert!(entry.name_eq("dir/"));
+        assert!(entry.is_dir());
+        assert_eq!(entry.name_str().to_owned(), ~"dir/");
+        assert_eq!(entry.file_name_as_str(), ~"dir/");
+    }
+
+    #[test]
+    fn test_zip_entry_table() {
+        let data = bytes!("ABCDEFGH\r\n");
+        let zip_path = Path::new("test_zip_entry_table.zip");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "dir/b.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "dir/c.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
         }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        let table = zip_file.get_zip_entry_table().unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.name(1), ~"dir/b.txt");
+        assert_eq!(table.uncompressed_size(2), 10);
+        assert_eq!(table.find(bytes!("dir/c.txt")), Some(2));
+        assert_eq!(table.find(bytes!("d.txt")), None);
+        let entry = table.entry(2);
+        assert_eq!(entry.file_name_as_str(), entries[2].file_name_as_str());
+        assert_eq!(entry.crc32, entries[2].crc32);
+        assert_eq!(entry.local_header_offset, entries[2].local_header_offset);
+        let dir_entries = table.filter_entries(|name| name.starts_with(bytes!("dir/")));
+        assert_eq!(dir_entries.len(), 2);
 
-        self.index += 1;
-        self.finished = (self.index == self.zip_file.cd_metadata.cd_entry_count);
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entry, &mut writer);
+        assert!(( writer.inner() == data.to_owned() ));
+        fs::unlink(&zip_path);
+    }
 
-        match ZipEntry32::read_zip_entry(&mut self.zip_file.inner_file) {
-            Ok(entry) => Some(entry),
-            Err(s) => fail!(s)      // TODO: return error
+    #[test]
+    fn test_extract_progress() {
+        let data = bytes!("ABCDEFGH\r\n");
+        let zip_path = Path::new("test_extract_progress.zip");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "a.txt", 0, 0x1A4, METHOD_STORE, 0);
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "b.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
         }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        zip_file.set_progress_fn(Some(count_progress));
+        let entries = zip_file.get_zip_entries().unwrap();
+        zip_file.start_progress(2, 20);
+        for entry in entries.iter() {
+            zip_file.extract_to_writer(entry, &mut MemWriter::new());
+        }
+        unsafe {
+            assert!(progress_calls >= 2);
+            assert_eq!(progress_last_done, 20);
+        }
+        let progress = ExtractProgress::new(2, 0);
+        assert_eq!(progress.eta_secs(), None);
+        assert_eq!(progress.percent(), 100f64);
+        fs::unlink(&zip_path);
     }
 
+    #[test]
+    fn test_required_features() {
+        let mut stored = ZipEntry32::new();
+        stored.compression_method = METHOD_STORE;
+        let mut deflated = ZipEntry32::new();
+        deflated.compression_method = METHOD_DEFLATE;
+        deflated.general_flag = FLAG_ENCRYPTED;
+        assert_eq!(required_features([stored.clone(), deflated.clone(), stored.clone()]), ~[FeatureStore, FeatureDeflate, FeatureZipCrypto]);
+        assert!(required_features([stored.clone(), deflated.clone()]).iter().all(|feature| feature.is_supported()));
 
-    #[inline]
-    fn size_hint(&self) -> (uint, Option<uint>) {
-        if self.finished {
-            (0u, Some(0u))
-        } else {
-            (self.index as uint, Some(self.zip_file.cd_metadata.cd_entry_count as uint))
+        let mut aes = ZipEntry32::new();
+        aes.compression_method = METHOD_AES;
+        aes.general_flag = FLAG_ENCRYPTED;
+        let mut bzip2 = ZipEntry32::new();
+        bzip2.compression_method = 12;
+        bzip2.version_needed = 46;
+        bzip2.uncompressed_size = 0xFFFFFFFF;
+        assert_eq!(required_features([aes, bzip2]), ~[FeatureAes, FeatureBzip2, FeatureZip64]);
+        assert!(!FeatureZip64.is_supported());
+        assert_eq!(FeatureVersion(63).to_str(), ~"zip version 6.3");
+
+        let zip_path = Path::new("test_required_features.zip");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_reader(&mut MemReader::new(bytes!("ABCDEFGH\r\n").to_owned()), "a.txt", 0, 0x1A4, METHOD_DEFLATE, 6);
+            zip_writer.finish();
         }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        assert_eq!(zip_file.required_features(), Ok(~[FeatureDeflate]));
+        assert_eq!(zip_file.check_supported(), Ok(()));
+        fs::unlink(&zip_path);
     }
-}
 
-/// Reader for reading the content of the file item at the zip entry.
-pub struct ZipReader<'self> {
-    priv zip_file:      &'self mut ZipFile,
-    priv zip_entry:     ZipEntry32,
-    priv read_total:    u64,
-    priv cmp_crc32:     u32,
-    priv is_eof:        bool,
-    priv inflator:      Option<Inflator>,
-}
+    #[test]
+    fn test_entry_options() {
+        let zip_path = Path::new("test_entry_options.zip");
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\n");
+        let mtime = 1300000000000u64;
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            let mut options = EntryOptions::new();
+            options.mtime = mtime;
+            options.unix_mode = 0x81ED;         // -rwxr-xr-x
+            options.internal_attributes = 1;
+            zip_writer.add_reader_with_options(&mut MemReader::new(data.to_owned()), "script.sh", &options).unwrap();
+            options.external_attributes = Some(0x20);
+            zip_writer.add_reader_with_options(&mut MemReader::new(data.to_owned()), "dos.txt", &options).unwrap();
+            zip_writer.finish();
+        }
 
-impl<'self> ZipReader<'self> {
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries[0].unix_mode(), Some(0x81ED));
+        assert_eq!(entries[0].internal_file_attributes, 1);
+        assert!(!entries[0].is_older_than(mtime) && entries[0].is_older_than(mtime + 2000));
+        assert_eq!(entries[1].external_file_attributes, 0x20);
 
-    fn init(&mut self) {
-        self.zip_entry.read_local_file_header(&mut self.zip_file.inner_file);
-        match self.zip_entry.compression_method {
-            METHOD_STORE => (),
-            METHOD_DEFLATE => {
-                self.inflator = Some(Inflator::with_size_factor(deflate::DEFAULT_SIZE_FACTOR));
-            },
-            _ => {
-                io_error::cond.raise(IoError {
-                        kind: OtherIoError,
-                        desc: "Unsupported compression method",
-                        detail: Some(format!("Unsupported compression method: {:u}", self.zip_entry.compression_method as uint))
-                    });
+        let options = EntryOptions::from_entry(&entries[0]);
+        assert_eq!(options.unix_mode, 0x81ED);
+        assert_eq!(options.mtime / 1000, mtime / 1000);
+        assert_eq!(options.method, METHOD_DEFLATE);
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[0], &mut writer);
+        assert_eq!(writer.inner(), data.to_owned());
+
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let zip_path = Path::new("test_alignment.zip");
+        let data = bytes!("ABCDEFGH\r\n");
+        let names = ["a", "lib/armeabi/libfoo.so", "resources.arsc", "res/x.png"];
+        for &alignment in [0u, 4u].iter() {
+            {
+                let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+                zip_writer.set_alignment(alignment);
+                zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "classes.dex", 0, 0x1A4, METHOD_DEFLATE, 6);
+                for name in names.iter() {
+                    zip_writer.add_reader(&mut MemReader::new(data.to_owned()), *name, 0, 0x1A4, METHOD_STORE, 0);
+                }
+                zip_writer.finish();
+            }
+
+            let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+            let misaligned = zip_file.check_alignment(4).unwrap();
+            if alignment == 4 {
+                assert_eq!(misaligned.len(), 0);
+            } else {
+                assert!(misaligned.len() > 0);
+            }
+            for entry in zip_file.get_zip_entries().unwrap().iter() {
+                assert_eq!(zip_file.verify_entry(entry), Ok(data.len() as u64));
             }
         }
+        fs::unlink(&zip_path);
     }
 
-    fn store_read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
-        if self.is_eof {
-            return None;
-        }
-        let read_len = self.zip_entry.read_file_data(&mut self.zip_file.inner_file, self.read_total, output_buf);
-        self.read_total += read_len as u64;
-        if read_len > 0 {
-            Some(read_len)
-        } else {
-            self.is_eof = true;
-            None
+    #[test]
+    fn test_archive_preset() {
+        let apk = ArchivePreset::apk();
+        assert_eq!(apk.method_for("resources.arsc"), METHOD_STORE);
+        assert_eq!(apk.method_for("lib/arm64-v8a/libfoo.so"), METHOD_STORE);
+        assert_eq!(apk.method_for("classes.dex"), METHOD_DEFLATE);
+        assert_eq!(ArchivePreset::jar().method_for("lib/libfoo.so"), METHOD_DEFLATE);
+        assert!(glob_match("*.so".as_bytes(), "a/b.so".as_bytes()));
+        assert!(!glob_match("*.so".as_bytes(), "a/b.sox".as_bytes()));
+
+        let names = ~[~"classes.dex", ~"META-INF/MANIFEST.MF", ~"res/a.png", ~"META-INF/", ~"META-INF/CERT.SF"];
+        let ordered = apk.order(names, |name| name.clone());
+        assert_eq!(ordered, ~[~"META-INF/", ~"META-INF/MANIFEST.MF", ~"classes.dex", ~"res/a.png", ~"META-INF/CERT.SF"]);
+
+        let zip_path = Path::new("test_archive_preset.zip");
+        let data = bytes!("ABCDEFGH\r\n");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            apk.apply(&mut zip_writer);
+            for name in ordered.iter() {
+                zip_writer.add_reader(&mut MemReader::new(data.to_owned()), *name, 0, 0x1A4, apk.method_for(*name), 6);
+            }
+            zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "lib/x86/libfoo.so", 0, 0x1A4, apk.method_for("lib/x86/libfoo.so"), 6);
+            zip_writer.finish();
         }
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries[1].file_name_as_str(), ~"META-INF/MANIFEST.MF");
+        assert_eq!(entries[5].compression_method, METHOD_STORE);
+        assert_eq!(zip_file.check_alignment(4).unwrap().len(), 0);
+        fs::unlink(&zip_path);
     }
 
-    fn deflate_read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
-        let mut end_buf = [0u8, ..DATA_DESCRIPTOR_SIZE];
-        let mut end_len;
-        let mut inflator = self.inflator.get_mut_ref();
-        let status = inflator.decompress_read(
-            // Callback to read input data.
-            |in_buf| {
-                if self.is_eof {
-                    0
+    #[test]
+    fn test_mimetype_first() {
+        let zip_path = Path::new("test_mimetype_first.zip");
+        let mimetype = bytes!("application/epub+zip");
+        let data = bytes!("ABCDEFGH\r\n");
+        let preset = ArchivePreset::mimetype_first();
+        for &use_preset in [false, true].iter() {
+            {
+                let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+                zip_writer.set_ntfs_times(true);
+                let names = ~[~"META-INF/container.xml", ~"mimetype"];
+                let names = if use_preset {
+                    preset.apply(&mut zip_writer);
+                    preset.order(names, |name| name.clone())
                 } else {
-                    let read_len = self.zip_entry.read_file_data(&mut self.zip_file.inner_file, self.read_total, in_buf);
-                    self.read_total += read_len as u64;
-                    read_len    // Return number of bytes read, including 0 for EOF
+                    names
+                };
+                for name in names.iter() {
+                    let content = if name.as_slice() == "mimetype" { mimetype.to_owned() } else { data.to_owned() };
+                    let method = if use_preset { preset.method_for(*name) } else { METHOD_DEFLATE };
+                    zip_writer.add_reader(&mut MemReader::new(content), *name, 0, 0x1A4, method, 6);
                 }
-            },
-            output_buf);
-
-        match status {
-            Ok(0) => {
-                self.is_eof = true;
+                zip_writer.finish();
+            }
 
-                if self.zip_entry.has_data_descriptor() {
-                    // Move the rest of the bytes into end_buf, and read more into end_buf if not enough bytes for it.
-                    end_len = inflator.get_rest(end_buf);
-                    if end_len < DATA_DESCRIPTOR_SIZE {
-                        end_len += read_buf_upto(&mut self.zip_file.inner_file, end_buf, end_len, DATA_DESCRIPTOR_SIZE - end_len);
-                    }
-                    self.zip_entry.unpack_data_descriptor(end_buf);
-                    self.zip_entry.checkCrc();
-                }
-                None
-            },
-            Ok(output_len) => {
-                self.cmp_crc32 = update_crc(self.cmp_crc32, output_buf, 0, output_len);
-                return Some(output_len);
-            },
-            _ => {
-                // Clean up states before raising error.
-                self.is_eof = true;
-                io_error::cond.raise(IoError {
-                        kind: OtherIoError,
-                        desc: "Read failure in decompression",
-                        detail: Some(format!("Read failure in deflate_read().  status: {:?}", status))
-                    });
-                None
+            let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+            let problems = preset.check(&mut zip_file).unwrap();
+            if use_preset {
+                assert_eq!(problems.len(), 0);
+                // The mimetype is readable at the fixed offset 38, right after its 30-byte header and 8-byte name.
+                let buf = File::open_mode(&zip_path, Open, Read).unwrap().read_to_end();
+                assert_eq!(buf.slice(38, 38 + mimetype.len()), mimetype);
+            } else {
+                // Out of order, deflated, and with the NTFS times extra field.
+                assert_eq!(problems.len(), 3);
             }
         }
+        fs::unlink(&zip_path);
     }
 
+    #[test]
+    fn test_zip_stream_writer() {
+        let zip_path = Path::new("test_zip_stream_writer.zip");
+        let data = bytes!("ABCDEFGH\r\nABCDEFGH\r\nABCDEFGH\r\n");
+        let mut zip_writer = ZipStreamWriter::new(MemWriter::new());
+        zip_writer.set_spill_threshold(100);        // spill after a couple of entries
+        let mut options = EntryOptions::new();
+        for i in range(0u, 10) {
+            options.method = if i % 2 == 0 { METHOD_DEFLATE } else { METHOD_STORE };
+            let entry = zip_writer.add_reader(&mut MemReader::new(data.to_owned()), format!("{:u}.txt", i), &options).unwrap();
+            assert_eq!(entry.uncompressed_size as uint, data.len());
+        }
+        let spill_path = zip_writer.spill.get_ref().path.clone();
+        assert!(spill_path.exists());
+        assert!(zip_writer.finish().is_ok());
+        assert!(!spill_path.exists());
+        assert!(zip_writer.add_reader(&mut MemReader::new(data.to_owned()), "late.txt", &options).is_err());
+        let zip_data = zip_writer.inner().inner();
 
-}
+        // Readable through the central directory and as a stream.
+        File::create(&zip_path).write(zip_data);
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 10);
+        for entry in entries.iter() {
+            let mut writer = MemWriter::new();
+            zip_file.extract_to_writer(entry, &mut writer);
+            assert_eq!(writer.inner(), data.to_owned());
+        }
+        let mut zip_stream = ZipStreamReader::new(MemReader::new(zip_data.clone()));
+        let mut count = 0u;
+        loop {
+            match zip_stream.next_header() {
+                Some(header) => {
+                    let mut writer = MemWriter::new();
+                    assert!(zip_stream.read_data(&header, &mut writer).is_ok());
+                    assert_eq!(writer.inner(), data.to_owned());
+                    count += 1;
+                },
+                None => break
+            }
+        }
+        assert_eq!(count, 10);
 
-impl<'self> Reader for ZipReader<'self> {
+        // The temporary file is removed when the writer is dropped without finish().
+        let spill_path = {
+            let mut zip_writer = ZipStreamWriter::new(MemWriter::new());
+            zip_writer.set_spill_threshold(100);
+            for i in range(0u, 10) {
+                zip_writer.add_reader(&mut MemReader::new(data.to_owned()), format!("{:u}.txt", i), &options).unwrap();
+            }
+            zip_writer.spill.get_ref().path.clone()
+        };
+        assert!(!spill_path.exists());
 
-    /// Read the decompressed data from the file item inside the zip file.
-    fn read(&mut self, output_buf: &mut [u8]) -> Option<uint> {
-        match self.zip_entry.compression_method {
-            METHOD_STORE    => self.store_read(output_buf),
-            METHOD_DEFLATE  => self.deflate_read(output_buf),
-            _               => {
-                io_error::cond.raise(IoError {
-                        kind: OtherIoError,
-                        desc: "Unsupported compression method",
-                        detail: Some(format!("Unsupported compression method: {:u}", self.zip_entry.compression_method as uint))
-                    });
-                None
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_lenient_entry_count() {
+        let zip_path = Path::new("test_lenient_entry_count.zip");
+        let data = bytes!("ABCDEFGH");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            for i in range(0u, 3) {
+                zip_writer.add_reader(&mut MemReader::new(data.to_owned()), format!("{:u}.txt", i), 0, 0x1A4, METHOD_STORE, 0);
             }
+            zip_writer.finish();
         }
-    }
 
-    fn eof(&mut self) -> bool {
-        return self.is_eof;
+        // Understate the entry counts in the end record.
+        let mut zip_data = File::open_mode(&zip_path, Open, Read).unwrap().read_to_end();
+        let end = zip_data.len() - 22;
+        zip_data[end + 8] = 1;
+        zip_data[end + 10] = 1;
+        File::create(&zip_path).write(zip_data);
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        assert_eq!(zip_file.get_zip_entries().unwrap().len(), 1);
+        zip_file.set_lenient(true);
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].file_name_as_str(), ~"2.txt");
+        let mut writer = MemWriter::new();
+        zip_file.extract_to_writer(&entries[2], &mut writer);
+        assert_eq!(writer.inner(), data.to_owned());
+
+        fs::unlink(&zip_path);
     }
-}
 
+    #[test]
+    fn test_overstated_entry_count() {
+        let zip_path = Path::new("test_overstated_entry_count.zip");
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            for i in range(0u, 3) {
+                zip_writer.add_reader(&mut MemReader::new(bytes!("ABCDEFGH").to_owned()), format!("{:u}.txt", i), 0, 0x1A4, METHOD_STORE, 0);
+            }
+            zip_writer.finish();
+        }
 
+        // Overstate the entry counts in the end record so the strict parsing runs past the central directory.
+        let mut zip_data = File::open_mode(&zip_path, Open, Read).unwrap().read_to_end();
+        let end = zip_data.len() - 22;
+        zip_data[end + 8] = 4;
+        zip_data[end + 10] = 4;
+        File::create(&zip_path).write(zip_data);
 
-/// Pack a u16 into byte buffer in little-endian
-fn pack_u16_le(buf: &mut [u8], offset: uint, value: u16) -> uint {
-    buf[offset + 0] = (value >> 0) as u8;
-    buf[offset + 1] = (value >> 8) as u8;
-    offset + 2
-}
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        assert!(zip_file.get_zip_entries().is_err());
 
-/// Unpack a u16 from byte buffer in little-endian
-fn unpack_u16_le(buf: &[u8], offset: uint) -> u16 {
-    ( ((buf[offset + 0] as u16) & 0xFF)      ) |
-    ( ((buf[offset + 1] as u16) & 0xFF) << 8 )
-}
+        fs::unlink(&zip_path);
+    }
 
-/// Pack a u32 into byte buffer in little-endian
-fn pack_u32_le(buf: &mut [u8], offset: uint, value: u32) -> uint {
-    buf[offset + 0] = (value >> 0) as u8;
-    buf[offset + 1] = (value >> 8) as u8;
-    buf[offset + 2] = (value >> 16) as u8;
-    buf[offset + 3] = (value >> 24) as u8;
-    offset + 4
-}
+    #[test]
+    fn test_ntfs_times() {
+        let zip_path = Path::new("test_ntfs_times.zip");
+        let dest_dir = Path::new("test_ntfs_times");
+        let mtime = 1300000000123u64;
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.set_ntfs_times(true);
+            zip_writer.add_reader(&mut MemReader::new(bytes!("ABCDEFGH").to_owned()), "ntfs.txt", mtime, 0x1A4, METHOD_STORE, 0);
+            zip_writer.set_ntfs_times(false);
+            zip_writer.add_reader(&mut MemReader::new(bytes!("ABCDEFGH").to_owned()), "dos.txt", mtime, 0x1A4, METHOD_STORE, 0);
+            zip_writer.finish();
+        }
 
-/// Unpack a u32 from byte buffer in little-endian
-fn unpack_u32_le(buf: &[u8], offset: uint) -> u32 {
-    ( ((buf[offset + 0] as u32) & 0xFF)       ) |
-    ( ((buf[offset + 1] as u32) & 0xFF) << 8  ) |
-    ( ((buf[offset + 2] as u32) & 0xFF) << 16 ) |
-    ( ((buf[offset + 3] as u32) & 0xFF) << 24 )
-}
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries[0].ntfs_times(), Some((mtime, mtime, mtime)));
+        assert_eq!(entries[1].ntfs_times(), None);
 
-/// Pack a string into a zero-terminated buffer.
-fn to_strz(str_value: &str) -> ~[u8] {
-    let str_bytes = str_value.as_bytes();
-    let mut buf = vec::from_elem(str_bytes.len() + 1, 0u8);
-    vec::bytes::copy_memory(buf, str_bytes, str_bytes.len());
-    buf[buf.len() - 1] = 0;
-    return buf;
-}
+        let options = ExtractOptions::new();
+        assert!(zip_file.extract_all(&dest_dir, &options).is_ok());
+        assert_eq!(fs::stat(&dest_dir.join("ntfs.txt")).modified / 1000, mtime / 1000);
+        assert_eq!(fs::stat(&dest_dir.join("dos.txt")).modified / 1000, mtime / 1000);      // even seconds for MS-DOS time
 
-/// Read a zero-terminated str.  Read until encountering the terminating 0.
-fn read_strz<R: Reader>(reader: &mut R) -> ~str {
-    let mut buf = ~[];
-    loop {
-        match reader.read_byte() {
-            Some(0)     => break,
-            Some(ch)    => buf.push(ch),
-            None        => break
+        let (dos_time, dos_date) = super::to_dos_time(mtime / 1000);
+        assert_eq!(super::from_dos_time(dos_time, dos_date), mtime / 1000);
+        assert_eq!(super::from_ntfs_time(super::to_ntfs_time(mtime)), mtime);
+
+        fs::unlink(&dest_dir.join("ntfs.txt"));
+        fs::unlink(&dest_dir.join("dos.txt"));
+        fs::rmdir(&dest_dir);
+        fs::unlink(&zip_path);
+    }
+
+    #[test]
+    fn test_unix_owner() {
+        let zip_path = Path::new("test_unix_owner.zip");
+        let file_path = Path::new("test_unix_owner.txt");
+        File::create(&file_path).unwrap().write(bytes!("ABCDEFGH"));
+        let stat = fs::stat(&file_path);
+        {
+            let mut zip_writer = ZipWriter::new(File::open_mode(&zip_path, Truncate, Write).unwrap());
+            zip_writer.add_file(&file_path, "owned.txt", METHOD_STORE, 0);
+            zip_writer.set_unix_owner(false);
+            zip_writer.add_file(&file_path, "unowned.txt", METHOD_STORE, 0);
+            zip_writer.finish();
         }
+
+        let mut zip_file = ZipFile::open(File::open_mode(&zip_path, Open, Read).unwrap()).unwrap();
+        let entries = zip_file.get_zip_entries().unwrap();
+        assert_eq!(entries[0].unix_owner(), Some((stat.unstable.uid as u32, stat.unstable.gid as u32)));
+        assert_eq!(entries[1].unix_owner(), None);
+
+        // The uid and gid of other sizes, with another field in front.
+        let extra_field = [0x0D, 0x00, 0x00, 0x00, 0x75, 0x78, 0x06, 0x00, 0x01, 0x01, 0xE8, 0x02, 0x64, 0x00];
+        assert_eq!(super::find_unix_owner(extra_field), Some((232, 100)));
+
+        fs::unlink(&zip_path);
+        fs::unlink(&file_path);
     }
-    return str::from_utf8(buf);
-}
 
-fn read_upto<R: Reader>(reader: &mut R, len_to_read: uint) -> ~[u8] {
-    let mut buf = vec::from_elem(len_to_read, 0u8);
-    read_buf_upto(reader, buf, 0, len_to_read);
-    return buf;
-}
+    #[test]
+    fn test_junk_paths() {
+        let dest_dir = Path::new("out");
+        let mut options = ExtractOptions::new();
+        options.junk_paths = true;
+        assert_eq!(options.dest_path(&dest_dir, "a/b/c.txt"), Some(Path::new("out/c.txt")));
+        assert_eq!(options.dest_path(&dest_dir, "c.txt"), Some(Path::new("out/c.txt")));
+        assert_eq!(options.dest_path(&dest_dir, "a/b/"), Some(Path::new("out/b")));
+        assert_eq!(options.dest_path(&dest_dir, "a/.."), None);
+    }
 
-/// Read data upto the len_to_read, unless encounters EOF.
-fn read_buf_upto<R: Reader>(reader: &mut R, buf: &mut [u8], offset: uint, len_to_read: uint) -> uint {
-    let mut total_read = 0u;
-    while total_read < len_to_read {
-        let remaining_len = len_to_read - total_read;
-        let begin = offset + total_read;
-        let end   = offset + total_read + remaining_len;
-        let slice_buf = buf.mut_slice(begin, end);
-        match reader.read(slice_buf) {
-            Some(read_len) => total_read = total_read + read_len,
-            None => break
-        }
+    #[test]
+    fn test_entry_dest_path() {
+        let dest_dir = Path::new("out");
+        assert_eq!(entry_dest_path(&dest_dir, "a/b.txt"), Some(Path::new("out/a/b.txt")));
+        assert_eq!(entry_dest_path(&dest_dir, "/a/b.txt"), Some(Path::new("out/a/b.txt")));
+        assert_eq!(entry_dest_path(&dest_dir, "a/../../b.txt"), None);
+        assert_eq!(entry_dest_path(&dest_dir, "/"), None);
     }
-    return total_read;
-}
 
-fn update_crc(mut crc: u32, buf: &[u8], from: uint, to: uint) -> u32 {
-    crc = crc ^ 0xFFFFFFFF;     // Pre one's complement;
-    // TODO
-    return crc ^ 0xFFFFFFFF;    // Post one's complement
-}
+    #[test]
+    fn test_path_policy() {
+        let dest_dir = Path::new("out");
+        let mut policy = PathPolicy::new();
+        assert_eq!(policy.dest_path(&dest_dir, "./a//b.txt"), Some(Path::new("out/a/b.txt")));
+        assert_eq!(policy.dest_path(&dest_dir, "a/../b.txt"), None);
+        assert_eq!(policy.dest_path(&dest_dir, "C:\\a\\b.txt"), Some(Path::new("out/C:\\a\\b.txt")));
 
+        policy.allow_absolute = false;
+        assert_eq!(policy.dest_path(&dest_dir, "/a/b.txt"), None);
 
-#[cfg(test)]
-mod tests {
+        policy.strip_drive = true;
+        assert_eq!(policy.dest_path(&dest_dir, "C:\\a\\b.txt"), None);         // absolute after the drive
+        assert_eq!(policy.dest_path(&dest_dir, "C:a\\b.txt"), Some(Path::new("out/a/b.txt")));
 
+        policy.resolve_dot_dot = true;
+        assert_eq!(policy.dest_path(&dest_dir, "a/../b.txt"), Some(Path::new("out/b.txt")));
+        assert_eq!(policy.dest_path(&dest_dir, "a/../../b.txt"), None);
+
+        policy.jail = Some(~"site/");
+        assert_eq!(policy.dest_path(&dest_dir, "site/a/b.txt"), Some(Path::new("out/site/a/b.txt")));
+        assert_eq!(policy.dest_path(&dest_dir, "site/../other/b.txt"), None);
+        assert_eq!(policy.dest_path(&dest_dir, "site"), None);
+        assert_eq!(policy.dest_path(&dest_dir, "other/b.txt"), None);
+    }
 
 }
 
@@ -0,0 +1,402 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+// You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Software distributed under the License is distributed on an "AS IS" basis,
+// WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+// the specific language governing rights and limitations under the License.
+//
+// The Original Code is: zlib.rs
+// The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+// Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+
+
+/*!
+
+The zlib module is the system zlib backend of the deflate module, built with --cfg zlib.
+It has the same functions as the miniz API used by deflate.rs, implemented on the
+zlib z_stream, so Deflator and Inflator run on either library unchanged.
+
+The miniz flags and status codes are mapped to zlib's.  The compression level is
+derived from the number of probes in the tdefl flags, and the strategy from the
+RLE, filtered and static-only block flags.  The greedy and deterministic flags have
+no zlib equivalent and are ignored; zlib's output is deterministic for a given level.
+
+*/
+
+
+use std::{cast, mem, ptr, str, vec};
+use std::libc;
+use std::libc::{c_void, size_t, c_int, c_uint, c_ulong, c_char};
+
+
+// The miniz flags, as defined in deflate.rs.
+static TDEFL_MAX_PROBES_MASK : c_int                = 0xFFF;
+static TDEFL_WRITE_ZLIB_HEADER : c_int              = 0x01000;
+static TDEFL_RLE_MATCHES : c_int                    = 0x10000;
+static TDEFL_FILTER_MATCHES : c_int                 = 0x20000;
+static TDEFL_FORCE_ALL_STATIC_BLOCKS : c_int        = 0x40000;
+static TDEFL_FORCE_ALL_RAW_BLOCKS : c_int           = 0x80000;
+static TDEFL_NUM_PROBES : [c_int, ..10] = [ 0, 2, 8, 32, 128, 256, 512, 1024, 2048, 4095 ];
+
+static TDEFL_SYNC_FLUSH : c_int = 2;
+static TDEFL_FULL_FLUSH : c_int = 3;
+static TDEFL_FINISH : c_int     = 4;
+
+static TDEFL_STATUS_BAD_PARAM : c_int   = -2;
+static TDEFL_STATUS_OKAY : c_int        = 0;
+static TDEFL_STATUS_DONE : c_int        = 1;
+
+static TINFL_FLAG_PARSE_ZLIB_HEADER : c_uint    = 1;
+static TINFL_FLAG_HAS_MORE_INPUT : c_uint       = 2;
+
+static TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS : c_int = -4;
+static TINFL_STATUS_BAD_PARAM : c_int                   = -3;
+static TINFL_STATUS_ADLER32_MISMATCH : c_int            = -2;
+static TINFL_STATUS_FAILED : c_int                      = -1;
+static TINFL_STATUS_DONE : c_int                        = 0;
+static TINFL_STATUS_NEEDS_MORE_INPUT : c_int            = 1;
+static TINFL_STATUS_HAS_MORE_OUTPUT : c_int             = 2;
+
+// The zlib constants.
+static Z_NO_FLUSH : c_int       = 0;
+static Z_SYNC_FLUSH : c_int     = 2;
+static Z_FULL_FLUSH : c_int     = 3;
+static Z_FINISH : c_int         = 4;
+static Z_OK : c_int             = 0;
+static Z_STREAM_END : c_int     = 1;
+static Z_DATA_ERROR : c_int     = -3;
+static Z_BUF_ERROR : c_int      = -5;
+static Z_DEFLATED : c_int       = 8;
+static Z_DEFAULT_STRATEGY : c_int = 0;
+static Z_FILTERED : c_int       = 1;
+static Z_RLE : c_int            = 3;
+static Z_FIXED : c_int          = 4;
+static MAX_WBITS : c_int        = 15;
+static DEF_MEM_LEVEL : c_int    = 8;
+
+// The heap buffers grow by this much when the output doesn't fit.
+static HEAP_BUF_INCREMENT : uint = 64 * 1024;
+
+
+// The z_stream struct of zlib.h.
+struct ZStream {
+    next_in:    *u8,
+    avail_in:   c_uint,
+    total_in:   c_ulong,
+    next_out:   *mut u8,
+    avail_out:  c_uint,
+    total_out:  c_ulong,
+    msg:        *c_char,
+    state:      *c_void,
+    zalloc:     *c_void,
+    zfree:      *c_void,
+    opaque:     *c_void,
+    data_type:  c_int,
+    adler:      c_ulong,
+    reserved:   c_ulong,
+}
+
+// The compressor and decompressor handles passed out as *c_void.
+struct ZState {
+    stream:         ZStream,
+    is_deflate:     bool,
+    initialized:    bool,
+    done:           bool,
+}
+
+mod zlibrt {
+    use std::libc::{c_int, c_char};
+    use super::ZStream;
+
+    #[link_name = "z"]
+    extern {
+        pub fn zlibVersion() -> *c_char;
+        pub fn deflateInit2_(strm: *mut ZStream, level: c_int, method: c_int, window_bits: c_int,
+                             mem_level: c_int, strategy: c_int, version: *c_char, stream_size: c_int) -> c_int;
+        pub fn deflate(strm: *mut ZStream, flush: c_int) -> c_int;
+        pub fn deflateEnd(strm: *mut ZStream) -> c_int;
+        pub fn inflateInit2_(strm: *mut ZStream, window_bits: c_int, version: *c_char, stream_size: c_int) -> c_int;
+        pub fn inflate(strm: *mut ZStream, flush: c_int) -> c_int;
+        pub fn inflateEnd(strm: *mut ZStream) -> c_int;
+    }
+}
+
+
+fn new_state(is_deflate: bool) -> *c_void {
+    let state = ~ZState {
+        stream: ZStream {
+            next_in:    ptr::null(),
+            avail_in:   0,
+            total_in:   0,
+            next_out:   ptr::mut_null(),
+            avail_out:  0,
+            total_out:  0,
+            msg:        ptr::null(),
+            state:      ptr::null(),
+            zalloc:     ptr::null(),
+            zfree:      ptr::null(),
+            opaque:     ptr::null(),
+            data_type:  0,
+            adler:      0,
+            reserved:   0,
+        },
+        is_deflate:     is_deflate,
+        initialized:    false,
+        done:           false,
+    };
+    unsafe { cast::transmute(state) }
+}
+
+unsafe fn end_state(state: &mut ZState) {
+    if state.initialized {
+        if state.is_deflate {
+            zlibrt::deflateEnd(&mut state.stream);
+        } else {
+            zlibrt::inflateEnd(&mut state.stream);
+        }
+        state.initialized = false;
+    }
+}
+
+unsafe fn free_state(handle: *c_void) {
+    if handle != ptr::null() {
+        let mut state: ~ZState = cast::transmute(handle);
+        end_state(&mut *state);
+    }
+}
+
+// The zlib level with the number of probes of the tdefl flags, rounding up.
+fn flags_to_level(flags: c_int) -> c_int {
+    if flags & TDEFL_FORCE_ALL_RAW_BLOCKS != 0 {
+        return 0;
+    }
+    let probes = flags & TDEFL_MAX_PROBES_MASK;
+    match TDEFL_NUM_PROBES.iter().position(|&level_probes| level_probes >= probes) {
+        Some(level) => level as c_int,
+        None        => 9
+    }
+}
+
+// The zlib strategy with the strategy flags of the tdefl flags.
+fn flags_to_strategy(flags: c_int) -> c_int {
+    if flags & TDEFL_RLE_MATCHES != 0 {
+        Z_RLE
+    } else if flags & TDEFL_FILTER_MATCHES != 0 {
+        Z_FILTERED
+    } else if flags & TDEFL_FORCE_ALL_STATIC_BLOCKS != 0 {
+        Z_FIXED
+    } else {
+        Z_DEFAULT_STRATEGY
+    }
+}
+
+/// Allocate the compressor handle, to be initialized by tdefl_init().
+pub unsafe fn tdefl_compressor_alloc() -> *c_void {
+    new_state(true)
+}
+
+/// Free the compressor handle.
+pub unsafe fn tdefl_compressor_free(tdefl_compressor: *c_void) {
+    free_state(tdefl_compressor);
+}
+
+/// Initialize or re-initialize the compressor with the tdefl flags.  The put_buf callback is not supported.
+pub unsafe fn tdefl_init(tdefl_compressor: *c_void, pPut_buf_func: *c_void, _pPut_buf_user: *c_void, compress_flags: c_int) -> c_int {
+    if pPut_buf_func != ptr::null() {
+        return TDEFL_STATUS_BAD_PARAM;
+    }
+    let state: &mut ZState = cast::transmute(tdefl_compressor);
+    end_state(state);
+    let window_bits = if compress_flags & TDEFL_WRITE_ZLIB_HEADER != 0 { MAX_WBITS } else { -MAX_WBITS };
+    let status = zlibrt::deflateInit2_(&mut state.stream, flags_to_level(compress_flags), Z_DEFLATED, window_bits,
+                                       DEF_MEM_LEVEL, flags_to_strategy(compress_flags), zlibrt::zlibVersion(), mem::size_of::<ZStream>() as c_int);
+    state.initialized = status == Z_OK;
+    state.done = false;
+    if state.initialized { TDEFL_STATUS_OKAY } else { TDEFL_STATUS_BAD_PARAM }
+}
+
+/// Compress the input buffer into the output buffer with the tdefl flush mode.
+/// The sizes are updated to the bytes consumed and the bytes written.
+pub unsafe fn tdefl_compress(tdefl_compressor: *c_void,
+                             pIn_buf: *c_void,
+                             pIn_buf_size: *mut size_t,
+                             pOut_buf: *c_void,
+                             pOut_buf_size: *mut size_t,
+                             tdefl_flush: c_int) -> c_int {
+    let state: &mut ZState = cast::transmute(tdefl_compressor);
+    if !state.initialized {
+        return TDEFL_STATUS_BAD_PARAM;
+    }
+    if state.done {
+        *pIn_buf_size = 0;
+        *pOut_buf_size = 0;
+        return TDEFL_STATUS_DONE;
+    }
+    let flush = match tdefl_flush {
+        TDEFL_SYNC_FLUSH    => Z_SYNC_FLUSH,
+        TDEFL_FULL_FLUSH    => Z_FULL_FLUSH,
+        TDEFL_FINISH        => Z_FINISH,
+        _                   => Z_NO_FLUSH
+    };
+    state.stream.next_in = pIn_buf as *u8;
+    state.stream.avail_in = *pIn_buf_size as c_uint;
+    state.stream.next_out = pOut_buf as *mut u8;
+    state.stream.avail_out = *pOut_buf_size as c_uint;
+    let status = zlibrt::deflate(&mut state.stream, flush);
+    *pIn_buf_size -= state.stream.avail_in as size_t;
+    *pOut_buf_size -= state.stream.avail_out as size_t;
+    match status {
+        Z_STREAM_END        => {
+            state.done = true;
+            TDEFL_STATUS_DONE
+        },
+        Z_OK | Z_BUF_ERROR  => TDEFL_STATUS_OKAY,       // Z_BUF_ERROR is no progress possible, e.g. a completed flush.
+        _                   => TDEFL_STATUS_BAD_PARAM
+    }
+}
+
+/// Allocate the decompressor handle.  It's initialized on the first tinfl_decompress() call,
+/// where the flags tell whether to parse the zlib header.
+pub unsafe fn tinfl_decompressor_alloc() -> *c_void {
+    new_state(false)
+}
+
+/// Free the decompressor handle.
+pub unsafe fn tinfl_decompressor_free(tinfl_decompressor: *c_void) {
+    free_state(tinfl_decompressor);
+}
+
+/// Decompress the input buffer into the output buffer at pOut_buf_next.  zlib keeps its own window,
+/// so the wrapping of the output buffer doesn't matter and pOut_buf_start is not used.
+/// The sizes are updated to the bytes consumed and the bytes written.
+pub unsafe fn tinfl_decompress(tinfl_decompressor: *c_void,
+                               pIn_buf_next: *c_void,
+                               pIn_buf_size: *mut size_t,
+                               _pOut_buf_start: *c_void,
+                               pOut_buf_next: *c_void,
+                               pOut_buf_size: *mut size_t,
+                               decompress_flags: c_uint) -> c_int {
+    let state: &mut ZState = cast::transmute(tinfl_decompressor);
+    if state.done {
+        *pIn_buf_size = 0;
+        *pOut_buf_size = 0;
+        return TINFL_STATUS_DONE;
+    }
+    if !state.initialized {
+        let window_bits = if decompress_flags & TINFL_FLAG_PARSE_ZLIB_HEADER != 0 { MAX_WBITS } else { -MAX_WBITS };
+        if zlibrt::inflateInit2_(&mut state.stream, window_bits, zlibrt::zlibVersion(), mem::size_of::<ZStream>() as c_int) != Z_OK {
+            return TINFL_STATUS_BAD_PARAM;
+        }
+        state.initialized = true;
+    }
+    state.stream.next_in = pIn_buf_next as *u8;
+    state.stream.avail_in = *pIn_buf_size as c_uint;
+    state.stream.next_out = pOut_buf_next as *mut u8;
+    state.stream.avail_out = *pOut_buf_size as c_uint;
+    let status = zlibrt::inflate(&mut state.stream, Z_NO_FLUSH);
+    let avail_in = state.stream.avail_in;
+    let avail_out = state.stream.avail_out;
+    *pIn_buf_size -= avail_in as size_t;
+    *pOut_buf_size -= avail_out as size_t;
+    match status {
+        Z_STREAM_END => {
+            state.done = true;
+            TINFL_STATUS_DONE
+        },
+        Z_OK | Z_BUF_ERROR => {
+            if avail_out == 0 {
+                TINFL_STATUS_HAS_MORE_OUTPUT
+            } else if decompress_flags & TINFL_FLAG_HAS_MORE_INPUT != 0 {
+                TINFL_STATUS_NEEDS_MORE_INPUT
+            } else {
+                TINFL_STATUS_FAILED_CANNOT_MAKE_PROGRESS
+            }
+        },
+        Z_DATA_ERROR if state.stream.msg != ptr::null() && is_adler_error(state.stream.msg) =>
+            TINFL_STATUS_ADLER32_MISMATCH,
+        _ => TINFL_STATUS_FAILED
+    }
+}
+
+// zlib reports the checksum mismatch as a data error with this message.
+unsafe fn is_adler_error(msg: *c_char) -> bool {
+    str::raw::from_c_str(msg) == ~"incorrect data check"
+}
+
+// Copy the buffer into a malloc'ed block to be freed by mz_free().
+unsafe fn to_heap(buf: &[u8], pout_len: *mut size_t) -> *c_void {
+    let heap_buf = libc::malloc(if buf.len() > 0 { buf.len() } else { 1 } as size_t);
+    if heap_buf != ptr::null() {
+        buf.as_imm_buf(|buf_ptr, len| ptr::copy_memory(heap_buf as *mut u8, buf_ptr, len));
+        *pout_len = buf.len() as size_t;
+    }
+    heap_buf
+}
+
+/// Compress the whole source buffer into a malloc'ed buffer.  Return null on failure.
+pub unsafe fn tdefl_compress_mem_to_heap(psrc_buf: *c_void, src_buf_len: size_t, pout_len: *mut size_t, flags: c_int) -> *c_void {
+    let compressor = tdefl_compressor_alloc();
+    let mut out_buf = vec::from_elem(src_buf_len as uint + src_buf_len as uint / 1000 + HEAP_BUF_INCREMENT, 0u8);
+    let mut out_len = 0u;
+    let mut in_offset = 0u;
+    let mut result = ptr::null();
+    if tdefl_init(compressor, ptr::null(), ptr::null(), flags) == TDEFL_STATUS_OKAY {
+        loop {
+            let mut in_size = src_buf_len - in_offset as size_t;
+            let mut out_size = (out_buf.len() - out_len) as size_t;
+            let status = out_buf.as_mut_buf(|out_ptr, _| {
+                    tdefl_compress(compressor, (psrc_buf as *u8).offset(in_offset as int) as *c_void, &mut in_size,
+                                   out_ptr.offset(out_len as int) as *c_void, &mut out_size, TDEFL_FINISH)
+                });
+            in_offset += in_size as uint;
+            out_len += out_size as uint;
+            match status {
+                TDEFL_STATUS_DONE => {
+                    result = to_heap(out_buf.slice(0, out_len), pout_len);
+                    break;
+                },
+                TDEFL_STATUS_OKAY => out_buf.grow(HEAP_BUF_INCREMENT, &0u8),
+                _ => break
+            }
+        }
+    }
+    tdefl_compressor_free(compressor);
+    result
+}
+
+/// Decompress the whole source buffer into a malloc'ed buffer.  Return null on corrupted data.
+pub unsafe fn tinfl_decompress_mem_to_heap(psrc_buf: *c_void, src_buf_len: size_t, pout_len: *mut size_t, flags: c_int) -> *c_void {
+    let decompressor = tinfl_decompressor_alloc();
+    let mut out_buf = vec::from_elem(src_buf_len as uint * 2 + HEAP_BUF_INCREMENT, 0u8);
+    let mut out_len = 0u;
+    let mut in_offset = 0u;
+    let mut result = ptr::null();
+    loop {
+        let mut in_size = src_buf_len - in_offset as size_t;
+        let mut out_size = (out_buf.len() - out_len) as size_t;
+        let status = out_buf.as_mut_buf(|out_ptr, _| {
+                tinfl_decompress(decompressor, (psrc_buf as *u8).offset(in_offset as int) as *c_void, &mut in_size,
+                                 out_ptr as *c_void, out_ptr.offset(out_len as int) as *c_void, &mut out_size,
+                                 (flags as c_uint) & TINFL_FLAG_PARSE_ZLIB_HEADER)
+            });
+        in_offset += in_size as uint;
+        out_len += out_size as uint;
+        match status {
+            TINFL_STATUS_DONE => {
+                result = to_heap(out_buf.slice(0, out_len), pout_len);
+                break;
+            },
+            TINFL_STATUS_HAS_MORE_OUTPUT => out_buf.grow(HEAP_BUF_INCREMENT, &0u8),
+            _ => break
+        }
+    }
+    tinfl_decompressor_free(decompressor);
+    result
+}
+
+/// Free a buffer returned by the mem_to_heap functions.
+pub unsafe fn mz_free(pBuf: *c_void) -> *c_void {
+    libc::free(pBuf);
+    ptr::null()
+}
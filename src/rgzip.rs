@@ -19,32 +19,46 @@ extern mod extra;
 // Uncomment either one of the following sections to link to one or the other library.
 
 // Uncomment these to use the local modules in the local rustyzip.lib.
-// extern mod rustyzip;
-// use rustyzip::gzip;
-// use rustyzip::gzip::{GZip, GZipReader, GZipWriter};
+extern mod rustyzip;
+use rustyzip::gzip;
+use rustyzip::gzip::{GZip, GZipReader, GZipWriter};
+use rustyzip::trace;
+use rustyzip::ioutil;
+use rustyzip::ioutil::DoubleBufferedReader;
 
 // Uncomment these to use the modules in the system's libextra.
-use extra::gzip;
-use extra::gzip::{GZip, GZipReader, GZipWriter};
+// use extra::gzip;
+// use extra::gzip::{GZip, GZipReader, GZipWriter};
 
 
 
 use std::os;
+use std::rt;
 use std::num;
 use std::vec;
 use std::result::{Result, Ok, Err};
 use std::to_str::ToStr;
 use std::path::Path;
 use std::io;
-use std::io::{Reader, Writer, Open, Read, Truncate, Write, io_error};
+use std::io::{Reader, Writer, Open, Read, Truncate, Write, SeekSet, io_error};
+use std::io::stdio;
 use std::io::fs;
 use std::io::fs::File;
 use std::io::{IoError, OtherIoError};
 use extra::getopts::{optflag, optopt, getopts};
+use extra::time;
 
 
 
 static VERSION_STR : &'static str = "0.9";
+static DEFAULT_SUFFIX : &'static str = ".gz";
+// Exit status, same as gzip.
+static EXIT_OK : int = 0;
+static EXIT_ERROR : int = 1;
+static EXIT_WARNING : int = 2;
+
+static PROGRESS_INTERVAL_NS : u64 = 500000000;     // update the progress display every 0.5 second
+static BENCH_LEVELS : [uint, ..3] = [1, 6, 9];       // the compression levels compared by --bench
 
 
 macro_rules! raise_io(
@@ -62,21 +76,39 @@ macro_rules! raise_io(
     )
 )
 
+/// Messages reported from processing a file, by severity.
+enum Report {
+    /// Normal output, printed to stdout.
+    Info(~str),
+    /// Printed to stderr unless -q is given.  Set the exit status to 2 if there's no error.
+    Warning(~str),
+    /// Printed to stderr.  Set the exit status to 1.
+    Error(~str),
+}
+
 enum Cmd {
-    HELP, VERSION, COMPRESS, DECOMPRESS, LIST
+    HELP, VERSION, COMPRESS, DECOMPRESS, LIST, TEST, BENCH
 }
 
 struct Options {
     cmd:            Cmd,
     stdout:         bool,
     force:          bool,
+    keep:           bool,
     no_name:        bool,
     name:           bool,
     quiet:          bool,
-    verbose:        bool,
+    verbose:        uint,
+    progress:       bool,
     compress_level: uint,
     use_stream:     bool,
     size_factor:    uint,
+    num_tasks:      uint,
+    rsyncable:      bool,
+    pipeline:       bool,
+    double_buffer:  bool,
+    salvage:        bool,
+    suffix:         ~str,
     files:          ~[~str],
 }
 
@@ -88,13 +120,21 @@ impl Options {
             cmd: COMPRESS,          // default command is to compress
             stdout: false,
             force: false,
+            keep: false,
             no_name: false,
             name: false,
             quiet: false,
-            verbose: false,
+            verbose: 0,
+            progress: false,
             compress_level: gzip::DEFAULT_COMPRESS_LEVEL,
             use_stream: true,
             size_factor: gzip::DEFAULT_SIZE_FACTOR,
+            num_tasks: rt::default_sched_threads(),
+            rsyncable: false,
+            pipeline: false,
+            double_buffer: false,
+            salvage: false,
+            suffix: DEFAULT_SUFFIX.to_owned(),
             files: ~[],
         };
         let opts = ~[
@@ -106,10 +146,14 @@ impl Options {
                      optflag("decompress"),
                      optflag("l"),
                      optflag("list"),
+                     optflag("t"),
+                     optflag("test"),
                      optflag("c"),
                      optflag("stdout"),
                      optflag("f"),
                      optflag("force"),
+                     optflag("k"),
+                     optflag("keep"),
                      optflag("n"),
                      optflag("no-name"),
                      optflag("N"),
@@ -118,6 +162,7 @@ impl Options {
                      optflag("quiet"),
                      optflag("v"),
                      optflag("verbose"),
+                     optflag("progress"),
                      optflag("0"),
                      optflag("1"),
                      optflag("2"),
@@ -131,6 +176,15 @@ impl Options {
                      optflag("Stream"),
                      optopt("b"),
                      optopt("bufsize"),
+                     optopt("p"),
+                     optopt("threads"),
+                     optflag("rsyncable"),
+                     optflag("pipeline"),
+                     optflag("double-buffer"),
+                     optflag("salvage"),
+                     optflag("bench"),
+                     optopt("S"),
+                     optopt("suffix"),
                      
                      ];
 
@@ -140,13 +194,18 @@ impl Options {
                 options.cmd = if matches.opt_present("V") || matches.opt_present("version") { VERSION } else { options.cmd };
                 options.cmd = if matches.opt_present("d") || matches.opt_present("decompress") { DECOMPRESS } else { options.cmd };
                 options.cmd = if matches.opt_present("l") || matches.opt_present("list") { LIST } else { options.cmd };
+                options.cmd = if matches.opt_present("t") || matches.opt_present("test") { TEST } else { options.cmd };
+                options.cmd = if matches.opt_present("bench") { BENCH } else { options.cmd };
 
                 options.stdout = matches.opt_present("c") || matches.opt_present("stdout");
                 options.force = matches.opt_present("f") || matches.opt_present("force");
+                options.keep = matches.opt_present("k") || matches.opt_present("keep");
                 options.no_name = matches.opt_present("n") || matches.opt_present("no-name");
                 options.name = matches.opt_present("N") || matches.opt_present("name");
                 options.quiet = matches.opt_present("q") || matches.opt_present("quiet");
-                options.verbose = matches.opt_present("v") || matches.opt_present("verbose");
+                // -v reports each file with its ratio and timing.  -vv adds the library trace.
+                options.verbose = matches.opt_count("v") + matches.opt_count("verbose");
+                options.progress = options.verbose > 0 || matches.opt_present("progress");
                 for level in range(0u, 10u) {
                     let slevel = format!("{:u}", level);
                     options.compress_level = if matches.opt_present(slevel) { level } else { options.compress_level };
@@ -155,6 +214,19 @@ impl Options {
                 let mut size_factor = if matches.opt_present("bufsize") { maybe_to_num(matches.opt_str("bufsize"), gzip::DEFAULT_SIZE_FACTOR) } else { gzip::DEFAULT_SIZE_FACTOR };
                 size_factor = if matches.opt_present("b")               { maybe_to_num(matches.opt_str("b"), size_factor) } else { size_factor };
                 options.size_factor = num::max(gzip::MIN_SIZE_FACTOR, size_factor);
+                let mut num_tasks = if matches.opt_present("threads") { maybe_to_num(matches.opt_str("threads"), options.num_tasks) } else { options.num_tasks };
+                num_tasks = if matches.opt_present("p")               { maybe_to_num(matches.opt_str("p"), num_tasks) } else { num_tasks };
+                options.num_tasks = num::max(1u, num_tasks);
+                options.rsyncable = matches.opt_present("rsyncable");
+                options.pipeline = matches.opt_present("pipeline");
+                options.double_buffer = matches.opt_present("double-buffer");
+                options.salvage = matches.opt_present("salvage");
+                let mut suffix = if matches.opt_present("suffix") { matches.opt_str("suffix").unwrap_or(options.suffix.clone()) } else { options.suffix.clone() };
+                suffix = if matches.opt_present("S")               { matches.opt_str("S").unwrap_or(suffix.clone()) } else { suffix };
+                if suffix.len() == 0 {
+                    return Err(~"Invalid suffix.  The suffix cannot be empty.");
+                }
+                options.suffix = suffix;
                 options.files = matches.free;
 
                 Ok(options)
@@ -190,7 +262,7 @@ fn get_program(args: &~[~str]) -> ~str {
 }
 
 fn print_usage(args: &~[~str]) {
-    println(format!("Usage: {:s}  -h --help -d --decompress -c --stdout FILE ...", get_program(args)));
+    println(format!("Usage: {:s}  -h --help -d --decompress -l --list -t --test -c --stdout -k --keep -q --quiet -v --verbose (-vv to trace) -S --suffix SUF --progress -p --threads N --rsyncable --pipeline --double-buffer --salvage --bench FILE ...", get_program(args)));
 }
 
 fn print_version(args: &~[~str]) {
@@ -205,15 +277,102 @@ fn get_file_name(filepath: &Path) -> ~str {
     }
 }
 
+/// Reader decorator to report the progress of reading the inner reader.
+/// The progress is written to stderr so that it doesn't mix with the data written to stdout.
+struct ProgressReader<R> {
+    priv inner_reader:  R,
+    priv enabled:       bool,
+    priv total_size:    u64,
+    priv read_total:    u64,
+    priv start_ns:      u64,
+    priv last_ns:       u64,
+}
 
-fn open_compressed_writer(options: &Options, file: &str) -> Result<File, ~str> {
-    if options.stdout {
-        //let writer = stdio::stdout();
-        //return writer;
-        fail!("std::io::stdout is not implemented yet");
+impl<R: Reader> ProgressReader<R> {
+
+    fn new(inner_reader: R, total_size: u64, enabled: bool) -> ProgressReader<R> {
+        let now = time::precise_time_ns();
+        ProgressReader {
+            inner_reader:   inner_reader,
+            enabled:        enabled,
+            total_size:     total_size,
+            read_total:     0u64,
+            start_ns:       now,
+            last_ns:        now,
+        }
     }
 
-    let gz_filepath = file + ".gz";
+    /// Print the percentage done, the throughput, and the estimated time remaining.
+    fn report(&mut self, done: bool) {
+        let now = time::precise_time_ns();
+        if !self.enabled || (!done && now - self.last_ns < PROGRESS_INTERVAL_NS) {
+            return;
+        }
+        self.last_ns = now;
+
+        let elapsed_secs = num::max(now - self.start_ns, 1u64) as f64 / 1000000000f64;
+        let mb_per_sec = self.read_total as f64 / 1048576f64 / elapsed_secs;
+        let percent = if self.total_size > 0 { self.read_total as f64 * 100f64 / self.total_size as f64 } else { 100f64 };
+        let eta_secs = if self.read_total > 0 && self.total_size > self.read_total {
+            ((self.total_size - self.read_total) as f64 * elapsed_secs / self.read_total as f64) as uint
+        } else {
+            0u
+        };
+        let line = format!("\r{:5.1f}%  {:8.2f} MB/s  ETA {:u}:{:02u}:{:02u} {:s}", 
+                           percent, mb_per_sec, eta_secs / 3600, (eta_secs / 60) % 60, eta_secs % 60,
+                           if done { "\n" } else { "" });
+        stdio::stderr().write(line.as_bytes());
+        if done {
+            self.enabled = false;       // Report the completion only once, in case of repeated reads at EOF.
+        }
+    }
+}
+
+impl<R: Reader> Reader for ProgressReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        let result = self.inner_reader.read(buf);
+        match result {
+            Some(n) => {
+                self.read_total += n as u64;
+                self.report(false);
+            },
+            None => 
+                self.report(true)
+        }
+        result
+    }
+
+    fn eof(&mut self) -> bool {
+        self.inner_reader.eof()
+    }
+}
+
+/// Get the file size for progress reporting; 0 if it's not available.
+fn get_file_size(filepath: &Path) -> u64 {
+    match io::result(|| fs::stat(filepath)) {
+        Ok(stat) => stat.size,
+        Err(_)   => 0u64
+    }
+}
+
+/// Check whether the file name ends with the suffix, ignoring case.  The suffix alone is not a valid file name.
+fn has_suffix(file: &str, suffix: &str) -> bool {
+    file.len() > suffix.len() && file.to_ascii_lower().ends_with(suffix.to_ascii_lower())
+}
+
+/// Derive the decompressed file name by removing the suffix.  Like gzip, the .tgz and .taz short forms become .tar.
+fn get_decompressed_name(file: &str, suffix: &str) -> ~str {
+    let stem = file.slice_to(file.len() - suffix.len());
+    match suffix.to_ascii_lower().as_slice() {
+        ".tgz" | ".taz" => stem + ".tar",
+        _               => stem.to_owned()
+    }
+}
+
+
+fn open_compressed_writer(options: &Options, file: &str) -> Result<File, ~str> {
+    let gz_filepath = file + options.suffix;
     let out_filepath = Path::new(gz_filepath.clone());
     if out_filepath.exists() && !options.force {
         return Err(format!("File {:s} already exists.  Use -f to overwrite it.", gz_filepath));
@@ -225,63 +384,159 @@ fn open_compressed_writer(options: &Options, file: &str) -> Result<File, ~str> {
     }
 }
 
-fn compress_stream_loop<R: Reader, W: Writer>(mut stream_reader: R, mut stream_writer: W, filepath: &Path, options: &Options) {
+// Compress the reader as one gzip member into the writer.  Return the CRC of the uncompressed data.
+// With --double-buffer, the input is read ahead on another task, except in the pipeline which has its own reading task.
+fn compress_to<R: Reader + Send, W: Writer>(stream_reader: R, stream_writer: W, filepath: &Path, options: &Options) -> u32 {
+    if options.double_buffer && !options.pipeline {
+        let stream_reader = DoubleBufferedReader::new(stream_reader, gzip::calc_buf_size(options.size_factor));
+        compress_reader_to(stream_reader, stream_writer, filepath, options)
+    } else {
+        compress_reader_to(stream_reader, stream_writer, filepath, options)
+    }
+}
+
+fn compress_reader_to<R: Reader + Send, W: Writer>(stream_reader: R, stream_writer: W, filepath: &Path, options: &Options) -> u32 {
+    if options.use_stream || options.rsyncable {
+        compress_stream_loop(stream_reader, stream_writer, filepath, options)
+    } else {
+        compress_write_loop(stream_reader, stream_writer, filepath, options)
+    }
+}
+
+fn compress_stream_loop<R: Reader + Send, W: Writer>(mut stream_reader: R, mut stream_writer: W, filepath: &Path, options: &Options) -> u32 {
     let stat = fs::stat(filepath);
     let file_name = if options.no_name { ~"" } else { get_file_name(filepath) };
     let mtime = if options.no_name { 0u32 } else { (stat.modified / 1000) as u32 };
     let file_size = stat.size as u32;
     let mut gzip = GZip::compress_init(&mut stream_writer, file_name.as_bytes(), mtime, file_size);
-    gzip.compress_stream(&mut stream_reader, &mut stream_writer, options.compress_level, options.size_factor);
+    if options.rsyncable {
+        // Rsyncable chunks are found sequentially; it takes precedence over the parallel compression.
+        gzip.compress_rsyncable(&mut stream_reader, &mut stream_writer, options.compress_level, options.size_factor);
+    } else if options.pipeline {
+        // The reading, compression and writing overlap on separate tasks, without splitting the blocks.
+        gzip.compress_pipelined(stream_reader, &mut stream_writer, options.compress_level, options.size_factor);
+    } else if options.num_tasks > 1 {
+        gzip.compress_parallel(&mut stream_reader, &mut stream_writer, options.compress_level, options.size_factor, options.num_tasks);
+    } else {
+        gzip.compress_stream(&mut stream_reader, &mut stream_writer, options.compress_level, options.size_factor);
+    }
+    gzip.crc32
 }
 
-fn compress_write_loop<R: Reader, W: Writer>(mut stream_reader: R, stream_writer: W, filepath: &Path, options: &Options) {
+fn compress_write_loop<R: Reader, W: Writer>(mut stream_reader: R, stream_writer: W, filepath: &Path, options: &Options) -> u32 {
     let stat = fs::stat(filepath);
     let file_name = get_file_name(filepath);
     let mtime = if options.no_name { 0u32 } else { (stat.modified / 1000) as u32 };
     let file_size = stat.size as u32;
     let mut gz_writer = GZipWriter::with_size_factor(stream_writer, file_name.as_bytes(), mtime, file_size, options.compress_level, options.size_factor);
-    let mut input_buf = vec::from_elem(gzip::calc_buf_size(options.size_factor), 0u8);
-    loop {
-        match stream_reader.read(input_buf) {
-            Some(n) => {
-                gz_writer.write(input_buf.slice(0, n));
+    match gz_writer.write_from(&mut stream_reader) {
+        Ok(_)       => (),
+        Err(err)    => raise_io!("Compression failure.", err)
+    }
+    gz_writer.gzip.crc32
+}
+
+/// Check the compressed file has been fully written by decompressing it back.  The decompressed data must pass
+/// the checks of the end section, and match the CRC computed on the input during compression and the input size.
+fn verify_compressed(gz_filepath: &Path, crc32: u32, size: u64, options: &Options) -> bool {
+    let mut failed = false;
+    let mut opened = false;
+    let mut more_members = false;
+    let mut cmp_crc32 = 0u32;
+    let mut cmp_size = 0u64;
+    io_error::cond.trap(|_| failed = true).inside(|| {
+        match File::open_mode(gz_filepath, Open, Read) {
+            Some(gz_file) => {
+                opened = true;
+                let mut gz_reader = GZipReader::with_size_factor(gz_file, options.size_factor);
+                let mut buf = vec::from_elem(gzip::calc_buf_size(options.size_factor), 0u8);
+                loop {
+                    match gz_reader.read(buf) {
+                        Some(n) => {
+                            cmp_crc32 = gzip::update_crc(cmp_crc32, buf, 0, n);
+                            cmp_size += n as u64;
+                        },
+                        None    => break
+                    }
+                }
+                // The output has the one member only.
+                more_members = gz_reader.next_member();
             },
-            None    => {
-                gz_writer.finalize();
-                break;
-            }
+            None => ()
         }
-    }
+    });
+    opened && !failed && !more_members && cmp_crc32 == crc32 && cmp_size == size
 }
 
-fn compress_file(options: &Options, file: &str) -> ~[~str] {
-    let mut results : ~[~str] = ~[];
+/// Copy the permissions and the modified time of the source file onto the output file, like gzip does.
+/// A non-zero mtime (in seconds) overrides the modified time of the source file.
+fn copy_file_metadata(src_filepath: &Path, out_filepath: &Path, mtime: u32) {
+    let stat = fs::stat(src_filepath);
+    let modified = if mtime > 0 { mtime as u64 * 1000 } else { stat.modified };
+    fs::chmod(out_filepath, stat.perm);
+    fs::change_file_times(out_filepath, stat.accessed, modified);
+}
+
+/// The verbose line on a compressed file, with the space saved, the compression mode and the time taken.
+fn format_compressed_line(file: &str, original_size: u64, compressed_size: u64, secs: f64, options: &Options) -> ~str {
+    let ratio = if original_size > 0 { 100f64 - (compressed_size as f64 * 100f64 / original_size as f64) } else { 0f64 };
+    format!("{:s}:\t{:5.1f}% -- created {:s}{:s}{:s}  ({:.3f}s)", file, ratio, file, options.suffix, 
+            if options.rsyncable { "  (rsyncable)" } else { "" }, secs)
+}
+
+/// The verbose line on a decompressed file, with the space saved and the time taken.
+fn format_decompressed_line(file: &str, compressed_size: u64, original_size: u64, out_filepath: &Path, secs: f64) -> ~str {
+    let ratio = if original_size > 0 { 100f64 - (compressed_size as f64 * 100f64 / original_size as f64) } else { 0f64 };
+    format!("{:s}:\t{:5.1f}% -- created {:s}  ({:.3f}s)", file, ratio, out_filepath.as_str().unwrap_or(""), secs)
+}
+
+fn compress_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
 
     let filepath = Path::new(file);
-    if filepath.extension_str().unwrap_or("").to_ascii().to_lower().into_str().equals(&~"gz") {
-        results.push(format!("File {:s} already has the .gz suffix -- unchanged", file));
+    if has_suffix(file, options.suffix) {
+        results.push(Warning(format!("File {:s} already has the {:s} suffix -- unchanged", file, options.suffix)));
         return results;
     }
 
+    let start_time = time::precise_time_s();
     io_error::cond.trap(|c| {
-        results.push(c.to_str());
+        results.push(Error(c.to_str()));
     }).inside(|| {
         match File::open_mode(&filepath, Open, Read) {
+            Some(stream_reader) if options.stdout => {
+                // Each file becomes one member of the multi-member gzip stream written to stdout.
+                let stream_reader = ProgressReader::new(stream_reader, get_file_size(&filepath), options.progress);
+                compress_to(stream_reader, stdio::stdout(), &filepath, options);
+            },
             Some(stream_reader) => {
                 match open_compressed_writer(options, file) {
                     Ok(stream_writer) => {
-                        if options.use_stream {
-                            compress_stream_loop(stream_reader, stream_writer, &filepath, options)
-                        } else {
-                            compress_write_loop(stream_reader, stream_writer, &filepath, options)
-                        };
+                        let stream_reader = ProgressReader::new(stream_reader, get_file_size(&filepath), options.progress);
+                        let crc32 = compress_to(stream_reader, stream_writer, &filepath, options);
+                        if results.len() == 0 {
+                            let out_filepath = Path::new(file + options.suffix);
+                            copy_file_metadata(&filepath, &out_filepath, 0);
+                            if options.verbose > 0 {
+                                results.push(Info(format_compressed_line(file, get_file_size(&filepath), get_file_size(&out_filepath), 
+                                                                         time::precise_time_s() - start_time, options)));
+                            }
+                            // Like gzip, remove the source file only after the output is completely written and verified.
+                            if !options.keep {
+                                if verify_compressed(&out_filepath, crc32, get_file_size(&filepath), options) {
+                                    fs::unlink(&filepath);
+                                } else {
+                                    results.push(Error(format!("Failed to verify the compressed output of {:s}.  Source file is kept.", file)));
+                                }
+                            }
+                        }
                     },
                     Err(errstr) => 
-                        results.push(format!("{0:s} {1:s}", errstr, filepath.as_str().unwrap_or("")))
+                        results.push(Error(format!("{0:s} {1:s}", errstr, filepath.as_str().unwrap_or(""))))
                 }
             },
             None => 
-                results.push(format!("Failed to open file {:s}", filepath.as_str().unwrap_or("")))
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
         }
     });
 
@@ -289,26 +544,11 @@ fn compress_file(options: &Options, file: &str) -> ~[~str] {
 }
 
 
-fn open_decompressed_writer(options: &Options, filepath: &Path) -> File {
-    if options.stdout {
-        //let writer = stdio::stdout();
-        //return writer;
-        fail!("std::io::stdout is not implemented yet");
-    }
-
-    let filestem = match filepath.filestem_str() {
-        Some(stem) => stem,
-        None => {
-            raise_io!("Not a file.");
-            fail!("Not a file.");
-        }
-    };
-
-    let out_filepath = filepath.with_filename(filestem);
+fn open_decompressed_writer(options: &Options, out_filepath: &Path) -> File {
     if out_filepath.exists() && !options.force {
         raise_io!("File already exists.  Use -f to overwrite it.");
     }
-    match File::open_mode(&out_filepath, Truncate, Write) {
+    match File::open_mode(out_filepath, Truncate, Write) {
         Some(writer_stream) => writer_stream,
         None => {
             raise_io!("Failed to open file for write.");
@@ -317,170 +557,381 @@ fn open_decompressed_writer(options: &Options, filepath: &Path) -> File {
     }
 }
 
-fn decompress_stream_loop<R: Reader>(mut stream_reader: R, out_file: &str, options: &Options) {
+/// Get the path of the decompressed file.  With -N, use the original file name stored in the gzip header.
+fn get_decompressed_filepath(gzip: &GZip, out_file: &str, options: &Options) -> Path {
+    let out_filepath = Path::new(out_file);
+    if options.name {
+        out_filepath.with_filename(gzip.file_name_as_str(get_file_name(&out_filepath)))
+    } else {
+        out_filepath
+    }
+}
+
+// Decompress the reader into the decompressed file, or stdout.  With --double-buffer, the input is read ahead on another task.
+// Return the decompressed file path, the modified time stored in the gzip header, and what has been salvaged if any.
+fn decompress_to<R: Reader + Send>(stream_reader: R, out_file: &str, options: &Options) -> (Path, u32, Option<~str>) {
+    if options.double_buffer {
+        let stream_reader = DoubleBufferedReader::new(stream_reader, gzip::calc_buf_size(options.size_factor));
+        decompress_reader_to(stream_reader, out_file, options)
+    } else {
+        decompress_reader_to(stream_reader, out_file, options)
+    }
+}
+
+fn decompress_reader_to<R: Reader>(stream_reader: R, out_file: &str, options: &Options) -> (Path, u32, Option<~str>) {
+    // Both loops handle the concatenated members, e.g. from rgzip -c.  The stream loop also does the salvage.
+    if options.use_stream || options.stdout || options.salvage {
+        decompress_stream_loop(stream_reader, out_file, options)
+    } else {
+        decompress_read_loop(stream_reader, out_file, options)
+    }
+}
+
+// Return the decompressed file path, the modified time stored in the gzip header, and what has been salvaged if any.
+fn decompress_stream_loop<R: Reader>(mut stream_reader: R, out_file: &str, options: &Options) -> (Path, u32, Option<~str>) {
     let mut gzip = GZip::decompress_init(&mut stream_reader);
-    let decomp_filename = if options.name { 
-            gzip.file_name_as_str(out_file) 
-    } else { 
-            out_file.to_owned() 
+    gzip.set_salvage(options.salvage);
+    let decomp_filepath = get_decompressed_filepath(&gzip, out_file, options);
+    if options.stdout {
+        let mut stream_writer = stdio::stdout();
+        gzip.decompress_all(&mut stream_reader, &mut stream_writer, options.size_factor);
+    } else {
+        let mut stream_writer = open_decompressed_writer(options, &decomp_filepath);
+        gzip.decompress_all(&mut stream_reader, &mut stream_writer, options.size_factor);
+    }
+    let salvaged = match (&gzip.end_mismatch, &gzip.truncation) {
+        (_, &Some(ref truncation))      => Some(truncation.to_str()),
+        (&Some(ref mismatch), &None)    => Some(format!("the end section mismatches: {:s}", mismatch.to_str())),
+        (&None, &None)                  => None
     };
-    let decomp_filepath = Path::new(decomp_filename);
-    let mut stream_writer = open_decompressed_writer(options, &decomp_filepath);
-    gzip.decompress_stream(&mut stream_reader, &mut stream_writer, options.size_factor);
+    (decomp_filepath, gzip.mtime, salvaged)
 }
 
-fn decompress_read_loop<R: Reader>(stream_reader: R, out_file: &str, options: &Options) {
+// Return the decompressed file path, the modified time stored in the gzip header, and nothing salvaged.
+// The concatenated members are decompressed one after another into the same file.
+fn decompress_read_loop<R: Reader>(stream_reader: R, out_file: &str, options: &Options) -> (Path, u32, Option<~str>) {
     let mut gzip_reader = GZipReader::with_size_factor(stream_reader, options.size_factor);
-    let decomp_filename = if options.name {
-            gzip_reader.gzip.file_name_as_str(out_file)
-    } else {
-            out_file.to_owned()
-    };
-    let decomp_filepath = Path::new(decomp_filename);
+    let decomp_filepath = get_decompressed_filepath(&gzip_reader.gzip, out_file, options);
+    let mtime = gzip_reader.gzip.mtime;
     let mut stream_writer = open_decompressed_writer(options, &decomp_filepath);
     let mut out_buf = vec::from_elem(gzip::calc_buf_size(options.size_factor), 0u8);
     loop {
         match gzip_reader.read(out_buf) {
             Some(n) => stream_writer.write(out_buf.slice(0, n)),
+            None if gzip_reader.next_member() => (),
             None    => break
         }
     }
     stream_writer.flush();
+    (decomp_filepath, mtime, None)
 }
 
-fn decompress_file(options: &Options, file: &str) -> ~[~str] {
-    let mut results : ~[~str] = ~[];
+fn decompress_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
 
     // Check for valid filetype
     let filepath = Path::new(file);
-    match filepath.extension_str() {
-        Some(filetype) => {
-            if !filetype.to_ascii().to_lower().into_str().equals(&~"gz") {
-                results.push(format!("File {:s} does not have the .gz suffix.  No action.", file))
-            }
-        },
-        None =>
-            results.push(format!("File {:s} has no .gz suffix.  No action.", file))
-    };
-    if results.len() > 0 {
+    if !has_suffix(file, options.suffix) {
+        results.push(Warning(format!("File {:s} does not have the {:s} suffix.  No action.", file, options.suffix)));
         return results;
     }
+    let out_file = get_decompressed_name(file, options.suffix);
 
+    let start_time = time::precise_time_s();
     io_error::cond.trap(|c| {
-        results.push(c.to_str());
+        results.push(Error(c.to_str()));
     }).inside (|| {
         match File::open_mode(&filepath, Open, Read) {
             Some(stream_reader) => {
-                if options.use_stream {
-                    decompress_stream_loop(stream_reader, file, options)
-                } else {
-                    decompress_read_loop(stream_reader, file, options)
+                let stream_reader = ProgressReader::new(stream_reader, get_file_size(&filepath), options.progress);
+                let (decomp_filepath, mtime, salvaged) = decompress_to(stream_reader, out_file, options);
+                let decomp_ok = results.len() == 0;
+                match salvaged {
+                    Some(ref salvaged) =>
+                        results.push(Warning(format!("{:s}: salvaged, {:s}", file, *salvaged))),
+                    None => ()
+                }
+                if decomp_ok && !options.stdout {
+                    // With -N, restore the modified time stored in the gzip header.
+                    copy_file_metadata(&filepath, &decomp_filepath, if options.name { mtime } else { 0 });
+                    if options.verbose > 0 {
+                        results.push(Info(format_decompressed_line(file, get_file_size(&filepath), get_file_size(&decomp_filepath), 
+                                                                   &decomp_filepath, time::precise_time_s() - start_time)));
+                    }
+                    // The CRC has been checked at the end of decompression.  Any failure would have been trapped into results.
+                    // The damaged file is kept when its data are salvaged.
+                    if !options.keep && salvaged.is_none() {
+                        fs::unlink(&filepath);
+                    }
                 }
             },
             None => 
-                results.push(format!("Failed to open file {:s}", filepath.as_str().unwrap_or("")))
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
         }
     });
     results
 }
 
-fn list_file(file: &str) -> ~[~str] {
-    let mut results : ~[~str] = ~[];
+/// Running totals of the listed files.
+struct ListTotals {
+    files:          uint,
+    compressed:     u64,
+    uncompressed:   u64,
+}
+
+fn print_list_header() {
+    println("method  crc       date   time   compressed  uncompressed  ratio  uncompressed_name");
+}
+
+fn format_list_line(method: &str, crc32: u32, mtime: u32, compressed: u64, uncompressed: u64, name: &str) -> ~str {
+    // Ratio is the space saved, as in gzip.
+    let ratio = if uncompressed > 0 { 100f64 - (compressed as f64 * 100f64 / uncompressed as f64) } else { 0f64 };
+    let date = if mtime > 0 { time::at(time::Timespec::new(mtime as i64, 0)).strftime("%b %d %H:%M") } else { ~"            " };
+    let crc = if method.len() > 0 { format!("{:08x}", crc32 as uint) } else { ~"        " };
+    format!("{:6s}  {:s}  {:s}  {:10u}  {:12u} {:5.1f}%  {:s}", 
+            method, crc, date, compressed as uint, uncompressed as uint, ratio, name)
+}
+
+// Sniff the type of the content from the beginning of the decompressed data.
+// Errors are ignored; only the beginning is decompressed, and checking the data is for -t.
+fn sniff_file(filepath: &Path) -> gzip::ContentType {
+    let mut head = ~[];
+    io_error::cond.trap(|_| ()).inside(|| {
+        match File::open_mode(filepath, Open, Read) {
+            Some(file)  => head = ioutil::read_upto(&mut GZipReader::new(file), gzip::SNIFF_LEN),
+            None        => ()
+        }
+    });
+    gzip::sniff_content(head)
+}
+
+fn list_file(options: &Options, file: &str, totals: &mut ListTotals) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
 
     // Check for valid filetype
     let filepath = Path::new(file);
-    match filepath.extension_str() {
-        Some(filetype) => {
-            if !filetype.to_ascii().to_lower().into_str().equals(&~"gz") {
-                results.push(format!("File {:s} does not have the .gz suffix.  No action.", file))
-            }
-        },
-        None =>
-            results.push(format!("File {:s} has no .gz suffix.  No action.", file))
-    };
-    let mut file_size: u64;
-    match io::result(|| fs::stat(&filepath)) {
-        Ok(stat) => {
-            file_size = stat.size;
-        },
-        Err(_) => {
-            file_size = 0;
+    if !has_suffix(file, options.suffix) {
+        results.push(Warning(format!("File {:s} does not have the {:s} suffix.  No action.", file, options.suffix)));
+        return results;
+    }
+    let file_size = get_file_size(&filepath);
+    let uncompressed_name = get_decompressed_name(file, options.suffix);
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        match File::open_mode(&filepath, Open, Read) {
+            Some(stream_reader) => {
+                let mut stream_reader = stream_reader;
+                let gzip = GZip::read_info(&mut stream_reader);
+                let method = if gzip.compression == 8 { "defla" } else { "?" };
+
+                // The end section only has the size of the last member.  Walk through the members for the total size.
+                stream_reader.seek(0, SeekSet);
+                let (members, total_size) = GZip::scan_members(&mut stream_reader, options.size_factor);
+                let uncompressed_size = if members > 1 { total_size } else { gzip.original_size as u64 };
+                results.push(Info(format_list_line(method, gzip.crc32, gzip.mtime, 
+                                                   file_size, uncompressed_size, uncompressed_name)));
+                if members > 1 {
+                    results.push(Info(format!("        {:u} members, combined uncompressed size {:u}", members, total_size as uint)));
+                }
+                if options.verbose > 0 {
+                    results.push(Info(format!("        stored name: {:s}", gzip.file_name_as_str(""))));
+                    results.push(Info(format!("        comment: {:s}", gzip.comment.clone().unwrap_or(~""))));
+                    results.push(Info(format!("        flags: {:s}", gzip.flags_as_str())));
+                    match gzip.extra_subfields() {
+                        Ok(subfields)   => {
+                            for subfield in subfields.iter() {
+                                results.push(Info(format!("        extra: {:s}", subfield.to_str())));
+                            }
+                        },
+                        Err(s)          => results.push(Warning(format!("        extra: {:s}", s)))
+                    }
+                    results.push(Info(format!("        content: {:s}", sniff_file(&filepath).to_str())));
+                }
+                totals.files += 1;
+                totals.compressed += file_size;
+                totals.uncompressed += uncompressed_size;
+            },
+            None => 
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
         }
+    });
+
+    results
+}
+
+
+fn test_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    // Check for valid filetype
+    let filepath = Path::new(file);
+    if !has_suffix(file, options.suffix) {
+        results.push(Warning(format!("File {:s} does not have the {:s} suffix.  No action.", file, options.suffix)));
+        return results;
     }
+
+    let mut members = 0u;
+    let mut total_size = 0u64;
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        match File::open_mode(&filepath, Open, Read) {
+            Some(stream_reader) => {
+                let (m, t) = GZip::verify(stream_reader, options.size_factor);
+                members = m;
+                total_size = t;
+            },
+            None => 
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
+        }
+    });
+
     if results.len() > 0 {
-        return results;
+        results.unshift(Error(format!("{:s}: FAILED", file)));
+    } else {
+        results.push(Info(format!("{:s}: OK  ({:u} member(s), {:u} bytes)", file, members, total_size as uint)));
     }
+    results
+}
+
+/// Compress the file in memory at each of the BENCH_LEVELS, with one task and with the -p tasks when more than one,
+/// and report the time, the throughput and the ratio of each.  Nothing is written to disk.
+fn bench_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
 
+    let filepath = Path::new(file);
+    let mut data = ~[];
     io_error::cond.trap(|c| {
-        results.push(c.to_str());
+        results.push(Error(c.to_str()));
     }).inside(|| {
         match File::open_mode(&filepath, Open, Read) {
             Some(stream_reader) => {
                 let mut stream_reader = stream_reader;
-                let gzip = GZip::read_info(&mut stream_reader);
-                results.push(format!("{:10u}  {:10u} {:5.1f}%  {:s}", 
-                                     file_size as uint, 
-                                     gzip.original_size as uint, 
-                                     (file_size as f64 * 100f64 / gzip.original_size as f64), 
-                                     gzip.file_name_as_str("")));
+                data = stream_reader.read_to_end();
             },
             None => 
-                results.push(format!("Failed to open file {:s}", filepath.as_str().unwrap_or("")))
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
         }
     });
+    if results.len() > 0 {
+        return results;
+    }
 
+    let mut task_counts = ~[1u];
+    if options.num_tasks > 1 {
+        task_counts.push(options.num_tasks);
+    }
+    results.push(Info(format!("{:s}: {:u} bytes", file, data.len())));
+    results.push(Info(~"level  tasks      time        MB/s       compressed  ratio"));
+    for &level in BENCH_LEVELS.iter() {
+        for &num_tasks in task_counts.iter() {
+            let result = gzip::bench_compress(data, level, num_tasks, options.size_factor);
+            results.push(Info(format!("{:5u}  {:5u}  {:8.3f}s  {:10.2f}  {:15u}  {:4.1f}%", 
+                                      result.compress_level, result.num_tasks, result.secs, result.mb_per_sec(), 
+                                      result.compressed_size as uint, result.ratio())));
+        }
+    }
     results
 }
 
+/// Print a line to stderr.
+fn eprintln(line: &str) {
+    stdio::stderr().write((line + "\n").as_bytes());
+}
 
-fn print_lines(lines: ~[~str]) {
-    for line in lines.iter() {
-        if line.len() > 0 {
-            println(*line);
+/// Print the reports, info to stdout, warnings and errors to stderr.  Return the exit status for the reports.
+/// With -c, stdout carries the data so info goes to stderr as well.  -q suppresses all but the errors and the listing.
+fn print_reports(options: &Options, reports: ~[Report]) -> int {
+    let mut status = EXIT_OK;
+    for report in reports.iter() {
+        match *report {
+            Info(ref line) => {
+                let listing = match options.cmd { LIST => true, _ => false };
+                if line.len() > 0 && (listing || !options.quiet) {
+                    if options.stdout { eprintln(*line) } else { println(*line) }
+                }
+            },
+            Warning(ref line) => {
+                if !options.quiet {
+                    eprintln(*line);
+                }
+                status = merge_status(status, EXIT_WARNING);
+            },
+            Error(ref line) => {
+                eprintln(*line);
+                status = merge_status(status, EXIT_ERROR);
+            }
         }
     }
+    status
+}
+
+/// Merge exit statuses.  Error takes precedence over warning.
+fn merge_status(status: int, new_status: int) -> int {
+    if status == EXIT_ERROR || new_status == EXIT_ERROR {
+        EXIT_ERROR
+    } else {
+        num::max(status, new_status)
+    }
 }
 
 fn main()  {
     let args = os::args();
+    let mut status = EXIT_OK;
     match Options::from_args(&args) {
         Ok(options) => {
+            if options.verbose > 1 {
+                trace::set_trace_fn(Some(eprintln));
+            }
             match options.cmd {
                 HELP =>
                     print_usage(&args),
                 VERSION =>
                     print_version(&args),
+                COMPRESS | DECOMPRESS | TEST | BENCH if options.files.len() == 0 => {
+                    eprintln("Missing file(s)");
+                    print_usage(&args);
+                    status = EXIT_ERROR;
+                },
                 COMPRESS => {
-                    if options.files.len() > 0 {
-                        for file in options.files.iter() {
-                            print_lines(compress_file(&options, *file));
-                        }
-                    } else {
-                        println("Missing file(s)");
-                        print_usage(&args);
+                    for file in options.files.iter() {
+                        status = merge_status(status, print_reports(&options, compress_file(&options, *file)));
                     }
                 },
                 DECOMPRESS => {
-                    if options.files.len() > 0 {
-                        for file in options.files.iter() {
-                            print_lines(decompress_file(&options, *file));
-                        }
-                    } else {
-                        println("Missing file(s)");
-                        print_usage(&args);
+                    for file in options.files.iter() {
+                        status = merge_status(status, print_reports(&options, decompress_file(&options, *file)));
+                    }
+                },
+                TEST => {
+                    for file in options.files.iter() {
+                        status = merge_status(status, print_reports(&options, test_file(&options, *file)));
+                    }
+                },
+                BENCH => {
+                    for file in options.files.iter() {
+                        status = merge_status(status, print_reports(&options, bench_file(&options, *file)));
                     }
                 },
                 LIST => {
-                    println("compressed  uncompress  ratio  uncompressed_name");
+                    let mut totals = ListTotals { files: 0, compressed: 0, uncompressed: 0 };
+                    print_list_header();
                     for file in options.files.iter() {
-                        print_lines(list_file(*file));
+                        status = merge_status(status, print_reports(&options, list_file(&options, *file, &mut totals)));
+                    }
+                    if totals.files > 1 {
+                        println(format_list_line("", 0, 0, totals.compressed, totals.uncompressed, "(totals)"));
                     }
                 }
             }
         },
         Err(err) => {
-            println(format!("\n{:s}\n", err));
+            eprintln(format!("\n{:s}\n", err));
             print_usage(&args);
+            status = EXIT_ERROR;
         }
     }
+    os::set_exit_status(status);
 }
@@ -21,7 +21,14 @@ extern mod extra;
 extern mod rustyzip;
 use rustyzip::gzip;
 use rustyzip::zip;
-use rustyzip::zip::{ZipFile};
+use rustyzip::trace;
+use rustyzip::zip::{ZipFile, ZipEntry32, ZipWriter, ZipStreamReader, ZipStreamWriter, EntryOptions, ExtractOptions};
+use rustyzip::zip::{OverwritePolicy, OverwriteNever, OverwriteAlways, OverwriteRename, OverwritePrompt};
+use rustyzip::zip::{DuplicatePolicy, DuplicateFirst, DuplicateLast, DuplicateError};
+use rustyzip::zip::{ExtractLimits, ExtractProgress, PathPolicy};
+use rustyzip::zip::ZipStats;
+use rustyzip::zip::{ArchivePreset, glob_match};
+use rustyzip::zip::{ExtractMode, ExtractAll, ExtractFreshen, ExtractUpdate};
 
 // Uncomment these to use the modules in the system's libextra.
 // use extra::gzip;
@@ -30,34 +37,77 @@ use rustyzip::zip::{ZipFile};
 
 
 use std::os;
+use std::rt;
+use std::run;
 use std::num;
+use std::str;
+use std::vec;
 use std::result::{Result, Ok, Err};
 use std::to_str::ToStr;
 use std::path::Path;
-use std::io::{Open, Read, io_error};
+use std::io;
+use std::io::{Writer, Open, Read, Truncate, Write, io_error};
+use std::io::stdio;
+use std::io::buffered::BufferedReader;
+use std::io::fs;
 use std::io::fs::File;
-use extra::getopts::{optflag, optopt, getopts};
+use extra::getopts::{optflag, optopt, optmulti, getopts};
+use extra::time;
+use extra::future::Future;
 
 
 
 static VERSION_STR : &'static str = "0.9";
+// Exit status
+static EXIT_OK : int = 0;
+static EXIT_ERROR : int = 1;
+static EXIT_WARNING : int = 2;
+// rwxr-xr-x, before the umask, for the -d destination directories created
+static DEST_DIR_MODE : io::FilePermission = io::UserRWX | io::GroupRead | io::GroupExecute | io::OtherRead | io::OtherExecute;
 
 
+/// Messages reported from processing an archive, by severity.
+enum Report {
+    /// Normal output, printed to stdout unless -q is given.
+    Info(~str),
+    /// Printed to stderr unless -q is given.  Set the exit status to 2 if there's no error.
+    Warning(~str),
+    /// Printed to stderr.  Set the exit status to 1.
+    Error(~str),
+}
+
 enum Cmd {
-    HELP, VERSION, COMPRESS, DECOMPRESS, LIST
+    HELP, VERSION, CREATE, ADD, UPDATE, DELETE, EXTRACT, PIPE, LIST, ZIPINFO, TEST
 }
 
 struct Options {
     cmd:            Cmd,
+    dest_dir:       ~str,
     stdout:         bool,
     force:          bool,
-    no_name:        bool,
+    overwrite:      OverwritePolicy,
+    junk_paths:     bool,
+    extract_mode:   ExtractMode,
+    symlinks:       bool,
+    owner:          bool,
+    duplicates:     DuplicatePolicy,
+    limits:         ExtractLimits,
     name:           bool,
     quiet:          bool,
-    verbose:        bool,
+    verbose:        uint,
+    progress:       bool,
     compress_level: uint,
+    method:         u16,
+    auto_store:     bool,
+    ntfs_times:     bool,
+    alignment:      uint,
+    preset:         Option<ArchivePreset>,
     use_stream:     bool,
     size_factor:    uint,
+    password:       Option<~str>,
+    prompt:         bool,
+    include:        ~[~str],
+    exclude:        ~[~str],
     files:          ~[~str],
 }
 
@@ -66,16 +116,33 @@ impl Options {
 
     pub fn from_args(args: &~[~str]) -> Result<Options, ~str> {
         let mut options = Options {
-            cmd: COMPRESS,          // default command is to compress
+            cmd: CREATE,            // default command is to create
+            dest_dir: ~".",
             stdout: false,
             force: false,
-            no_name: false,
+            overwrite: OverwritePrompt(prompt_overwrite),
+            junk_paths: false,
+            extract_mode: ExtractAll,
+            symlinks: false,
+            owner: false,
+            duplicates: DuplicateError,
+            limits: ExtractLimits::new(),
             name: false,
             quiet: false,
-            verbose: false,
+            verbose: 0,
+            progress: false,
             compress_level: gzip::DEFAULT_COMPRESS_LEVEL,
+            method: zip::METHOD_DEFLATE,
+            auto_store: true,
+            ntfs_times: false,
+            alignment: 0,
+            preset: None,
             use_stream: true,
             size_factor: gzip::DEFAULT_SIZE_FACTOR,
+            password: None,
+            prompt: true,
+            include: ~[],
+            exclude: ~[],
             files: ~[],
         };
         let opts = ~[
@@ -83,22 +150,54 @@ impl Options {
                      optflag("help"),
                      optflag("V"),
                      optflag("version"),
-                     optflag("d"),
-                     optflag("decompress"),
+                     optflag("x"),
+                     optflag("extract"),
+                     optflag("p"),
+                     optflag("pipe"),
+                     optopt("d"),
+                     optopt("dir"),
                      optflag("l"),
                      optflag("list"),
+                     optflag("t"),
+                     optflag("test"),
+                     optflag("Z"),
+                     optflag("zipinfo"),
                      optflag("c"),
                      optflag("stdout"),
+                     optflag("create"),
+                     optopt("m"),
+                     optopt("method"),
+                     optflag("ntfs-times"),
+                     optopt("align"),
+                     optflag("jar"),
+                     optflag("apk"),
+                     optflag("epub"),
+                     optflag("odf"),
                      optflag("f"),
                      optflag("force"),
                      optflag("n"),
-                     optflag("no-name"),
+                     optflag("never-overwrite"),
+                     optflag("o"),
+                     optflag("overwrite"),
+                     optflag("rename"),
+                     optflag("j"),
+                     optflag("junk-paths"),
+                     optflag("u"),
+                     optflag("update"),
+                     optflag("y"),
+                     optflag("symlinks"),
+                     optflag("owner"),
+                     optopt("duplicates"),
+                     optopt("max-entries"),
+                     optopt("max-size"),
+                     optopt("max-ratio"),
                      optflag("N"),
                      optflag("name"),
                      optflag("q"),
                      optflag("quiet"),
                      optflag("v"),
                      optflag("verbose"),
+                     optflag("progress"),
                      optflag("0"),
                      optflag("1"),
                      optflag("2"),
@@ -112,6 +211,12 @@ impl Options {
                      optflag("Stream"),
                      optopt("b"),
                      optopt("bufsize"),
+                     optopt("P"),
+                     optopt("password"),
+                     optmulti("i"),
+                     optmulti("include"),
+                     optmulti("X"),
+                     optmulti("exclude"),
                      
                      ];
 
@@ -119,24 +224,88 @@ impl Options {
             Ok(matches) => {
                 options.cmd = if matches.opt_present("h") || matches.opt_present("help") {  HELP } else { options.cmd };
                 options.cmd = if matches.opt_present("V") || matches.opt_present("version") { VERSION } else { options.cmd };
-                options.cmd = if matches.opt_present("d") || matches.opt_present("decompress") { DECOMPRESS } else { options.cmd };
+                options.cmd = if matches.opt_present("x") || matches.opt_present("extract") { EXTRACT } else { options.cmd };
+                options.cmd = if matches.opt_present("p") || matches.opt_present("pipe") { PIPE } else { options.cmd };
                 options.cmd = if matches.opt_present("l") || matches.opt_present("list") { LIST } else { options.cmd };
+                options.cmd = if matches.opt_present("t") || matches.opt_present("test") { TEST } else { options.cmd };
+                options.cmd = if matches.opt_present("Z") || matches.opt_present("zipinfo") { ZIPINFO } else { options.cmd };
 
+                let dest_dir = if matches.opt_present("dir") { matches.opt_str("dir").unwrap_or(options.dest_dir.clone()) } else { options.dest_dir.clone() };
+                options.dest_dir = if matches.opt_present("d") { matches.opt_str("d").unwrap_or(dest_dir) } else { dest_dir };
+                options.cmd = if matches.opt_present("create") { CREATE } else { options.cmd };
                 options.stdout = matches.opt_present("c") || matches.opt_present("stdout");
                 options.force = matches.opt_present("f") || matches.opt_present("force");
-                options.no_name = matches.opt_present("n") || matches.opt_present("no-name");
+                options.overwrite = if matches.opt_present("o") || matches.opt_present("overwrite") { OverwriteAlways } else { options.overwrite };
+                options.overwrite = if matches.opt_present("n") || matches.opt_present("never-overwrite") { OverwriteNever } else { options.overwrite };
+                options.overwrite = if matches.opt_present("rename") { OverwriteRename } else { options.overwrite };
+                options.junk_paths = matches.opt_present("j") || matches.opt_present("junk-paths");
+                // Like unzip, -f on extract freshens the existing files and -u also extracts the new ones.
+                options.extract_mode = if options.force { ExtractFreshen } else { ExtractAll };
+                options.extract_mode = if matches.opt_present("u") || matches.opt_present("update") { ExtractUpdate } else { options.extract_mode };
+                options.symlinks = matches.opt_present("y") || matches.opt_present("symlinks");
+                options.owner = matches.opt_present("owner");
+                options.duplicates = match matches.opt_str("duplicates") {
+                    Some(ref d) if d.as_slice() == "first"  => DuplicateFirst,
+                    Some(ref d) if d.as_slice() == "last"   => DuplicateLast,
+                    Some(ref d) if d.as_slice() == "error"  => DuplicateError,
+                    Some(d)                                 => return Err(format!("Unknown duplicate policy {:s}.  Use first, last or error.", d)),
+                    None                                    => options.duplicates
+                };
+                options.limits.max_entries = matches.opt_str("max-entries").and_then(|n| from_str::<uint>(n.trim()));
+                options.limits.max_total_size = matches.opt_str("max-size").and_then(|n| from_str::<u64>(n.trim()));
+                options.limits.max_ratio = matches.opt_str("max-ratio").and_then(|n| from_str::<f64>(n.trim()));
                 options.name = matches.opt_present("N") || matches.opt_present("name");
                 options.quiet = matches.opt_present("q") || matches.opt_present("quiet");
-                options.verbose = matches.opt_present("v") || matches.opt_present("verbose");
+                // -v reports each file with its ratio and timing.  -vv adds the library trace.
+                options.verbose = matches.opt_count("v") + matches.opt_count("verbose");
+                options.progress = options.verbose > 0 || matches.opt_present("progress");
                 for level in range(0u, 10u) {
                     let slevel = format!("{:u}", level);
                     options.compress_level = if matches.opt_present(slevel) { level } else { options.compress_level };
                 }
+                // Like Info-ZIP, -0 stores the files without compression.
+                let method = if matches.opt_present("method") { matches.opt_str("method") } else { None };
+                let method = if matches.opt_present("m") { matches.opt_str("m") } else { method };
+                // Without an explicit method, the files not worth deflating are stored.
+                options.auto_store = method.is_none();
+                options.ntfs_times = matches.opt_present("ntfs-times");
+                options.alignment = maybe_to_num(matches.opt_str("align"), 0u);
+                options.preset = if matches.opt_present("jar") { Some(ArchivePreset::jar()) } else { None };
+                options.preset = if matches.opt_present("apk") { Some(ArchivePreset::apk()) } else { options.preset };
+                options.preset = if matches.opt_present("epub") || matches.opt_present("odf") { Some(ArchivePreset::mimetype_first()) } else { options.preset };
+                options.method = match method {
+                    Some(ref m) if m.as_slice() == "store"      => zip::METHOD_STORE,
+                    Some(ref m) if m.as_slice() == "deflate"    => zip::METHOD_DEFLATE,
+                    Some(m)                                     => return Err(format!("Unknown compression method {:s}.  Use store or deflate.", m)),
+                    None if options.compress_level == 0         => zip::METHOD_STORE,
+                    None                                        => zip::METHOD_DEFLATE
+                };
                 options.use_stream = !matches.opt_present("Stream");
                 let mut size_factor = if matches.opt_present("bufsize") { maybe_to_num(matches.opt_str("bufsize"), gzip::DEFAULT_SIZE_FACTOR) } else { gzip::DEFAULT_SIZE_FACTOR };
                 size_factor = if matches.opt_present("b")               { maybe_to_num(matches.opt_str("b"), size_factor) } else { size_factor };
                 options.size_factor = num::max(gzip::MIN_SIZE_FACTOR, size_factor);
+                let password = if matches.opt_present("password") { matches.opt_str("password") } else { None };
+                options.password = if matches.opt_present("P") { matches.opt_str("P") } else { password };
+                options.include = vec::append(matches.opt_strs("i"), matches.opt_strs("include"));
+                options.exclude = vec::append(matches.opt_strs("X"), matches.opt_strs("exclude"));
                 options.files = matches.free;
+                // The subcommands: rzip a|add|update|delete archive.zip files...
+                if options.files.len() > 0 {
+                    let subcmd = match options.files[0].as_slice() {
+                        "a"         => Some(CREATE),
+                        "add"       => Some(ADD),
+                        "update"    => Some(UPDATE),
+                        "delete"    => Some(DELETE),
+                        _           => None
+                    };
+                    match subcmd {
+                        Some(cmd) => {
+                            options.cmd = cmd;
+                            options.files.shift();
+                        },
+                        None => ()
+                    }
+                }
 
                 Ok(options)
             },
@@ -171,7 +340,32 @@ fn get_program(args: &~[~str]) -> ~str {
 }
 
 fn print_usage(args: &~[~str]) {
-    println(format!("Usage: {:s}  -h --help -d --decompress -c --stdout FILE ...", get_program(args)));
+    println(format!("Usage: {:s}  -h --help -l --list -Z --zipinfo -t --test -x --extract -d --dir DIR -f --force FILE ...", get_program(args)));
+    println("       -d --dir DIR extracts under DIR, created with its parent directories if missing.");
+    println("       -n --never-overwrite or -o --overwrite the existing files on extract, or --rename the new ones.  Prompt for each one otherwise.");
+    println("       -j --junk-paths extracts all the files into DIR without their directories.");
+    println("       -f --force on extract freshens only the existing older files; -u --update also extracts the new ones.");
+    println("       -y --symlinks stores the symlinks as links instead of following them, and recreates them on extract instead of skipping them.");
+    println("       --owner restores the uid and gid of the extracted files, when running with the privilege to.");
+    println("       --duplicates first|last extracts the first or the last of the entries of the same name.  The archive fails to extract otherwise.");
+    println("       --max-entries N, --max-size BYTES and --max-ratio N limit the entries, their total size and their compression ratio on extract.");
+    println(format!("       {:s}  [--create] -0..-9 -m --method store|deflate ARCHIVE.zip FILE|DIR ...", get_program(args)));
+    println(format!("       {:s}  -c --stdout -0..-9 -m --method store|deflate FILE|DIR ...  writes the archive of the files to stdout", get_program(args)));
+    println("       Without -m, the files that don't compress, like already-compressed media, are stored.");
+    println("       --ntfs-times adds the full-resolution modified, accessed and created times for the Windows tools.");
+    println("       --jar or --apk writes META-INF/MANIFEST.MF first, and with --apk stores resources.arsc and *.so aligned to 4 bytes.");
+    println("       --epub or --odf writes the mimetype file first, stored and without any extra field.");
+    println("       --align N aligns the data of the stored entries to N bytes, e.g. 4 for Android zipalign.");
+    println("       With -t, --jar, --apk, --epub, --odf and --align check the existing archive for them.");
+    println(format!("       {:s}  -x -  extracts the zip stream from stdin", get_program(args)));
+    println(format!("       {:s}  -p --pipe ARCHIVE.zip ENTRY ...", get_program(args)));
+    println(format!("       {:s}  add|update ARCHIVE.zip FILE|DIR ...", get_program(args)));
+    println("       -P --password PASSWORD for the encrypted entries, visible to other users in the process list.  Prompted without echo when needed if not given.");
+    println("       Several archives are listed, tested or extracted in parallel, without prompting.  Extracting them needs -o, -n or --rename.");
+    println("       -q --quiet prints only the errors and the listing.  -v --verbose adds the ratios and timings; -vv adds the library trace.");
+    println("       --progress shows the progress of the extraction with its ETA on stderr, as -v also does.");
+    println("       -i --include PATTERN and -X --exclude PATTERN select the entries to list, extract, create, add or update.");
+    println(format!("       {:s}  delete ARCHIVE.zip PATTERN ...", get_program(args)));
 }
 
 fn print_version(args: &~[~str]) {
@@ -187,81 +381,1023 @@ fn get_file_name(filepath: &Path) -> ~str {
 }
 
 
-fn list_file(file: &str) -> ~[~str] {
-    let mut results : ~[~str] = ~[];
+// The suffixes of the zip-based archives, like .jar, .apk and .epub for the presets.
+static ZIP_SUFFIXES: [&'static str, ..9] = ["zip", "jar", "apk", "epub", "odt", "ods", "odp", "docx", "xlsx"];
 
-    // Check for valid filetype
+/// Check for the .zip suffix, or .jar or .apk.  Return the error message if the file doesn't have it.
+fn check_zip_suffix(file: &str) -> Option<~str> {
     let filepath = Path::new(file);
     match filepath.extension_str() {
         Some(filetype) => {
-            if !filetype.to_ascii().to_lower().into_str().equals(&~"zip") {
-                results.push(format!("File {:s} does not have the .zip suffix.  No action.", file))
+            let filetype = filetype.to_ascii().to_lower().into_str();
+            if !ZIP_SUFFIXES.iter().any(|suffix| filetype.as_slice() == *suffix) {
+                Some(format!("File {:s} does not have the .zip suffix or one of a zip-based format.  No action.", file))
+            } else {
+                None
             }
         },
         None =>
-            results.push(format!("File {:s} has no .zip suffix.  No action.", file))
+            Some(format!("File {:s} has no .zip suffix.  No action.", file))
+    }
+}
+
+/// Format the entries as a table like unzip -v, with a totals footer.  The CRC column is shown with -v.
+fn format_list(options: &Options, file: &str, entries: &[&ZipEntry32]) -> ~[~str] {
+    let mut lines = ~[];
+    let crc_header = if options.verbose > 0 { " CRC-32  " } else { "" };
+    let crc_line = if options.verbose > 0 { " --------" } else { "" };
+    lines.push(format!("Archive:  {:s}", file));
+    lines.push(format!("    Length  Method        Size  Cmpr     Date    Time {:s}  Name", crc_header));
+    lines.push(format!("----------  ------  ----------  ----  ---------- -----{:s}  ----", crc_line));
+
+    for entry in entries.iter() {
+        let (year, month, day, hour, minute, _) = entry.modified_datetime();
+        let crc = if options.verbose > 0 { format!(" {:08x}", entry.crc32 as uint) } else { ~"" };
+        lines.push(format!("{:10u}  {:6s}  {:10u}  {:3.0f}%  {:04u}-{:02u}-{:02u} {:02u}:{:02u}{:s}  {:s}", 
+                           entry.uncompressed_size as uint, entry.method_as_str(), entry.compressed_size as uint, 
+                           entry.compression_ratio(), year, month, day, hour, minute, crc, entry.file_name_as_str()));
+    }
+
+    let stats = ZipStats::from_entries(entries.iter().map(|entry| *entry));
+    lines.push(format!("----------          ----------  ----                  {:s}  -------", crc_line));
+    lines.push(format!("{:10u}          {:10u}  {:3.0f}%                  {:s}  {:u} file(s)", 
+                       stats.uncompressed_size as uint, stats.compressed_size as uint, stats.compression_ratio(), 
+                       if options.verbose > 0 { "         " } else { "" }, stats.entry_count));
+    if options.verbose > 0 && stats.encrypted_count > 0 {
+        lines.push(format!("{:u} encrypted file(s)", stats.encrypted_count));
+    }
+    if options.verbose > 0 {
+        let features = zip::required_features(entries.iter().map(|entry| (*entry).clone()).collect::<~[ZipEntry32]>());
+        let features = features.iter().map(|feature| {
+            if feature.is_supported() { feature.to_str() } else { format!("{:s} (unsupported)", feature.to_str()) }
+        }).collect::<~[~str]>();
+        lines.push(format!("Requires: {:s}", features.connect(", ")));
+    }
+    lines
+}
+
+/// Format the Unix mode like ls -l does, e.g. -rw-r--r--.
+fn format_unix_mode(mode: u32) -> ~str {
+    let file_type = match mode & 0xF000 {
+        0x4000  => 'd',
+        0xA000  => 'l',
+        _       => '-'
+    };
+    let mut mode_str = str::from_char(file_type);
+    let chars = ['r', 'w', 'x'];
+    for i in range(0u, 9u) {
+        let bit = 1u32 << (8 - i);
+        mode_str.push_char(if (mode & bit) != 0 { chars[i % 3] } else { '-' });
+    }
+    mode_str
+}
+
+/// Format all the metadata of the zip file and its entries, like zipinfo -v.
+fn format_zipinfo(zipfile: &ZipFile, file: &str, entries: &[&ZipEntry32]) -> ~[~str] {
+    let mut lines = ~[];
+    lines.push(format!("Archive:  {:s}   {:u} bytes   {:u} entries", file, zipfile.cd_metadata.file_size as uint, zipfile.cd_metadata.cd_entry_count as uint));
+    match zipfile.cd_metadata.comment {
+        Some(ref comment)   => lines.push(format!("Zip file comment: {:s}", *comment)),
+        None                => lines.push(~"There is no zip file comment.")
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        let (year, month, day, hour, minute, second) = entry.modified_datetime();
+        lines.push(~"");
+        lines.push(format!("Central directory entry \#{:u}:", i + 1));
+        lines.push(format!("  name:                   {:s}", entry.file_name_as_str()));
+        lines.push(format!("  offset of local header: {:u}", entry.local_header_offset as uint));
+        lines.push(format!("  version made by:        {:s} ({:s})", entry.version_made_by_as_str(), entry.host_os_as_str()));
+        lines.push(format!("  version needed:         {:s}", entry.version_needed_as_str()));
+        lines.push(format!("  general flags:          0x{:04x} ({:s})", entry.general_flag as uint, entry.flags_as_str()));
+        lines.push(format!("  method:                 {:s} ({:u})", entry.method_as_str(), entry.compression_method as uint));
+        lines.push(format!("  modified:               {:04u}-{:02u}-{:02u} {:02u}:{:02u}:{:02u}", year, month, day, hour, minute, second));
+        lines.push(format!("  crc-32:                 {:08x}", entry.crc32 as uint));
+        lines.push(format!("  compressed size:        {:u}", entry.compressed_size as uint));
+        lines.push(format!("  uncompressed size:      {:u}", entry.uncompressed_size as uint));
+        let mode = match entry.unix_mode() {
+            Some(mode)  => format!("  {:s} ({:o})", format_unix_mode(mode), (mode & 0xFFF) as uint),
+            None        => ~""
+        };
+        lines.push(format!("  external attributes:    0x{:08x}{:s}", entry.external_file_attributes as uint, mode));
+        lines.push(format!("  internal attributes:    0x{:04x}", entry.internal_file_attributes as uint));
+        let fields = entry.extra_fields();
+        if fields.len() == 0 {
+            lines.push(~"  extra fields:           none");
+        }
+        for &(id, ref data) in fields.iter() {
+            lines.push(format!("  extra field:            0x{:04x} {:s}, {:u} bytes", id as uint, zip::extra_field_name(id), data.len()));
+        }
+        match entry.file_comment {
+            Some(ref comment)   => lines.push(format!("  comment:                {:s}", *comment)),
+            None                => ()
+        }
+    }
+    lines
+}
+
+fn list_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    // Check for valid filetype
+    let filepath = Path::new(file);
+    match check_zip_suffix(file) {
+        Some(err) => return ~[Error(err)],
+        None => ()
+    }
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        match File::open_mode(&filepath, Open, Read) {
+            Some(stream_reader) => {
+                match ZipFile::open(stream_reader) {
+                    Ok(zipfile) => {
+                        let mut zipfile = zipfile;
+                        match zipfile.get_zip_entries() {
+                            Ok(entries) => {
+                                let selected = entries.iter().filter(|ze| is_selected(options, ze.file_name_as_str())).to_owned_vec();
+                                match options.cmd {
+                                    ZIPINFO => results.push_all_move(format_zipinfo(&zipfile, file, selected).move_iter().map(|line| Info(line)).collect()),
+                                    _       => results.push_all_move(format_list(options, file, selected).move_iter().map(|line| Info(line)).collect())
+                                }
+                            },
+                            Err(errstr) =>
+                                results.push(Error(format!("{:s} {:s}", errstr, filepath.as_str().unwrap_or(""))))
+                        }
+                    }
+                    Err(errstr) =>
+                        results.push(Error(format!("{:s} {:s}", errstr, filepath.as_str().unwrap_or(""))))
+                }
+            },
+            None => 
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
+        }
+    });
+
+    results
+}
+
+
+/// Extract the zip stream from stdin as the entries arrive, like funzip.  The central directory is not used.
+fn extract_stdin(options: &Options) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    // stdin carries the zip stream and can't answer the prompt; skip the existing files unless -o.
+    let dest_dir = Path::new(options.dest_dir.clone());
+    let extract_options = ExtractOptions {
+        overwrite:  match options.overwrite {
+                        OverwritePrompt(_)  => OverwriteNever,
+                        policy              => policy
+                    },
+        junk_paths: options.junk_paths,
+        mode:       options.extract_mode,
+        symlinks:   options.symlinks,
+        owner:      options.owner,
+        duplicates: options.duplicates,
+        path_policy: PathPolicy::new(),
     };
-    if results.len() > 0 {
-        return results;
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        let mut zip_stream = ZipStreamReader::new(stdio::stdin());
+        let mut extracted = 0u;
+        let mut failed = 0u;
+        loop {
+            match zip_stream.next_header() {
+                Some(header) => {
+                    let name = header.file_name_as_str();
+                    if !is_selected(options, name) ||
+                        !extract_options.is_wanted(&dest_dir, name, header.modified_date, header.modified_time) {
+                        zip_stream.skip_entry(&header);
+                        continue;
+                    }
+                    let start_time = time::precise_time_s();
+                    match zip_stream.extract_entry(&header, &dest_dir, &extract_options) {
+                        Ok(size)    => {
+                            results.push(Info(format!("  extracted: {:s}  ({:u} bytes){:s}", name, size as uint, format_timing(options, start_time))));
+                            extracted += 1;
+                        },
+                        Err(err)    => {
+                            results.push(Error(format!("  error:     {:s}  {:s}", name, err)));
+                            failed += 1;
+                        }
+                    }
+                },
+                None => break
+            }
+        }
+        if failed > 0 {
+            results.push(Error(format!("{:u} of {:u} entries failed to extract.", failed, extracted + failed)));
+        }
+    });
+
+    results
+}
+
+fn extract_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    // Read the zip stream from stdin with -
+    if file == "-" {
+        return extract_stdin(options);
+    }
+
+    // Check for valid filetype
+    let filepath = Path::new(file);
+    match check_zip_suffix(file) {
+        Some(err) => return ~[Error(err)],
+        None => ()
     }
 
+    let dest_dir = Path::new(options.dest_dir.clone());
+    let extract_options = ExtractOptions {
+        overwrite:  options.overwrite,
+        junk_paths: options.junk_paths,
+        mode:       options.extract_mode,
+        symlinks:   options.symlinks,
+        owner:      options.owner,
+        duplicates: options.duplicates,
+        path_policy: PathPolicy::new(),
+    };
+
     io_error::cond.trap(|c| {
-        results.push(c.to_str());
+        results.push(Error(c.to_str()));
     }).inside(|| {
         match File::open_mode(&filepath, Open, Read) {
             Some(stream_reader) => {
                 match ZipFile::open(stream_reader) {
                     Ok(zipfile) => {
                         let mut zipfile = zipfile;
-                        
-                        let entries = zipfile.get_zip_entries().unwrap();
-                        for ze in entries.iter() {
-                            println(format!("{:?}\r\n", ze));
+                        results.push(Info(format!("Archive:  {:s}", file)));
+                        let entries = match zipfile.check_supported() {
+                            Ok(())  => zipfile.get_zip_entries(),
+                            Err(s)  => Err(s)
+                        };
+                        let entries = entries.and_then(|entries| zip::resolve_duplicates(entries, options.duplicates));
+                        let entries = entries.and_then(|entries| match options.limits.check_entries(entries, 0) {
+                            Some(exceeded)  => Err(exceeded.to_str()),
+                            None            => Ok(entries)
+                        });
+                        zipfile.set_limits(options.limits.clone());
+                        match entries {
+                            Ok(entries) => {
+                                set_password(options, &mut zipfile, entries, file);
+                                let mut extracted = 0u;
+                                let mut failed = 0u;
+                                let wanted = entries.iter().filter(|entry| {
+                                    let name = entry.file_name_as_str();
+                                    is_selected(options, name) && extract_options.is_wanted(&dest_dir, name, entry.modified_date, entry.modified_time)
+                                }).collect::<~[&ZipEntry32]>();
+                                if options.progress {
+                                    zipfile.set_progress_fn(Some(print_progress));
+                                    zipfile.start_progress(wanted.len(), wanted.iter().fold(0u64, |total, entry| total + entry.uncompressed_size as u64));
+                                }
+                                for &entry in wanted.iter() {
+                                    let name = entry.file_name_as_str();
+                                    let start_time = time::precise_time_s();
+                                    match zipfile.extract_entry(entry, &dest_dir, &extract_options) {
+                                        Ok(size)    => {
+                                            let details = if options.verbose > 0 {
+                                                format!("  ({:s} {:.0f}%){:s}", entry.method_as_str(), entry.compression_ratio(), format_timing(options, start_time))
+                                            } else {
+                                                ~""
+                                            };
+                                            results.push(Info(format!("  extracted: {:s}  ({:u} bytes){:s}", name, size as uint, details)));
+                                            extracted += 1;
+                                        },
+                                        Err(err)    => {
+                                            results.push(Error(format!("  error:     {:s}  {:s}", name, err)));
+                                            failed += 1;
+                                        }
+                                    }
+                                }
+                                if failed > 0 {
+                                    results.push(Error(format!("{:u} of {:u} entries failed to extract.", failed, extracted + failed)));
+                                }
+                            },
+                            Err(errstr) =>
+                                results.push(Error(format!("{:s} {:s}", errstr, filepath.as_str().unwrap_or(""))))
                         }
                     }
                     Err(errstr) =>
-                        results.push(format!("{:s} {:s}", errstr, filepath.as_str().unwrap_or("")))
+                        results.push(Error(format!("{:s} {:s}", errstr, filepath.as_str().unwrap_or(""))))
                 }
             },
             None => 
-                results.push(format!("Failed to open file {:s}", filepath.as_str().unwrap_or("")))
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
+        }
+    });
+
+    results
+}
+
+
+/// Print the progress of the extraction on stderr, over the same line until all the entries are done.
+fn print_progress(progress: &ExtractProgress) {
+    let eta = match progress.eta_secs() {
+        Some(secs)  => format!("{:u}:{:02u}:{:02u}", (secs / 3600) as uint, ((secs / 60) % 60) as uint, (secs % 60) as uint),
+        None        => ~"-:--:--"
+    };
+    let entry_percent = if progress.entry_size > 0 { progress.entry_done as f64 * 100f64 / progress.entry_size as f64 } else { 100f64 };
+    let line = format!("\r{:5.1f}%  {:u}/{:u} files  {:u}/{:u} bytes  ETA {:s}  {:s} {:3.0f}%\x1b[K{:s}",
+                       progress.percent(), progress.entries_done, progress.entries_total,
+                       progress.bytes_done as uint, progress.bytes_total as uint, eta, progress.entry_name, entry_percent,
+                       if progress.entries_done >= progress.entries_total { "\n" } else { "" });
+    stdio::stderr().write(line.as_bytes());
+}
+
+/// Create the -d destination directory with its missing parent directories before extracting, so that a bad
+/// destination fails once up front instead of on each entry.
+fn prepare_dest_dir(options: &Options) -> Result<(), ~str> {
+    let dest_dir = Path::new(options.dest_dir.clone());
+    if dest_dir.is_dir() {
+        return Ok(());
+    }
+    if dest_dir.exists() {
+        return Err(format!("Destination {:s} exists and is not a directory.", options.dest_dir));
+    }
+    match io::result(|| fs::mkdir_recursive(&dest_dir, DEST_DIR_MODE)) {
+        Ok(_)   => Ok(()),
+        Err(e)  => Err(format!("Failed to create the destination directory {:s}.  {:s}", options.dest_dir, e.to_str()))
+    }
+}
+
+/// Ask on stderr whether to overwrite the existing file.
+fn prompt_overwrite(path: &Path) -> bool {
+    stdio::stderr().write(format!("replace {:s}? [y]es, [n]o: ", path.as_str().unwrap_or("")).as_bytes());
+    let mut stdin = BufferedReader::new(stdio::stdin());
+    match stdin.read_line() {
+        Some(line)  => line.trim().starts_with("y") || line.trim().starts_with("Y"),
+        None        => false
+    }
+}
+
+/// Set the password on the zip file if any of the entries is encrypted.  Prompt for it if -P is not given.
+fn set_password(options: &Options, zipfile: &mut ZipFile, entries: &[ZipEntry32], file: &str) {
+    if !entries.iter().any(|entry| entry.is_encrypted()) {
+        return;
+    }
+    match options.password {
+        Some(ref password) => zipfile.set_password(*password),
+        None if !options.prompt => (),
+        None => {
+            // Prompt on stderr to keep stdout clean for -p.
+            stdio::stderr().write(format!("[{:s}] password: ", file).as_bytes());
+            let mut stdin = BufferedReader::new(stdio::stdin());
+            set_terminal_echo(false);
+            let line = stdin.read_line();
+            set_terminal_echo(true);
+            stdio::stderr().write(bytes!("\n"));
+            match line {
+                Some(line)  => zipfile.set_password(line.trim_right_chars(&['\r', '\n'])),
+                None        => ()
+            }
+        }
+    }
+}
+
+/// Turn the echo of the terminal on or off with stty, to read the password without showing it.
+/// Where stty is not available, e.g. on Windows, the password is echoed.
+fn set_terminal_echo(on: bool) {
+    io_error::cond.trap(|_| ()).inside(|| {
+        run::process_status("stty", [if on { ~"echo" } else { ~"-echo" }]);
+    });
+}
+
+/// Extract the named entries of the archive, the first file in the list, to stdout with no other output.
+/// The entry names can be wildcard patterns.  Return the error lines, for printing to stderr.
+fn pipe_entries(options: &Options) -> ~[~str] {
+    let mut errors : ~[~str] = ~[];
+
+    let archive = options.files[0].clone();
+    let names = options.files.slice_from(1);
+
+    io_error::cond.trap(|c| {
+        errors.push(c.to_str());
+    }).inside(|| {
+        match File::open_mode(&Path::new(archive.clone()), Open, Read) {
+            Some(stream_reader) => {
+                match ZipFile::open(stream_reader) {
+                    Ok(zipfile) => {
+                        let mut zipfile = zipfile;
+                        match zipfile.get_zip_entries() {
+                            Ok(entries) => {
+                                set_password(options, &mut zipfile, entries, archive);
+                                let mut stdout = stdio::stdout();
+                                for name in names.iter() {
+                                    let mut found = false;
+                                    for entry in entries.iter().filter(|entry| !entry.is_dir()) {
+                                        if glob_match(name.as_bytes(), entry.file_name_as_str().as_bytes()) {
+                                            zipfile.extract_to_writer(entry, &mut stdout);
+                                            found = true;
+                                        }
+                                    }
+                                    if !found {
+                                        errors.push(format!("{:s}: {:s} not found in archive.", archive, *name));
+                                    }
+                                }
+                            },
+                            Err(errstr) =>
+                                errors.push(format!("{:s} {:s}", errstr, archive))
+                        }
+                    },
+                    Err(errstr) =>
+                        errors.push(format!("{:s} {:s}", errstr, archive))
+                }
+            },
+            None =>
+                errors.push(format!("Failed to open file {:s}", archive))
+        }
+    });
+
+    errors
+}
+
+/// Print a line to stderr.
+fn eprintln(line: &str) {
+    stdio::stderr().write((line + "\n").as_bytes());
+}
+
+/// Test the archive by decompressing each entry and checking its CRC.  The failed entries are reported as errors.
+fn test_file(options: &Options, file: &str) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    // Check for valid filetype
+    let filepath = Path::new(file);
+    match check_zip_suffix(file) {
+        Some(err) => return ~[Error(err)],
+        None => ()
+    }
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        match File::open_mode(&filepath, Open, Read) {
+            Some(stream_reader) => {
+                match ZipFile::open(stream_reader) {
+                    Ok(zipfile) => {
+                        let mut zipfile = zipfile;
+                        results.push(Info(format!("Archive:  {:s}", file)));
+                        match zipfile.get_zip_entries() {
+                            Ok(entries) => {
+                                set_password(options, &mut zipfile, entries, file);
+                                let mut tested = 0u;
+                                let mut failed = 0u;
+                                for entry in entries.iter().filter(|entry| is_selected(options, entry.file_name_as_str())) {
+                                    let name = entry.file_name_as_str();
+                                    match zipfile.verify_entry(entry) {
+                                        Ok(_)       => results.push(Info(format!("    testing: {:s}  OK", name))),
+                                        Err(err)    => {
+                                            results.push(Error(format!("    testing: {:s}  FAILED  {:s}", name, err)));
+                                            failed += 1;
+                                        }
+                                    }
+                                    tested += 1;
+                                }
+                                let mut layout_failed = 0u;
+                                for problem in check_layout(options, &mut zipfile).iter() {
+                                    results.push(Error(format!("    testing layout: FAILED  {:s}", *problem)));
+                                    layout_failed += 1;
+                                }
+                                if layout_failed > 0 {
+                                    results.push(Error(format!("{:u} layout problems in {:s}: FAILED", layout_failed, file)));
+                                }
+                                if failed == 0 && layout_failed == 0 {
+                                    results.push(Info(format!("No errors detected in {:s}, {:u} entries tested: OK", file, tested)));
+                                } else if failed > 0 {
+                                    results.push(Error(format!("{:u} of {:u} entries failed in {:s}: FAILED", failed, tested, file)));
+                                }
+                            },
+                            Err(errstr) =>
+                                results.push(Error(format!("{:s} {:s}: FAILED", errstr, file)))
+                        }
+                    }
+                    Err(errstr) =>
+                        results.push(Error(format!("{:s} {:s}: FAILED", errstr, file)))
+                }
+            },
+            None =>
+                results.push(Error(format!("Failed to open file {:s}", filepath.as_str().unwrap_or(""))))
+        }
+    });
+
+    results
+}
+
+
+/// Check the archive against the --jar, --apk, --epub or --odf preset and the --align alignment, for -t.
+/// Return the problems found.
+fn check_layout(options: &Options, zipfile: &mut ZipFile) -> ~[~str] {
+    let mut problems = ~[];
+    match options.preset {
+        Some(ref preset) => {
+            match preset.check(zipfile) {
+                Ok(found)   => problems.push_all_move(found),
+                Err(errstr) => problems.push(errstr)
+            }
+        },
+        None => ()
+    }
+    if options.alignment > 1 {
+        match zipfile.check_alignment(options.alignment) {
+            Ok(misaligned)  => problems.push_all_move(misaligned.map(|name| format!("{:s} is not aligned to {:u} bytes.", *name, options.alignment))),
+            Err(errstr)     => problems.push(errstr)
+        }
+    }
+    problems
+}
+
+/// The entry name of the path in the archive, relative and with / as separator.
+fn get_entry_name(path: &Path) -> ~str {
+    let name = path.as_str().unwrap_or("").replace("\\", "/");
+    let mut name = name.as_slice();
+    while name.starts_with("./") {
+        name = name.slice_from(2);
+    }
+    name.trim_left_chars(&'/').to_owned()
+}
+
+/// Collect the file, or the directory and everything under it, to add to the archive.  The archive itself is skipped.
+/// Only the paths with their entry names selected by the include and exclude patterns are collected.
+fn collect_paths(options: &Options, path: &Path, archive_name: &str, paths: &mut ~[Path]) {
+    if get_entry_name(path).as_slice() == archive_name {
+        return;
+    }
+    if is_selected(options, get_path_entry_name(options, path)) {
+        paths.push(path.clone());
+    }
+    if path.is_dir() && !is_stored_link(options, path) {
+        for child_path in fs::readdir(path).iter() {
+            collect_paths(options, child_path, archive_name, paths);
+        }
+    }
+}
+
+/// Collect all the paths of the file arguments after the archive.
+fn collect_file_args(options: &Options, results: &mut ~[Report]) -> ~[Path] {
+    let archive_name = get_entry_name(&Path::new(options.files[0].clone()));
+    let mut paths = ~[];
+    for file in options.files.slice_from(1).iter() {
+        let path = Path::new(file.clone());
+        if path.exists() {
+            collect_paths(options, &path, archive_name, &mut paths);
+        } else {
+            results.push(Error(format!("  error:  {:s}  File not found.", *file)));
+        }
+    }
+    paths
+}
+
+/// Check whether the path is a symlink to store as a link, with -y.
+fn is_stored_link(options: &Options, path: &Path) -> bool {
+    if !options.symlinks {
+        return false;
+    }
+    match fs::lstat(path).kind {
+        io::TypeSymlink => true,
+        _               => false
+    }
+}
+
+/// The entry name of the path in the archive.  A directory's entry name ends with /.
+fn get_path_entry_name(options: &Options, path: &Path) -> ~str {
+    let name = get_entry_name(path);
+    if path.is_dir() && !is_stored_link(options, path) && name.len() > 0 { name + "/" } else { name }
+}
+
+/// Add the file or the directory at the path as an entry.
+fn add_entry(zip_writer: &mut ZipWriter, path: &Path, options: &Options, results: &mut ~[Report]) {
+    let name = get_path_entry_name(options, path);
+    if name.len() == 0 {
+        return;
+    }
+    if is_stored_link(options, path) {
+        let target = fs::readlink(path).unwrap_or(Path::new(""));
+        match zip_writer.add_symlink(name, target.as_str().unwrap_or(""), fs::lstat(path).modified) {
+            Ok(_)       => results.push(Info(format!("  adding: {:s} -> {:s}", name, target.as_str().unwrap_or("")))),
+            Err(err)    => results.push(Error(format!("  error:  {:s}  {:s}", name, err)))
+        }
+    } else if path.is_dir() {
+        let stat = fs::stat(path);
+        match zip_writer.add_dir(name, stat.modified, stat.perm as u32) {
+            Ok(_)       => results.push(Info(format!("  adding: {:s}", name))),
+            Err(err)    => results.push(Error(format!("  error:  {:s}  {:s}", name, err)))
+        }
+    } else {
+        let start_time = time::precise_time_s();
+        let method = match options.preset {
+            Some(ref preset)    => preset.method_for(name),
+            None                => options.method
+        };
+        match zip_writer.add_file(path, name, method, options.compress_level) {
+            Ok(entry) => {
+                let method = if entry.compression_method == zip::METHOD_STORE { "stored" } else { "deflated" };
+                let details = if options.verbose > 0 {
+                    format!("  {:u} -> {:u} bytes{:s}", entry.uncompressed_size as uint, entry.compressed_size as uint, format_timing(options, start_time))
+                } else {
+                    ~""
+                };
+                results.push(Info(format!("  adding: {:s}  ({:s} {:.0f}%){:s}", name, method, entry.compression_ratio(), details)));
+            },
+            Err(err) =>
+                results.push(Error(format!("  error:  {:s}  {:s}", name, err)))
+        }
+    }
+}
+
+/// Set up the zip writer for the --jar or --apk preset.  An explicit --align overrides the preset's alignment.
+fn set_preset(options: &Options, zip_writer: &mut ZipWriter) {
+    match options.preset {
+        Some(ref preset)    => preset.apply(zip_writer),
+        None                => ()
+    }
+    if options.alignment > 0 {
+        zip_writer.set_alignment(options.alignment);
+    }
+}
+
+/// Create the archive, the first file in the list, with the rest of the files and directories.
+fn create_archive(options: &Options) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    let archive = options.files[0].clone();
+    match check_zip_suffix(archive) {
+        Some(err) => return ~[Error(err)],
+        None => ()
+    }
+    let archive_path = Path::new(archive.clone());
+    if archive_path.exists() && !options.force {
+        return ~[Error(format!("File {:s} already exists.  Use -f to overwrite it.", archive))];
+    }
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        let paths = collect_file_args(options, &mut results);
+        let paths = match options.preset {
+            Some(ref preset)    => preset.order(paths, |path| get_path_entry_name(options, path)),
+            None                => paths
+        };
+        match File::open_mode(&archive_path, Truncate, Write) {
+            Some(file) => {
+                let mut zip_writer = ZipWriter::new(file);
+                zip_writer.set_auto_store(options.auto_store);
+                zip_writer.set_ntfs_times(options.ntfs_times);
+                set_preset(options, &mut zip_writer);
+                for path in paths.iter() {
+                    add_entry(&mut zip_writer, path, options, &mut results);
+                }
+                zip_writer.finish();
+            },
+            None =>
+                results.push(Error(format!("Failed to open file {:s} for write.", archive)))
+        }
+    });
+
+    results
+}
+
+/// Create the archive of all the files and directories in the list, streamed to stdout with -c.
+/// The deflated entries have their CRC and sizes in the data descriptors after the data.  Only the regular files
+/// are added, since the stream writer takes the entries from readers.  Return the error lines, for printing to stderr.
+fn create_to_stdout(options: &Options) -> ~[~str] {
+    let mut errors : ~[~str] = ~[];
+
+    io_error::cond.trap(|c| {
+        errors.push(c.to_str());
+    }).inside(|| {
+        let mut paths = ~[];
+        for file in options.files.iter() {
+            let path = Path::new(file.clone());
+            if path.exists() {
+                // No entry name is "/"; there's no archive file to skip.
+                collect_paths(options, &path, "/", &mut paths);
+            } else {
+                errors.push(format!("  error:  {:s}  File not found.", *file));
+            }
+        }
+        let mut zip_writer = ZipStreamWriter::new(stdio::stdout());
+        for path in paths.iter() {
+            let name = get_path_entry_name(options, path);
+            if name.len() == 0 || path.is_dir() || is_stored_link(options, path) {
+                continue;
+            }
+            let stat = fs::stat(path);
+            let mut entry_options = EntryOptions::new();
+            entry_options.method = options.method;
+            entry_options.compress_level = options.compress_level;
+            entry_options.mtime = stat.modified;
+            entry_options.unix_mode = 0x8000 | stat.perm as u32;     // S_IFREG
+            match File::open_mode(path, Open, Read) {
+                Some(file) => {
+                    let mut file = file;
+                    match zip_writer.add_reader(&mut file, name, &entry_options) {
+                        Ok(_)       => (),
+                        Err(err)    => errors.push(format!("  error:  {:s}  {:s}", name, err))
+                    }
+                },
+                None =>
+                    errors.push(format!("Failed to open file {:s}", name))
+            }
+        }
+        match zip_writer.finish() {
+            Ok(_)       => (),
+            Err(err)    => errors.push(err)
+        }
+    });
+
+    errors
+}
+
+/// Add the files to the existing archive, replacing the entries of the same names.
+/// With only_newer, as in update, only the entries older than their files are replaced.
+fn add_to_archive(options: &Options, only_newer: bool) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    let archive = options.files[0].clone();
+    match check_zip_suffix(archive) {
+        Some(err) => return ~[Error(err)],
+        None => ()
+    }
+    let archive_path = Path::new(archive.clone());
+    if !archive_path.exists() {
+        return ~[Error(format!("File {:s} not found.", archive))];
+    }
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        let paths = collect_file_args(options, &mut results);
+        let names = paths.map(|path| get_path_entry_name(options, path));
+        // With only_newer, the files not newer than their entries are left unchanged.
+        let entries = if only_newer {
+            match read_zip_entries(&archive_path) {
+                Ok(entries) => entries,
+                Err(errstr) => {
+                    results.push(Error(format!("{:s} {:s}", errstr, archive)));
+                    return;
+                }
+            }
+        } else {
+            ~[]
+        };
+        let unchanged = paths.iter().zip(names.iter()).map(|(path, name)| {
+            !path.is_dir() && entries.iter().any(|entry| entry.file_name_as_str() == *name && !entry.is_older_than(fs::stat(path).modified))
+        }).collect::<~[bool]>();
+        let result = zip::modify_zip_file(&archive_path,
+            // Drop the entries to be replaced.
+            |entry| {
+                match names.position_elem(&entry.file_name_as_str()) {
+                    Some(i) => !unchanged[i],
+                    None    => false
+                }
+            },
+            |zip_writer| {
+                zip_writer.set_auto_store(options.auto_store);
+                zip_writer.set_ntfs_times(options.ntfs_times);
+                set_preset(options, zip_writer);
+                for (i, path) in paths.iter().enumerate() {
+                    if !unchanged[i] {
+                        add_entry(zip_writer, path, options, &mut results);
+                    }
+                }
+            });
+        match result {
+            Ok(_)       => (),
+            Err(errstr) => results.push(Error(format!("{:s} {:s}", errstr, archive)))
+        }
+    });
+
+    results
+}
+
+/// Read all the entries of the zip file.
+fn read_zip_entries(archive_path: &Path) -> Result<~[ZipEntry32], ~str> {
+    match File::open_mode(archive_path, Open, Read) {
+        Some(file) => {
+            match ZipFile::open(file) {
+                Ok(zipfile) => {
+                    let mut zipfile = zipfile;
+                    zipfile.get_zip_entries()
+                },
+                Err(errstr) => Err(errstr)
+            }
+        },
+        None => Err(format!("Failed to open file {:s}", archive_path.as_str().unwrap_or("")))
+    }
+}
+
+/// Delete the entries matching the patterns from the archive.
+fn delete_from_archive(options: &Options) -> ~[Report] {
+    let mut results : ~[Report] = ~[];
+
+    let archive = options.files[0].clone();
+    match check_zip_suffix(archive) {
+        Some(err) => return ~[Error(err)],
+        None => ()
+    }
+    let patterns = options.files.slice_from(1);
+
+    io_error::cond.trap(|c| {
+        results.push(Error(c.to_str()));
+    }).inside(|| {
+        let mut deleted = 0u;
+        let result = zip::modify_zip_file(&Path::new(archive.clone()),
+            |entry| {
+                let entry_name = entry.file_name_as_str();
+                if patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), entry_name.as_bytes())) {
+                    results.push(Info(format!("  deleting: {:s}", entry_name)));
+                    deleted += 1;
+                    true
+                } else {
+                    false
+                }
+            },
+            |_| ());
+        match result {
+            Ok(_) if deleted == 0   => results.push(Warning(~"  No matching entries to delete.")),
+            Ok(_)                   => (),
+            Err(errstr)             => results.push(Error(format!("{:s} {:s}", errstr, archive)))
         }
     });
 
     results
 }
 
+/// Check whether the entry name is selected by the -i include and -X exclude patterns.
+/// Without any include pattern, all names are included.
+fn is_selected(options: &Options, name: &str) -> bool {
+    let included = options.include.len() == 0 || options.include.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()));
+    included && !options.exclude.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+}
+
+/// The time taken since the start time, shown with -v.
+fn format_timing(options: &Options, start_time: f64) -> ~str {
+    if options.verbose > 0 {
+        format!("  {:.3f}s", time::precise_time_s() - start_time)
+    } else {
+        ~""
+    }
+}
+
+/// Print the reports, info to stdout, warnings and errors to stderr.  Return the exit status for the reports.
+/// -q suppresses all but the errors and the listing.
+fn print_reports(options: &Options, reports: ~[Report]) -> int {
+    let listing = match options.cmd { LIST | ZIPINFO => true, _ => false };
+    let mut status = EXIT_OK;
+    for report in reports.iter() {
+        match *report {
+            Info(ref line) => {
+                if line.len() > 0 && (listing || !options.quiet) {
+                    println(*line);
+                }
+            },
+            Warning(ref line) => {
+                if !options.quiet {
+                    eprintln(*line);
+                }
+                status = merge_status(status, EXIT_WARNING);
+            },
+            Error(ref line) => {
+                eprintln(*line);
+                status = merge_status(status, EXIT_ERROR);
+            }
+        }
+    }
+    status
+}
+
+/// Check whether processing the archives may prompt on the terminal, so that they have to be processed one by one.
+/// The parallel tasks never prompt for the password; the encrypted entries fail without -P.
+fn needs_terminal(options: &Options) -> bool {
+    match options.cmd {
+        EXTRACT => {
+            let prompt_overwrite = match options.overwrite {
+                OverwritePrompt(_)  => true,
+                _                   => false
+            };
+            prompt_overwrite || options.progress
+        },
+        _ => false
+    }
+}
 
-fn print_lines(lines: ~[~str]) {
-    for line in lines.iter() {
-        if line.len() > 0 {
-            println(*line);
+/// Process each of the archive files with process_fn, on up to one task per scheduler thread at a time when there
+/// are several.  The reports of each archive are printed together, in the order of the files.  Return the exit status.
+fn process_files(args: &~[~str], options: &Options, process_fn: fn(&Options, &str) -> ~[Report]) -> int {
+    let mut status = EXIT_OK;
+    if options.files.len() < 2 || needs_terminal(options) {
+        for file in options.files.iter() {
+            status = merge_status(status, print_reports(options, process_fn(options, *file)));
         }
+        return status;
+    }
+
+    let num_tasks = num::max(rt::default_sched_threads(), 1u);
+    for files in options.files.chunks(num_tasks) {
+        let futures = files.iter().map(|file| {
+            let args = args.clone();
+            let file = file.clone();
+            Future::spawn(proc() {
+                // Each task gets its own options, parsed from the same arguments.
+                let mut options = Options::from_args(&args).unwrap();
+                options.prompt = false;
+                process_fn(&options, file)
+            })
+        }).collect::<~[Future<~[Report]>]>();
+        for future in futures.move_iter() {
+            status = merge_status(status, print_reports(options, future.unwrap()));
+        }
+    }
+    status
+}
+
+/// Merge exit statuses.  Error takes precedence over warning.
+fn merge_status(status: int, new_status: int) -> int {
+    if status == EXIT_ERROR || new_status == EXIT_ERROR {
+        EXIT_ERROR
+    } else {
+        num::max(status, new_status)
     }
 }
 
 fn main()  {
     
     let args = os::args();
+    let mut status = EXIT_OK;
     match Options::from_args(&args) {
         Ok(options) => {
+            if options.verbose > 1 {
+                trace::set_trace_fn(Some(eprintln));
+            }
             match options.cmd {
                 HELP =>
                     print_usage(&args),
                 VERSION =>
                     print_version(&args),
-                LIST => {
-                    for file in options.files.iter() {
-                        print_lines(list_file(*file));
+                LIST | ZIPINFO => {
+                    status = merge_status(status, process_files(&args, &options, list_file));
+                },
+                CREATE if options.stdout && options.files.len() > 0 => {
+                    let errors = create_to_stdout(&options);
+                    for line in errors.iter() {
+                        eprintln(*line);
+                    }
+                    if errors.len() > 0 {
+                        status = EXIT_ERROR;
                     }
                 },
-                _ => ()
-
+                CREATE | ADD | UPDATE | DELETE if options.files.len() < 2 => {
+                    eprintln("Missing archive or file(s)");
+                    print_usage(&args);
+                    status = EXIT_ERROR;
+                },
+                CREATE => {
+                    status = merge_status(status, print_reports(&options, create_archive(&options)));
+                },
+                ADD => {
+                    status = merge_status(status, print_reports(&options, add_to_archive(&options, false)));
+                },
+                UPDATE => {
+                    status = merge_status(status, print_reports(&options, add_to_archive(&options, true)));
+                },
+                DELETE => {
+                    status = merge_status(status, print_reports(&options, delete_from_archive(&options)));
+                },
+                TEST => {
+                    status = merge_status(status, process_files(&args, &options, test_file));
+                },
+                PIPE if options.files.len() < 2 => {
+                    eprintln("Missing archive or entry name(s)");
+                    status = EXIT_ERROR;
+                },
+                PIPE => {
+                    let errors = pipe_entries(&options);
+                    for line in errors.iter() {
+                        eprintln(*line);
+                    }
+                    if errors.len() > 0 {
+                        status = EXIT_ERROR;
+                    }
+                },
+                EXTRACT => {
+                    match prepare_dest_dir(&options) {
+                        Ok(_)   => status = merge_status(status, process_files(&args, &options, extract_file)),
+                        Err(s)  => {
+                            eprintln(s);
+                            status = EXIT_ERROR;
+                        }
+                    }
+                }
             }
         },
         Err(err) => {
             println(format!("\n{:s}\n", err));
             print_usage(&args);
+            status = EXIT_ERROR;
         }
     }
+    os::set_exit_status(status);
 }